@@ -1,35 +1,258 @@
-use super::commits::{Change, CommitDb};
+use super::commits::{to_datetime, Change, CommitDb, CommitInfo};
+use super::dependency::{Dependencies, Dependency, RelOp};
+use super::diff::{diff_snapshots, format_dependency, MergeSimulation, OverrideConflict, Snapshot};
 use super::entities::{
-    package_changes, package_dependencies, package_duplicate, package_errors, package_spec,
-    package_testing, package_versions, packages, prelude::*, tree_branches, trees,
+    collector_meta, deferred_packages, event_consumers, events_outbox, package_arch_versions,
+    package_architectures, package_changes, package_dependencies, package_dependency_counts,
+    package_duplicate, package_duplicate_resolution, package_error_events, package_errors,
+    package_groups, package_moves, package_spec, package_stats, package_sync_status,
+    package_testing, package_testing_spec, package_update_sources, package_versions, packages,
+    prelude::*, scan_run_packages, tree_branches, trees,
 };
-use super::{exec, get_full_version, replace_many, InstertExt};
-use crate::config::{Global, Repo};
+use super::hash::parse_stored;
+use super::page::{into_page, Page, PageRequest};
+use super::validate::{InvariantViolation, Validate};
+use super::verify::{compare_package, Verification};
+use super::{
+    check_schema, connect, connect_read_only, digest, exec, format_full_version, get_full_version,
+    get_schema_meta, replace_many, set_schema_meta, setup_schema, table_columns, InstertExt, Role,
+};
+use crate::config::{BranchFilter, Global, Repo, TreeId, ValueLimits};
 use crate::db::CreateTable;
 use crate::git::Repository;
-use crate::package::Meta;
+use crate::package::{
+    parse_chkupdate, scan_groups, scan_package, scan_packages, section_source,
+    spec_path_to_defines_path, typed_relationships, unknown_keys, Meta, VersionSource,
+};
+use crate::report::LatencySummary;
 use crate::skip_none;
+use crate::warnings::Warnings;
 use abbs_meta_tree::Package;
 use anyhow::{bail, Result};
+use chrono::Local;
 use git2::Oid;
 use itertools::Itertools;
+use regex::Regex;
+use sea_orm::prelude::DateTimeWithTimeZone;
+use sea_orm::sea_query::{Expr, Func, Query, SelectStatement};
 use sea_orm::{entity::*, query::*};
-use sea_orm::{ConnectionTrait, Database, DatabaseConnection, EntityTrait, QueryFilter};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DatabaseTransaction, EntityTrait, QueryFilter, Statement,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use tracing::info;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tracing::log::warn;
+use tracing::{debug, info};
 
 pub struct AbbsDb {
     conn: DatabaseConnection,
-    tree: String,
+    /// connection of the commit tables, the same as conn unless they are separate
+    commits_conn: DatabaseConnection,
+    tree: TreeId,
     branch: String,
+    name_pattern: Regex,
+    reject_invalid_names: bool,
+    /// validate rows before writing them, see [Validate]
+    strict_writes: bool,
+    mass_change_threshold: usize,
+    store_testing_spec: bool,
+    testing_branch_max_age_days: Option<u64>,
+    testing_branch_filter: BranchFilter,
+    architectures: Vec<String>,
+    /// architectures packages of the tree are built for, unless excluded by FAIL_ARCH
+    build_architectures: Vec<String>,
+    flapping_runs: u64,
+    flapping_threshold: usize,
+    url_template: Option<String>,
+    value_limits: ValueLimits,
+    extra_spec_keys: Vec<String>,
+    max_changes_per_package: Option<usize>,
+    /// identifier of the current scan, saved in last_run_id of written rows
+    run_id: Option<String>,
+    warnings: Warnings,
+}
+
+/// Definition of the v_packages view
+///
+/// The digest of this definition is saved in schema_meta, the view is
+/// recreated when the definition changes.
+pub const V_PACKAGES_VIEW: &str = "
+    CREATE VIEW v_packages AS
+    SELECT
+        p.name AS name,
+        p.tree AS tree,
+        t.category AS tree_category,
+        pv.branch AS branch,
+        p.category AS category,
+        section,
+        pkg_section,
+        p.section_source AS section_source,
+        directory,
+        description,
+        version,
+        spec_path,
+        pv.full_version full_version,
+        pv.version_source AS version_source,
+        pv.commit_time AS commit_time,
+        pv.committer AS committer,
+        pv.version_first_commit_time AS version_first_commit_time,
+        pv.version_first_githash AS version_first_githash,
+        COALESCE(pdc.count, 0) AS pkgdep_count,
+        COALESCE(bdc.count, 0) AS builddep_count
+    FROM
+        packages p
+        INNER JOIN trees t ON t.name = p.tree
+        LEFT JOIN package_versions pv ON pv.package = p.name
+        AND pv.branch = t.mainbranch
+        LEFT JOIN package_dependency_counts pdc ON pdc.package = p.name
+        AND pdc.relationship = 'PKGDEP'
+        LEFT JOIN package_dependency_counts bdc ON bdc.package = p.name
+        AND bdc.relationship = 'BUILDDEP'";
+
+/// Definition of the v_package_arch_versions view, versions overridden by
+/// architecture in the main branch of each tree
+pub const V_PACKAGE_ARCH_VERSIONS_VIEW: &str = "
+    CREATE VIEW v_package_arch_versions AS
+    SELECT
+        p.name AS name,
+        p.tree AS tree,
+        pav.branch AS branch,
+        pav.architecture AS architecture,
+        pav.full_version AS full_version
+    FROM
+        packages p
+        INNER JOIN trees t ON t.name = p.tree
+        INNER JOIN package_arch_versions pav ON pav.package = p.name
+        AND pav.branch = t.mainbranch";
+
+/// Definition of the v_trees view, trees with the commit their metadata is current as of
+pub const V_TREES_VIEW: &str = "
+    CREATE VIEW v_trees AS
+    SELECT
+        t.name AS name,
+        t.category AS category,
+        t.url AS url,
+        t.mainbranch AS branch,
+        t.head_commit AS head_commit,
+        t.head_commit_time AS head_commit_time,
+        t.updated_at AS updated_at,
+        COUNT(p.name) AS packages
+    FROM
+        trees t
+        LEFT JOIN packages p ON p.tree = t.name
+    GROUP BY
+        t.tid";
+
+/// Materialized copy of v_packages for queries where the view join is too slow
+pub const M_PACKAGES_VIEW: &str =
+    "CREATE MATERIALIZED VIEW IF NOT EXISTS m_packages AS SELECT * FROM v_packages";
+
+/// Views recreated when their definitions change, with the schema_meta key of their digest
+const VIEWS: [(&str, &str, &str); 3] = [
+    ("v_packages", V_PACKAGES_VIEW, "v_packages_digest"),
+    (
+        "v_package_arch_versions",
+        V_PACKAGE_ARCH_VERSIONS_VIEW,
+        "v_package_arch_versions_digest",
+    ),
+    ("v_trees", V_TREES_VIEW, "v_trees_digest"),
+];
+
+/// schema_meta key of [schema_version]
+const SCHEMA_VERSION_KEY: &str = "abbs_schema_version";
+
+/// Identifier of the tables and views created by [create_schema]
+///
+/// Stamped on every setup, and checked by [AbbsDb::open_read_only] which
+/// doesn't migrate anything. Tables added to [create_schema] must be added here.
+fn schema_version() -> String {
+    let tables = [
+        table_columns(Packages),
+        table_columns(PackageDependencies),
+        table_columns(PackageDependencyCounts),
+        table_columns(PackageDuplicate),
+        table_columns(PackageDuplicateResolution),
+        table_columns(PackageSpec),
+        table_columns(PackageVersions),
+        table_columns(PackageArchVersions),
+        table_columns(PackageArchitectures),
+        table_columns(TreeBranches),
+        table_columns(Trees),
+        table_columns(PackageChanges),
+        table_columns(PackageErrors),
+        table_columns(PackageErrorEvents),
+        table_columns(EventsOutbox),
+        table_columns(EventConsumers),
+        table_columns(PackageTesting),
+        table_columns(PackageTestingSpec),
+        table_columns(PackageUpdateSources),
+        table_columns(PackageSyncStatus),
+        table_columns(PackageGroups),
+        table_columns(PackageMoves),
+        table_columns(PackageStats),
+        table_columns(DeferredPackages),
+        table_columns(ScanRunPackages),
+        table_columns(CollectorMeta),
+    ];
+    let views = VIEWS.map(|(_, definition, _)| definition.to_string());
+
+    digest(tables.into_iter().chain(views).join(";"))
 }
 
+/// Text of a package matched by [AbbsDb::fts_search], indexed by idx_packages_fts
+///
+/// Queries have to use the same expression for the index to be used.
+const FTS_DOCUMENT: &str = "to_tsvector('simple', name || ' ' || description)";
+
+/// Packages matching a web search style query, best matches first
+const FTS_SEARCH_QUERY: &str = "
+    SELECT name, description
+    FROM packages, websearch_to_tsquery('simple', $1) query
+    WHERE to_tsvector('simple', name || ' ' || description) @@ query
+    ORDER BY ts_rank(to_tsvector('simple', name || ' ' || description), query) DESC, name";
+
+/// Keys of package_spec which set the version, overridable by architecture
+/// with a suffix, e.g. PKGVER__RETRO
+const VERSION_KEYS: [&str; 3] = ["PKGVER", "PKGREL", "PKGEPOCH"];
+
+/// Delete changes of packages of the tree beyond the newest $2 of each package,
+/// only of the package $3 unless it is null
+const TRIM_CHANGES: &str = "
+    DELETE FROM package_changes c
+    USING (
+        SELECT package, githash, row_number() OVER (
+            PARTITION BY package ORDER BY timestamp DESC, githash DESC
+        ) AS n
+        FROM package_changes
+        WHERE tree = $1 AND ($3::varchar IS NULL OR package = $3)
+    ) ranked
+    WHERE c.package = ranked.package AND c.githash = ranked.githash AND ranked.n > $2";
+
+/// Number of collector_meta entries to keep
+const COLLECTOR_META_KEEP: u64 = 100;
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ErrorType {
     Parse,
     Package,
+    /// the same name is provided by multiple packages
+    Provider,
+    /// package name violates naming policy or collides with another package
+    Name,
+    /// malformed CHKUPDATE
+    UpdateSource,
+    /// PKGDES is missing or empty
+    Description,
+    /// a value is truncated to its limit or contains NUL bytes
+    Value,
+    /// a row breaks an invariant of its table, with strict writes
+    Invariant,
+    /// a versioned dependency isn't satisfied by the version in the tree
+    Constraint,
+    /// an unknown key of spec or defines is close to a known one, likely misspelled
+    SpecKey,
 }
 
 impl ToString for ErrorType {
@@ -37,11 +260,548 @@ impl ToString for ErrorType {
         match self {
             Self::Parse => "parse",
             Self::Package => "package",
+            Self::Provider => "provider",
+            Self::Name => "name",
+            Self::UpdateSource => "update_source",
+            Self::Description => "description",
+            Self::Value => "value",
+            Self::Invariant => "invariant",
+            Self::Constraint => "constraint",
+            Self::SpecKey => "spec_key",
         }
         .to_string()
     }
 }
 
+impl FromStr for ErrorType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "parse" => Self::Parse,
+            "package" => Self::Package,
+            "provider" => Self::Provider,
+            "name" => Self::Name,
+            "update_source" => Self::UpdateSource,
+            "description" => Self::Description,
+            "value" => Self::Value,
+            "invariant" => Self::Invariant,
+            "constraint" => Self::Constraint,
+            "spec_key" => Self::SpecKey,
+            _ => bail!("unknown error type {s}"),
+        })
+    }
+}
+
+/// Error types recorded by the reconcile pass, kept when a single package is written
+fn reconcile_error_types() -> [String; 2] {
+    [
+        ErrorType::Provider.to_string(),
+        ErrorType::Constraint.to_string(),
+    ]
+}
+
+/// A versioned dependency checked against the versions of the tree
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ConstraintCheck {
+    pub package: String,
+    pub relationship: String,
+    /// empty for all architectures
+    pub architecture: String,
+    /// e.g. libfoo>=2.0
+    pub constraint: String,
+    /// package of the tree the dependency resolves to, directly or by PKGPROV
+    pub provider: Option<String>,
+    /// full version of the provider in the main branch, or the version it provides
+    pub version: Option<String>,
+}
+
+/// Versioned dependencies which can't be installed with the versions of the tree
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UnsatisfiedConstraints {
+    /// the provider version doesn't satisfy the constraint
+    pub violated: Vec<ConstraintCheck>,
+    /// no package of the tree provides the dependency, it may come from another tree
+    pub unevaluable: Vec<ConstraintCheck>,
+}
+
+/// A package which declares the name in PKGPROV
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Provider {
+    pub package: String,
+    pub tree: String,
+    pub priority: i32,
+}
+
+/// Package version of a testing branch
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TestingInfo {
+    pub branch: String,
+    pub version: String,
+    pub commit: String,
+    pub spec_path: String,
+}
+
+/// Latest change of a package on a branch
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageBranch {
+    /// without the origin/ prefix
+    pub branch: String,
+    pub version: String,
+    pub commit_time: String,
+}
+
+/// Latest version and commit time of a package on each branch, from commits
+///
+/// Branches are filtered by [AbbsDb::get_package_branches], package_testing
+/// may live in another database than commits.
+const PACKAGE_BRANCHES_QUERY: &str = "
+    SELECT DISTINCT ON (c.branch) c.branch, c.pkg_version, c.commit_time
+    FROM (
+        SELECT regexp_replace(branch, '^origin/', '') AS branch, pkg_version, commit_time
+        FROM commits
+        WHERE pkg_name = $1 AND tree = $2
+    ) c
+    ORDER BY c.branch, c.commit_time DESC";
+
+/// Topic branches of a tree which still have rows in package_testing
+const TESTING_BRANCHES_QUERY: &str = "
+    SELECT DISTINCT regexp_replace(branch, '^origin/', '') AS branch
+    FROM package_testing
+    WHERE tree = $1";
+
+/// Packages of a tree without changes since $2, newest change excluding mass changes first
+const INACTIVE_PACKAGES_QUERY: &str = "
+    SELECT p.name, p.section, c.timestamp, c.maintainer
+    FROM packages p
+    LEFT JOIN (
+        SELECT DISTINCT ON (package)
+            package, timestamp, maintainer_name || ' <' || maintainer_email || '>' AS maintainer
+        FROM package_changes
+        WHERE tree = $1 AND NOT mass_change
+        ORDER BY package, timestamp DESC
+    ) c ON c.package = p.name
+    WHERE p.tree = $1 AND (c.timestamp IS NULL OR c.timestamp < $2)
+    ORDER BY c.timestamp ASC NULLS FIRST, p.name";
+
+/// A package nobody changed recently, see [AbbsDb::get_inactive_packages]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct InactivePackage {
+    pub package: String,
+    pub section: String,
+    /// newest change which is not a mass change, none if there is no such change
+    pub last_change_time: Option<DateTimeWithTimeZone>,
+    /// committer of the newest change, like `Name <email>`
+    pub last_maintainer: Option<String>,
+}
+
+/// A package found in multiple locations
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DuplicatePackage {
+    pub package: String,
+    pub locations: Vec<DuplicateLocation>,
+}
+
+/// A location of a duplicate package, like aosc-os-abbs/app-shells/bash
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DuplicateLocation {
+    pub location: String,
+    /// full version when the location was last scanned
+    pub version: Option<String>,
+    /// this copy is the one in packages
+    pub canonical: bool,
+    /// this copy was chosen with `duplicates resolve`
+    pub preferred: bool,
+    /// the preferred location no longer exists, the choice has no effect
+    pub stale: bool,
+}
+
+/// tree/category-section/directory, e.g. aosc-os-abbs/app-shells/bash
+fn location(tree: &str, category: &str, section: &str, directory: &str) -> String {
+    format!("{tree}/{category}-{section}/{directory}")
+}
+
+/// Package information exported to consumers
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub name: String,
+    pub tree: String,
+    pub category: String,
+    pub section: String,
+    pub pkg_section: String,
+    /// directory or pkgsec, see [crate::package::SectionSource]
+    pub section_source: Option<String>,
+    pub directory: String,
+    pub description: String,
+    pub spec_path: String,
+    /// full version in the main branch
+    pub version: Option<String>,
+    /// spec, defines or preserved, see [VersionSource]
+    pub version_source: Option<String>,
+    /// testing branches carrying changes of the package
+    pub testing: Vec<TestingInfo>,
+    /// where to find new upstream versions, from CHKUPDATE
+    pub update_sources: Vec<UpdateSource>,
+    pub dependencies: Vec<PackageDependency>,
+    /// number of distinct dependencies of each relationship
+    pub dependency_counts: BTreeMap<String, i64>,
+    /// branches changing the package, see [AbbsDb::get_package_branches]
+    pub branches: Vec<PackageBranch>,
+    /// groups listing the package, from the groups directory
+    pub groups: Vec<String>,
+    /// commit of the package last picked up by the build system
+    pub synced_githash: Option<String>,
+    /// the latest change of the package is not synced yet
+    pub out_of_sync: bool,
+    /// earlier locations of the package in the tree, oldest first
+    pub moves: Vec<PackageMove>,
+}
+
+/// Stored rows of a package, see [AbbsDb::get_package_full]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredPackage {
+    pub package: packages::Model,
+    /// full version in the main branch
+    pub version: Option<String>,
+    /// keys and values of package_spec
+    pub spec: BTreeMap<String, String>,
+    /// formatted like PKGDEP glibc>=2.38 [amd64]
+    pub dependencies: BTreeSet<String>,
+}
+
+/// A package directory moved to another location, e.g. on a section rename
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageMove {
+    pub old_spec_path: String,
+    pub new_spec_path: String,
+    /// commit the move was found at
+    pub commit: String,
+    pub commit_time: String,
+}
+
+impl From<package_moves::Model> for PackageMove {
+    fn from(model: package_moves::Model) -> Self {
+        Self {
+            old_spec_path: model.old_spec_path,
+            new_spec_path: model.new_spec_path,
+            commit: model.githash,
+            commit_time: model.commit_time.to_rfc3339(),
+        }
+    }
+}
+
+/// A dependency of a package, e.g. PKGDEP glibc>=2.38 for all architectures
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageDependency {
+    /// e.g. PKGDEP, BUILDDEP
+    pub relationship: String,
+    /// empty for all architectures
+    pub architecture: String,
+    pub dependency: Dependency,
+}
+
+impl TryFrom<package_dependencies::Model> for PackageDependency {
+    type Error = anyhow::Error;
+
+    fn try_from(model: package_dependencies::Model) -> Result<Self> {
+        Ok(Self {
+            dependency: Dependency::try_from(&model)?,
+            relationship: model.relationship,
+            architecture: model.architecture,
+        })
+    }
+}
+
+/// A parameter of CHKUPDATE, e.g. method anitya, key id, value 1234
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UpdateSource {
+    pub package: String,
+    pub method: String,
+    pub key: String,
+    pub value: String,
+}
+
+impl From<package_update_sources::Model> for UpdateSource {
+    fn from(model: package_update_sources::Model) -> Self {
+        Self {
+            package: model.package,
+            method: model.method,
+            key: model.key,
+            value: model.value,
+        }
+    }
+}
+
+/// Number of errors recorded for a package
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ErrorCount {
+    pub total: usize,
+    /// errors which were not recorded before
+    pub new: usize,
+}
+
+/// A row of package listing
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageSummary {
+    pub name: String,
+    pub section: String,
+    pub directory: String,
+    pub version: Option<String>,
+    /// number of testing branches overriding the package
+    pub testing: usize,
+}
+
+/// An error of a package which was introduced or resolved
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ErrorEvent {
+    /// introduced or resolved
+    pub event: String,
+    pub err_type: String,
+    pub message: String,
+    /// commit of the package when the event was recorded, if known
+    pub githash: Option<String>,
+    pub recorded_at: String,
+}
+
+impl ErrorEvent {
+    pub const INTRODUCED: &'static str = "introduced";
+    pub const RESOLVED: &'static str = "resolved";
+}
+
+/// A change of package state appended to events_outbox, see [AbbsDb::poll_events]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEvent {
+    pub id: i64,
+    /// one of the constants of this type, e.g. package_updated
+    pub event_type: String,
+    pub package: String,
+    pub tree: String,
+    pub branch: String,
+    pub payload: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+impl OutboxEvent {
+    /// the package is added or updated on the main branch, with its full_version and errors
+    pub const PACKAGE_UPDATED: &'static str = "package_updated";
+    pub const PACKAGE_DELETED: &'static str = "package_deleted";
+    /// a testing branch changes the package, with its full_version and commit
+    pub const TESTING_UPDATED: &'static str = "testing_updated";
+    /// a testing branch no longer changes the package
+    pub const TESTING_REMOVED: &'static str = "testing_removed";
+}
+
+/// A row referencing a package which doesn't exist
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IntegrityViolation {
+    pub table: String,
+    pub package: String,
+}
+
+/// Version of the [DepMatrix] format, bumped on incompatible changes
+pub const DEP_MATRIX_VERSION: u32 = 1;
+
+/// Dependencies of every package of a tree, for dependency solvers
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DepMatrix {
+    pub format_version: u32,
+    pub tree: String,
+    pub branch: String,
+    /// commit recorded by the last scan, see [AbbsDb::record_head]
+    pub head_commit: Option<String>,
+    pub generated_at: DateTimeWithTimeZone,
+    pub packages: Vec<DepMatrixPackage>,
+    /// provided name to the packages providing it
+    pub provides: BTreeMap<String, Vec<String>>,
+}
+
+/// A package in [DepMatrix]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DepMatrixPackage {
+    pub name: String,
+    /// full version in the main branch
+    pub version: Option<String>,
+    /// relationship, e.g. PKGDEP, to its dependencies
+    pub dependencies: BTreeMap<String, Vec<ArchDependency>>,
+}
+
+/// A dependency for all architectures (empty) or one architecture
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ArchDependency {
+    pub architecture: String,
+    #[serde(flatten)]
+    pub dependency: Dependency,
+}
+
+/// A package depending on another, see [AbbsDb::get_reverse_dependencies]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReverseDependency {
+    pub package: String,
+    #[serde(flatten)]
+    pub dependency: PackageDependency,
+}
+
+/// Relationships followed by [AbbsDb::get_reverse_dependencies]
+const DEPENDENCY_RELATIONSHIPS: [&str; 4] = ["PKGDEP", "BUILDDEP", "PKGRECOM", "PKGSUG"];
+
+/// A package switching between updated and deleted across recent runs
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FlappingPackage {
+    pub package: String,
+    /// number of switches between updated and deleted
+    pub flaps: usize,
+    /// number of runs the package appeared in
+    pub runs: usize,
+}
+
+/// What a scan would do to a package, see [PendingPackage]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingAction {
+    Add,
+    Update,
+    Delete,
+    /// spec or defines is missing, only errors are recorded
+    Broken,
+}
+
+impl PendingAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::Broken => "broken",
+        }
+    }
+}
+
+/// A package a scan would write, reported by dry runs instead of writing it
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PendingPackage {
+    pub package: String,
+    pub action: PendingAction,
+    /// full version after the scan, none for deleted and broken packages
+    pub full_version: Option<String>,
+    /// changes of the package in the commits database, missing commits not scanned yet
+    pub changes: usize,
+    /// errors which would be recorded in package_errors
+    pub errors: Vec<PackageError>,
+}
+
+impl PendingPackage {
+    /// An updated package, added if it isn't in `existing`
+    pub fn updated(meta: &Meta, existing: &HashSet<String>, changes: usize) -> Self {
+        let (pkg, _, errors, _) = meta;
+        let action = if existing.contains(&pkg.name) {
+            PendingAction::Update
+        } else {
+            PendingAction::Add
+        };
+
+        Self {
+            package: pkg.name.clone(),
+            action,
+            full_version: Some(get_full_version(pkg)),
+            changes,
+            errors: errors.clone(),
+        }
+    }
+
+    pub fn deleted(package: &str) -> Self {
+        Self {
+            package: package.to_string(),
+            action: PendingAction::Delete,
+            full_version: None,
+            changes: 0,
+            errors: vec![],
+        }
+    }
+
+    pub fn broken(error: PackageError) -> Self {
+        Self {
+            package: error.package.clone(),
+            action: PendingAction::Broken,
+            full_version: None,
+            changes: 0,
+            errors: vec![error],
+        }
+    }
+}
+
+/// What a name refers to, see [AbbsDb::classify_names]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "class", rename_all = "snake_case")]
+pub enum NameClass {
+    /// a package, `name` is spelled as in the database
+    Package {
+        name: String,
+        tree: String,
+        section: String,
+    },
+    /// declared in PKGPROV of these packages
+    Provided {
+        by: Vec<String>,
+    },
+    Unknown,
+}
+
+/// A row last written by a scan
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunRow {
+    pub table: String,
+    pub package: String,
+    /// branch of the row, for tables keyed by branch
+    pub branch: Option<String>,
+}
+
+impl RunRow {
+    fn new(table: impl EntityName, package: String, branch: Option<String>) -> Self {
+        Self {
+            table: table.table_name().to_string(),
+            package,
+            branch,
+        }
+    }
+}
+
+/// Spec keys changed by a testing branch, compared with the main branch
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SpecDiff {
+    pub package: String,
+    pub branch: String,
+    /// (key, value)
+    pub added: Vec<(String, String)>,
+    /// (key, value)
+    pub removed: Vec<(String, String)>,
+    /// (key, old value, new value)
+    pub changed: Vec<(String, String, String)>,
+}
+
+/// Filters of package listing
+///
+/// Different kinds of filters are combined with AND, repeated values of one
+/// kind with OR. Tree and branch default to the ones of the database.
+#[derive(Debug, Clone, Default)]
+pub struct PackageFilter {
+    pub sections: Vec<String>,
+    pub categories: Vec<String>,
+    pub tree: Option<String>,
+    pub branch: Option<String>,
+}
+
+/// A line of the tree-wide changelog
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChangelogEntry {
+    pub githash: String,
+    pub timestamp: String,
+    /// first line of the commit message
+    pub summary: String,
+    /// packages changed by the commit, more than one for collapsed mass changes
+    pub packages: Vec<(String, String)>,
+    pub mass_change: bool,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PackageError {
     pub package: String,
@@ -50,6 +810,26 @@ pub struct PackageError {
     pub err_type: ErrorType,
     pub line: Option<i32>,
     pub col: Option<i32>,
+    /// end of the range the error applies to, none for errors at a single position
+    pub end_line: Option<i32>,
+    pub end_col: Option<i32>,
+}
+
+impl TryFrom<package_errors::Model> for PackageError {
+    type Error = anyhow::Error;
+
+    fn try_from(model: package_errors::Model) -> Result<Self> {
+        Ok(PackageError {
+            err_type: model.err_type.parse()?,
+            package: model.package,
+            path: model.path,
+            message: model.message,
+            line: model.line,
+            col: model.col,
+            end_line: model.end_line,
+            end_col: model.end_col,
+        })
+    }
 }
 
 impl AbbsDb {
@@ -63,45 +843,11 @@ impl AbbsDb {
             ..
         } = repo_config;
 
-        let conn = Database::connect(global_config.database_url.clone()).await?;
-
-        Packages.create_table(&conn).await?;
-        PackageDependencies.create_table(&conn).await?;
-        PackageDuplicate.create_table(&conn).await?;
-        PackageSpec.create_table(&conn).await?;
-        PackageVersions.create_table(&conn).await?;
-        TreeBranches.create_table(&conn).await?;
-        Trees.create_table(&conn).await?;
-        PackageChanges.create_table(&conn).await?;
-        PackageErrors.create_table(&conn).await?;
-        PackageTesting.create_table(&conn).await?;
-
-        exec(
-            &conn,
-            "
-            CREATE OR REPLACE VIEW v_packages AS
-            SELECT
-                p.name AS name,
-                p.tree AS tree,
-                t.category AS tree_category,
-                pv.branch AS branch,
-                p.category AS category,
-                section,
-                pkg_section,
-                directory,
-                description,
-                version,
-                spec_path,
-                pv.full_version full_version,
-                pv.commit_time AS commit_time,
-                pv.committer AS committer
-            FROM
-                packages p
-                INNER JOIN trees t ON t.name = p.tree
-                LEFT JOIN package_versions pv ON pv.package = p.name
-                AND pv.branch = t.mainbranch",
-            [],
-        )
+        let conn = connect(&global_config.database_url, &global_config.performance).await?;
+        let materialize_packages = global_config.materialize_packages;
+        setup_schema(&conn, Role::Abbs.layout(global_config), move |txn| {
+            Box::pin(create_schema(txn, materialize_packages))
+        })
         .await?;
 
         trees::Model {
@@ -110,8 +856,22 @@ impl AbbsDb {
             category: category.into(),
             url: url.into(),
             mainbranch: branch.into(),
+            head_commit: None,
+            head_commit_time: None,
+            updated_at: None,
         }
-        .replace(&conn, [trees::Column::Tid], trees::Column::iter())
+        // keep the head recorded by the last scan
+        .replace(
+            &conn,
+            [trees::Column::Tid],
+            [
+                trees::Column::Tid,
+                trees::Column::Name,
+                trees::Column::Category,
+                trees::Column::Url,
+                trees::Column::Mainbranch,
+            ],
+        )
         .await?;
 
         tree_branches::Model {
@@ -127,67 +887,661 @@ impl AbbsDb {
         )
         .await?;
 
+        let commits_conn = match global_config.separate_commits_database() {
+            Some(url) => Some(connect(url, &global_config.performance).await?),
+            None => None,
+        };
         info!("abbs db opened");
 
+        Self::new(conn, commits_conn, global_config, repo_config)
+    }
+
+    /// Open a database set up by a scan for queries only
+    ///
+    /// Unlike [Self::open], nothing is created, migrated or written, and the
+    /// schema lock isn't taken, so queries never wait for a running scan. The
+    /// connection is read only, methods writing to the database fail.
+    pub async fn open_read_only(global_config: &Global, repo_config: &Repo) -> Result<Self> {
+        let conn =
+            connect_read_only(&global_config.database_url, &global_config.performance).await?;
+        check_schema(
+            &conn,
+            Role::Abbs.layout(global_config),
+            SCHEMA_VERSION_KEY,
+            &schema_version(),
+        )
+        .await?;
+        let commits_conn = match global_config.separate_commits_database() {
+            Some(url) => Some(connect_read_only(url, &global_config.performance).await?),
+            None => None,
+        };
+
+        Self::new(conn, commits_conn, global_config, repo_config)
+    }
+
+    fn new(
+        conn: DatabaseConnection,
+        commits_conn: Option<DatabaseConnection>,
+        global_config: &Global,
+        repo_config: &Repo,
+    ) -> Result<Self> {
+        let branch = &repo_config.branch;
+
         Ok(Self {
+            commits_conn: commits_conn.unwrap_or_else(|| conn.clone()),
             conn,
-            tree: name.clone(),
+            tree: repo_config.tree_id(),
             branch: branch.clone(),
+            name_pattern: Regex::new(&global_config.package_name_pattern)?,
+            reject_invalid_names: false,
+            strict_writes: false,
+            mass_change_threshold: global_config.mass_change_threshold,
+            store_testing_spec: global_config.store_testing_spec,
+            testing_branch_max_age_days: repo_config.testing_branch_max_age_days,
+            testing_branch_filter: repo_config.testing_branch_filter()?,
+            architectures: global_config.architectures.clone(),
+            build_architectures: repo_config
+                .architectures
+                .clone()
+                .unwrap_or_else(|| global_config.architectures.clone()),
+            flapping_runs: global_config.flapping_runs,
+            flapping_threshold: global_config.flapping_threshold,
+            url_template: repo_config.url_template.clone(),
+            value_limits: global_config.value_limits.clone(),
+            extra_spec_keys: repo_config.extra_spec_keys.clone(),
+            max_changes_per_package: global_config.max_changes_per_package,
+            run_id: None,
+            warnings: Warnings::new(),
         })
     }
 
-    pub async fn add_package(&self, pkg_meta: Meta, pkg_changes: Vec<Change>) -> Result<()> {
-        let (pkg, context, errors) = pkg_meta;
-        let txn = self.conn.begin().await?;
-        let db = &txn;
+    /// Tree the rows written by this instance belong to
+    pub fn tree(&self) -> &TreeId {
+        &self.tree
+    }
 
-        if pkg_changes.is_empty() {
-            bail!("cannot find changes of package, please update commit database")
+    /// Check in debug builds that the repository and this instance belong to the tree
+    pub fn debug_assert_tree(&self, repo: &Repository, tree: &TreeId) {
+        debug_assert_eq!(&repo.tree, tree, "repository opened for another tree");
+        debug_assert_eq!(&self.tree, tree, "abbs db opened for another tree");
+    }
+
+    /// Record the scan identifier on rows written by this instance
+    pub fn with_run_id(mut self, run_id: &str) -> Self {
+        self.run_id = Some(run_id.to_string());
+        self
+    }
+
+    /// Coalesce repeated warnings into the given warnings
+    pub fn with_warnings(mut self, warnings: Warnings) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    /// Skip packages whose name violates naming policy, the errors are still recorded
+    pub fn reject_invalid_names(mut self, reject: bool) -> Self {
+        self.reject_invalid_names = reject;
+        self
+    }
+
+    /// Check rows of packages against the invariants of their tables before writing them
+    ///
+    /// A package with a row breaking an invariant is not written, the
+    /// violation is recorded as an error of the package instead.
+    pub fn strict_writes(mut self, strict: bool) -> Self {
+        self.strict_writes = strict;
+        self
+    }
+
+    /// Fail with the violation if strict writes are enabled and the row breaks an invariant
+    fn check_invariants(&self, model: &impl Validate) -> Result<()> {
+        if self.strict_writes {
+            model.validate()?;
         }
-        let existing = Packages::find_by_id(pkg.name.clone()).one(db).await?;
 
-        if let Some(existing) = existing {
-            let name = &pkg.name;
-            let existing_tree = &existing.tree;
-            let existing_category = &existing.category;
-            let existing_section = &existing.section;
-            let existing_directory = &existing.directory;
-            let tree = &self.tree;
-            let category = &pkg.category;
-            let section = &pkg.section;
-            let directory = &pkg.directory;
+        Ok(())
+    }
 
-            if existing.tree != self.tree {
-                warn!(
-                    "duplicate package \"{name}\" found in different trees {existing_tree}/{existing_category}-{existing_section}/{existing_directory} and {tree}/{category}-{section}/{directory}",
-                );
-                update_duplicate(&pkg, &existing, &self.tree, db).await?;
-            }
+    /// Rows whose last write was made by the scan
+    pub async fn get_run_rows(&self, run_id: &str) -> Result<Vec<RunRow>> {
+        let mut result = vec![];
+        let packages: Vec<String> = Packages::find()
+            .select_only()
+            .column(packages::Column::Name)
+            .filter(packages::Column::LastRunId.eq(run_id))
+            .order_by_asc(packages::Column::Name)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        result.extend(
+            packages
+                .into_iter()
+                .map(|package| RunRow::new(Packages, package, None)),
+        );
 
-            if (&pkg.category, &pkg.section, &pkg.directory)
-                != (&existing.category, &existing.section, &existing.directory)
-            {
+        let versions: Vec<(String, String)> = PackageVersions::find()
+            .select_only()
+            .columns([
+                package_versions::Column::Package,
+                package_versions::Column::Branch,
+            ])
+            .filter(package_versions::Column::LastRunId.eq(run_id))
+            .order_by_asc(package_versions::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        result.extend(
+            versions
+                .into_iter()
+                .map(|(package, branch)| RunRow::new(PackageVersions, package, Some(branch))),
+        );
+
+        let specs: Vec<String> = PackageSpec::find()
+            .select_only()
+            .column(package_spec::Column::Package)
+            .distinct()
+            .filter(package_spec::Column::LastRunId.eq(run_id))
+            .order_by_asc(package_spec::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        result.extend(
+            specs
+                .into_iter()
+                .map(|package| RunRow::new(PackageSpec, package, None)),
+        );
+
+        let errors: Vec<(String, String)> = PackageErrors::find()
+            .select_only()
+            .columns([
+                package_errors::Column::Package,
+                package_errors::Column::Branch,
+            ])
+            .distinct()
+            .filter(package_errors::Column::LastRunId.eq(run_id))
+            .order_by_asc(package_errors::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        result.extend(
+            errors
+                .into_iter()
+                .map(|(package, branch)| RunRow::new(PackageErrors, package, Some(branch))),
+        );
+
+        let testing: Vec<(String, String)> = PackageTesting::find()
+            .select_only()
+            .columns([
+                package_testing::Column::Package,
+                package_testing::Column::Branch,
+            ])
+            .filter(package_testing::Column::LastRunId.eq(run_id))
+            .order_by_asc(package_testing::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+        result.extend(
+            testing
+                .into_iter()
+                .map(|(package, branch)| RunRow::new(PackageTesting, package, Some(branch))),
+        );
+
+        Ok(result)
+    }
+
+    /// Record the collector version and configuration digest of this run
+    ///
+    /// `recovery` is the recovery from a corrupted database taken before it,
+    /// kept so it is still known after the scan report is gone.
+    pub async fn record_collector_meta(
+        &self,
+        config_digest: &str,
+        recovery: Option<&str>,
+    ) -> Result<()> {
+        let version = env!("CARGO_PKG_VERSION");
+        let git_hash = option_env!("ABBS_META_GIT_HASH");
+
+        if let Some(last) = self.get_latest_collector_meta().await? {
+            if last.version != version {
                 warn!(
-                    "duplicate package \"{name}\" found in {existing_category}-{existing_section}/{existing_directory} and {category}-{section}/{directory}",
+                    "database was last written by collector {}, current version is {version}",
+                    last.version
                 );
-                update_duplicate(&pkg, &existing, &self.tree, db).await?;
+            }
+            if last.config_digest != config_digest {
+                info!("configuration changed since the last run");
             }
         }
 
-        packages::Model {
-            name: pkg.name.clone(),
-            tree: self.tree.clone(),
+        collector_meta::ActiveModel {
+            version: Set(version.to_string()),
+            git_hash: Set(git_hash.map(|hash| hash.to_string())),
+            config_digest: Set(config_digest.to_string()),
+            timestamp: Set(Local::now().fixed_offset()),
+            run_id: Set(self.run_id.clone()),
+            id: NotSet,
+            latency: NotSet,
+            recovery: Set(recovery.map(|recovery| recovery.to_string())),
+        }
+        .insert(&self.conn)
+        .await?;
+
+        // keep the last COLLECTOR_META_KEEP entries
+        let oldest = CollectorMeta::find()
+            .order_by_desc(collector_meta::Column::Id)
+            .offset(COLLECTOR_META_KEEP)
+            .one(&self.conn)
+            .await?;
+        if let Some(oldest) = oldest {
+            CollectorMeta::delete_many()
+                .filter(collector_meta::Column::Id.lte(oldest.id))
+                .exec(&self.conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Save the commit to database latency of this run in its collector_meta entry
+    pub async fn record_latency(&self, latency: &LatencySummary) -> Result<()> {
+        let Some(run_id) = &self.run_id else {
+            return Ok(());
+        };
+        CollectorMeta::update_many()
+            .col_expr(
+                collector_meta::Column::Latency,
+                Expr::value(serde_json::to_value(latency)?),
+            )
+            .filter(collector_meta::Column::RunId.eq(run_id))
+            .exec(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the most recent collector_meta entry
+    pub async fn get_latest_collector_meta(&self) -> Result<Option<collector_meta::Model>> {
+        Ok(CollectorMeta::find()
+            .order_by_desc(collector_meta::Column::Id)
+            .one(&self.conn)
+            .await?)
+    }
+
+    /// Packages left unwritten by an earlier scan of the branch, as (name, spec path)
+    pub async fn get_deferred_packages(&self) -> Result<Vec<(String, String)>> {
+        Ok(DeferredPackages::find()
+            .select_only()
+            .columns([
+                deferred_packages::Column::Package,
+                deferred_packages::Column::SpecPath,
+            ])
+            .filter(deferred_packages::Column::Tree.eq(self.tree.to_string()))
+            .filter(deferred_packages::Column::Branch.eq(self.branch.clone()))
+            .order_by_asc(deferred_packages::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?)
+    }
+
+    /// Replace the packages left for the next scan of the branch, as (name, spec path)
+    ///
+    /// Called once the scan is done writing, so packages deferred by an
+    /// earlier scan are kept until they are written.
+    pub async fn set_deferred_packages(&self, packages: &[(String, String)]) -> Result<()> {
+        let txn = self.conn.begin().await?;
+        DeferredPackages::delete_many()
+            .filter(deferred_packages::Column::Tree.eq(self.tree.to_string()))
+            .filter(deferred_packages::Column::Branch.eq(self.branch.clone()))
+            .exec(&txn)
+            .await?;
+
+        let now = Local::now().fixed_offset();
+        let models = packages.iter().map(|(package, spec_path)| {
+            deferred_packages::Model {
+                package: package.clone(),
+                tree: self.tree.to_string(),
+                branch: self.branch.clone(),
+                spec_path: spec_path.clone(),
+                run_id: self.run_id.clone(),
+                deferred_at: now,
+            }
+            .into_active_model()
+        });
+        for chunk in &models.chunks(2048) {
+            replace_many(
+                chunk,
+                [
+                    deferred_packages::Column::Package,
+                    deferred_packages::Column::Tree,
+                    deferred_packages::Column::Branch,
+                ],
+                deferred_packages::Column::iter(),
+            )
+            .exec(&txn)
+            .await?;
+        }
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Save the packages updated and deleted by the current run, for [Self::get_flapping]
+    ///
+    /// Only the last flapping_runs runs changing packages of the tree are kept.
+    pub async fn record_run_packages(&self, updated: &[String], deleted: &[String]) -> Result<()> {
+        let Some(run_id) = &self.run_id else {
+            return Ok(());
+        };
+        let recorded_at = Local::now().fixed_offset();
+        let models = [("updated", updated), ("deleted", deleted)]
+            .into_iter()
+            .flat_map(|(action, packages)| {
+                packages.iter().unique().map(move |package| {
+                    scan_run_packages::Model {
+                        run_id: run_id.clone(),
+                        tree: self.tree.to_string(),
+                        package: package.clone(),
+                        action: action.to_string(),
+                        recorded_at,
+                    }
+                    .into_active_model()
+                })
+            });
+        for chunk in &models.chunks(2048) {
+            ScanRunPackages::insert_many(chunk)
+                .exec_without_returning(&self.conn)
+                .await?;
+        }
+
+        let runs = self.get_recent_runs().await?;
+        if let Some((_, oldest)) = runs
+            .last()
+            .filter(|_| runs.len() as u64 >= self.flapping_runs)
+        {
+            ScanRunPackages::delete_many()
+                .filter(scan_run_packages::Column::Tree.eq(self.tree.clone()))
+                .filter(scan_run_packages::Column::RecordedAt.lt(*oldest))
+                .exec(&self.conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Identifiers and times of the last flapping_runs runs changing packages, newest first
+    async fn get_recent_runs(&self) -> Result<Vec<(String, DateTimeWithTimeZone)>> {
+        Ok(ScanRunPackages::find()
+            .select_only()
+            .columns([
+                scan_run_packages::Column::RunId,
+                scan_run_packages::Column::RecordedAt,
+            ])
+            .distinct()
+            .filter(scan_run_packages::Column::Tree.eq(self.tree.clone()))
+            .order_by_desc(scan_run_packages::Column::RecordedAt)
+            .limit(self.flapping_runs)
+            .into_tuple()
+            .all(&self.conn)
+            .await?)
+    }
+
+    /// Packages switching between updated and deleted more than flapping_threshold
+    /// times in the recent runs recorded by [Self::record_run_packages]
+    pub async fn get_flapping(&self) -> Result<Vec<FlappingPackage>> {
+        let runs = self.get_recent_runs().await?;
+        let rows: Vec<(String, String)> = ScanRunPackages::find()
+            .select_only()
+            .columns([
+                scan_run_packages::Column::Package,
+                scan_run_packages::Column::Action,
+            ])
+            .filter(scan_run_packages::Column::Tree.eq(self.tree.clone()))
+            .filter(
+                scan_run_packages::Column::RunId.is_in(runs.into_iter().map(|(run_id, _)| run_id)),
+            )
+            .order_by_asc(scan_run_packages::Column::Package)
+            .order_by_asc(scan_run_packages::Column::RecordedAt)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        let mut res = vec![];
+        for (package, actions) in &rows.into_iter().group_by(|(package, _)| package.clone()) {
+            let actions = actions.map(|(_, action)| action).collect_vec();
+            let flaps = actions
+                .iter()
+                .tuple_windows()
+                .filter(|(left, right)| left != right)
+                .count();
+            if flaps > self.flapping_threshold {
+                res.push(FlappingPackage {
+                    package,
+                    flaps,
+                    runs: actions.len(),
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Add or update the package, returns the number of recorded errors
+    pub async fn add_package(
+        &self,
+        pkg_meta: Meta,
+        pkg_changes: Vec<Change>,
+    ) -> Result<ErrorCount> {
+        let txn = self.conn.begin().await?;
+        let count = self.write_package(pkg_meta, pkg_changes, &txn).await?;
+        txn.commit().await?;
+
+        Ok(count)
+    }
+
+    /// Add or update packages in a single transaction, for scans updating many packages
+    ///
+    /// Returns the number of recorded errors of each package, in the order of `pkgs`.
+    pub async fn add_packages_batch(
+        &self,
+        pkgs: Vec<(Meta, Vec<Change>)>,
+    ) -> Result<Vec<ErrorCount>> {
+        let txn = self.conn.begin().await?;
+        let mut counts = Vec::with_capacity(pkgs.len());
+        for (pkg_meta, pkg_changes) in pkgs {
+            counts.push(self.write_package(pkg_meta, pkg_changes, &txn).await?);
+        }
+        txn.commit().await?;
+
+        Ok(counts)
+    }
+
+    /// Write rows of the package in the transaction of the caller
+    ///
+    /// With strict writes, the rows are written in a nested transaction, which
+    /// is rolled back if one of them breaks an invariant.
+    async fn write_package(
+        &self,
+        pkg_meta: Meta,
+        pkg_changes: Vec<Change>,
+        db: &DatabaseTransaction,
+    ) -> Result<ErrorCount> {
+        if !self.strict_writes {
+            return self.write_package_rows(pkg_meta, pkg_changes, db).await;
+        }
+
+        let name = pkg_meta.0.name.clone();
+        let path = pkg_meta.0.spec_path.clone();
+        let githash = pkg_changes.first().map(|change| change.githash.clone());
+        let nested = db.begin().await?;
+        match self
+            .write_package_rows(pkg_meta, pkg_changes, &nested)
+            .await
+        {
+            Ok(count) => {
+                nested.commit().await?;
+                Ok(count)
+            }
+            Err(e) => {
+                let violation = e.downcast::<InvariantViolation>()?;
+                nested.rollback().await?;
+                warn!("skip package \"{name}\": {violation}");
+                let error = PackageError {
+                    package: name.clone(),
+                    path,
+                    message: violation.to_string(),
+                    err_type: ErrorType::Invariant,
+                    line: None,
+                    col: None,
+                    end_line: None,
+                    end_col: None,
+                };
+                self.replace_errors(&[name], vec![error], githash.as_deref(), db)
+                    .await
+            }
+        }
+    }
+
+    async fn write_package_rows(
+        &self,
+        pkg_meta: Meta,
+        mut pkg_changes: Vec<Change>,
+        db: &DatabaseTransaction,
+    ) -> Result<ErrorCount> {
+        let (pkg, context, mut errors, version_source) = pkg_meta;
+
+        if pkg_changes.is_empty() {
+            bail!("cannot find changes of package, please update commit database")
+        }
+
+        let valid_name = self.check_package_name(&pkg, &mut errors, db).await?;
+        if !valid_name && self.reject_invalid_names {
+            warn!("reject package \"{}\" with invalid name", pkg.name);
+            // written before the naming policy changed or rejection was enabled
+            let existing = Packages::find_by_id(pkg.name.clone())
+                .filter(packages::Column::Tree.eq(self.tree.clone()))
+                .one(db)
+                .await?;
+            if existing.is_some() {
+                self.delete_package_rows(&pkg.name, db).await?;
+            }
+            let githash = pkg_changes[0].githash.clone();
+            let count = self
+                .replace_errors(std::slice::from_ref(&pkg.name), errors, Some(&githash), db)
+                .await?;
+            return Ok(count);
+        }
+
+        let description = pkg.description.split_whitespace().join(" ");
+        let description = self
+            .limit_value(
+                &pkg,
+                "PKGDES",
+                description,
+                self.value_limits.description,
+                &mut errors,
+            )
+            .unwrap_or_default();
+        if description.is_empty() {
+            errors.push(PackageError {
+                package: pkg.name.clone(),
+                path: pkg.spec_path.clone(),
+                message: "missing description (PKGDES)".to_string(),
+                err_type: ErrorType::Description,
+                line: None,
+                col: None,
+                end_line: None,
+                end_col: None,
+            });
+        }
+
+        let unknown = unknown_keys(&context, &self.extra_spec_keys);
+        errors.extend(unknown.misspelled.iter().map(|(key, known)| PackageError {
+            package: pkg.name.clone(),
+            path: pkg.spec_path.clone(),
+            message: format!("unknown key {key}, did you mean {known}?"),
+            err_type: ErrorType::SpecKey,
+            line: None,
+            col: None,
+            end_line: None,
+            end_col: None,
+        }));
+        if unknown.other > 0 {
+            debug!("{}: {} other unknown keys", pkg.name, unknown.other);
+        }
+
+        let existing = Packages::find_by_id(pkg.name.clone()).one(db).await?;
+
+        if let Some(existing) = existing {
+            let existing_version =
+                PackageVersions::find_by_id((pkg.name.clone(), self.branch.clone()))
+                    .one(db)
+                    .await?
+                    .map(|model| model.full_version);
+            let name = &pkg.name;
+            let existing_tree = &existing.tree;
+            let existing_category = &existing.category;
+            let existing_section = &existing.section;
+            let existing_directory = &existing.directory;
+            let tree = &self.tree;
+            let category = &pkg.category;
+            let section = &pkg.section;
+            let directory = &pkg.directory;
+
+            if existing.tree != self.tree {
+                self.warnings.warn(
+                    "duplicate package",
+                    format_args!("duplicate package \"{name}\" found in different trees {existing_tree}/{existing_category}-{existing_section}/{existing_directory} and {tree}/{category}-{section}/{directory}"),
+                );
+                update_duplicate(&pkg, &existing, existing_version.clone(), &self.tree, db).await?;
+            }
+
+            if (&pkg.category, &pkg.section, &pkg.directory)
+                != (&existing.category, &existing.section, &existing.directory)
+            {
+                self.warnings.warn(
+                    "duplicate package",
+                    format_args!("duplicate package \"{name}\" found in {existing_category}-{existing_section}/{existing_directory} and {category}-{section}/{directory}"),
+                );
+                update_duplicate(&pkg, &existing, existing_version, &self.tree, db).await?;
+            }
+
+            if self.prefers_existing(&pkg, &existing, db).await? {
+                info!("keep the preferred location of duplicate package \"{name}\"");
+                return Ok(ErrorCount::default());
+            }
+        }
+
+        let package = packages::Model {
+            name: pkg.name.clone(),
+            tree: self.tree.to_string(),
             category: pkg.category.clone(),
             section: pkg.section.clone(),
             pkg_section: pkg.pkg_section.clone(),
             directory: pkg.directory.clone(),
-            description: pkg.description.clone(),
+            description,
             spec_path: pkg.spec_path.clone(),
-        }
-        .replace(&txn, [packages::Column::Name], packages::Column::iter())
-        .await?;
+            last_run_id: self.run_id.clone(),
+            section_source: Some(section_source(&context).as_str().to_string()),
+        };
+        self.check_invariants(&package)?;
+        package
+            .replace(db, [packages::Column::Name], packages::Column::iter())
+            .await?;
 
-        let first = pkg_changes[0].clone();
+        for change in &mut pkg_changes {
+            let message = std::mem::take(&mut change.message);
+            change.message = self
+                .limit_value(
+                    &pkg,
+                    &format!("commit message of {}", change.githash),
+                    message,
+                    self.value_limits.change_message,
+                    &mut errors,
+                )
+                .unwrap_or_default();
+        }
+        let first = self.version_change(&pkg, &pkg_changes).clone();
+        let landed = self.version_first_change(&pkg, &pkg_changes).cloned();
         let mut changes: Vec<_> = pkg_changes
             .into_iter()
             .map(|change| package_changes::Model {
@@ -201,6 +1555,8 @@ impl AbbsDb {
                 maintainer_email: change.maintainer_email,
                 timestamp: change.timestamp,
                 tree: change.tree,
+                mass_change: change.packages_touched > self.mass_change_threshold,
+                changed_files: change.changed_files,
             })
             .collect();
 
@@ -212,6 +1568,13 @@ impl AbbsDb {
         changes.dedup_by(|left, right| {
             (&left.package, &left.githash) == (&right.package, &right.githash)
         });
+        if let Some(limit) = self.max_changes_per_package {
+            changes.sort_by(|a, b| (b.timestamp, &b.githash).cmp(&(a.timestamp, &a.githash)));
+            changes.truncate(limit);
+        }
+        for change in &changes {
+            self.check_invariants(change)?;
+        }
 
         replace_many(
             changes.into_iter().map(|model| model.into_active_model()),
@@ -223,10 +1586,13 @@ impl AbbsDb {
         )
         .exec(db)
         .await?;
+        if let Some(limit) = self.max_changes_per_package {
+            trim_changes(db, &self.tree, Some(&pkg.name), limit).await?;
+        }
 
         let full_version = get_full_version(&pkg);
 
-        package_versions::Model {
+        let versions = package_versions::Model {
             package: pkg.name.clone(),
             branch: self.branch.clone(),
             version: pkg.version.clone(),
@@ -240,229 +1606,3351 @@ impl AbbsDb {
             ),
             githash: first.githash.clone(),
             full_version,
+            last_run_id: self.run_id.clone(),
+            version_source: Some(version_source.as_str().to_string()),
+            version_first_commit_time: landed.as_ref().map(|change| change.timestamp),
+            version_first_githash: landed.map(|change| change.githash),
+        };
+        self.check_invariants(&versions)?;
+        versions
+            .replace(
+                db,
+                [
+                    package_versions::Column::Package,
+                    package_versions::Column::Branch,
+                ],
+                package_versions::Column::iter(),
+            )
+            .await?;
+
+        PackageArchVersions::delete_many()
+            .filter(package_arch_versions::Column::Package.eq(pkg.name.clone()))
+            .filter(package_arch_versions::Column::Branch.eq(self.branch.clone()))
+            .exec(db)
+            .await?;
+        let arch_versions = arch_versions(&pkg, &context);
+        if !arch_versions.is_empty() {
+            let models =
+                arch_versions
+                    .into_iter()
+                    .map(|(architecture, epoch, version, release)| {
+                        package_arch_versions::Model {
+                            package: pkg.name.clone(),
+                            branch: self.branch.clone(),
+                            architecture,
+                            full_version: format_full_version(
+                                epoch.as_deref(),
+                                &version,
+                                release.as_deref(),
+                            ),
+                            version,
+                            release,
+                            epoch,
+                        }
+                        .into_active_model()
+                    });
+            replace_many(
+                models,
+                [
+                    package_arch_versions::Column::Package,
+                    package_arch_versions::Column::Branch,
+                    package_arch_versions::Column::Architecture,
+                ],
+                package_arch_versions::Column::iter(),
+            )
+            .exec(db)
+            .await?;
         }
-        .replace(
-            db,
-            [
-                package_versions::Column::Package,
-                package_versions::Column::Branch,
-            ],
-            package_versions::Column::iter(),
-        )
-        .await?;
 
-        PackageSpec::delete_many()
-            .filter(package_spec::Column::Package.eq(pkg.name.clone()))
+        PackageArchitectures::delete_many()
+            .filter(package_architectures::Column::Package.eq(pkg.name.clone()))
+            .filter(package_architectures::Column::Branch.eq(self.branch.clone()))
+            .exec(db)
+            .await?;
+        let fail_arch = context.get("FAIL_ARCH");
+        let supported = self
+            .build_architectures
+            .iter()
+            .filter(|arch| !fail_arch.is_some_and(|pattern| fail_arch_matches(pattern, arch)))
+            .map(|arch| arch.to_lowercase())
+            .unique()
+            .map(|architecture| {
+                package_architectures::Model {
+                    package: pkg.name.clone(),
+                    branch: self.branch.clone(),
+                    architecture,
+                }
+                .into_active_model()
+            })
+            .collect_vec();
+        if !supported.is_empty() {
+            replace_many(
+                supported,
+                [
+                    package_architectures::Column::Package,
+                    package_architectures::Column::Branch,
+                    package_architectures::Column::Architecture,
+                ],
+                package_architectures::Column::iter(),
+            )
             .exec(db)
             .await?;
+        }
+
+        // only write keys whose values changed, mass edits of specs touch few keys
+        let mut stored: HashMap<String, String> = PackageSpec::find()
+            .select_only()
+            .columns([package_spec::Column::Key, package_spec::Column::Value])
+            .filter(package_spec::Column::Package.eq(pkg.name.clone()))
+            .into_tuple()
+            .all(db)
+            .await?
+            .into_iter()
+            .collect();
 
-        let mut specs: Vec<_> = context
+        let mut limited = vec![];
+        for (k, v) in context {
+            let limit = self.value_limits.spec_value;
+            if let Some(v) = self.limit_value(&pkg, &k, v, limit, &mut errors) {
+                limited.push((k, v));
+            }
+        }
+        let chkupdate = limited
+            .iter()
+            .find(|(k, _)| k == "CHKUPDATE")
+            .map(|(_, v)| v.clone());
+        let mut specs: Vec<_> = limited
             .into_iter()
+            .filter(|(k, v)| stored.remove(k).as_ref() != Some(v))
             .map(|(k, v)| package_spec::Model {
                 package: pkg.name.clone(),
                 key: k,
                 value: v,
+                last_run_id: self.run_id.clone(),
             })
             .collect();
 
+        // keys left in stored are gone
+        if !stored.is_empty() {
+            PackageSpec::delete_many()
+                .filter(package_spec::Column::Package.eq(pkg.name.clone()))
+                .filter(package_spec::Column::Key.is_in(stored.into_keys()))
+                .exec(db)
+                .await?;
+        }
+
         // dedup before inserting into database
         // primary key: (package, key)
         specs.sort_by(|left, right| (&left.package, &left.key).cmp(&(&right.package, &right.key)));
         specs.dedup_by(|left, right| (&left.package, &left.key) == (&right.package, &right.key));
 
-        replace_many(
-            specs.into_iter().map(|model| model.into_active_model()),
-            [package_spec::Column::Package, package_spec::Column::Key],
-            package_spec::Column::iter(),
+        if !specs.is_empty() {
+            replace_many(
+                specs.into_iter().map(|model| model.into_active_model()),
+                [package_spec::Column::Package, package_spec::Column::Key],
+                package_spec::Column::iter(),
+            )
+            .exec(db)
+            .await?;
+        }
+
+        PackageDependencies::delete_many()
+            .filter(package_dependencies::Column::Package.eq(pkg.name.clone()))
+            .exec(db)
+            .await?;
+
+        let pkg_name = &pkg.name;
+
+        let (typed, invalid) = typed_relationships(&pkg);
+        errors.extend(invalid);
+        for (relationship, dependencies) in typed {
+            add_dependencies(dependencies, relationship, pkg_name, self.strict_writes, db).await?;
+        }
+        refresh_dependency_counts(db, Query::select().expr(Expr::val(pkg_name)).to_owned()).await?;
+
+        // package_update_sources
+        PackageUpdateSources::delete_many()
+            .filter(package_update_sources::Column::Package.eq(pkg_name.clone()))
+            .exec(db)
+            .await?;
+        if let Some(chkupdate) = chkupdate {
+            match parse_chkupdate(&chkupdate) {
+                Ok((method, params)) => {
+                    let sources =
+                        params
+                            .into_iter()
+                            .unique_by(|(key, _)| key.clone())
+                            .map(|(key, value)| {
+                                package_update_sources::Model {
+                                    package: pkg_name.clone(),
+                                    method: method.clone(),
+                                    key,
+                                    value,
+                                }
+                                .into_active_model()
+                            });
+                    PackageUpdateSources::insert_many(sources).exec(db).await?;
+                }
+                Err(e) => errors.push(PackageError {
+                    package: pkg_name.clone(),
+                    path: pkg.spec_path.clone(),
+                    message: e.to_string(),
+                    err_type: ErrorType::UpdateSource,
+                    line: None,
+                    col: None,
+                    end_line: None,
+                    end_col: None,
+                }),
+            }
+        }
+
+        // package_errors
+        let count = self
+            .replace_errors(
+                std::slice::from_ref(&pkg.name),
+                errors,
+                Some(&first.githash),
+                db,
+            )
+            .await?;
+
+        self.append_events(
+            OutboxEvent::PACKAGE_UPDATED,
+            &self.branch,
+            [(
+                pkg.name.clone(),
+                Some(serde_json::json!({
+                    "full_version": get_full_version(&pkg),
+                    "githash": first.githash,
+                    "errors": count.total,
+                })),
+            )],
+            db,
         )
-        .exec(db)
         .await?;
 
-        PackageDependencies::delete_many()
-            .filter(package_dependencies::Column::Package.eq(pkg.name.clone()))
-            .exec(db)
-            .await?;
+        Ok(count)
+    }
+
+    /// Append events of packages to events_outbox, in the transaction changing them
+    async fn append_events(
+        &self,
+        event_type: &str,
+        branch: &str,
+        events: impl IntoIterator<Item = (String, Option<serde_json::Value>)>,
+        db: &impl ConnectionTrait,
+    ) -> Result<()> {
+        let now = Local::now().fixed_offset();
+        let models = events
+            .into_iter()
+            .map(|(package, payload)| events_outbox::ActiveModel {
+                id: NotSet,
+                event_type: Set(event_type.to_string()),
+                package: Set(package),
+                tree: Set(self.tree.to_string()),
+                branch: Set(branch.to_string()),
+                payload: Set(payload),
+                created_at: Set(now),
+            })
+            .collect_vec();
+        for chunk in &models.into_iter().chunks(2048) {
+            EventsOutbox::insert_many(chunk).exec(db).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Events after the high-water mark of the consumer, at most `limit` of them
+    ///
+    /// Events of every tree are returned. The mark is moved past the returned
+    /// events, so each event is returned once per consumer unless `after` is
+    /// given, which reads from the event id instead of the mark.
+    pub async fn poll_events(
+        &self,
+        consumer: &str,
+        after: Option<i64>,
+        limit: u64,
+    ) -> Result<Vec<OutboxEvent>> {
+        let after = match after {
+            Some(after) => after,
+            None => EventConsumers::find_by_id(consumer)
+                .one(&self.conn)
+                .await?
+                .map_or(0, |model| model.last_id),
+        };
+        let events = EventsOutbox::find()
+            .filter(events_outbox::Column::Id.gt(after))
+            .order_by_asc(events_outbox::Column::Id)
+            .limit(limit)
+            .all(&self.conn)
+            .await?;
+
+        if let Some(last) = events.last() {
+            event_consumers::Model {
+                consumer: consumer.to_string(),
+                last_id: last.id,
+                updated_at: Local::now().fixed_offset(),
+            }
+            .replace(
+                &self.conn,
+                [event_consumers::Column::Consumer],
+                event_consumers::Column::iter(),
+            )
+            .await?;
+        }
+
+        Ok(events
+            .into_iter()
+            .map(|model| OutboxEvent {
+                id: model.id,
+                event_type: model.event_type,
+                package: model.package,
+                tree: model.tree,
+                branch: model.branch,
+                payload: model.payload,
+                created_at: model.created_at.to_rfc3339(),
+            })
+            .collect())
+    }
+
+    /// Delete events created before `before` which every consumer has read
+    ///
+    /// Without consumers nothing has been read, and nothing is deleted.
+    pub async fn prune_events(&self, before: DateTimeWithTimeZone) -> Result<u64> {
+        let Some(read) = EventConsumers::find()
+            .select_only()
+            .column_as(event_consumers::Column::LastId.min(), "last_id")
+            .into_tuple::<Option<i64>>()
+            .one(&self.conn)
+            .await?
+            .flatten()
+        else {
+            return Ok(0);
+        };
+        let res = EventsOutbox::delete_many()
+            .filter(events_outbox::Column::Id.lte(read))
+            .filter(events_outbox::Column::CreatedAt.lt(before))
+            .exec(&self.conn)
+            .await?;
+
+        Ok(res.rows_affected)
+    }
+
+    /// Delete changes of every package beyond the newest `limit`, then reclaim their space
+    ///
+    /// Returns the number of deleted changes.
+    pub async fn compact_changes(&self, limit: usize) -> Result<u64> {
+        if limit == 0 {
+            bail!("at least one change of each package must be kept");
+        }
+        let deleted = trim_changes(&self.conn, &self.tree, None, limit).await?;
+        exec(&self.conn, "VACUUM (ANALYZE) package_changes", []).await?;
+
+        Ok(deleted)
+    }
+
+    /// Apply the byte limit of a value, recording an error if it is truncated
+    ///
+    /// Values with NUL bytes can't be saved as text, None is returned for them
+    /// with an error instead.
+    fn limit_value(
+        &self,
+        pkg: &Package,
+        field: &str,
+        value: String,
+        limit: usize,
+        errors: &mut Vec<PackageError>,
+    ) -> Option<String> {
+        let error = |message| PackageError {
+            package: pkg.name.clone(),
+            path: pkg.spec_path.clone(),
+            message,
+            err_type: ErrorType::Value,
+            line: None,
+            col: None,
+            end_line: None,
+            end_col: None,
+        };
+
+        if value.contains('\0') {
+            errors.push(error(format!("{field} contains NUL bytes, not saved")));
+            return None;
+        }
+        if value.len() <= limit {
+            return Some(value);
+        }
+
+        let len = value.len();
+        self.warnings.warn(
+            "truncated value",
+            format_args!(
+                "{field} of {} is {len} bytes, truncated to {limit}",
+                pkg.name
+            ),
+        );
+        errors.push(error(format!(
+            "{field} is {len} bytes, truncated to {limit}"
+        )));
+        let mut end = limit;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(value[..end].to_string())
+    }
+
+    /// Check package name against the naming policy and find case-insensitive collisions
+    ///
+    /// Returns false if the name violates the naming policy.
+    async fn check_package_name(
+        &self,
+        pkg: &Package,
+        errors: &mut Vec<PackageError>,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool> {
+        let name = &pkg.name;
+        let valid = self.name_pattern.is_match(name);
+        if !valid {
+            warn!("package name \"{name}\" violates naming policy");
+            errors.push(PackageError {
+                package: name.clone(),
+                path: pkg.spec_path.clone(),
+                message: format!(
+                    "package name {name} doesn't match {}",
+                    self.name_pattern.as_str()
+                ),
+                err_type: ErrorType::Name,
+                line: None,
+                col: None,
+                end_line: None,
+                end_col: None,
+            });
+        }
+
+        let collisions = Packages::find()
+            .filter(
+                Expr::expr(Func::lower(Expr::col(packages::Column::Name))).eq(name.to_lowercase()),
+            )
+            .filter(packages::Column::Name.ne(name))
+            .all(db)
+            .await?;
+        for other in collisions {
+            warn!("package name \"{name}\" collides with \"{}\"", other.name);
+            let in_tree = other.tree == self.tree;
+            let message = if in_tree {
+                format!("package name {name} collides with {}", other.name)
+            } else {
+                format!(
+                    "package name {name} collides with {} of {}",
+                    other.name, other.tree
+                )
+            };
+            errors.push(PackageError {
+                package: name.clone(),
+                path: pkg.spec_path.clone(),
+                message,
+                err_type: ErrorType::Name,
+                line: None,
+                col: None,
+                end_line: None,
+                end_col: None,
+            });
+            // errors are written in the tree of this instance, a package of
+            // another tree gets its error when that tree is scanned
+            if !in_tree {
+                continue;
+            }
+            errors.push(PackageError {
+                package: other.name.clone(),
+                path: other.spec_path.clone(),
+                message: format!("package name {} collides with {name}", other.name),
+                err_type: ErrorType::Name,
+                line: None,
+                col: None,
+                end_line: None,
+                end_col: None,
+            });
+        }
+
+        Ok(valid)
+    }
+
+    /// Mark versions of packages which failed to parse as preserved from an earlier scan
+    pub async fn preserve_versions(&self, packages: &[String]) -> Result<()> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "keep versions of {} packages which failed to parse",
+            packages.len()
+        );
+        PackageVersions::update_many()
+            .col_expr(
+                package_versions::Column::VersionSource,
+                Expr::value(VersionSource::Preserved.as_str()),
+            )
+            .filter(package_versions::Column::Package.is_in(packages))
+            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
+            .exec(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record errors of packages which are not updated
+    pub async fn add_errors(&self, errors: Vec<PackageError>) -> Result<ErrorCount> {
+        let packages = errors
+            .iter()
+            .map(|e| e.package.clone())
+            .unique()
+            .collect_vec();
+        let txn = self.conn.begin().await?;
+        let count = self.replace_errors(&packages, errors, None, &txn).await?;
+        txn.commit().await?;
+        Ok(count)
+    }
+
+    /// Replace the errors of packages in the main branch with the errors found in this scan
+    async fn replace_errors(
+        &self,
+        packages: &[String],
+        errors: Vec<PackageError>,
+        githash: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<ErrorCount> {
+        self.replace_branch_errors(&self.branch, packages, errors, githash, db)
+            .await
+    }
+
+    /// Replace the errors of packages in the branch with the errors found in this scan
+    ///
+    /// Errors recorded by the reconcile pass are kept. Errors of other packages,
+    /// like a name collision reported for the package colliding with one of
+    /// `packages`, are added next to their existing errors unless already
+    /// recorded, as their own scan replaces them, and those no longer reported
+    /// are resolved by [Self::resolve_collisions]. Changes of the error set are
+    /// appended to package_error_events.
+    async fn replace_branch_errors(
+        &self,
+        branch: &str,
+        packages: &[String],
+        errors: Vec<PackageError>,
+        githash: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<ErrorCount> {
+        let replaced: HashSet<_> = packages.iter().collect();
+        let all_packages = packages
+            .iter()
+            .cloned()
+            .chain(errors.iter().map(|e| e.package.clone()))
+            .unique()
+            .collect_vec();
+        if all_packages.is_empty() {
+            return Ok(ErrorCount::default());
+        }
+
+        let condition = Condition::all()
+            .add(package_errors::Column::Tree.eq(self.tree.clone()))
+            .add(package_errors::Column::Branch.eq(branch))
+            .add(package_errors::Column::ErrType.is_not_in(reconcile_error_types()));
+        let (existing, others_existing): (Vec<_>, Vec<_>) = PackageErrors::find()
+            .filter(
+                condition
+                    .clone()
+                    .add(package_errors::Column::Package.is_in(all_packages)),
+            )
+            .all(db)
+            .await?
+            .into_iter()
+            .partition(|e| replaced.contains(&e.package));
+        if !packages.is_empty() {
+            PackageErrors::delete_many()
+                .filter(condition.add(package_errors::Column::Package.is_in(packages.to_vec())))
+                .exec(db)
+                .await?;
+            self.resolve_collisions(branch, packages, &errors, githash, db)
+                .await?;
+        }
+
+        let existing_keys: HashSet<_> = existing
+            .iter()
+            .chain(&others_existing)
+            .map(|e| {
+                (
+                    &e.package,
+                    e.err_type.clone(),
+                    &e.message,
+                    &e.path,
+                    e.line,
+                    e.col,
+                )
+            })
+            .collect();
+        let new_errors = errors
+            .iter()
+            .filter(|e| {
+                !existing_keys.contains(&(
+                    &e.package,
+                    e.err_type.to_string(),
+                    &e.message,
+                    &e.path,
+                    e.line,
+                    e.col,
+                ))
+            })
+            .count();
+        let count = ErrorCount {
+            total: errors.len(),
+            new: new_errors,
+        };
+
+        // errors of other packages already recorded are left as they are
+        let (errors, others): (Vec<_>, Vec<_>) = errors
+            .into_iter()
+            .partition(|e| replaced.contains(&e.package));
+        let others = others
+            .into_iter()
+            .filter(|e| {
+                !existing_keys.contains(&(
+                    &e.package,
+                    e.err_type.to_string(),
+                    &e.message,
+                    &e.path,
+                    e.line,
+                    e.col,
+                ))
+            })
+            .collect_vec();
+        self.record_error_events(branch, &existing, &errors, githash, db)
+            .await?;
+        // only introduced, the errors of the other packages are not replaced
+        let others_recorded: HashSet<_> = others_existing
+            .iter()
+            .map(|e| (&e.package, e.err_type.clone(), &e.message))
+            .collect();
+        let introduced = others
+            .iter()
+            .filter(|e| {
+                !others_recorded.contains(&(&e.package, e.err_type.to_string(), &e.message))
+            })
+            .cloned()
+            .collect_vec();
+        self.record_error_events(branch, &[], &introduced, githash, db)
+            .await?;
+        let errors = errors.into_iter().chain(others).collect_vec();
+        if errors.is_empty() {
+            return Ok(count);
+        }
+
+        let iter = errors.into_iter().map(|e| package_errors::ActiveModel {
+            package: Set(e.package),
+            err_type: Set(e.err_type.to_string()),
+            message: Set(e.message),
+            path: Set(e.path),
+            tree: Set(self.tree.to_string()),
+            branch: Set(branch.to_string()),
+            line: Set(e.line),
+            col: Set(e.col),
+            end_line: Set(e.end_line),
+            end_col: Set(e.end_col),
+            last_run_id: Set(self.run_id.clone()),
+            id: NotSet,
+        });
+        replace_many(
+            iter,
+            [package_errors::Column::Id],
+            package_errors::Column::iter(),
+        )
+        .exec(db)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Resolve name collisions recorded for other packages by earlier scans of `packages`
+    ///
+    /// The error of the package collided with is written by the scan of the
+    /// colliding one, so it is resolved when that package is scanned again
+    /// without reporting it, or deleted. `errors` are the errors of this scan.
+    async fn resolve_collisions(
+        &self,
+        branch: &str,
+        packages: &[String],
+        errors: &[PackageError],
+        githash: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<()> {
+        let packages: HashSet<_> = packages.iter().map(String::as_str).collect();
+        let reported: HashSet<_> = errors.iter().map(|e| (&e.package, &e.message)).collect();
+        let resolved = PackageErrors::find()
+            .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+            .filter(package_errors::Column::Branch.eq(branch))
+            .filter(package_errors::Column::ErrType.eq(ErrorType::Name.to_string()))
+            .filter(package_errors::Column::Message.contains(" collides with "))
+            .all(db)
+            .await?
+            .into_iter()
+            .filter(|e| {
+                !packages.contains(e.package.as_str())
+                    && !reported.contains(&(&e.package, &e.message))
+                    && e.message
+                        .strip_prefix(&format!("package name {} collides with ", e.package))
+                        .is_some_and(|other| packages.contains(other))
+            })
+            .collect_vec();
+        if resolved.is_empty() {
+            return Ok(());
+        }
+
+        self.record_error_events(branch, &resolved, &[], githash, db)
+            .await?;
+        PackageErrors::delete_many()
+            .filter(package_errors::Column::Id.is_in(resolved.iter().map(|e| e.id)))
+            .exec(db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Append introduced and resolved events of errors
+    ///
+    /// Errors are identified by package, type and message, so a moved line
+    /// doesn't count as a new error.
+    async fn record_error_events(
+        &self,
+        branch: &str,
+        before: &[package_errors::Model],
+        after: &[PackageError],
+        githash: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<()> {
+        let before: BTreeMap<_, _> = before
+            .iter()
+            .map(|e| {
+                (
+                    (e.package.clone(), e.err_type.clone(), digest(&e.message)),
+                    &e.message,
+                )
+            })
+            .collect();
+        let after: BTreeMap<_, _> = after
+            .iter()
+            .map(|e| {
+                let err_type = e.err_type.to_string();
+                (
+                    (e.package.clone(), err_type, digest(&e.message)),
+                    &e.message,
+                )
+            })
+            .collect();
+
+        let introduced = after
+            .iter()
+            .filter(|(key, _)| !before.contains_key(*key))
+            .map(|(key, message)| (ErrorEvent::INTRODUCED, key, message));
+        let resolved = before
+            .iter()
+            .filter(|(key, _)| !after.contains_key(*key))
+            .map(|(key, message)| (ErrorEvent::RESOLVED, key, message));
+
+        let now = Local::now().fixed_offset();
+        let events = introduced
+            .chain(resolved)
+            .map(|(event, (package, err_type, message_hash), message)| {
+                package_error_events::ActiveModel {
+                    package: Set(package.clone()),
+                    tree: Set(self.tree.to_string()),
+                    branch: Set(branch.to_string()),
+                    event: Set(event.to_string()),
+                    err_type: Set(err_type.clone()),
+                    message_hash: Set(message_hash.clone()),
+                    message: Set(message.to_string()),
+                    githash: Set(githash.map(|hash| hash.to_string())),
+                    recorded_at: Set(now),
+                    id: NotSet,
+                }
+            })
+            .collect_vec();
+
+        if !events.is_empty() {
+            PackageErrorEvents::insert_many(events).exec(db).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Errors of packages in the branch, defaults to the main branch
+    pub async fn get_errors(
+        &self,
+        branch: Option<&str>,
+        err_type: Option<ErrorType>,
+    ) -> Result<Vec<PackageError>> {
+        self.errors_after(branch, err_type, None, None)
+            .await?
+            .into_iter()
+            .map(PackageError::try_from)
+            .collect()
+    }
+
+    /// A page of [Self::get_errors], keyed by package and id of the error
+    pub async fn get_errors_page(
+        &self,
+        branch: Option<&str>,
+        err_type: Option<ErrorType>,
+        page: &PageRequest,
+    ) -> Result<Page<PackageError>> {
+        let limit = page.limit();
+        let rows = self
+            .errors_after(branch, err_type, page.after()?, Some(limit + 1))
+            .await?;
+        let page = into_page(rows, limit, |model| (model.package.clone(), model.id))?;
+
+        Ok(Page {
+            items: page
+                .items
+                .into_iter()
+                .map(PackageError::try_from)
+                .collect::<Result<_>>()?,
+            next: page.next,
+        })
+    }
+
+    async fn errors_after(
+        &self,
+        branch: Option<&str>,
+        err_type: Option<ErrorType>,
+        after: Option<(String, i32)>,
+        limit: Option<u64>,
+    ) -> Result<Vec<package_errors::Model>> {
+        let branch = branch.unwrap_or(&self.branch);
+        let mut query = PackageErrors::find()
+            .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+            .filter(package_errors::Column::Branch.eq(branch));
+        if let Some(err_type) = err_type {
+            query = query.filter(package_errors::Column::ErrType.eq(err_type.to_string()));
+        }
+        if let Some((package, id)) = after {
+            query = query.filter(
+                Condition::any()
+                    .add(package_errors::Column::Package.gt(package.clone()))
+                    .add(
+                        Condition::all()
+                            .add(package_errors::Column::Package.eq(package))
+                            .add(package_errors::Column::Id.gt(id)),
+                    ),
+            );
+        }
+
+        Ok(query
+            .order_by_asc(package_errors::Column::Package)
+            .order_by_asc(package_errors::Column::Id)
+            .limit(limit)
+            .all(&self.conn)
+            .await?)
+    }
+
+    /// Link to the location of the error at the commit, from url_template of the repository
+    ///
+    /// `{commit}`, `{path}`, `{line}` and `{end_line}` are replaced, `{end_line}`
+    /// is the line of the error for errors at a single position. Errors without
+    /// a line are linked to the file, dropping the fragment of the template.
+    pub fn render_location(&self, error: &PackageError, commit: &str) -> Option<String> {
+        let template = self.url_template.as_deref()?;
+        let (template, line) = match error.line {
+            Some(line) => (template, line),
+            None => (template.split('#').next().unwrap_or(template), 0),
+        };
+
+        Some(
+            template
+                .replace("{commit}", commit)
+                .replace("{path}", &error.path)
+                .replace("{line}", &line.to_string())
+                .replace("{end_line}", &error.end_line.unwrap_or(line).to_string()),
+        )
+    }
+
+    /// Commit recorded by the last successful scan, see [Self::record_head]
+    pub async fn get_head_commit(&self) -> Result<Option<String>> {
+        Ok(Trees::find()
+            .filter(trees::Column::Name.eq(self.tree.clone()))
+            .one(&self.conn)
+            .await?
+            .and_then(|tree| tree.head_commit))
+    }
+
+    /// Introduced and resolved errors of the package in the branch, oldest first
+    pub async fn get_error_timeline(&self, package: &str) -> Result<Vec<ErrorEvent>> {
+        let res = PackageErrorEvents::find()
+            .filter(package_error_events::Column::Package.eq(package))
+            .filter(package_error_events::Column::Tree.eq(self.tree.clone()))
+            .filter(package_error_events::Column::Branch.eq(self.branch.clone()))
+            .order_by_asc(package_error_events::Column::Id)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| ErrorEvent {
+                event: model.event,
+                err_type: model.err_type,
+                message: model.message,
+                githash: model.githash,
+                recorded_at: model.recorded_at.to_rfc3339(),
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Latest errors introduced in the branch, newest first, as (package, event)
+    pub async fn get_new_errors(&self, limit: u64) -> Result<Vec<(String, ErrorEvent)>> {
+        let res = PackageErrorEvents::find()
+            .filter(package_error_events::Column::Tree.eq(self.tree.clone()))
+            .filter(package_error_events::Column::Branch.eq(self.branch.clone()))
+            .filter(package_error_events::Column::Event.eq(ErrorEvent::INTRODUCED))
+            .order_by_desc(package_error_events::Column::Id)
+            .limit(limit)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| {
+                (
+                    model.package,
+                    ErrorEvent {
+                        event: model.event,
+                        err_type: model.err_type,
+                        message: model.message,
+                        githash: model.githash,
+                        recorded_at: model.recorded_at.to_rfc3339(),
+                    },
+                )
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Section of each package of the tree, e.g. app-shells/bash is in shells
+    pub async fn get_package_sections(&self) -> Result<HashMap<String, String>> {
+        Ok(Packages::find()
+            .select_only()
+            .columns([packages::Column::Name, packages::Column::Section])
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    pub async fn get_packages_name(&self) -> Result<HashSet<String>> {
+        let res = Packages::find()
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .all(&self.conn)
+            .await?;
+        Ok(res.into_iter().map(|model| model.name).collect())
+    }
+
+    /// Names and spec paths of packages of the tree, or only of the package
+    pub async fn get_spec_paths(&self, package: Option<&str>) -> Result<Vec<(String, String)>> {
+        let mut query = Packages::find()
+            .select_only()
+            .columns([packages::Column::Name, packages::Column::SpecPath])
+            .filter(packages::Column::Tree.eq(self.tree.clone()));
+        if let Some(package) = package {
+            query = query.filter(packages::Column::Name.eq(package));
+        }
+
+        Ok(query
+            .order_by_asc(packages::Column::Name)
+            .into_tuple()
+            .all(&self.conn)
+            .await?)
+    }
+
+    /// Stored packages of the tree with neither spec nor defines left at the commit
+    ///
+    /// A package whose spec or defines was removed first is kept as broken,
+    /// and can't be parsed once the other file is removed as well, so it is
+    /// not among the deleted packages found in the diff. Packages in `updated`
+    /// are skipped, their stored spec path is gone if they were moved.
+    pub async fn get_vanished_packages(
+        &self,
+        repo: &Repository,
+        commit: Oid,
+        updated: &[Meta],
+    ) -> Result<Vec<String>> {
+        let tree = repo.find_commit(commit)?.tree()?;
+        let updated: HashSet<_> = updated.iter().map(|(pkg, ..)| pkg.name.as_str()).collect();
+        let vanished = self
+            .get_spec_paths(None)
+            .await?
+            .into_iter()
+            .filter(|(name, spec_path)| {
+                if updated.contains(name.as_str()) {
+                    return false;
+                }
+                let spec_path = Path::new(spec_path);
+                tree.get_path(spec_path).is_err()
+                    && spec_path_to_defines_path(repo, commit, spec_path)
+                        .map_or(true, |defines| defines.is_empty())
+            })
+            .map(|(name, _)| name)
+            .collect();
+
+        Ok(vanished)
+    }
+
+    /// Delete the package and rows referencing it
+    ///
+    /// Rows referencing the package are deleted before the package itself in
+    /// one transaction, so no orphan rows are left if interrupted.
+    pub async fn delete_package(&self, pkg_name: impl AsRef<str>) -> Result<()> {
+        let pkg_name = pkg_name.as_ref();
+        let txn = self.conn.begin().await?;
+        let db = &txn;
+
+        let errors = PackageErrors::find()
+            .filter(package_errors::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_errors::Column::Tree.eq(self.tree.to_string()))
+            .filter(package_errors::Column::Branch.eq(self.branch.to_string()))
+            .filter(package_errors::Column::ErrType.is_not_in(reconcile_error_types()))
+            .all(db)
+            .await?;
+        self.record_error_events(&self.branch, &errors, &[], None, db)
+            .await?;
+        self.resolve_collisions(&self.branch, &[pkg_name.to_string()], &[], None, db)
+            .await?;
+
+        Delete::many(PackageErrors)
+            .filter(package_errors::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_errors::Column::Tree.eq(self.tree.to_string()))
+            .filter(package_errors::Column::Branch.eq(self.branch.to_string()))
+            .exec(db)
+            .await?;
+
+        self.delete_package_rows(pkg_name, db).await?;
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Delete the package and rows referencing it except its errors
+    async fn delete_package_rows(&self, pkg_name: &str, db: &DatabaseTransaction) -> Result<()> {
+        Delete::many(PackageTesting)
+            .filter(package_testing::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_testing::Column::Tree.eq(self.tree.to_string()))
+            .filter(package_testing::Column::Branch.eq(self.branch.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageTestingSpec)
+            .filter(package_testing_spec::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_testing_spec::Column::Tree.eq(self.tree.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageDuplicate)
+            .filter(package_duplicate::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_duplicate::Column::Tree.eq(self.tree.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageVersions)
+            .filter(package_versions::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageArchVersions)
+            .filter(package_arch_versions::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_arch_versions::Column::Branch.eq(self.branch.clone()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageArchitectures)
+            .filter(package_architectures::Column::Package.eq(pkg_name.to_string()))
+            .filter(package_architectures::Column::Branch.eq(self.branch.clone()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageSpec)
+            .filter(package_spec::Column::Package.eq(pkg_name.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageDependencies)
+            .filter(package_dependencies::Column::Package.eq(pkg_name.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageDependencyCounts)
+            .filter(package_dependency_counts::Column::Package.eq(pkg_name.to_string()))
+            .exec(db)
+            .await?;
+
+        Delete::many(PackageUpdateSources)
+            .filter(package_update_sources::Column::Package.eq(pkg_name.to_string()))
+            .exec(db)
+            .await?;
+
+        // packages last, rows above reference it
+        Delete::many(Packages)
+            .filter(packages::Column::Name.eq(pkg_name.to_string()))
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .exec(db)
+            .await?;
+
+        self.append_events(
+            OutboxEvent::PACKAGE_DELETED,
+            &self.branch,
+            [(pkg_name.to_string(), None)],
+            db,
+        )
+        .await
+    }
+
+    /// Find rows referencing packages which don't exist
+    pub async fn check_integrity(&self) -> Result<Vec<IntegrityViolation>> {
+        let mut result = vec![];
+        result.extend(
+            self.find_orphans::<PackageVersions>(package_versions::Column::Package)
+                .await?,
+        );
+        result.extend(
+            self.find_orphans::<PackageArchVersions>(package_arch_versions::Column::Package)
+                .await?,
+        );
+        result.extend(
+            self.find_orphans::<PackageArchitectures>(package_architectures::Column::Package)
+                .await?,
+        );
+        result.extend(
+            self.find_orphans::<PackageSpec>(package_spec::Column::Package)
+                .await?,
+        );
+        result.extend(
+            self.find_orphans::<PackageDependencies>(package_dependencies::Column::Package)
+                .await?,
+        );
+        result.extend(
+            self.find_orphans::<PackageUpdateSources>(package_update_sources::Column::Package)
+                .await?,
+        );
+
+        Ok(result)
+    }
+
+    /// Packages referenced by the column which don't exist in packages
+    async fn find_orphans<E>(&self, column: E::Column) -> Result<Vec<IntegrityViolation>>
+    where
+        E: EntityTrait,
+    {
+        let table = E::default().table_name().to_string();
+        let packages: Vec<String> = E::find()
+            .select_only()
+            .column(column)
+            .distinct()
+            .filter(
+                column.not_in_subquery(
+                    Query::select()
+                        .column(packages::Column::Name)
+                        .from(Packages)
+                        .to_owned(),
+                ),
+            )
+            .order_by_asc(column)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        Ok(packages
+            .into_iter()
+            .map(|package| IntegrityViolation {
+                table: table.clone(),
+                package,
+            })
+            .collect())
+    }
+
+    pub async fn update_testing_branch(
+        &self,
+        commit_db: &CommitDb,
+        repo: &Repository,
+    ) -> Result<Vec<String>> {
+        info!("updating testing branch");
+        let result = commit_db
+            .update_package_testing(
+                repo,
+                &self.testing_branch_filter,
+                self.testing_branch_max_age_days,
+            )
+            .await?;
+
+        let main = scan_branch(repo, repo.get_repo_branch(), Some(1000))?;
+        // packages of stale branches are dropped like those of outdated ones
+        let mut outdated_branches = result.stale.clone();
+
+        // commit of every package_testing row of the tree, instead of one query per package
+        let mut current: HashMap<(String, String), Option<Oid>> = PackageTesting::find()
+            .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let oid = parse_stored(
+                    &self.warnings,
+                    format_args!("package_testing row {}@{}", row.package, row.branch),
+                    &row.commit,
+                );
+                ((row.package, row.branch), oid)
+            })
+            .collect();
+        let mut point_queries = 0;
+        let mut statements = 1;
+        let mut rescanned = 0;
+
+        for (branch, info) in result.branches {
+            info!("scan testing branch {branch}");
+            let testing = scan_branch(repo, &branch, None)?;
+            let last = testing
+                .iter()
+                .filter_map(|(oid, order)| {
+                    main.get(oid)
+                        .map(|main_branch_order| (main_branch_order, order))
+                })
+                .max_by_key(|x| x.0);
+            let (_, last) = if let Some(last) = last {
+                last
+            } else {
+                outdated_branches.push(branch.to_string());
+                continue;
+            };
+
+            // one read per change before batching
+            point_queries += info
+                .iter()
+                .filter(|info| testing.contains_key(&info.commit_id))
+                .count();
+            let (upserts, deletes) =
+                plan_testing_rows(&branch, info, &testing, *last, &mut current);
+            // and at most one write per package
+            point_queries += upserts.len() + deletes.len();
+
+            // errors and specs of the packages are written along with their rows
+            let txn = self.conn.begin().await?;
+            for info in upserts
+                .values()
+                .sorted_by(|a, b| a.pkg_name.cmp(&b.pkg_name))
+            {
+                self.update_testing_package(repo, &branch, info, &txn)
+                    .await?;
+            }
+            rescanned += upserts.len();
+            let upserts = upserts
+                .into_values()
+                .map(|info| package_testing::Model {
+                    spec_path: info.spec_path,
+                    package: info.pkg_name,
+                    version: info.pkg_version,
+                    full_version: info.pkg_full_version,
+                    defines_path: info.defines_path,
+                    branch: branch.clone(),
+                    tree: repo.tree.to_string(),
+                    commit: info.commit_id.to_string(),
+                    last_run_id: self.run_id.clone(),
+                })
+                .collect_vec();
+            let updated = upserts
+                .iter()
+                .map(|model| {
+                    let payload = serde_json::json!({
+                        "full_version": model.full_version,
+                        "commit": model.commit,
+                    });
+                    (model.package.clone(), Some(payload))
+                })
+                .collect_vec();
+            self.append_events(OutboxEvent::TESTING_UPDATED, &branch, updated, &txn)
+                .await?;
+            let removed = deletes.iter().map(|package| (package.clone(), None));
+            self.append_events(OutboxEvent::TESTING_REMOVED, &branch, removed, &txn)
+                .await?;
+            for chunk in &upserts.into_iter().chunks(2048) {
+                replace_many(
+                    chunk.map(|model| model.into_active_model()),
+                    [
+                        package_testing::Column::Package,
+                        package_testing::Column::Tree,
+                        package_testing::Column::Branch,
+                    ],
+                    package_testing::Column::iter(),
+                )
+                .exec(&txn)
+                .await?;
+                statements += 1;
+            }
+            for chunk in &deletes.into_iter().chunks(2048) {
+                PackageTesting::delete_many()
+                    .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
+                    .filter(package_testing::Column::Branch.eq(branch.clone()))
+                    .filter(package_testing::Column::Package.is_in(chunk))
+                    .exec(&txn)
+                    .await?;
+                statements += 1;
+            }
+            txn.commit().await?;
+        }
+        info!(
+            "updated package_testing with {statements} queries instead of {point_queries}, \
+             and errors of {rescanned} packages in one transaction per branch"
+        );
+
+        // delete unused branch
+        let current_branches_name = repo
+            .get_git2repo()
+            .branches(None)?
+            .filter_map(|b| Some(b.ok()?.0.name().ok()??.to_string()))
+            .collect_vec();
+        let gone = Condition::any()
+            .add(package_testing::Column::Branch.is_not_in(current_branches_name))
+            .add(package_testing::Column::Branch.is_in(outdated_branches));
+        let txn = self.conn.begin().await?;
+        let removed = PackageTesting::find()
+            .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
+            .filter(gone.clone())
+            .order_by_asc(package_testing::Column::Branch)
+            .all(&txn)
+            .await?;
+        for (branch, rows) in &removed.into_iter().group_by(|row| row.branch.clone()) {
+            let packages = rows.map(|row| (row.package, None));
+            self.append_events(OutboxEvent::TESTING_REMOVED, &branch, packages, &txn)
+                .await?;
+        }
+        PackageTesting::delete_many()
+            .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
+            .filter(gone)
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        self.prune_testing_rows().await?;
+
+        Ok(result.stale)
+    }
+
+    /// Save errors and, if enabled, parsed spec of the package at the commit of the testing branch
+    async fn update_testing_package(
+        &self,
+        repo: &Repository,
+        branch: &str,
+        info: &CommitInfo,
+        txn: &DatabaseTransaction,
+    ) -> Result<()> {
+        let (res, errors) = scan_package(
+            repo,
+            info.commit_id,
+            &PathBuf::from(&info.spec_path),
+            &PathBuf::from(&info.defines_path),
+            &self.architectures,
+        );
+
+        let githash = info.commit_id.to_string();
+        self.replace_branch_errors(
+            branch,
+            std::slice::from_ref(&info.pkg_name),
+            errors,
+            Some(&githash),
+            txn,
+        )
+        .await?;
+
+        if !self.store_testing_spec {
+            return Ok(());
+        }
+        PackageTestingSpec::delete_many()
+            .filter(package_testing_spec::Column::Package.eq(info.pkg_name.clone()))
+            .filter(package_testing_spec::Column::Tree.eq(self.tree.clone()))
+            .filter(package_testing_spec::Column::Branch.eq(branch))
+            .exec(txn)
+            .await?;
+        // a package failing to parse at the commit keeps no spec, rather than
+        // an outdated one, and diff_testing_spec reports the errors saved above
+        if let Some((_, context, _)) = res {
+            let models = context.into_iter().map(|(key, value)| {
+                package_testing_spec::Model {
+                    package: info.pkg_name.clone(),
+                    tree: self.tree.to_string(),
+                    branch: branch.to_string(),
+                    key,
+                    value,
+                }
+                .into_active_model()
+            });
+            for chunk in &models.chunks(2048) {
+                PackageTestingSpec::insert_many(chunk).exec(txn).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete saved specs and errors of testing branches whose package_testing row is gone
+    ///
+    /// The deleted errors of this tree are recorded as resolved.
+    async fn prune_testing_rows(&self) -> Result<()> {
+        let txn = self.conn.begin().await?;
+        exec(
+            &txn,
+            "DELETE FROM package_testing_spec s WHERE NOT EXISTS (
+                SELECT 1 FROM package_testing t
+                WHERE t.package = s.package AND t.tree = s.tree AND t.branch = s.branch
+            )",
+            [],
+        )
+        .await?;
+        // errors of main branches follow the lifecycle of packages instead
+        let pruned = PackageErrors::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                txn.get_database_backend(),
+                "SELECT * FROM package_errors e WHERE e.tree = $1 AND NOT EXISTS (
+                    SELECT 1 FROM package_testing t
+                    WHERE t.package = e.package AND t.tree = e.tree AND t.branch = e.branch
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM trees WHERE trees.name = e.tree AND trees.mainbranch = e.branch
+                )",
+                [self.tree.clone().into()],
+            ))
+            .all(&txn)
+            .await?;
+        let by_branch = pruned
+            .iter()
+            .cloned()
+            .into_group_map_by(|e| e.branch.clone());
+        for (branch, errors) in by_branch {
+            self.record_error_events(&branch, &errors, &[], None, &txn)
+                .await?;
+        }
+        for ids in &pruned.iter().map(|e| e.id).chunks(4096) {
+            PackageErrors::delete_many()
+                .filter(package_errors::Column::Id.is_in(ids))
+                .exec(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Spec keys of the package changed by the testing branch
+    ///
+    /// Fails if no spec of the package is saved for the branch, like when it
+    /// failed to parse at the last commit of the branch touching it.
+    pub async fn diff_testing_spec(&self, package: &str, branch: &str) -> Result<SpecDiff> {
+        let testing: BTreeMap<_, _> = PackageTestingSpec::find()
+            .filter(package_testing_spec::Column::Package.eq(package))
+            .filter(package_testing_spec::Column::Tree.eq(self.tree.clone()))
+            .filter(package_testing_spec::Column::Branch.eq(branch))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| (model.key, model.value))
+            .collect();
+        if testing.is_empty() {
+            let error = PackageErrors::find()
+                .filter(package_errors::Column::Package.eq(package))
+                .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+                .filter(package_errors::Column::Branch.eq(branch))
+                .order_by_asc(package_errors::Column::Id)
+                .one(&self.conn)
+                .await?;
+            match error {
+                Some(error) => bail!(
+                    "{package} failed to parse in {branch}: {}: {}",
+                    error.path,
+                    error.message
+                ),
+                None => bail!("no spec of {package} is saved for {branch}"),
+            }
+        }
+        // a package of the same name in another tree is not the one in the main branch
+        let in_tree = Packages::find_by_id(package)
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .one(&self.conn)
+            .await?
+            .is_some();
+        let main: BTreeMap<_, _> = if in_tree {
+            PackageSpec::find()
+                .filter(package_spec::Column::Package.eq(package))
+                .all(&self.conn)
+                .await?
+                .into_iter()
+                .map(|model| (model.key, model.value))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut diff = SpecDiff {
+            package: package.to_string(),
+            branch: branch.to_string(),
+            ..Default::default()
+        };
+        for (key, value) in &testing {
+            match main.get(key) {
+                None => diff.added.push((key.clone(), value.clone())),
+                Some(old) if old != value => {
+                    diff.changed.push((key.clone(), old.clone(), value.clone()))
+                }
+                _ => {}
+            }
+        }
+        for (key, value) in &main {
+            if !testing.contains_key(key) {
+                diff.removed.push((key.clone(), value.clone()));
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Packages of the testing branch with a saved spec
+    pub async fn get_testing_spec_packages(&self, branch: &str) -> Result<Vec<String>> {
+        let res = PackageTestingSpec::find()
+            .select_only()
+            .column(package_testing_spec::Column::Package)
+            .distinct()
+            .filter(package_testing_spec::Column::Tree.eq(self.tree.clone()))
+            .filter(package_testing_spec::Column::Branch.eq(branch))
+            .order_by_asc(package_testing_spec::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Compare packages of the testing branch at its tip with those of the main branch
+    ///
+    /// Packages are the ones recorded in package_testing by the last scan, parsed
+    /// again at the current tip of the branch. Nothing is written.
+    pub async fn simulate_merge(&self, repo: &Repository, branch: &str) -> Result<MergeSimulation> {
+        let rows = PackageTesting::find()
+            .filter(package_testing::Column::Tree.eq(self.tree.clone()))
+            .filter(package_testing::Column::Branch.eq(branch))
+            .order_by_asc(package_testing::Column::Package)
+            .all(&self.conn)
+            .await?;
+        if rows.is_empty() {
+            bail!("no packages of testing branch {branch} are recorded, please scan it first");
+        }
+
+        let tip = repo.get_branch_oid(branch)?;
+        let tip_tree = repo.find_commit(tip)?.tree()?;
+        // packages deleted by the branch are not parsed, and show up as removed
+        let paths = rows
+            .iter()
+            .map(|row| {
+                (
+                    PathBuf::from(&row.spec_path),
+                    PathBuf::from(&row.defines_path),
+                )
+            })
+            .filter(|(spec, defines)| {
+                tip_tree.get_path(spec).is_ok() && tip_tree.get_path(defines).is_ok()
+            })
+            .collect_vec();
+        let (metas, failed) = scan_packages(
+            repo,
+            tip,
+            paths
+                .iter()
+                .map(|(spec, defines)| (spec, defines))
+                .collect(),
+            &self.architectures,
+        );
+
+        let names = rows
+            .iter()
+            .map(|row| row.package.clone())
+            .filter(|name| !failed.contains(name))
+            .collect_vec();
+        let old = Snapshot::load(&self.conn, Some((&names, &self.branch))).await?;
+        let new = Snapshot::from_packages(&metas, &self.branch);
+
+        let mut conflicts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for row in PackageTesting::find()
+            .filter(package_testing::Column::Tree.eq(self.tree.clone()))
+            .filter(package_testing::Column::Branch.ne(branch))
+            .filter(package_testing::Column::Package.is_in(rows.iter().map(|row| &row.package)))
+            .order_by_asc(package_testing::Column::Branch)
+            .all(&self.conn)
+            .await?
+        {
+            conflicts.entry(row.package).or_default().push(row.branch);
+        }
+
+        Ok(MergeSimulation {
+            branch: branch.to_string(),
+            commit: tip.to_string(),
+            diff: diff_snapshots(&old, &new),
+            conflicts: conflicts
+                .into_iter()
+                .map(|(package, branches)| OverrideConflict { package, branches })
+                .collect(),
+            failed: failed.into_iter().sorted().collect(),
+        })
+    }
+
+    /// Record the commit the metadata of the tree is current as of
+    ///
+    /// Should be called after a successful scan with the commit the packages
+    /// were scanned at, which may be behind the tip of the branch.
+    pub async fn record_head(&self, repo: &Repository, commit: Oid) -> Result<()> {
+        let time = to_datetime(&repo.find_commit(commit)?.time());
+        Trees::update_many()
+            .col_expr(trees::Column::HeadCommit, Expr::value(commit.to_string()))
+            .col_expr(trees::Column::HeadCommitTime, Expr::value(time))
+            .col_expr(
+                trees::Column::UpdatedAt,
+                Expr::value(Local::now().fixed_offset()),
+            )
+            .filter(trees::Column::Name.eq(self.tree.clone()))
+            .exec(&self.conn)
+            .await?;
+        info!("{} is current as of {commit} ({time})", self.tree);
+
+        Ok(())
+    }
+
+    /// Replace package groups of the tree with the group files at the tip of the branch
+    ///
+    /// Members which are not packages of the tree are only warned about.
+    pub async fn update_groups(&self, repo: &Repository) -> Result<()> {
+        let commit = repo.get_branch_oid(&self.branch)?;
+        let groups = scan_groups(repo, commit)?;
+        info!("updating {} package groups", groups.len());
+
+        let members: HashSet<_> = groups.values().flatten().cloned().collect();
+        let existing: HashSet<String> = Packages::find()
+            .select_only()
+            .column(packages::Column::Name)
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .filter(packages::Column::Name.is_in(members))
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect();
+
+        let mut models = vec![];
+        for (group, packages) in groups.into_iter().sorted() {
+            for package in packages {
+                if !existing.contains(&package) {
+                    self.warnings.warn(
+                        "group member",
+                        format_args!("group {group} lists nonexistent package {package}"),
+                    );
+                }
+                models.push(
+                    package_groups::Model {
+                        group: group.clone(),
+                        package,
+                        tree: self.tree.to_string(),
+                    }
+                    .into_active_model(),
+                );
+            }
+        }
+
+        let txn = self.conn.begin().await?;
+        PackageGroups::delete_many()
+            .filter(package_groups::Column::Tree.eq(self.tree.clone()))
+            .exec(&txn)
+            .await?;
+        for chunk in &models.into_iter().chunks(2048) {
+            PackageGroups::insert_many(chunk)
+                .exec_without_returning(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Members of the group, empty if the group doesn't exist
+    pub async fn get_group(&self, name: &str) -> Result<Vec<String>> {
+        let res = PackageGroups::find()
+            .select_only()
+            .column(package_groups::Column::Package)
+            .filter(package_groups::Column::Tree.eq(self.tree.clone()))
+            .filter(package_groups::Column::Group.eq(name))
+            .order_by_asc(package_groups::Column::Package)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Groups listing the package
+    pub async fn get_groups_for_package(&self, name: &str) -> Result<Vec<String>> {
+        let res = PackageGroups::find()
+            .select_only()
+            .column(package_groups::Column::Group)
+            .filter(package_groups::Column::Tree.eq(self.tree.clone()))
+            .filter(package_groups::Column::Package.eq(name))
+            .order_by_asc(package_groups::Column::Group)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Groups of the tree with their number of members
+    pub async fn get_groups(&self) -> Result<Vec<(String, i64)>> {
+        let res = PackageGroups::find()
+            .select_only()
+            .column(package_groups::Column::Group)
+            .column_as(package_groups::Column::Package.count(), "count")
+            .filter(package_groups::Column::Tree.eq(self.tree.clone()))
+            .group_by(package_groups::Column::Group)
+            .order_by_asc(package_groups::Column::Group)
+            .into_tuple()
+            .all(&self.conn)
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Recount package_stats of the tree, should be called at the end of each run
+    ///
+    /// Packages and packages with errors in the branch are counted by section
+    /// and category, so readers don't aggregate the tables while they are written.
+    pub async fn refresh_stats(&self) -> Result<()> {
+        let txn = self.conn.begin().await?;
+        let counts: Vec<(String, String, i64)> = Packages::find()
+            .select_only()
+            .columns([packages::Column::Section, packages::Column::Category])
+            .column_as(packages::Column::Name.count(), "count")
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .group_by(packages::Column::Section)
+            .group_by(packages::Column::Category)
+            .into_tuple()
+            .all(&txn)
+            .await?;
+        let with_errors = Query::select()
+            .column(package_errors::Column::Package)
+            .from(PackageErrors)
+            .and_where(package_errors::Column::Tree.eq(self.tree.clone()))
+            .and_where(package_errors::Column::Branch.eq(self.branch.clone()))
+            .to_owned();
+        let error_counts: HashMap<(String, String), i64> = Packages::find()
+            .select_only()
+            .columns([packages::Column::Section, packages::Column::Category])
+            .column_as(packages::Column::Name.count(), "count")
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .filter(packages::Column::Name.in_subquery(with_errors))
+            .group_by(packages::Column::Section)
+            .group_by(packages::Column::Category)
+            .into_tuple()
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|(section, category, count)| ((section, category), count))
+            .collect();
+
+        PackageStats::delete_many()
+            .filter(package_stats::Column::Tree.eq(self.tree.to_string()))
+            .filter(package_stats::Column::Branch.eq(self.branch.clone()))
+            .exec(&txn)
+            .await?;
+        let now = Local::now().fixed_offset();
+        let models = counts.into_iter().map(|(section, category, count)| {
+            let error_count = error_counts
+                .get(&(section.clone(), category.clone()))
+                .copied()
+                .unwrap_or_default();
+            package_stats::Model {
+                tree: self.tree.to_string(),
+                branch: self.branch.clone(),
+                section,
+                category,
+                count,
+                error_count,
+                updated_at: now,
+            }
+            .into_active_model()
+        });
+        for chunk in &models.chunks(2048) {
+            replace_many(
+                chunk,
+                [
+                    package_stats::Column::Tree,
+                    package_stats::Column::Branch,
+                    package_stats::Column::Section,
+                    package_stats::Column::Category,
+                ],
+                package_stats::Column::iter(),
+            )
+            .exec(&txn)
+            .await?;
+        }
+        txn.commit().await?;
+
+        Ok(())
+    }
+
+    /// Package counts of the tree in its main branch, by section and category
+    pub async fn get_stats(&self, tree: &str) -> Result<Vec<package_stats::Model>> {
+        Ok(PackageStats::find()
+            .filter(package_stats::Column::Tree.eq(tree))
+            .filter(
+                package_stats::Column::Branch.in_subquery(
+                    Query::select()
+                        .column(trees::Column::Mainbranch)
+                        .from(Trees)
+                        .and_where(trees::Column::Name.eq(tree))
+                        .to_owned(),
+                ),
+            )
+            .order_by_asc(package_stats::Column::Section)
+            .order_by_asc(package_stats::Column::Category)
+            .all(&self.conn)
+            .await?)
+    }
+
+    /// Post-scan checks across packages, should be called after all packages are updated
+    pub async fn reconcile(&self, repo: &Repository) -> Result<()> {
+        info!("reconciling packages");
+        self.check_provider_collisions().await?;
+        self.check_constraints().await?;
+        self.reconcile_duplicates(repo).await?;
+
+        Ok(())
+    }
+
+    /// Remove duplicate locations which no longer exist in the tree
+    ///
+    /// Locations of other trees are left to their own scans. When only one
+    /// location of a package remains, the conflict is resolved and the whole
+    /// group is removed.
+    pub async fn reconcile_duplicates(&self, repo: &Repository) -> Result<()> {
+        let txn = self.conn.begin().await?;
+        let head = repo.get_branch_oid(&self.branch)?;
+
+        let duplicates = PackageDuplicate::find()
+            .filter(
+                package_duplicate::Column::Package.in_subquery(
+                    Query::select()
+                        .column(package_duplicate::Column::Package)
+                        .from(PackageDuplicate)
+                        .and_where(package_duplicate::Column::Tree.eq(self.tree.clone()))
+                        .to_owned(),
+                ),
+            )
+            .all(&txn)
+            .await?;
+
+        let mut remaining: HashMap<String, usize> = HashMap::new();
+        for dup in duplicates {
+            let exists = dup.tree != self.tree
+                || repo.path_exists(
+                    format!("{}-{}/{}/spec", dup.category, dup.section, dup.directory),
+                    head,
+                )?;
+            if exists {
+                *remaining.entry(dup.package).or_default() += 1;
+            } else {
+                info!(
+                    "duplicate location {}-{}/{} of package \"{}\" no longer exists",
+                    dup.category, dup.section, dup.directory, dup.package
+                );
+                remaining.entry(dup.package.clone()).or_default();
+                dup.delete(&txn).await?;
+            }
+        }
+
+        let resolved = remaining
+            .into_iter()
+            .filter(|(_, count)| *count <= 1)
+            .map(|(package, _)| package)
+            .sorted()
+            .collect_vec();
+        for package in &resolved {
+            info!("duplicate package \"{package}\" resolved");
+        }
+        if !resolved.is_empty() {
+            PackageDuplicate::delete_many()
+                .filter(package_duplicate::Column::Package.is_in(resolved))
+                .exec(&txn)
+                .await?;
+        }
+        self.check_duplicate_resolutions(repo, head, &txn).await?;
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Oldest change on the branch in the newest run of changes with the
+    /// current version, i.e. when the version landed before packaging fixes
+    ///
+    /// Changes only record VER, so release bumps don't start a new run.
+    fn version_first_change<'a>(&self, pkg: &Package, changes: &'a [Change]) -> Option<&'a Change> {
+        changes
+            .iter()
+            .filter(|change| change.branch == self.branch)
+            .sorted_by(|a, b| (b.timestamp, &b.githash).cmp(&(a.timestamp, &a.githash)))
+            .take_while(|change| change.version == pkg.version)
+            .last()
+    }
+
+    /// Change whose commit is recorded as the source of the version of the branch
+    ///
+    /// The newest change on the branch with the version being written, or
+    /// else the newest change on the branch, or else the newest change at all.
+    /// Commit times break ties by hash, so the choice doesn't depend on the
+    /// order of `changes`, which must not be empty.
+    fn version_change<'a>(&self, pkg: &Package, changes: &'a [Change]) -> &'a Change {
+        let newest = |changes: &mut dyn Iterator<Item = &'a Change>| {
+            changes.max_by(|a, b| (a.timestamp, &a.githash).cmp(&(b.timestamp, &b.githash)))
+        };
+        let on_branch = || changes.iter().filter(|change| change.branch == self.branch);
+
+        if let Some(change) =
+            newest(&mut on_branch().filter(|change| change.version == pkg.version))
+        {
+            return change;
+        }
+        if let Some(change) = newest(&mut on_branch()) {
+            self.warnings.warn(
+                "version change",
+                format_args!(
+                    "no change of {} on {} has version {}, using the newest one",
+                    pkg.name, self.branch, pkg.version
+                ),
+            );
+            return change;
+        }
+        self.warnings.warn(
+            "version change",
+            format_args!(
+                "no change of {} on {}, using the newest one of any branch",
+                pkg.name, self.branch
+            ),
+        );
+        newest(&mut changes.iter()).unwrap_or(&changes[0])
+    }
+
+    /// Whether the existing copy of a duplicate package is preferred over the scanned one
+    async fn prefers_existing(
+        &self,
+        pkg: &Package,
+        existing: &packages::Model,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool> {
+        let Some(resolution) = PackageDuplicateResolution::find_by_id(pkg.name.clone())
+            .one(db)
+            .await?
+            .filter(|resolution| !resolution.stale)
+        else {
+            return Ok(false);
+        };
+
+        let preferred = location(
+            &resolution.tree,
+            &resolution.category,
+            &resolution.section,
+            &resolution.directory,
+        );
+        let scanned = location(&self.tree, &pkg.category, &pkg.section, &pkg.directory);
+        let existing = location(
+            &existing.tree,
+            &existing.category,
+            &existing.section,
+            &existing.directory,
+        );
+
+        Ok(preferred == existing && preferred != scanned)
+    }
+
+    /// Duplicate packages with a location in the tree
+    pub async fn get_duplicates(&self) -> Result<Vec<DuplicatePackage>> {
+        let duplicates = PackageDuplicate::find()
+            .filter(
+                package_duplicate::Column::Package.in_subquery(
+                    Query::select()
+                        .column(package_duplicate::Column::Package)
+                        .from(PackageDuplicate)
+                        .and_where(package_duplicate::Column::Tree.eq(self.tree.clone()))
+                        .to_owned(),
+                ),
+            )
+            .order_by_asc(package_duplicate::Column::Package)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .into_group_map_by(|dup| dup.package.clone());
+        let names = duplicates.keys().cloned().collect_vec();
+        let canonical: HashMap<_, _> = Packages::find()
+            .filter(packages::Column::Name.is_in(names.clone()))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|pkg| {
+                let location = location(&pkg.tree, &pkg.category, &pkg.section, &pkg.directory);
+                (pkg.name, location)
+            })
+            .collect();
+        let resolutions: HashMap<_, _> = PackageDuplicateResolution::find()
+            .filter(package_duplicate_resolution::Column::Package.is_in(names))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|r| {
+                let location = location(&r.tree, &r.category, &r.section, &r.directory);
+                (r.package, (location, r.stale))
+            })
+            .collect();
+
+        let res = duplicates
+            .into_iter()
+            .sorted_by(|a, b| a.0.cmp(&b.0))
+            .map(|(package, dups)| {
+                let locations = dups
+                    .into_iter()
+                    .map(|dup| {
+                        let location =
+                            location(&dup.tree, &dup.category, &dup.section, &dup.directory);
+                        let resolution = resolutions
+                            .get(&package)
+                            .filter(|(preferred, _)| *preferred == location);
+                        DuplicateLocation {
+                            canonical: canonical.get(&package) == Some(&location),
+                            preferred: resolution.is_some(),
+                            stale: resolution.is_some_and(|(_, stale)| *stale),
+                            version: dup.full_version,
+                            location,
+                        }
+                    })
+                    .sorted_by(|a, b| a.location.cmp(&b.location))
+                    .collect();
+                DuplicatePackage { package, locations }
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Keep the copy of a duplicate package at the location in future scans
+    ///
+    /// The location is written like aosc-os-abbs/app-shells/bash and must be a
+    /// recorded location of the package. The canonical row changes on the next
+    /// scan of the preferred copy.
+    pub async fn resolve_duplicate(&self, package: &str, prefer: &str) -> Result<()> {
+        let dups = PackageDuplicate::find()
+            .filter(package_duplicate::Column::Package.eq(package))
+            .all(&self.conn)
+            .await?;
+        if dups.is_empty() {
+            bail!("package {package} is not a duplicate");
+        }
+        let locations = dups
+            .iter()
+            .map(|dup| location(&dup.tree, &dup.category, &dup.section, &dup.directory))
+            .collect_vec();
+        let Some(dup) = locations
+            .iter()
+            .position(|location| location == prefer.trim_end_matches('/'))
+            .map(|i| &dups[i])
+        else {
+            bail!(
+                "{prefer} is not a location of {package}, expected one of {}",
+                locations.join(", ")
+            );
+        };
+
+        package_duplicate_resolution::Model {
+            package: package.to_string(),
+            tree: dup.tree.clone(),
+            category: dup.category.clone(),
+            section: dup.section.clone(),
+            directory: dup.directory.clone(),
+            stale: false,
+            resolved_at: Local::now().fixed_offset(),
+        }
+        .replace(
+            &self.conn,
+            [package_duplicate_resolution::Column::Package],
+            package_duplicate_resolution::Column::iter(),
+        )
+        .await?;
+        info!("prefer {prefer} for duplicate package \"{package}\"");
+
+        Ok(())
+    }
+
+    /// Flag resolutions of the tree whose preferred location is gone, and unflag returning ones
+    async fn check_duplicate_resolutions(
+        &self,
+        repo: &Repository,
+        head: Oid,
+        db: &impl ConnectionTrait,
+    ) -> Result<()> {
+        let resolutions = PackageDuplicateResolution::find()
+            .filter(package_duplicate_resolution::Column::Tree.eq(self.tree.clone()))
+            .all(db)
+            .await?;
+        for resolution in resolutions {
+            let exists = repo.path_exists(
+                format!(
+                    "{}-{}/{}/spec",
+                    resolution.category, resolution.section, resolution.directory
+                ),
+                head,
+            )?;
+            if exists != resolution.stale {
+                continue;
+            }
+
+            if exists {
+                info!(
+                    "preferred location of duplicate package \"{}\" exists again",
+                    resolution.package
+                );
+            } else {
+                self.warnings.warn(
+                    "stale resolution",
+                    format_args!(
+                        "preferred location {}-{}/{} of duplicate package \"{}\" no longer exists",
+                        resolution.category,
+                        resolution.section,
+                        resolution.directory,
+                        resolution.package
+                    ),
+                );
+            }
+            let mut resolution = resolution.into_active_model();
+            resolution.stale = Set(!exists);
+            resolution.update(db).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Record an issue on each package which provides the same name as other packages
+    pub async fn check_provider_collisions(&self) -> Result<()> {
+        let txn = self.conn.begin().await?;
+
+        PackageErrors::delete_many()
+            .filter(package_errors::Column::ErrType.eq(ErrorType::Provider.to_string()))
+            .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+            .filter(package_errors::Column::Branch.eq(self.branch.clone()))
+            .exec(&txn)
+            .await?;
+
+        let provides = PackageDependencies::find()
+            .filter(package_dependencies::Column::Relationship.eq("PKGPROV"))
+            .find_also_related(Packages)
+            .all(&txn)
+            .await?;
+
+        // provided name -> package name -> package
+        let mut providers: HashMap<String, BTreeMap<String, packages::Model>> = HashMap::new();
+        for (dep, pkg) in provides {
+            let pkg = skip_none!(pkg);
+            providers
+                .entry(dep.dependency)
+                .or_default()
+                .insert(pkg.name.clone(), pkg);
+        }
+
+        let errors = providers
+            .iter()
+            .filter(|(_, packages)| packages.len() > 1)
+            .flat_map(|(name, packages)| {
+                packages
+                    .values()
+                    .filter(|pkg| pkg.tree == self.tree)
+                    .map(move |pkg| {
+                        let others = packages
+                            .keys()
+                            .filter(|other| **other != pkg.name)
+                            .join(", ");
+                        package_errors::ActiveModel {
+                            package: Set(pkg.name.clone()),
+                            err_type: Set(ErrorType::Provider.to_string()),
+                            message: Set(format!("{name} is also provided by {others}")),
+                            path: Set(pkg.spec_path.clone()),
+                            tree: Set(self.tree.to_string()),
+                            branch: Set(self.branch.clone()),
+                            line: Set(None),
+                            col: Set(None),
+                            end_line: Set(None),
+                            end_col: Set(None),
+                            last_run_id: Set(self.run_id.clone()),
+                            id: NotSet,
+                        }
+                    })
+            })
+            .collect_vec();
+
+        if !errors.is_empty() {
+            info!("{} packages provide names shared with others", errors.len());
+            PackageErrors::insert_many(errors).exec(&txn).await?;
+        }
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Check versioned dependencies of the tree against the versions of its main branch
+    ///
+    /// Dependencies resolve to the package of the same name, or to a package
+    /// providing the name, with the version given in PKGPROV if any. Only
+    /// packages of this tree are considered.
+    pub async fn get_unsatisfied_constraints(&self) -> Result<UnsatisfiedConstraints> {
+        let names = Query::select()
+            .column(packages::Column::Name)
+            .from(Packages)
+            .and_where(packages::Column::Tree.eq(self.tree.clone()))
+            .to_owned();
+
+        let versions: HashMap<String, String> = PackageVersions::find()
+            .select_only()
+            .columns([
+                package_versions::Column::Package,
+                package_versions::Column::FullVersion,
+            ])
+            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
+            .filter(package_versions::Column::Package.in_subquery(names.clone()))
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect();
+
+        // provided name -> (provider, version)
+        let mut provided: HashMap<String, (String, Option<String>)> = HashMap::new();
+        for dep in PackageDependencies::find()
+            .filter(package_dependencies::Column::Relationship.eq("PKGPROV"))
+            .filter(package_dependencies::Column::Package.in_subquery(names.clone()))
+            .order_by_asc(package_dependencies::Column::Package)
+            .all(&self.conn)
+            .await?
+        {
+            let version = match dep.relop.as_deref() {
+                Some("=") => dep.version,
+                _ => versions.get(&dep.package).cloned(),
+            };
+            provided
+                .entry(dep.dependency)
+                .or_insert((dep.package, version));
+        }
+
+        let mut res = UnsatisfiedConstraints::default();
+        for dep in PackageDependencies::find()
+            .filter(package_dependencies::Column::Relationship.is_in(DEPENDENCY_RELATIONSHIPS))
+            .filter(package_dependencies::Column::Package.in_subquery(names))
+            .filter(package_dependencies::Column::Relop.is_not_null())
+            .order_by_asc(package_dependencies::Column::Package)
+            .order_by_asc(package_dependencies::Column::Relationship)
+            .order_by_asc(package_dependencies::Column::Dependency)
+            .all(&self.conn)
+            .await?
+        {
+            let (Some(relop), Some(required)) = (&dep.relop, &dep.version) else {
+                continue;
+            };
+            let Ok(op) = relop.parse::<RelOp>() else {
+                continue;
+            };
+            let (provider, version) = match versions.get(&dep.dependency) {
+                Some(version) => (Some(dep.dependency.clone()), Some(version.clone())),
+                None => match provided.get(&dep.dependency) {
+                    Some((provider, version)) => (Some(provider.clone()), version.clone()),
+                    None => (None, None),
+                },
+            };
+            let satisfied = version
+                .as_deref()
+                .map(|version| op.satisfied_by(version, required));
+            let check = ConstraintCheck {
+                constraint: format!("{}{relop}{required}", dep.dependency),
+                package: dep.package,
+                relationship: dep.relationship,
+                architecture: dep.architecture,
+                provider,
+                version,
+            };
+            match satisfied {
+                Some(true) => {}
+                Some(false) => res.violated.push(check),
+                None => res.unevaluable.push(check),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Record an issue on each package with versioned dependencies the tree can't satisfy
+    pub async fn check_constraints(&self) -> Result<()> {
+        let violated = self.get_unsatisfied_constraints().await?.violated;
+        let spec_paths: HashMap<_, _> = self.get_spec_paths(None).await?.into_iter().collect();
+
+        let txn = self.conn.begin().await?;
+        PackageErrors::delete_many()
+            .filter(package_errors::Column::ErrType.eq(ErrorType::Constraint.to_string()))
+            .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+            .filter(package_errors::Column::Branch.eq(self.branch.clone()))
+            .exec(&txn)
+            .await?;
+
+        let errors = violated
+            .into_iter()
+            .map(|check| {
+                let by = match (&check.provider, &check.version) {
+                    (Some(provider), Some(version)) => format!("{provider} {version}"),
+                    _ => "the tree".to_string(),
+                };
+                package_errors::ActiveModel {
+                    message: Set(format!(
+                        "{} {} is not satisfied by {by}",
+                        check.relationship, check.constraint
+                    )),
+                    path: Set(spec_paths.get(&check.package).cloned().unwrap_or_default()),
+                    package: Set(check.package),
+                    err_type: Set(ErrorType::Constraint.to_string()),
+                    tree: Set(self.tree.to_string()),
+                    branch: Set(self.branch.clone()),
+                    line: Set(None),
+                    col: Set(None),
+                    end_line: Set(None),
+                    end_col: Set(None),
+                    last_run_id: Set(self.run_id.clone()),
+                    id: NotSet,
+                }
+            })
+            .collect_vec();
+
+        if !errors.is_empty() {
+            info!("{} versioned dependencies are not satisfied", errors.len());
+            PackageErrors::insert_many(errors).exec(&txn).await?;
+        }
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Get all packages providing the name, ordered by tree priority in descending order
+    pub async fn get_providers(&self, name: &str) -> Result<Vec<Provider>> {
+        let priorities: HashMap<_, _> = Trees::find()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|tree| (tree.name, tree.tid))
+            .collect();
+
+        let providers = PackageDependencies::find()
+            .filter(package_dependencies::Column::Relationship.eq("PKGPROV"))
+            .filter(package_dependencies::Column::Dependency.eq(name))
+            .find_also_related(Packages)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .filter_map(|(_, pkg)| pkg)
+            .map(|pkg| Provider {
+                priority: priorities.get(&pkg.tree).copied().unwrap_or_default(),
+                package: pkg.name,
+                tree: pkg.tree,
+            })
+            .sorted_by(|left, right| {
+                (right.priority, &left.package).cmp(&(left.priority, &right.package))
+            })
+            .dedup_by(|left, right| left.package == right.package)
+            .collect();
+
+        Ok(providers)
+    }
+
+    /// Packages depending on the name, optionally only on one architecture
+    ///
+    /// With `arch`, dependencies of a relationship overridden for the
+    /// architecture (e.g. PKGDEP__ARM64) replace the default ones, like abbs
+    /// does when building, and packages whose FAIL_ARCH matches the
+    /// architecture are left out. Without it every row is returned.
+    pub async fn get_reverse_dependencies(
+        &self,
+        name: &str,
+        arch: Option<&str>,
+    ) -> Result<Vec<ReverseDependency>> {
+        let rows = PackageDependencies::find()
+            .filter(package_dependencies::Column::Dependency.eq(name))
+            .filter(package_dependencies::Column::Relationship.is_in(DEPENDENCY_RELATIONSHIPS))
+            .order_by_asc(package_dependencies::Column::Package)
+            .order_by_asc(package_dependencies::Column::Relationship)
+            .order_by_asc(package_dependencies::Column::Architecture)
+            .all(&self.conn)
+            .await?;
+        let Some(arch) = arch.map(str::to_lowercase) else {
+            return rows
+                .into_iter()
+                .map(|model| {
+                    Ok(ReverseDependency {
+                        package: model.package.clone(),
+                        dependency: model.try_into()?,
+                    })
+                })
+                .collect();
+        };
+
+        let packages = rows.iter().map(|model| model.package.clone()).unique();
+        // (package, relationship) overridden for the architecture
+        let overridden: HashSet<(String, String)> = PackageDependencies::find()
+            .select_only()
+            .columns([
+                package_dependencies::Column::Package,
+                package_dependencies::Column::Relationship,
+            ])
+            .distinct()
+            .filter(package_dependencies::Column::Package.is_in(packages.clone()))
+            .filter(
+                Expr::expr(Func::lower(Expr::col(
+                    package_dependencies::Column::Architecture,
+                )))
+                .eq(arch.as_str()),
+            )
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect();
+        let unavailable: HashSet<String> = PackageSpec::find()
+            .filter(package_spec::Column::Package.is_in(packages))
+            .filter(package_spec::Column::Key.eq("FAIL_ARCH"))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .filter(|model| fail_arch_matches(&model.value, &arch))
+            .map(|model| model.package)
+            .collect();
+
+        rows.into_iter()
+            .filter(|model| !unavailable.contains(&model.package))
+            .filter(|model| {
+                let overrides =
+                    overridden.contains(&(model.package.clone(), model.relationship.clone()));
+                if model.architecture.is_empty() {
+                    !overrides
+                } else {
+                    model.architecture.eq_ignore_ascii_case(&arch)
+                }
+            })
+            .map(|model| {
+                Ok(ReverseDependency {
+                    package: model.package.clone(),
+                    dependency: model.try_into()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Dependencies of every package of the tree, with three queries
+    pub async fn get_dep_matrix(&self) -> Result<DepMatrix> {
+        let names = Query::select()
+            .column(packages::Column::Name)
+            .from(Packages)
+            .and_where(packages::Column::Tree.eq(self.tree.clone()))
+            .to_owned();
+
+        let mut versions: HashMap<String, String> = PackageVersions::find()
+            .select_only()
+            .columns([
+                package_versions::Column::Package,
+                package_versions::Column::FullVersion,
+            ])
+            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
+            .filter(package_versions::Column::Package.in_subquery(names.clone()))
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect();
+
+        let mut provides: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut dependencies: HashMap<String, BTreeMap<String, Vec<ArchDependency>>> =
+            HashMap::new();
+        for model in PackageDependencies::find()
+            .filter(package_dependencies::Column::Package.in_subquery(names))
+            .order_by_asc(package_dependencies::Column::Package)
+            .order_by_asc(package_dependencies::Column::Relationship)
+            .order_by_asc(package_dependencies::Column::Architecture)
+            .order_by_asc(package_dependencies::Column::Dependency)
+            .all(&self.conn)
+            .await?
+        {
+            if model.relationship == "PKGPROV" {
+                provides
+                    .entry(model.dependency)
+                    .or_default()
+                    .push(model.package);
+                continue;
+            }
+            let dependency = Dependency::try_from(&model)?;
+            dependencies
+                .entry(model.package)
+                .or_default()
+                .entry(model.relationship)
+                .or_default()
+                .push(ArchDependency {
+                    architecture: model.architecture,
+                    dependency,
+                });
+        }
+
+        let packages = Packages::find()
+            .select_only()
+            .column(packages::Column::Name)
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .order_by_asc(packages::Column::Name)
+            .into_tuple::<String>()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|name| DepMatrixPackage {
+                version: versions.remove(&name),
+                dependencies: dependencies.remove(&name).unwrap_or_default(),
+                name,
+            })
+            .collect();
+
+        Ok(DepMatrix {
+            format_version: DEP_MATRIX_VERSION,
+            tree: self.tree.to_string(),
+            branch: self.branch.clone(),
+            head_commit: self.get_head_commit().await?,
+            generated_at: Local::now().fixed_offset(),
+            packages,
+            provides,
+        })
+    }
+
+    /// Classify names as packages, names provided by packages, or unknown names
+    ///
+    /// Names are compared case-insensitively, as the naming policy treats
+    /// names differing only in case as collisions.
+    pub async fn classify_names(&self, names: &[String]) -> Result<HashMap<String, NameClass>> {
+        let lowered = names
+            .iter()
+            .map(|name| name.to_lowercase())
+            .unique()
+            .collect_vec();
+
+        let packages: HashMap<_, _> = Packages::find()
+            .filter(Expr::expr(Func::lower(Expr::col(packages::Column::Name))).is_in(&lowered))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|pkg| (pkg.name.to_lowercase(), pkg))
+            .collect();
+
+        let mut providers: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for dep in PackageDependencies::find()
+            .filter(package_dependencies::Column::Relationship.eq("PKGPROV"))
+            .filter(
+                Expr::expr(Func::lower(Expr::col(
+                    package_dependencies::Column::Dependency,
+                )))
+                .is_in(&lowered),
+            )
+            .all(&self.conn)
+            .await?
+        {
+            providers
+                .entry(dep.dependency.to_lowercase())
+                .or_default()
+                .insert(dep.package);
+        }
+
+        let result = names
+            .iter()
+            .map(|name| {
+                let key = name.to_lowercase();
+                let class = if let Some(pkg) = packages.get(&key) {
+                    NameClass::Package {
+                        name: pkg.name.clone(),
+                        tree: pkg.tree.clone(),
+                        section: pkg.section.clone(),
+                    }
+                } else if let Some(by) = providers.get(&key) {
+                    NameClass::Provided {
+                        by: by.iter().cloned().collect(),
+                    }
+                } else {
+                    NameClass::Unknown
+                };
+                (name.clone(), class)
+            })
+            .collect();
+
+        Ok(result)
+    }
+
+    /// Get testing branches carrying changes of the package
+    pub async fn get_package_testing(&self, name: &str) -> Result<Vec<TestingInfo>> {
+        let res = PackageTesting::find()
+            .filter(package_testing::Column::Package.eq(name))
+            .filter(package_testing::Column::Tree.eq(self.tree.clone()))
+            .order_by_asc(package_testing::Column::Branch)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| TestingInfo {
+                branch: model.branch,
+                version: model.full_version,
+                commit: model.commit,
+                spec_path: model.spec_path,
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Packages without changes since the time, oldest first
+    ///
+    /// Mass changes (see mass_change_threshold) don't count as activity, so
+    /// packages only touched by tree-wide commits are inactive, and listed
+    /// first without a last change. Empty `sections` means every section.
+    pub async fn get_inactive_packages(
+        &self,
+        since: DateTimeWithTimeZone,
+        sections: &[String],
+    ) -> Result<Vec<InactivePackage>> {
+        let rows = self
+            .conn
+            .query_all(Statement::from_sql_and_values(
+                self.conn.get_database_backend(),
+                INACTIVE_PACKAGES_QUERY,
+                [self.tree.clone().into(), since.into()],
+            ))
+            .await?;
+
+        let mut res = vec![];
+        for row in rows {
+            let section: String = row.try_get("", "section")?;
+            if !sections.is_empty() && !sections.contains(&section) {
+                continue;
+            }
+            res.push(InactivePackage {
+                package: row.try_get("", "name")?,
+                section,
+                last_change_time: row.try_get("", "timestamp")?,
+                last_maintainer: row.try_get("", "maintainer")?,
+            });
+        }
+
+        Ok(res)
+    }
+
+    /// Branches saved both with and without the origin/ prefix, e.g. foo and origin/foo
+    ///
+    /// Returns (table, branch without the prefix). Both forms are scanned when a
+    /// topic branch exists locally and as a remote-tracking branch.
+    pub async fn get_mixed_branch_names(&self) -> Result<Vec<(String, String)>> {
+        let mut res = vec![];
+        for (table, conn) in [
+            ("commits", &self.commits_conn),
+            ("histories", &self.commits_conn),
+            ("package_testing", &self.conn),
+            ("package_errors", &self.conn),
+        ] {
+            let sql = format!(
+                "SELECT regexp_replace(branch, '^origin/', '') AS branch
+                FROM (SELECT DISTINCT branch FROM {table} WHERE tree = $1) b
+                GROUP BY 1 HAVING count(*) > 1 ORDER BY 1"
+            );
+            let rows = conn
+                .query_all(Statement::from_sql_and_values(
+                    conn.get_database_backend(),
+                    &sql,
+                    [self.tree.clone().into()],
+                ))
+                .await?;
+            for row in rows {
+                res.push((table.to_string(), row.try_get("", "branch")?));
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Drop and recreate the full text search index of packages, returns the number of packages indexed
+    ///
+    /// The index follows writes to packages by itself, this is for changes of
+    /// its definition or an index left invalid by an interrupted build.
+    pub async fn rebuild_fts(&self) -> Result<u64> {
+        let txn = self.conn.begin().await?;
+        let backend = txn.get_database_backend();
+        txn.execute(Statement::from_string(
+            backend,
+            "DROP INDEX IF EXISTS idx_packages_fts".to_string(),
+        ))
+        .await?;
+        txn.execute(Statement::from_string(backend, fts_index_statement()))
+            .await?;
+        let indexed = Packages::find().count(&txn).await?;
+        txn.commit().await?;
+
+        Ok(indexed)
+    }
+
+    /// Search names and descriptions of packages, returns (name, description) with the best matches first
+    ///
+    /// The query is like one of a web search engine, e.g. `"text editor" -vim`.
+    /// Packages of every tree are searched.
+    pub async fn fts_search(&self, query: &str) -> Result<Vec<(String, String)>> {
+        let rows = self
+            .conn
+            .query_all(Statement::from_sql_and_values(
+                self.conn.get_database_backend(),
+                FTS_SEARCH_QUERY,
+                [query.into()],
+            ))
+            .await?;
+
+        let mut res = vec![];
+        for row in rows {
+            res.push((row.try_get("", "name")?, row.try_get("", "description")?));
+        }
+
+        Ok(res)
+    }
+
+    /// Branches with changes of the package, with the latest version on each
+    pub async fn get_package_branches(&self, name: &str) -> Result<Vec<PackageBranch>> {
+        let rows = self
+            .commits_conn
+            .query_all(Statement::from_sql_and_values(
+                self.commits_conn.get_database_backend(),
+                PACKAGE_BRANCHES_QUERY,
+                [name.into(), self.tree.clone().into()],
+            ))
+            .await?;
+        // the main branch, and topic branches until they are deleted
+        let mut branches = HashSet::from([self.branch.clone()]);
+        for row in self
+            .conn
+            .query_all(Statement::from_sql_and_values(
+                self.conn.get_database_backend(),
+                TESTING_BRANCHES_QUERY,
+                [self.tree.clone().into()],
+            ))
+            .await?
+        {
+            branches.insert(row.try_get::<String>("", "branch")?);
+        }
+
+        rows.into_iter()
+            .filter_map(|row| match row.try_get::<String>("", "branch") {
+                Ok(branch) if !branches.contains(&branch) => None,
+                _ => Some(row),
+            })
+            .map(|row| {
+                Ok(PackageBranch {
+                    branch: row.try_get("", "branch")?,
+                    version: row.try_get("", "pkg_version")?,
+                    commit_time: row
+                        .try_get::<DateTimeWithTimeZone>("", "commit_time")?
+                        .to_rfc3339(),
+                })
+            })
+            .collect()
+    }
+
+    /// Get the stored rows of a package of this tree as they are, for consistency checks
+    pub async fn get_package_full(&self, name: &str) -> Result<Option<StoredPackage>> {
+        let Some(package) = Packages::find_by_id(name)
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .one(&self.conn)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let version = PackageVersions::find()
+            .filter(package_versions::Column::Package.eq(name))
+            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
+            .one(&self.conn)
+            .await?
+            .map(|model| model.full_version);
+        let spec = PackageSpec::find()
+            .filter(package_spec::Column::Package.eq(name))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| (model.key, model.value))
+            .collect();
+        let dependencies = PackageDependencies::find()
+            .filter(package_dependencies::Column::Package.eq(name))
+            .all(&self.conn)
+            .await?
+            .iter()
+            .map(format_dependency)
+            .collect();
+
+        Ok(Some(StoredPackage {
+            package,
+            version,
+            spec,
+            dependencies,
+        }))
+    }
+
+    /// Compare the stored packages of the tree with the packages parsed at
+    /// `commit`, like [crate::package::scan_tree] returns them
+    ///
+    /// Copies of duplicate packages are compared at their stored location.
+    pub async fn verify(
+        &self,
+        commit: Oid,
+        metas: &[Meta],
+        failed: Vec<String>,
+    ) -> Result<Verification> {
+        let by_name = metas.iter().into_group_map_by(|meta| meta.0.name.as_str());
+        let stored: BTreeSet<_> = self
+            .get_spec_paths(None)
+            .await?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        let mut res = Verification {
+            commit: commit.to_string(),
+            missing: by_name
+                .keys()
+                .filter(|name| !stored.contains(**name))
+                .map(|name| name.to_string())
+                .sorted()
+                .collect(),
+            extra: stored
+                .iter()
+                .filter(|name| !by_name.contains_key(name.as_str()) && !failed.contains(name))
+                .cloned()
+                .collect(),
+            ..Default::default()
+        };
+
+        for name in &stored {
+            let Some(copies) = by_name.get(name.as_str()) else {
+                continue;
+            };
+            let Some(package) = self.get_package_full(name).await? else {
+                continue;
+            };
+            let meta = copies
+                .iter()
+                .find(|meta| meta.0.spec_path == package.package.spec_path)
+                .unwrap_or(&copies[0]);
+            let scanned = Snapshot::from_packages(std::slice::from_ref(*meta), &self.branch);
+            let dependencies = scanned.dependencies.get(name).cloned().unwrap_or_default();
+            res.mismatched.extend(compare_package(
+                &package,
+                meta,
+                &dependencies,
+                &self.value_limits,
+            ));
+        }
+        res.failed = failed.into_iter().sorted().collect();
+
+        Ok(res)
+    }
+
+    /// Get package information for export
+    pub async fn get_package(&self, name: &str) -> Result<Option<PackageInfo>> {
+        let Some(pkg) = Packages::find_by_id(name).one(&self.conn).await? else {
+            return Ok(None);
+        };
+
+        let version = PackageVersions::find_by_id((name.to_string(), self.branch.clone()))
+            .one(&self.conn)
+            .await?;
+        let synced_githash = PackageSyncStatus::find_by_id((
+            name.to_string(),
+            self.tree.to_string(),
+            self.branch.clone(),
+        ))
+        .one(&self.conn)
+        .await?
+        .map(|model| model.synced_githash);
+        let out_of_sync = version.as_ref().map(|model| &model.githash) != synced_githash.as_ref();
+        let version_source = version
+            .as_ref()
+            .and_then(|model| model.version_source.clone());
+        let version = version.map(|model| model.full_version);
+        let testing = self.get_package_testing(name).await?;
+        let update_sources = PackageUpdateSources::find()
+            .filter(package_update_sources::Column::Package.eq(name))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(UpdateSource::from)
+            .collect();
+        let dependencies = self.get_dependencies(name).await?;
+        let dependency_counts = PackageDependencyCounts::find()
+            .filter(package_dependency_counts::Column::Package.eq(name))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| (model.relationship, model.count))
+            .collect();
+        let groups = self.get_groups_for_package(name).await?;
+        let branches = self.get_package_branches(name).await?;
+        let moves = PackageMoves::find()
+            .filter(package_moves::Column::Package.eq(name))
+            .filter(package_moves::Column::Tree.eq(self.tree.to_string()))
+            .order_by_asc(package_moves::Column::CommitTime)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(PackageMove::from)
+            .collect();
+
+        Ok(Some(PackageInfo {
+            name: pkg.name,
+            tree: pkg.tree,
+            category: pkg.category,
+            section: pkg.section,
+            pkg_section: pkg.pkg_section,
+            section_source: pkg.section_source,
+            directory: pkg.directory,
+            description: pkg.description,
+            spec_path: pkg.spec_path,
+            version,
+            version_source,
+            testing,
+            update_sources,
+            dependencies,
+            dependency_counts,
+            branches,
+            groups,
+            synced_githash,
+            out_of_sync,
+            moves,
+        }))
+    }
+
+    /// Dependencies of the package of all relationships
+    pub async fn get_dependencies(&self, name: &str) -> Result<Vec<PackageDependency>> {
+        PackageDependencies::find()
+            .filter(package_dependencies::Column::Package.eq(name))
+            .order_by_asc(package_dependencies::Column::Relationship)
+            .order_by_asc(package_dependencies::Column::Architecture)
+            .order_by_asc(package_dependencies::Column::Dependency)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(PackageDependency::try_from)
+            .collect()
+    }
+
+    /// Record that the build system picked up the packages at the commit
+    pub async fn mark_synced(
+        &self,
+        packages: impl IntoIterator<Item = impl AsRef<str>>,
+        githash: &str,
+        source: &str,
+    ) -> Result<()> {
+        let now = Local::now().fixed_offset();
+        let models = packages.into_iter().map(|package| {
+            package_sync_status::Model {
+                package: package.as_ref().to_string(),
+                tree: self.tree.to_string(),
+                branch: self.branch.clone(),
+                synced_githash: githash.to_string(),
+                synced_at: now,
+                source: source.to_string(),
+            }
+            .into_active_model()
+        });
+
+        for chunk in &models.chunks(2048) {
+            replace_many(
+                chunk,
+                [
+                    package_sync_status::Column::Package,
+                    package_sync_status::Column::Tree,
+                    package_sync_status::Column::Branch,
+                ],
+                package_sync_status::Column::iter(),
+            )
+            .exec(&self.conn)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get update sources of packages in the tree, optionally filtered by method
+    pub async fn get_update_sources(&self, method: Option<&str>) -> Result<Vec<UpdateSource>> {
+        let mut query = PackageUpdateSources::find()
+            .join(
+                JoinType::InnerJoin,
+                package_update_sources::Entity::belongs_to(Packages)
+                    .from(package_update_sources::Column::Package)
+                    .to(packages::Column::Name)
+                    .into(),
+            )
+            .filter(packages::Column::Tree.eq(self.tree.clone()));
+        if let Some(method) = method {
+            query = query.filter(package_update_sources::Column::Method.eq(method));
+        }
+
+        let res = query
+            .order_by_asc(package_update_sources::Column::Package)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(UpdateSource::from)
+            .collect();
+
+        Ok(res)
+    }
+
+    /// List packages of the tree ordered by name
+    pub async fn list_packages(&self, filter: &PackageFilter) -> Result<Vec<PackageSummary>> {
+        self.list_packages_after(filter, None, None).await
+    }
+
+    /// A page of [Self::list_packages], keyed by package name
+    pub async fn list_packages_page(
+        &self,
+        filter: &PackageFilter,
+        page: &PageRequest,
+    ) -> Result<Page<PackageSummary>> {
+        let limit = page.limit();
+        let rows = self
+            .list_packages_after(filter, page.after()?, Some(limit + 1))
+            .await?;
+        into_page(rows, limit, |pkg| pkg.name.clone())
+    }
+
+    async fn list_packages_after(
+        &self,
+        filter: &PackageFilter,
+        after: Option<String>,
+        limit: Option<u64>,
+    ) -> Result<Vec<PackageSummary>> {
+        let tree = filter.tree.clone().unwrap_or_else(|| self.tree.to_string());
+        let branch = filter.branch.clone().unwrap_or_else(|| self.branch.clone());
+        self.warn_unknown_sections(&tree, &filter.sections).await?;
+
+        let mut condition = Condition::all().add(packages::Column::Tree.eq(tree.clone()));
+        if !filter.sections.is_empty() {
+            condition = condition.add(packages::Column::Section.is_in(filter.sections.clone()));
+        }
+        if !filter.categories.is_empty() {
+            condition = condition.add(packages::Column::Category.is_in(filter.categories.clone()));
+        }
+        if let Some(after) = after {
+            condition = condition.add(packages::Column::Name.gt(after));
+        }
+        let mut names = Query::select()
+            .column(packages::Column::Name)
+            .from(Packages)
+            .cond_where(condition.clone())
+            .to_owned();
+        if let Some(limit) = limit {
+            names
+                .order_by(packages::Column::Name, Order::Asc)
+                .limit(limit);
+        }
+
+        let versions: HashMap<_, _> = PackageVersions::find()
+            .filter(package_versions::Column::Branch.eq(branch))
+            .filter(package_versions::Column::Package.in_subquery(names.clone()))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|model| (model.package, model.full_version))
+            .collect();
+
+        let testing = PackageTesting::find()
+            .filter(package_testing::Column::Tree.eq(tree))
+            .filter(package_testing::Column::Package.in_subquery(names))
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .counts_by(|model| model.package);
+
+        let res = Packages::find()
+            .filter(condition)
+            .order_by_asc(packages::Column::Name)
+            .limit(limit)
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|pkg| PackageSummary {
+                version: versions.get(&pkg.name).cloned(),
+                testing: testing.get(&pkg.name).copied().unwrap_or_default(),
+                name: pkg.name,
+                section: pkg.section,
+                directory: pkg.directory,
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Changes of the latest `limit` commits, mass changes are collapsed unless `expand`
+    pub async fn get_changelog(&self, limit: u64, expand: bool) -> Result<Vec<ChangelogEntry>> {
+        let commits = self.changelog_commits(None, limit).await?;
+        self.changelog_entries(commits, expand).await
+    }
 
-        let pkg_name = &pkg.name;
+    /// A page of [Self::get_changelog] with at most the limit of commits, keyed
+    /// by time and hash of the commit
+    pub async fn get_changelog_page(
+        &self,
+        page: &PageRequest,
+        expand: bool,
+    ) -> Result<Page<ChangelogEntry>> {
+        let after = match page.after::<(String, String)>()? {
+            Some((timestamp, githash)) => Some((
+                DateTimeWithTimeZone::parse_from_rfc3339(&timestamp)?,
+                githash,
+            )),
+            None => None,
+        };
+        let limit = page.limit();
+        let commits = self.changelog_commits(after, limit + 1).await?;
+        let commits = into_page(commits, limit, |(githash, timestamp)| {
+            (timestamp.to_rfc3339(), githash.clone())
+        })?;
 
-        add_dependencies(pkg.dependencies, "PKGDEP", pkg_name, db).await?;
-        add_dependencies(pkg.build_dependencies, "BUILDDEP", pkg_name, db).await?;
-        add_dependencies(pkg.package_suggests, "PKGSUG", pkg_name, db).await?;
-        add_dependencies(pkg.package_provides, "PKGPROV", pkg_name, db).await?;
-        add_dependencies(pkg.package_recommands, "PKGRECOM", pkg_name, db).await?;
-        add_dependencies(pkg.package_replaces, "PKGREP", pkg_name, db).await?;
-        add_dependencies(pkg.package_breaks, "PKGBREAK", pkg_name, db).await?;
-        add_dependencies(pkg.package_configs, "PKGCONFIG", pkg_name, db).await?;
+        Ok(Page {
+            items: self.changelog_entries(commits.items, expand).await?,
+            next: commits.next,
+        })
+    }
 
-        // package_errors
-        if !errors.is_empty() {
-            let iter = errors.into_iter().map(|e| package_errors::ActiveModel {
-                package: Set(e.package),
-                err_type: Set(e.err_type.to_string()),
-                message: Set(e.message),
-                path: Set(e.path),
-                tree: Set(self.tree.clone()),
-                branch: Set(self.branch.clone()),
-                line: Set(e.line),
-                col: Set(e.col),
-                id: NotSet,
-            });
-            replace_many(
-                iter,
-                [package_errors::Column::Id],
-                package_errors::Column::iter(),
-            )
-            .exec(db)
-            .await?;
+    /// Hash and time of the latest commits, older than `after` if set
+    async fn changelog_commits(
+        &self,
+        after: Option<(DateTimeWithTimeZone, String)>,
+        limit: u64,
+    ) -> Result<Vec<(String, DateTimeWithTimeZone)>> {
+        let mut query = PackageChanges::find()
+            .select_only()
+            .column(package_changes::Column::Githash)
+            .column_as(package_changes::Column::Timestamp.max(), "latest")
+            .filter(package_changes::Column::Tree.eq(self.tree.clone()))
+            .group_by(package_changes::Column::Githash);
+        if let Some((timestamp, githash)) = after {
+            query = query.having(
+                Condition::any()
+                    .add(Expr::expr(package_changes::Column::Timestamp.max()).lt(timestamp))
+                    .add(
+                        Condition::all()
+                            .add(Expr::expr(package_changes::Column::Timestamp.max()).eq(timestamp))
+                            .add(package_changes::Column::Githash.lt(githash)),
+                    ),
+            );
         }
 
-        txn.commit().await?;
-        Ok(())
+        Ok(query
+            .order_by_desc(package_changes::Column::Timestamp.max())
+            .order_by_desc(package_changes::Column::Githash)
+            .limit(limit)
+            .into_tuple()
+            .all(&self.conn)
+            .await?)
     }
 
-    pub async fn get_packages_name(&self) -> Result<HashSet<String>> {
-        let res = Packages::find()
-            .filter(packages::Column::Tree.eq(self.tree.clone()))
+    async fn changelog_entries(
+        &self,
+        commits: Vec<(String, DateTimeWithTimeZone)>,
+        expand: bool,
+    ) -> Result<Vec<ChangelogEntry>> {
+        let githashes = commits
+            .into_iter()
+            .map(|(githash, _)| githash)
+            .collect_vec();
+        let changes = PackageChanges::find()
+            .filter(package_changes::Column::Tree.eq(self.tree.clone()))
+            .filter(package_changes::Column::Githash.is_in(githashes))
+            .order_by_desc(package_changes::Column::Timestamp)
+            .order_by_desc(package_changes::Column::Githash)
+            .order_by_asc(package_changes::Column::Package)
             .all(&self.conn)
             .await?;
-        Ok(res.into_iter().map(|model| model.name).collect())
-    }
 
-    pub async fn delete_package(&self, pkg_name: impl AsRef<str>) -> Result<()> {
-        let pkg_name = pkg_name.as_ref();
-        let db = &self.conn;
+        let entry = |change: &package_changes::Model| ChangelogEntry {
+            githash: change.githash.clone(),
+            timestamp: change.timestamp.to_rfc3339(),
+            summary: change
+                .message
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            packages: vec![],
+            mass_change: change.mass_change,
+        };
 
-        Delete::many(PackageVersions)
-            .filter(package_versions::Column::Package.eq(pkg_name.to_string()))
-            .filter(package_versions::Column::Branch.eq(self.branch.clone()))
-            .exec(db)
-            .await?;
+        let mut res: Vec<ChangelogEntry> = vec![];
+        for change in changes {
+            let package = (change.package.clone(), change.version.clone());
+            match res.last_mut() {
+                Some(last) if !expand && change.mass_change && last.githash == change.githash => {
+                    last.packages.push(package)
+                }
+                _ => {
+                    let mut entry = entry(&change);
+                    entry.packages.push(package);
+                    res.push(entry);
+                }
+            }
+        }
 
-        Delete::many(PackageSpec)
-            .filter(package_spec::Column::Package.eq(pkg_name.to_string()))
-            .exec(db)
-            .await?;
+        Ok(res)
+    }
 
-        Delete::many(PackageDependencies)
-            .filter(package_dependencies::Column::Package.eq(pkg_name.to_string()))
-            .exec(db)
-            .await?;
+    /// Warn about sections which no package of the tree belongs to
+    async fn warn_unknown_sections(&self, tree: &str, sections: &[String]) -> Result<()> {
+        if sections.is_empty() {
+            return Ok(());
+        }
 
-        Delete::many(Packages)
-            .filter(packages::Column::Name.eq(pkg_name.to_string()))
-            .filter(packages::Column::Tree.eq(self.tree.clone()))
-            .exec(db)
+        let valid: Vec<String> = Packages::find()
+            .select_only()
+            .column(packages::Column::Section)
+            .distinct()
+            .filter(packages::Column::Tree.eq(tree.to_string()))
+            .order_by_asc(packages::Column::Section)
+            .into_tuple()
+            .all(&self.conn)
             .await?;
 
-        Delete::many(PackageErrors)
-            .filter(package_errors::Column::Package.eq(pkg_name.to_string()))
-            .filter(package_errors::Column::Tree.eq(self.tree.to_string()))
-            .filter(package_errors::Column::Branch.eq(self.branch.to_string()))
-            .exec(db)
-            .await?;
+        let unknown = sections
+            .iter()
+            .filter(|section| !valid.contains(section))
+            .collect_vec();
+        if !unknown.is_empty() {
+            warn!(
+                "unknown sections {}, valid sections: {}",
+                unknown.iter().join(", "),
+                valid.join(", ")
+            );
+        }
 
-        Delete::many(PackageTesting)
-            .filter(package_testing::Column::Package.eq(pkg_name.to_string()))
-            .filter(package_testing::Column::Tree.eq(self.tree.to_string()))
-            .filter(package_testing::Column::Branch.eq(self.branch.to_string()))
-            .exec(db)
+        Ok(())
+    }
+
+    /// Remove all testing branch overrides of the tree
+    pub async fn clear_testing_branches(&self) -> Result<()> {
+        let res = PackageTesting::delete_many()
+            .filter(package_testing::Column::Tree.eq(self.tree.clone()))
+            .exec(&self.conn)
             .await?;
+        if res.rows_affected > 0 {
+            info!("removed {} testing branch packages", res.rows_affected);
+        }
+        self.prune_testing_rows().await?;
 
         Ok(())
     }
 
-    pub async fn update_testing_branch(
+    /// Point packages found at a new location in `commit` to it, returns the number of moves
+    ///
+    /// `moved` holds the metadata at the old location, and `updated` the one at
+    /// the new location. The row in packages is updated before the package is
+    /// written, so the move isn't reported as a duplicate, entries of the old
+    /// location are deleted from package_duplicate and the move is recorded in
+    /// package_moves. Packages whose canonical location is another one are left
+    /// to the duplicate handling of [Self::add_package].
+    pub async fn move_packages(
         &self,
-        commit_db: &CommitDb,
         repo: &Repository,
-        exculde: &HashSet<String>,
-    ) -> Result<()> {
-        info!("updating testing branch");
-        let result = commit_db.update_package_testing(repo, exculde).await?;
-
-        let main = scan_branch(repo, repo.get_repo_branch(), Some(1000))?;
-        let mut outdated_branches = vec![];
+        moved: &[Meta],
+        updated: &[Meta],
+        commit: Oid,
+    ) -> Result<usize> {
+        if moved.is_empty() {
+            return Ok(0);
+        }
+        let commit_time = to_datetime(&repo.find_commit(commit)?.time());
 
-        for (branch, info) in result {
-            info!("scan testing branch {branch}");
-            let testing = scan_branch(repo, &branch, None)?;
-            let last = testing
+        let txn = self.conn.begin().await?;
+        let mut count = 0;
+        for (old, _, _, _) in moved {
+            let new = skip_none!(updated
                 .iter()
-                .filter_map(|(oid, order)| {
-                    main.get(oid)
-                        .map(|main_branch_order| (main_branch_order, order))
-                })
-                .max_by_key(|x| x.0);
-            let (_, last) = if let Some(last) = last {
-                last
-            } else {
-                outdated_branches.push(branch.to_string());
+                .map(|(pkg, _, _, _)| pkg)
+                .find(|pkg| pkg.name == old.name));
+            let existing = skip_none!(Packages::find_by_id(old.name.clone()).one(&txn).await?);
+            if existing.tree != self.tree.as_str()
+                || existing.spec_path != old.spec_path
+                || old.spec_path == new.spec_path
+            {
                 continue;
-            };
-
-            for info in info {
-                let new_order = skip_none!(testing.get(&info.commit_id));
-
-                let db_order = PackageTesting::find()
-                    .filter(package_testing::Column::Package.eq(info.pkg_name.clone()))
-                    .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
-                    .filter(package_testing::Column::Branch.eq(branch.clone()))
-                    .one(&self.conn)
-                    .await?
-                    .and_then(|current| testing.get(&Oid::from_str(&current.commit).ok()?))
-                    .unwrap_or(&10_0000);
-
-                if (new_order < db_order) & (new_order <= last) {
-                    package_testing::Model {
-                        spec_path: info.spec_path,
-                        package: info.pkg_name,
-                        version: info.pkg_version,
-                        full_version: info.pkg_full_version,
-                        defines_path: info.defines_path,
-                        branch: branch.clone(),
-                        tree: repo.tree.clone(),
-                        commit: info.commit_id.to_string(),
-                    }
-                    .replace(
-                        &self.conn,
-                        [
-                            package_testing::Column::Package,
-                            package_testing::Column::Tree,
-                            package_testing::Column::Branch,
-                        ],
-                        package_testing::Column::iter(),
-                    )
-                    .await?;
-                } else if (new_order > last) & (db_order > last) {
-                    PackageTesting::delete_by_id((
-                        info.pkg_name,
-                        repo.tree.clone(),
-                        branch.clone(),
-                    ))
-                    .exec(&self.conn)
-                    .await?;
-                }
             }
-        }
+            info!(
+                "package \"{}\" moved from {} to {}",
+                old.name, old.spec_path, new.spec_path
+            );
 
-        // delete unused branch
-        let current_branches_name = repo
-            .get_git2repo()
-            .branches(None)?
-            .filter_map(|b| Some(b.ok()?.0.name().ok()??.to_string()))
-            .collect_vec();
-        PackageTesting::delete_many()
-            .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
-            .filter(package_testing::Column::Branch.is_not_in(current_branches_name))
-            .exec(&self.conn)
-            .await?;
-        PackageTesting::delete_many()
-            .filter(package_testing::Column::Tree.eq(repo.tree.clone()))
-            .filter(package_testing::Column::Branch.is_in(outdated_branches))
-            .exec(&self.conn)
+            package_moves::Model {
+                package: old.name.clone(),
+                tree: self.tree.to_string(),
+                githash: commit.to_string(),
+                branch: self.branch.clone(),
+                old_spec_path: old.spec_path.clone(),
+                new_spec_path: new.spec_path.clone(),
+                commit_time,
+            }
+            .replace(
+                &txn,
+                [
+                    package_moves::Column::Package,
+                    package_moves::Column::Tree,
+                    package_moves::Column::Githash,
+                ],
+                package_moves::Column::iter(),
+            )
             .await?;
 
-        Ok(())
+            Delete::many(PackageDuplicate)
+                .filter(package_duplicate::Column::Package.eq(old.name.clone()))
+                .filter(package_duplicate::Column::Tree.eq(self.tree.to_string()))
+                .filter(package_duplicate::Column::Category.eq(old.category.clone()))
+                .filter(package_duplicate::Column::Section.eq(old.section.clone()))
+                .filter(package_duplicate::Column::Directory.eq(old.directory.clone()))
+                .exec(&txn)
+                .await?;
+
+            Packages::update_many()
+                .col_expr(
+                    packages::Column::Category,
+                    Expr::value(new.category.clone()),
+                )
+                .col_expr(packages::Column::Section, Expr::value(new.section.clone()))
+                .col_expr(
+                    packages::Column::PkgSection,
+                    Expr::value(new.pkg_section.clone()),
+                )
+                .col_expr(
+                    packages::Column::Directory,
+                    Expr::value(new.directory.clone()),
+                )
+                .col_expr(
+                    packages::Column::SpecPath,
+                    Expr::value(new.spec_path.clone()),
+                )
+                .filter(packages::Column::Name.eq(old.name.clone()))
+                .exec(&txn)
+                .await?;
+            count += 1;
+        }
+        txn.commit().await?;
+
+        Ok(count)
     }
 
     pub async fn delete_packages(
@@ -477,25 +4965,279 @@ impl AbbsDb {
     }
 }
 
+/// Create the full text search index of packages, see [FTS_DOCUMENT]
+fn fts_index_statement() -> String {
+    format!("CREATE INDEX IF NOT EXISTS idx_packages_fts ON packages USING gin (({FTS_DOCUMENT}))")
+}
+
+/// FAIL_ARCH matches the architecture, e.g. `riscv64`, `(mips64r6el|riscv64)`
+/// or `!(amd64|arm64)` for every architecture except the listed ones
+fn fail_arch_matches(pattern: &str, arch: &str) -> bool {
+    let pattern = pattern.trim().trim_matches(|c| c == '"' || c == '\'');
+    let (negated, pattern) = match pattern.strip_prefix('!') {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+    let listed = pattern
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split('|')
+        .any(|listed| listed.trim().eq_ignore_ascii_case(arch));
+
+    listed != negated
+}
+
+/// Versions overridden by architecture, returns (architecture, epoch, version, release)
+///
+/// Keys which are not overridden fall back to the default ones of the package.
+fn arch_versions(
+    pkg: &Package,
+    context: &HashMap<String, String>,
+) -> Vec<(String, Option<String>, String, Option<String>)> {
+    // overrides by architecture, keys are matched as written like PKGVER__retro,
+    // which sorts after PKGVER__RETRO and wins over it
+    let mut archs: BTreeMap<String, HashMap<&str, &String>> = BTreeMap::new();
+    for (key, value) in context.iter().sorted_by_key(|(key, _)| key.as_str()) {
+        let Some((key, arch)) = key.split_once("__") else {
+            continue;
+        };
+        if VERSION_KEYS.contains(&key) && !arch.is_empty() {
+            archs
+                .entry(arch.to_lowercase())
+                .or_default()
+                .insert(key, value);
+        }
+    }
+
+    archs
+        .into_iter()
+        .map(|(arch, overrides)| {
+            let get = |key: &str| overrides.get(key).copied();
+            let epoch = get("PKGEPOCH")
+                .cloned()
+                .or_else(|| Some(pkg.epoch.to_string()))
+                .filter(|x| x != "0");
+            let version = get("PKGVER")
+                .cloned()
+                .unwrap_or_else(|| pkg.version.clone());
+            let release = get("PKGREL")
+                .cloned()
+                .or_else(|| Some(pkg.release.to_string()))
+                .filter(|x| x != "0");
+            (arch, epoch, version, release)
+        })
+        .collect()
+}
+
+/// Create and migrate the tables, indexes and views of the abbs database
+async fn create_schema(conn: &DatabaseTransaction, materialize_packages: bool) -> Result<()> {
+    Packages.create_table(conn).await?;
+    Packages
+        .add_columns(
+            conn,
+            vec![packages::Column::LastRunId, packages::Column::SectionSource],
+        )
+        .await?;
+    exec(conn, &fts_index_statement(), []).await?;
+    PackageDependencies.create_table(conn).await?;
+    PackageDependencyCounts.create_table(conn).await?;
+    if PackageDependencyCounts::find().one(conn).await?.is_none() {
+        // fill in counts of packages written before the table existed
+        refresh_dependency_counts(
+            conn,
+            Query::select()
+                .column(packages::Column::Name)
+                .from(Packages)
+                .to_owned(),
+        )
+        .await?;
+    }
+    PackageDuplicate.create_table(conn).await?;
+    PackageDuplicate
+        .add_columns(conn, vec![package_duplicate::Column::FullVersion])
+        .await?;
+    PackageDuplicateResolution.create_table(conn).await?;
+    PackageSpec.create_table(conn).await?;
+    PackageSpec
+        .add_columns(conn, vec![package_spec::Column::LastRunId])
+        .await?;
+    PackageVersions.create_table(conn).await?;
+    PackageVersions
+        .add_columns(
+            conn,
+            vec![
+                package_versions::Column::LastRunId,
+                package_versions::Column::VersionSource,
+                package_versions::Column::VersionFirstCommitTime,
+                package_versions::Column::VersionFirstGithash,
+            ],
+        )
+        .await?;
+    PackageArchVersions.create_table(conn).await?;
+    PackageArchitectures.create_table(conn).await?;
+    TreeBranches.create_table(conn).await?;
+    Trees.create_table(conn).await?;
+    Trees
+        .add_columns(
+            conn,
+            vec![
+                trees::Column::HeadCommit,
+                trees::Column::HeadCommitTime,
+                trees::Column::UpdatedAt,
+            ],
+        )
+        .await?;
+    PackageChanges.create_table(conn).await?;
+    PackageChanges
+        .add_columns(
+            conn,
+            vec![
+                package_changes::Column::MassChange,
+                package_changes::Column::ChangedFiles,
+            ],
+        )
+        .await?;
+    PackageErrors.create_table(conn).await?;
+    PackageErrors
+        .add_columns(
+            conn,
+            vec![
+                package_errors::Column::LastRunId,
+                package_errors::Column::EndLine,
+                package_errors::Column::EndCol,
+            ],
+        )
+        .await?;
+    PackageErrorEvents.create_table(conn).await?;
+    EventsOutbox.create_table(conn).await?;
+    EventConsumers.create_table(conn).await?;
+    PackageTesting.create_table(conn).await?;
+    PackageTesting
+        .add_columns(conn, vec![package_testing::Column::LastRunId])
+        .await?;
+    PackageTestingSpec.create_table(conn).await?;
+    PackageUpdateSources.create_table(conn).await?;
+    PackageSyncStatus.create_table(conn).await?;
+    PackageGroups.create_table(conn).await?;
+    PackageMoves.create_table(conn).await?;
+    PackageStats.create_table(conn).await?;
+    DeferredPackages.create_table(conn).await?;
+    ScanRunPackages.create_table(conn).await?;
+    ScanRunPackages
+        .create_index(
+            conn,
+            "idx_scan_run_packages_tree_recorded_at",
+            vec![
+                scan_run_packages::Column::Tree,
+                scan_run_packages::Column::RecordedAt,
+            ],
+        )
+        .await?;
+    SchemaMeta.create_table(conn).await?;
+    CollectorMeta.create_table(conn).await?;
+    CollectorMeta
+        .add_columns(
+            conn,
+            vec![
+                collector_meta::Column::RunId,
+                collector_meta::Column::Latency,
+                collector_meta::Column::Recovery,
+            ],
+        )
+        .await?;
+
+    update_views(conn, materialize_packages).await?;
+    set_schema_meta(conn, SCHEMA_VERSION_KEY, &schema_version()).await
+}
+
+/// Refresh m_packages if it is enabled
+///
+/// m_packages covers every tree, so it is refreshed once after all trees of a
+/// run are scanned rather than after each of them.
+pub async fn refresh_materialized_views(global_config: &Global) -> Result<()> {
+    if !global_config.materialize_packages {
+        return Ok(());
+    }
+    let conn = connect(&global_config.database_url, &global_config.performance).await?;
+    info!("refreshing m_packages");
+    exec(&conn, "REFRESH MATERIALIZED VIEW m_packages", []).await?;
+
+    Ok(())
+}
+
+/// Recreate views when their definitions change, and create or drop m_packages
+async fn update_views(conn: &impl ConnectionTrait, materialize_packages: bool) -> Result<()> {
+    for (name, definition, digest_key) in VIEWS {
+        let digest = digest(definition);
+        if get_schema_meta(conn, digest_key).await?.as_ref() != Some(&digest) {
+            info!("{name} definition changed, recreating");
+            // m_packages depends on v_packages, so it is dropped as well
+            exec(conn, &format!("DROP VIEW IF EXISTS {name} CASCADE"), []).await?;
+            exec(conn, definition, []).await?;
+            set_schema_meta(conn, digest_key, &digest).await?;
+        }
+    }
+
+    if materialize_packages {
+        exec(conn, M_PACKAGES_VIEW, []).await?;
+    } else {
+        exec(conn, "DROP MATERIALIZED VIEW IF EXISTS m_packages", []).await?;
+    }
+
+    Ok(())
+}
+
+/// Rows of the testing branch to write and to delete, from its changes in the commits database
+///
+/// `testing` is the order of the commits of the branch from its tip, and `last`
+/// the order of its newest commit in the main branch. `current` is the commit
+/// of every package_testing row of the tree, and is updated with the rows.
+/// Applying the result at once gives the rows of deciding each change in turn.
+fn plan_testing_rows(
+    branch: &str,
+    changes: Vec<CommitInfo>,
+    testing: &HashMap<Oid, usize>,
+    last: usize,
+    current: &mut HashMap<(String, String), Option<Oid>>,
+) -> (HashMap<String, CommitInfo>, HashSet<String>) {
+    let mut upserts = HashMap::new();
+    let mut deletes = HashSet::new();
+    for info in changes {
+        let Some(&new_order) = testing.get(&info.commit_id) else {
+            continue;
+        };
+
+        let key = (info.pkg_name.clone(), branch.to_string());
+        let db_order = current
+            .get(&key)
+            .copied()
+            .flatten()
+            .and_then(|oid| testing.get(&oid).copied())
+            .unwrap_or(10_0000);
+
+        if (new_order < db_order) & (new_order <= last) {
+            current.insert(key, Some(info.commit_id));
+            deletes.remove(&info.pkg_name);
+            upserts.insert(info.pkg_name.clone(), info);
+        } else if (new_order > last) & (db_order > last) {
+            current.remove(&key);
+            upserts.remove(&info.pkg_name);
+            deletes.insert(info.pkg_name);
+        }
+    }
+
+    (upserts, deletes)
+}
+
 fn scan_branch(
     repo: &Repository,
     branch_name: &str,
     take: Option<usize>,
 ) -> Result<HashMap<Oid, usize>> {
     info!("scanning {} branch", branch_name);
-    use anyhow::Context;
-    let repo = repo.get_git2repo();
-
-    let branch = repo
-        .find_branch(branch_name, git2::BranchType::Remote)
-        .or_else(|_| repo.find_branch(branch_name, git2::BranchType::Local))?;
-    let mut revwalk = repo.revwalk()?;
-    revwalk.push(
-        branch
-            .get()
-            .target()
-            .with_context(|| format!("failed to get commit of branch {}", branch_name))?,
-    )?;
+    let oid = repo.get_branch_oid(branch_name)?;
+    let mut revwalk = repo.get_git2repo().revwalk()?;
+    revwalk.push(oid)?;
     Ok(revwalk
         .take(take.unwrap_or(100000000))
         .enumerate()
@@ -503,68 +5245,259 @@ fn scan_branch(
         .collect())
 }
 
+/// Delete changes beyond the newest `limit` of each package, see [TRIM_CHANGES]
+async fn trim_changes(
+    db: &impl ConnectionTrait,
+    tree: &TreeId,
+    package: Option<&str>,
+    limit: usize,
+) -> Result<u64> {
+    let res = exec(
+        db,
+        TRIM_CHANGES,
+        [
+            tree.to_string().into(),
+            (limit as i64).into(),
+            package.map(str::to_string).into(),
+        ],
+    )
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
 async fn update_duplicate(
     pkg: &Package,
     existing: &packages::Model,
+    existing_version: Option<String>,
     tree: &str,
     db: &impl ConnectionTrait,
 ) -> Result<()> {
+    let keys = [
+        package_duplicate::Column::Package,
+        package_duplicate::Column::Tree,
+        package_duplicate::Column::Category,
+        package_duplicate::Column::Section,
+        package_duplicate::Column::Directory,
+    ];
+
     package_duplicate::Model {
         package: pkg.name.clone(),
         tree: tree.to_string(),
         category: pkg.category.clone(),
         section: pkg.section.clone(),
         directory: pkg.directory.clone(),
+        full_version: Some(get_full_version(pkg)),
     }
-    .insert_or_ignore(db)
+    .replace(db, keys, [package_duplicate::Column::FullVersion])
     .await?;
 
+    // the version of the existing copy is only known while it is canonical
     package_duplicate::Model {
         package: pkg.name.clone(),
         tree: existing.tree.clone(),
         category: existing.category.clone(),
         section: existing.section.clone(),
         directory: existing.directory.clone(),
+        full_version: existing_version,
     }
-    .insert_or_ignore(db)
+    .replace(db, keys, [package_duplicate::Column::FullVersion])
     .await?;
 
     Ok(())
 }
 
-type PkgDep = HashMap<String, Vec<(String, Option<String>, Option<String>)>>;
+/// Recount package_dependency_counts of the packages selected by the subquery
+///
+/// Counts are kept at write time so listing pages don't aggregate
+/// package_dependencies for every package. Dependencies differing only by
+/// architecture are counted once.
+pub(crate) async fn refresh_dependency_counts(
+    db: &impl ConnectionTrait,
+    packages: SelectStatement,
+) -> Result<()> {
+    PackageDependencyCounts::delete_many()
+        .filter(package_dependency_counts::Column::Package.in_subquery(packages.clone()))
+        .exec(db)
+        .await?;
+
+    let counts = Query::select()
+        .columns([
+            package_dependencies::Column::Package,
+            package_dependencies::Column::Relationship,
+        ])
+        .expr(Expr::col(package_dependencies::Column::Dependency).count_distinct())
+        .from(PackageDependencies)
+        .and_where(package_dependencies::Column::Package.in_subquery(packages))
+        .group_by_columns([
+            package_dependencies::Column::Package,
+            package_dependencies::Column::Relationship,
+        ])
+        .to_owned();
+    let insert = Query::insert()
+        .into_table(PackageDependencyCounts)
+        .columns([
+            package_dependency_counts::Column::Package,
+            package_dependency_counts::Column::Relationship,
+            package_dependency_counts::Column::Count,
+        ])
+        .select_from(counts)?
+        .to_owned();
+    db.execute(db.get_database_backend().build(&insert)).await?;
+
+    Ok(())
+}
+
 async fn add_dependencies(
-    pkgdep: PkgDep,
+    dependencies: Dependencies,
     relationship: &str,
     pkg_name: &str,
+    strict: bool,
     db: &impl ConnectionTrait,
 ) -> Result<()> {
-    for (architecture, v) in pkgdep {
+    let mut models = vec![];
+    for (architecture, v) in dependencies {
         let architecture = (architecture == "default")
             .then_some("")
             .unwrap_or(architecture.as_str());
 
-        for (dependency, relop, version) in v.clone() {
-            package_dependencies::Model {
+        for Dependency {
+            name,
+            relop,
+            version,
+        } in v
+        {
+            models.push(package_dependencies::Model {
                 package: pkg_name.into(),
-                dependency,
-                relop,
+                dependency: name,
+                relop: relop.map(|relop| relop.to_string()),
                 version,
                 architecture: architecture.into(),
                 relationship: relationship.into(),
-            }
-            .replace(
-                db,
-                [
-                    package_dependencies::Column::Package,
-                    package_dependencies::Column::Dependency,
-                    package_dependencies::Column::Architecture,
-                    package_dependencies::Column::Relationship,
-                ],
-                package_dependencies::Column::iter(),
-            )
-            .await?;
+            });
         }
     }
+
+    // a statement can't update a row twice, keep the last duplicate like writing row by row did
+    let models = models
+        .into_iter()
+        .rev()
+        .unique_by(|model| (model.dependency.clone(), model.architecture.clone()))
+        .collect_vec();
+    if strict {
+        for model in &models {
+            model.validate()?;
+        }
+    }
+    for chunk in &models.into_iter().chunks(2048) {
+        replace_many(
+            chunk.map(|model| model.into_active_model()),
+            [
+                package_dependencies::Column::Package,
+                package_dependencies::Column::Dependency,
+                package_dependencies::Column::Architecture,
+                package_dependencies::Column::Relationship,
+            ],
+            package_dependencies::Column::iter(),
+        )
+        .exec(db)
+        .await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::commit::FileStatus;
+
+    fn change(package: &str, commit: u8) -> CommitInfo {
+        CommitInfo {
+            commit_id: Oid::from_bytes(&[commit; 20]).unwrap(),
+            commit_time: chrono::DateTime::from_timestamp(0, 0)
+                .unwrap()
+                .fixed_offset(),
+            pkg_name: package.to_string(),
+            pkg_version: "1.0".to_string(),
+            pkg_full_version: "1.0".to_string(),
+            defines_path: format!("app-utils/{package}/autobuild/defines"),
+            spec_path: format!("app-utils/{package}/spec"),
+            status: FileStatus::Modified,
+            changed_files: vec![],
+        }
+    }
+
+    /// Rows after deciding and writing each change in turn, like before batching
+    fn per_package(
+        table: &mut HashMap<(String, String), Option<Oid>>,
+        branch: &str,
+        changes: &[CommitInfo],
+        testing: &HashMap<Oid, usize>,
+        last: usize,
+    ) {
+        for info in changes {
+            let Some(new_order) = testing.get(&info.commit_id) else {
+                continue;
+            };
+            let key = (info.pkg_name.clone(), branch.to_string());
+            let db_order = table
+                .get(&key)
+                .copied()
+                .flatten()
+                .and_then(|oid| testing.get(&oid))
+                .unwrap_or(&10_0000);
+            if (new_order < db_order) & (new_order <= &last) {
+                table.insert(key, Some(info.commit_id));
+            } else if (new_order > &last) & (db_order > &last) {
+                table.remove(&key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_testing_rows() {
+        // commit i is the i-th from the tip, 10 and 11 are not in the branch
+        let testing: HashMap<_, _> = (0..10)
+            .map(|i| (Oid::from_bytes(&[i; 20]).unwrap(), i as usize))
+            .collect();
+        let packages = ["foo", "bar", "baz"];
+        let mut seed = 1u64;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+
+        for _ in 0..500 {
+            let last = next(10) as usize;
+            let mut table = HashMap::new();
+            for package in packages {
+                if next(2) == 0 {
+                    let commit = next(12) as u8;
+                    let oid = Oid::from_bytes(&[commit; 20]).unwrap();
+                    table.insert((package.to_string(), "topic".to_string()), Some(oid));
+                }
+            }
+            let changes = (0..next(8))
+                .map(|_| change(packages[next(3) as usize], next(12) as u8))
+                .collect_vec();
+
+            let mut expected = table.clone();
+            per_package(&mut expected, "topic", &changes, &testing, last);
+            let mut current = table.clone();
+            let (upserts, deletes) =
+                plan_testing_rows("topic", changes.clone(), &testing, last, &mut current);
+            assert_eq!(current, expected, "{changes:?}");
+
+            // the batched writes in the order of update_testing_branch
+            for (package, info) in upserts {
+                table.insert((package, "topic".to_string()), Some(info.commit_id));
+            }
+            for package in deletes {
+                table.remove(&(package, "topic".to_string()));
+            }
+            assert_eq!(table, expected, "{changes:?}");
+        }
+    }
+}
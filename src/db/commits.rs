@@ -1,36 +1,67 @@
 use super::entities::prelude::*;
-use super::entities::{commits, histories};
-use super::{replace_many, CreateTable};
+use super::entities::{commit_meta, commits, histories};
+use super::hash::{parse_stored, CommitHash};
+use super::{
+    check_schema, connect, connect_read_only, database_size, digest, replace_many, set_schema_meta,
+    setup_schema, table_columns, table_exists, CreateTable, Role,
+};
+use crate::config::{BranchFilter, Global, TreeId};
+use crate::db::abbs::{ErrorType, PackageError};
 use crate::db::get_full_version;
+use crate::events::ScanEvent;
 use crate::git::commit::FileStatus;
 use crate::git::{Repository, SyncRepository};
 use crate::package::{
-    defines_path_to_spec_path, path_to_defines_path, scan_package, scan_packages, Meta,
+    defines_package_name, defines_path_to_spec_path, path_to_defines_path, scan_packages,
+    scan_spec_packages, spec_path_to_defines_path, DefinesCache, Meta,
 };
+use crate::progress::Progress;
 use crate::skip_error;
+use crate::warnings::Warnings;
+use abbs_meta_tree::Package;
 use anyhow::{bail, Result};
 use chrono::{DateTime, FixedOffset, Local, TimeZone};
-use git2::Oid;
+use git2::{BranchType, Oid};
 use indicatif::ParallelProgressIterator;
 use itertools::Itertools;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rayon::ThreadPool;
 use sea_orm::prelude::DateTimeWithTimeZone;
 use sea_orm::ActiveValue::NotSet;
 use sea_orm::{
-    ActiveModelTrait, Database, IntoActiveModel, Iterable, QueryOrder, TransactionTrait,
+    ActiveModelTrait, IntoActiveModel, Iterable, QueryOrder, QuerySelect, TransactionTrait,
+};
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, DatabaseTransaction, DbBackend, EntityTrait, QueryFilter, Set,
+    Statement,
 };
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use thread_local::ThreadLocal;
 use tracing::{debug, info, warn};
 use FileStatus::*;
 
+/// Packages changed by testing branches
+#[derive(Debug, Default)]
+pub struct TestingCommits {
+    /// changed packages of each branch
+    pub branches: HashMap<String, Vec<CommitInfo>>,
+    /// branches skipped for being older than testing_branch_max_age_days
+    pub stale: Vec<String>,
+}
+
 /// Collect git commits in database
-#[derive(Debug)]
 pub struct CommitDb {
     conn: DatabaseConnection,
+    progress: Progress,
+    warnings: Warnings,
+    pool: Option<Arc<ThreadPool>>,
+    /// architectures of per-architecture parses of defines
+    architectures: Vec<String>,
+    changed_files_limit: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -46,6 +77,28 @@ pub struct Change {
     pub maintainer_name: String,
     pub maintainer_email: String,
     pub timestamp: DateTimeWithTimeZone,
+    /// number of packages touched by the commit
+    pub packages_touched: usize,
+    /// [ChangedFiles] of the package, None for changes not found in the commits table
+    pub changed_files: Option<serde_json::Value>,
+}
+
+/// Packages changed between two scans
+#[derive(Debug)]
+pub struct UpdatedPackages {
+    pub deleted: Vec<Meta>,
+    pub updated: Vec<Meta>,
+    /// packages removed from one location and added at another, parsed at the
+    /// old location, they are in `updated` as well
+    pub moved: Vec<Meta>,
+    /// packages with only one of spec and defines left, they are kept as is
+    pub broken: Vec<PackageError>,
+    /// names of updated packages which failed to parse, their versions are kept
+    pub failed: Vec<String>,
+    /// commit the packages were scanned at
+    pub commit: Oid,
+    /// commit the changes are found from, none if every package of the tree is updated
+    pub from: Option<Oid>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -58,10 +111,43 @@ pub struct CommitInfo {
     pub defines_path: String,
     pub spec_path: String,
     pub status: FileStatus,
+    /// files of the package changed by the commit, relative to the directory of the spec
+    pub changed_files: Vec<(String, FileStatus)>,
+}
+
+/// Files of a package changed by a commit, saved as JSON in commits and package_changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedFiles {
+    pub files: Vec<ChangedFile>,
+    /// number of files left out over changed_files_limit
+    pub more: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedFile {
+    pub path: String,
+    /// Added, Deleted or Modified
+    pub status: String,
+}
+
+impl ChangedFiles {
+    fn new(files: &[(String, FileStatus)], limit: usize) -> Self {
+        Self {
+            files: files
+                .iter()
+                .take(limit)
+                .map(|(path, status)| ChangedFile {
+                    path: path.clone(),
+                    status: status.to_string(),
+                })
+                .collect(),
+            more: files.len().saturating_sub(limit),
+        }
+    }
 }
 
 /// Convert git2::Time to DataTimeWithTimeZone
-fn to_datetime(time: &git2::Time) -> DateTimeWithTimeZone {
+pub(crate) fn to_datetime(time: &git2::Time) -> DateTimeWithTimeZone {
     DateTime::from_timestamp(time.seconds(), 0)
         .unwrap()
         .with_timezone(&TimeZone::from_offset(
@@ -70,16 +156,77 @@ fn to_datetime(time: &git2::Time) -> DateTimeWithTimeZone {
 }
 
 impl CommitDb {
-    pub async fn open<P: AsRef<str>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
-        let conn = Database::connect(path).await?;
-
-        Commits.create_table(&conn).await?;
-        Histories.create_table(&conn).await?;
+    pub async fn open(global_config: &Global) -> Result<Self> {
+        let conn = connect(
+            Role::Commits.database_url(global_config),
+            &global_config.performance,
+        )
+        .await?;
+        setup_schema(&conn, Role::Commits.layout(global_config), |txn| {
+            Box::pin(create_schema(txn))
+        })
+        .await?;
 
         info!("commit db opened");
 
-        Ok(Self { conn })
+        Ok(Self::new(conn, global_config))
+    }
+
+    /// Open a database set up by a scan for queries only, see [AbbsDb::open_read_only]
+    ///
+    /// [AbbsDb::open_read_only]: crate::db::abbs::AbbsDb::open_read_only
+    pub async fn open_read_only(global_config: &Global) -> Result<Self> {
+        let conn = connect_read_only(
+            Role::Commits.database_url(global_config),
+            &global_config.performance,
+        )
+        .await?;
+        check_schema(
+            &conn,
+            Role::Commits.layout(global_config),
+            SCHEMA_VERSION_KEY,
+            &schema_version(),
+        )
+        .await?;
+
+        Ok(Self::new(conn, global_config))
+    }
+
+    fn new(conn: DatabaseConnection, global_config: &Global) -> Self {
+        Self {
+            conn,
+            progress: Progress::hidden(),
+            warnings: Warnings::new(),
+            pool: None,
+            architectures: global_config.architectures.clone(),
+            changed_files_limit: global_config.changed_files_limit,
+        }
+    }
+
+    /// Run parallel scanning in the given thread pool instead of the global one
+    pub fn with_thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Run `f` in the thread pool of this instance
+    fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    /// Coalesce repeated warnings into the given warnings
+    pub fn with_warnings(mut self, warnings: Warnings) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    /// Report progress of scanning commits to the given progress
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
     }
 
     /// Add commits from branch to database
@@ -94,82 +241,228 @@ impl CommitDb {
 
         let sync_repo: &SyncRepository = &repo.into();
         let local_repo: ThreadLocal<Repository> = ThreadLocal::new();
-        let result = repo.scan_commits(commits)?;
+        let thread_repo = || {
+            local_repo.get_or(|| {
+                debug!("opening the repository for a parsing thread");
+                sync_repo.try_into().unwrap()
+            })
+        };
+        let defines_cache = &DefinesCache::new();
+        let result = match &self.pool {
+            // git2::Repository can't be shared with the pool, open another one
+            Some(pool) => pool.install(|| {
+                let repo: Repository = sync_repo.try_into()?;
+                repo.scan_commits(commits, &self.progress)
+            })?,
+            None => repo.scan_commits(commits, &self.progress)?,
+        };
 
-        info!("collecting commit info");
+        info!("locating changed packages");
         // iterate each added/modified/deleted file in each commit
-        let mut commit_info: Vec<_> = (&result)
-            .into_par_iter()
-            .progress()
-            .filter_map(|(commit_id, time, file_path, file_status)| {
-                let repo = local_repo.get_or(|| sync_repo.try_into().unwrap());
-                let commit_id = *commit_id;
-                let commit = match file_status {
-                    Added | Modified => commit_id,
-                    Deleted => {
-                        // find parent commit where the file still exists
-                        let commit = repo.find_commit(commit_id).ok()?;
-                        let parents: Vec<_> = commit.parents().collect();
-                        match parents.len() {
-                            1 | 2 => parents[0].id(),
-                            n => {
-                                warn!("{n} parents in commit {commit:?}");
+        let bar = self.progress.bar(result.len() as u64, "locate packages");
+        let located: Vec<_> = self.install(|| {
+            (&result)
+                .into_par_iter()
+                .progress_with(bar.clone())
+                .filter_map(|(commit_id, time, file_path, file_status)| {
+                    let repo = thread_repo();
+                    let commit_id = *commit_id;
+                    let commit = match file_status {
+                        Added | Modified => commit_id,
+                        Deleted => {
+                            // find the first parent commit where the file still exists
+                            let commit = repo.find_commit(commit_id).ok()?;
+                            let parent = commit.parents().find(|parent| {
+                                parent
+                                    .tree()
+                                    .is_ok_and(|tree| tree.get_path(file_path).is_ok())
+                            })?;
+                            parent.id()
+                        }
+                        _ => return None,
+                    };
+
+                    // locate defines files related to the changed file
+                    let defines =
+                        path_to_defines_path(repo, commit, file_path, defines_cache).ok()?;
+                    let located = defines
+                        .into_iter()
+                        .filter_map(|defines_path| {
+                            let spec_path = defines_path_to_spec_path(&defines_path).ok()?;
+                            Some((
+                                (commit_id, spec_path),
+                                (defines_path, *time, *file_status, file_path.clone()),
+                            ))
+                        })
+                        .collect_vec();
+                    Some(located)
+                })
+                .flatten()
+                .collect()
+        });
+        debug!(
+            "located {} changed packages with {} tree lookups",
+            located.len(),
+            defines_cache.tree_lookups()
+        );
+
+        info!("collecting commit info");
+        // parse each spec only once per commit, then each changed package on top of it
+        let located = located.into_iter().into_group_map();
+        let bar = self.progress.bar(located.len() as u64, "parse packages");
+        let mut commit_info: Vec<_> = self.install(|| {
+            located
+                .into_iter()
+                .collect_vec()
+                .into_par_iter()
+                .progress_with(bar.clone())
+                .flat_map_iter(|((commit_id, spec_path), changes)| {
+                    let repo = thread_repo();
+                    let defines_paths = changes
+                        .iter()
+                        .map(|(defines_path, _, _, _)| defines_path)
+                        .unique()
+                        .collect_vec();
+                    // read package info from the specified commit
+                    let packages: HashMap<_, _> = defines_paths
+                        .iter()
+                        .copied()
+                        .zip(scan_spec_packages(
+                            repo,
+                            commit_id,
+                            &spec_path,
+                            &defines_paths,
+                            &[],
+                        ))
+                        .filter_map(|(defines_path, (res, _))| Some((defines_path, res?.0)))
+                        .collect();
+                    // packages removed by the commit are read from the parent they
+                    // are removed from, so the removal is recorded too
+                    let removed: HashMap<_, _> = defines_paths
+                        .iter()
+                        .copied()
+                        .filter(|defines_path| !packages.contains_key(defines_path))
+                        .filter_map(|defines_path| {
+                            let commit = repo.find_commit(commit_id).ok()?;
+                            let has_defines =
+                                |tree: git2::Tree| tree.get_path(defines_path).is_ok();
+                            if commit.tree().is_ok_and(has_defines) {
+                                // still there but broken
                                 return None;
                             }
-                        }
+                            let parent = commit
+                                .parents()
+                                .find(|parent| parent.tree().is_ok_and(has_defines))?;
+                            let (res, _) = scan_spec_packages(
+                                repo,
+                                parent.id(),
+                                &spec_path,
+                                &[defines_path],
+                                &[],
+                            )
+                            .pop()?;
+                            Some((defines_path, res?.0))
+                        })
+                        .collect();
+
+                    // files of each package, relative to the directory of the spec
+                    let dir = spec_path.parent().unwrap_or(&spec_path);
+                    let mut changed_files: HashMap<_, Vec<_>> = HashMap::new();
+                    for (defines_path, _, file_status, file_path) in &changes {
+                        let path = file_path.strip_prefix(dir).unwrap_or(file_path);
+                        changed_files
+                            .entry(defines_path)
+                            .or_default()
+                            .push((path.to_string_lossy().to_string(), *file_status));
                     }
-                    _ => return None,
-                };
 
-                let generate_package_commit_info = |defines_path: &PathBuf| {
                     // for each change package, create an entry in commits table
-                    // read package info from the specified commit
-                    let spec_path = defines_path_to_spec_path(defines_path).ok()?;
-                    let (res, _) = scan_package(repo, commit_id, &spec_path, defines_path);
-                    let (pkg, _) = res?;
-
-                    let full_version = get_full_version(&pkg);
-
-                    Some(CommitInfo {
-                        commit_id,
-                        commit_time: to_datetime(time),
-                        pkg_name: pkg.name.clone(),
-                        pkg_version: pkg.version,
-                        pkg_full_version: full_version,
-                        defines_path: defines_path.to_str()?.to_string(),
-                        spec_path: spec_path.to_str()?.to_string(),
-                        status: *file_status,
-                    })
-                };
-
-                // locate defines files related to the changed file
-                path_to_defines_path(repo, commit, file_path)
-                    .ok()
-                    .map(|path| {
-                        path.iter()
-                            .filter_map(|path| generate_package_commit_info(path))
-                            .collect_vec()
-                    })
-            })
-            .flatten()
-            .collect();
+                    changes
+                        .iter()
+                        .filter_map(|(defines_path, time, file_status, _)| {
+                            // a file deleted from a package which remains modifies it
+                            let (pkg, status) = match packages.get(defines_path) {
+                                Some(pkg) if *file_status == Deleted => (pkg, Modified),
+                                Some(pkg) => (pkg, *file_status),
+                                None => (removed.get(defines_path)?, Deleted),
+                            };
+                            Some(CommitInfo {
+                                commit_id,
+                                commit_time: to_datetime(time),
+                                pkg_name: pkg.name.clone(),
+                                pkg_version: pkg.version.clone(),
+                                pkg_full_version: get_full_version(pkg),
+                                defines_path: defines_path.to_str()?.to_string(),
+                                spec_path: spec_path.to_str()?.to_string(),
+                                status,
+                                changed_files: changed_files
+                                    .get(defines_path)
+                                    .cloned()
+                                    .unwrap_or_default(),
+                            })
+                        })
+                        .collect_vec()
+                })
+                .collect()
+        });
 
         // dedup before inserting into database
         // primary key: (pkg_name, pkg_version, tree, branch, commit_id)
         // tree and branch are common
+        // rows of the same key keep the first, which is not Deleted if any is, like
+        // when the package moved to another directory
         commit_info.sort_by(|left, right| {
-            (&left.pkg_name, &left.pkg_version, &left.commit_id).cmp(&(
-                &right.pkg_name,
-                &right.pkg_version,
-                &right.commit_id,
-            ))
+            (
+                &left.pkg_name,
+                &left.pkg_version,
+                &left.commit_id,
+                left.status == Deleted,
+            )
+                .cmp(&(
+                    &right.pkg_name,
+                    &right.pkg_version,
+                    &right.commit_id,
+                    right.status == Deleted,
+                ))
         });
+        // a package can be found through more than one spec, keep the files of all of them
         commit_info.dedup_by(|left, right| {
-            (&left.pkg_name, &left.pkg_version, &left.commit_id)
-                == (&right.pkg_name, &right.pkg_version, &right.commit_id)
+            let duplicate = (&left.pkg_name, &left.pkg_version, &left.commit_id)
+                == (&right.pkg_name, &right.pkg_version, &right.commit_id);
+            if duplicate {
+                for file in left.changed_files.drain(..) {
+                    if !right.changed_files.contains(&file) {
+                        right.changed_files.push(file);
+                    }
+                }
+            }
+            duplicate
         });
 
+        // count packages touched by each commit, tree-wide commits are mass changes
+        let touched = commit_info
+            .iter()
+            .map(|info| (info.commit_id, &info.pkg_name))
+            .unique()
+            .counts_by(|(commit_id, _)| commit_id);
+        for chunk in &touched.into_iter().chunks(2048) {
+            replace_many(
+                chunk.map(|(commit_id, count)| {
+                    commit_meta::Model {
+                        commit_id: commit_id.to_string(),
+                        packages_touched: count as i32,
+                    }
+                    .into_active_model()
+                }),
+                [commit_meta::Column::CommitId],
+                commit_meta::Column::iter(),
+            )
+            .exec(&db)
+            .await?;
+        }
+
         info!("saving commit info to database");
+        let changed_files_limit = self.changed_files_limit;
         // insert to database in chunks
         let iters = commit_info
             .clone()
@@ -184,17 +477,20 @@ impl CommitDb {
                      defines_path,
                      spec_path,
                      status,
+                     changed_files,
                  }| {
+                    let changed_files = ChangedFiles::new(&changed_files, changed_files_limit);
                     commits::Model {
                         pkg_name,
                         pkg_version,
                         spec_path,
                         defines_path,
-                        tree: tree.clone(),
+                        tree: tree.to_string(),
                         branch: branch.to_string(),
                         commit_id: commit_id.to_string(),
                         commit_time,
                         status: status.to_string(),
+                        changed_files: serde_json::to_value(changed_files).ok(),
                     }
                     .into_active_model()
                 },
@@ -220,43 +516,44 @@ impl CommitDb {
         Ok(commit_info)
     }
 
-    // update packages from testing branches (topic branches)
+    // update packages from testing branches (topic branches) selected by `filter`
+    //
+    // Branches whose tip is older than `max_age_days` are skipped before
+    // walking their commits.
     pub async fn update_package_testing(
         &self,
         repo: &Repository,
-        exculde: &HashSet<String>,
-    ) -> Result<HashMap<String, Vec<CommitInfo>>> {
-        let branches = repo
-            .get_git2repo()
-            .branches(None)?
-            .filter_map(|x| Some(x.ok()?.0.name().ok()??.to_string()))
-            .collect_vec();
+        filter: &BranchFilter,
+        max_age_days: Option<u64>,
+    ) -> Result<TestingCommits> {
+        let branches = topic_branches(repo)?;
 
         let stable_commits = repo
-            .get_commits_by_range(None, repo.get_branch_oid("stable")?)?
+            .get_commits_by_range(None, repo.get_branch_oid(repo.get_repo_branch())?)?
             .into_iter()
             .collect();
 
         let testing_branches = branches
             .into_iter()
-            .filter_map(|name| {
-                (!(name.starts_with("retro")
-                    | name.starts_with("origin/retro")
-                    | ["stable", "origin/HEAD", "origin/stable"].contains(&name.as_str())
-                    | exculde.contains(&name)))
-                .then_some(name)
-            })
+            .filter(|name| filter.matches(name))
             .collect_vec();
 
-        let mut result = HashMap::new();
+        let mut result = TestingCommits::default();
+        let now = Local::now().timestamp();
         for testing in testing_branches.iter() {
-            info!("processing testing branch {}", testing);
             // collect new commits
             let to = skip_error!(repo.get_branch_oid(testing));
-            let from = self
-                .get_latest_history(&repo.tree, testing)
-                .await?
-                .and_then(|m| Oid::from_str(&m.commit_id).ok());
+            if let Some(days) = max_age_days {
+                let age = now - repo.find_commit(to)?.time().seconds();
+                if age > days as i64 * 24 * 3600 {
+                    info!("skip testing branch {testing}, no commits in {days} days");
+                    result.stale.push(testing.to_string());
+                    continue;
+                }
+            }
+            info!("processing testing branch {}", testing);
+            let from = self.get_start_commit(repo, testing).await?;
+            self.delete_dropped_commits(repo, testing, from, to).await?;
 
             let testing_commits: HashSet<_> =
                 repo.get_commits_by_range(from, to)?.into_iter().collect();
@@ -268,25 +565,32 @@ impl CommitDb {
                 .await?;
 
             self.insert_history(&repo.tree, testing, to).await?;
+            self.progress.emit(|| ScanEvent::BranchScanned {
+                branch: testing.to_string(),
+                packages: info.len(),
+            });
 
             if !info.is_empty() {
-                result.insert(testing.to_string(), info);
+                result.branches.insert(testing.to_string(), info);
             }
         }
 
         Ok(result)
     }
 
-    /// Get branch histories from db
+    /// Get branch histories from db, newest first
+    ///
+    /// Ordered by the serial id instead of the timestamp, which can go
+    /// backwards with the system clock or repeat within a second.
     async fn get_branch_histories(
         &self,
-        tree: &str,
+        tree: &TreeId,
         branch: &str,
     ) -> Result<Vec<histories::Model>> {
         Ok(Histories::find()
             .filter(histories::Column::Tree.eq(tree.to_string()))
             .filter(histories::Column::Branch.eq(branch.to_string()))
-            .order_by_desc(histories::Column::Timestamp)
+            .order_by_desc(histories::Column::Id)
             .all(&self.conn)
             .await?)
     }
@@ -294,19 +598,112 @@ impl CommitDb {
     /// Get latest commit history of the branch
     async fn get_latest_history(
         &self,
-        tree: &str,
+        tree: &TreeId,
         branch: &str,
     ) -> Result<Option<histories::Model>> {
         Ok(Histories::find()
             .filter(histories::Column::Tree.eq(tree.to_string()))
             .filter(histories::Column::Branch.eq(branch.to_string()))
-            .order_by_desc(histories::Column::Timestamp)
+            .order_by_desc(histories::Column::Id)
             .one(&self.conn)
             .await?)
     }
 
+    /// Commit of the latest history of the branch
+    ///
+    /// A malformed hash is warned about and treated as no history, which
+    /// rescans the whole branch.
+    async fn get_latest_commit(&self, tree: &TreeId, branch: &str) -> Result<Option<Oid>> {
+        Ok(self
+            .get_latest_history(tree, branch)
+            .await?
+            .and_then(|history| {
+                parse_stored(
+                    &self.warnings,
+                    format_args!("histories row {}", history.id),
+                    &history.commit_id,
+                )
+            }))
+    }
+
+    /// Commit of the latest history of the branch if it is still in the repository
+    ///
+    /// A commit lost to a force-push and garbage collection can't be compared
+    /// with, so the histories of the branch are reset and it is scanned again.
+    async fn get_start_commit(&self, repo: &Repository, branch: &str) -> Result<Option<Oid>> {
+        match self.get_latest_commit(&repo.tree, branch).await? {
+            Some(oid) if repo.find_commit(oid).is_err() => {
+                warn!("commit {oid} of {branch} is no longer in the repository, rescanning the branch");
+                self.reset_branch_history(&repo.tree, branch).await?;
+                Ok(None)
+            }
+            from => Ok(from),
+        }
+    }
+
+    /// Delete commits of the branch saved before `from` was force-pushed away from `to`
+    ///
+    /// Commits still in the repository are no longer in the branch, but would
+    /// be found as its changes otherwise.
+    async fn delete_dropped_commits(
+        &self,
+        repo: &Repository,
+        branch: &str,
+        from: Option<Oid>,
+        to: Oid,
+    ) -> Result<()> {
+        let Some(from) = from else {
+            return Ok(());
+        };
+        let dropped = repo.get_dropped_commits(from, to)?;
+        if dropped.is_empty() {
+            return Ok(());
+        }
+
+        let mut deleted = 0;
+        for chunk in &dropped.into_iter().chunks(4096) {
+            deleted += Commits::delete_many()
+                .filter(commits::Column::Tree.eq(repo.tree.to_string()))
+                .filter(commits::Column::Branch.eq(branch))
+                .filter(commits::Column::CommitId.is_in(chunk.map(|oid| oid.to_string())))
+                .exec(&self.conn)
+                .await?
+                .rows_affected;
+        }
+        info!("deleted {deleted} rows of commits no longer in {branch}");
+
+        Ok(())
+    }
+
+    /// Delete the histories of the branch, the next update walks all of its commits
+    pub async fn reset_branch_history(&self, tree: &TreeId, branch: &str) -> Result<u64> {
+        let res = Histories::delete_many()
+            .filter(histories::Column::Tree.eq(tree.to_string()))
+            .filter(histories::Column::Branch.eq(branch.to_string()))
+            .exec(&self.conn)
+            .await?;
+
+        Ok(res.rows_affected)
+    }
+
     /// Save history to database
-    async fn insert_history(&self, tree: &str, branch: &str, commit: Oid) -> Result<()> {
+    ///
+    /// Skipped if the two latest histories are already at the commit: the
+    /// latest two are the from/to pair of [Self::get_updated_packages], and a
+    /// pair at the same commit already makes it a no-op.
+    async fn insert_history(&self, tree: &TreeId, branch: &str, commit: Oid) -> Result<()> {
+        let latest = Histories::find()
+            .filter(histories::Column::Tree.eq(tree.to_string()))
+            .filter(histories::Column::Branch.eq(branch.to_string()))
+            .order_by_desc(histories::Column::Id)
+            .limit(2)
+            .all(&self.conn)
+            .await?;
+        if latest.len() == 2 && latest.iter().all(|h| h.commit_id == commit.to_string()) {
+            debug!("{branch} is still at {commit}, not adding a history");
+            return Ok(());
+        }
+
         histories::ActiveModel {
             tree: Set(tree.to_string()),
             branch: Set(branch.to_string()),
@@ -320,31 +717,70 @@ impl CommitDb {
         Ok(())
     }
 
+    /// Count commits of the branch which are not in database yet
+    ///
+    /// Returns the size of the database in bytes too. Nothing is created or
+    /// migrated, so it can run before [Self::open] writes anything.
+    pub async fn count_new_commits(
+        global_config: &Global,
+        repo: &Repository,
+    ) -> Result<(usize, u64)> {
+        let conn = connect(
+            Role::Commits.database_url(global_config),
+            &global_config.performance,
+        )
+        .await?;
+        let size = database_size(&conn).await?;
+        let from = if table_exists(&conn, "histories").await? {
+            Histories::find()
+                .filter(histories::Column::Tree.eq(repo.tree.to_string()))
+                .filter(histories::Column::Branch.eq(repo.branch.clone()))
+                .order_by_desc(histories::Column::Id)
+                .one(&conn)
+                .await?
+                .and_then(|history| Oid::from_str(&history.commit_id).ok())
+                // lost to a force-push, the branch is scanned again
+                .filter(|oid| repo.find_commit(*oid).is_ok())
+        } else {
+            None
+        };
+        conn.close().await?;
+        let to = repo.get_branch_oid(&repo.branch)?;
+
+        Ok((repo.get_commits_by_range(from, to)?.len(), size))
+    }
+
     /// Update commits in stable branch
     pub async fn update_branch(&self, repo: &Repository, branch: &str) -> Result<Vec<CommitInfo>> {
         info!("save commits from branch {} to db", branch);
         // find new commits in stable branch
-        // SELECT commit_id, history FROM history WHERE timestamp = (SELECT MAX(timestamp) FROM history)
-        let from = self
-            .get_latest_history(&repo.tree, branch)
-            .await?
-            .and_then(|x| Oid::from_str(&x.commit_id).ok());
+        // SELECT commit_id FROM histories WHERE id = (SELECT MAX(id) FROM histories)
+        let from = self.get_start_commit(repo, branch).await?;
 
         let to = repo.get_branch_oid(&repo.branch)?;
+        self.delete_dropped_commits(repo, branch, from, to).await?;
         let commits = repo.get_commits_by_range(from, to)?;
         let result = self.add_commits(repo, &repo.branch, commits).await?;
 
         self.insert_history(&repo.tree, &repo.branch, to).await?;
+        self.progress.emit(|| ScanEvent::BranchScanned {
+            branch: repo.branch.clone(),
+            packages: result.len(),
+        });
 
         Ok(result)
     }
 
     /// Find deleted/updated packages
+    ///
+    /// Packages are classified by the state at the newest commit: a package is
+    /// deleted only if both spec and defines are gone, and a package with only
+    /// one of them left is reported as broken instead of being deleted.
     pub async fn get_updated_packages(
         &self,
         repo: &Repository,
         branch: &str,
-    ) -> Result<(Vec<Meta>, Vec<Meta>)> {
+    ) -> Result<UpdatedPackages> {
         let histories = self.get_branch_histories(&repo.tree, branch).await?;
         // from old to new
         // we only insert one history, so the second latest one is the previous one
@@ -352,14 +788,40 @@ impl CommitDb {
             0 => {
                 bail!("please update branch {branch}")
             }
-            1 => (None, Oid::from_str(&histories[0].commit_id)?),
+            1 => (None, histories[0].commit_id.parse::<CommitHash>()?.oid()),
             _ => (
-                Some(Oid::from_str(&histories[1].commit_id)?),
-                Oid::from_str(&histories[0].commit_id)?,
+                Some(histories[1].commit_id.parse::<CommitHash>()?.oid()),
+                histories[0].commit_id.parse::<CommitHash>()?.oid(),
             ),
         };
 
-        // compare two commits, find deleted/updated packages
+        debug!("from: {from:?}  to: {to:?}");
+
+        self.updated_packages_between(repo, from, to)
+    }
+
+    /// Find packages which [Self::update_branch] followed by [Self::get_updated_packages] would
+    /// report, without saving commits or histories
+    pub async fn get_pending_packages(
+        &self,
+        repo: &Repository,
+        branch: &str,
+    ) -> Result<UpdatedPackages> {
+        let from = self.get_latest_commit(&repo.tree, branch).await?;
+        let to = repo.get_branch_oid(branch)?;
+        debug!("pending from: {from:?}  to: {to:?}");
+
+        self.updated_packages_between(repo, from, to)
+    }
+
+    /// Compare two commits, find deleted/updated packages
+    fn updated_packages_between(
+        &self,
+        repo: &Repository,
+        from: Option<Oid>,
+        to: Oid,
+    ) -> Result<UpdatedPackages> {
+        let defines_cache = &DefinesCache::new();
         let diff: HashSet<_> = walk_diff_tree(repo, from, Some(to))?
             .into_iter()
             .filter_map(|(path, status)| {
@@ -370,38 +832,163 @@ impl CommitDb {
                     to
                 };
 
-                path_to_defines_path(repo, commit, &path)
+                path_to_defines_path(repo, commit, &path, defines_cache)
                     .ok()
                     .map(|defines| {
                         defines.into_iter().filter_map(move |defines| {
                             let spec = defines_path_to_spec_path(&defines).ok()?;
-                            Some((spec, defines, status))
+                            Some((spec, defines))
                         })
                     })
             })
             .flatten()
             .collect();
-        debug!("from: {from:?}  to: {to:?}");
 
-        let deleted = diff
-            .iter()
-            .filter(|(_, _, status)| status == &FileStatus::Deleted)
-            .map(|(spec, defines, _)| (spec, defines))
-            .collect_vec();
-        let updated = diff
-            .iter()
-            .filter(|(_, _, status)| [FileStatus::Modified, FileStatus::Added].contains(status))
-            .map(|(spec, defines, _)| (spec, defines))
-            .collect_vec();
+        // classify by the state at `to`
+        let to_tree = repo.find_commit(to)?.tree()?;
+        let exists = |path: &PathBuf| to_tree.get_path(path).is_ok();
+        let mut deleted = vec![];
+        let mut updated = vec![];
+        let mut broken = vec![];
+        for (spec, defines) in &diff {
+            match (exists(spec), exists(defines)) {
+                (true, true) => updated.push((spec, defines)),
+                (false, false) => deleted.push((spec, defines)),
+                (false, true) => broken.push(missing_file_error(repo, from, to, defines, spec)),
+                (true, false) => {
+                    // a subpackage is removed while others remain
+                    let remaining = spec_path_to_defines_path(repo, to, spec)
+                        .map(|defines| !defines.is_empty())
+                        .unwrap_or(false);
+                    if remaining {
+                        deleted.push((spec, defines));
+                    } else {
+                        broken.push(missing_file_error(repo, from, to, defines, defines));
+                    }
+                }
+            }
+        }
+        for error in &broken {
+            self.warnings.warn(
+                "missing file",
+                format_args!("{}: {}", error.package, error.message),
+            );
+        }
 
         let deleted_packages = if let Some(from) = from {
-            scan_packages(repo, from, deleted)
+            scan_packages(repo, from, deleted, &[]).0
         } else {
             vec![]
         };
-        let updated_packages = scan_packages(repo, to, updated);
+        let (updated_packages, failed) = scan_packages(repo, to, updated, &self.architectures);
+        let updated_names: HashSet<_> = updated_packages
+            .iter()
+            .map(|(pkg, _, _, _)| pkg.name.as_str())
+            .collect();
+        let (moved_packages, deleted_packages): (Vec<_>, Vec<_>) = deleted_packages
+            .into_iter()
+            .partition(|(pkg, _, _, _)| updated_names.contains(pkg.name.as_str()));
+
+        Ok(UpdatedPackages {
+            deleted: deleted_packages,
+            updated: updated_packages,
+            moved: moved_packages,
+            broken,
+            failed,
+            commit: to,
+            from,
+        })
+    }
+
+    /// A single change for a package without recorded commits, from the last commit of its spec
+    ///
+    /// Used when the commits database starts after the package was last
+    /// changed, e.g. after a bounded initial scan.
+    pub fn fallback_change(&self, repo: &Repository, pkg: &Package) -> Result<Option<Change>> {
+        let tip = repo.get_branch_oid(&repo.branch)?;
+        let Some(oid) = repo.last_commit_touching(&pkg.spec_path, tip)? else {
+            return Ok(None);
+        };
+        let commit = repo.find_commit(oid)?;
+        let message = commit.message().unwrap_or_default().to_string();
+        let maintainer = commit.committer();
+
+        Ok(Some(Change {
+            pkg_name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            tree: repo.tree.to_string(),
+            branch: repo.branch.clone(),
+            urgency: message
+                .find("security")
+                .map_or("medium", |_| "high")
+                .to_string(),
+            message,
+            githash: oid.to_string(),
+            maintainer_name: maintainer.name().unwrap_or_default().to_string(),
+            maintainer_email: maintainer.email().unwrap_or_default().to_string(),
+            timestamp: to_datetime(&commit.time()),
+            packages_touched: 1,
+            changed_files: None,
+        }))
+    }
+
+    /// Add commits changing the package directory which are missing in the commits table
+    ///
+    /// At most `max_commits` commits of the main branch are looked at, returns the added ones.
+    pub async fn backfill_package(
+        &self,
+        repo: &Repository,
+        pkg_name: &str,
+        directory: &Path,
+        max_commits: usize,
+    ) -> Result<Vec<Oid>> {
+        let tip = repo.get_branch_oid(&repo.branch)?;
+        let known: HashSet<String> = self
+            .get_commits_by_packages(pkg_name)
+            .await?
+            .into_iter()
+            .filter(|model| model.tree == repo.tree && model.branch == repo.branch)
+            .map(|model| model.commit_id)
+            .collect();
+        let missing = repo
+            .commits_touching(directory, tip, max_commits)?
+            .into_iter()
+            .filter(|oid| !known.contains(&oid.to_string()))
+            .collect_vec();
+        if missing.is_empty() {
+            return Ok(missing);
+        }
+
+        self.add_commits(repo, &repo.branch, missing.clone())
+            .await?;
 
-        Ok((deleted_packages, updated_packages))
+        Ok(missing)
+    }
+
+    /// Parse the package at the tip of the main branch, from the spec it was last seen at
+    pub fn rescan_package(
+        &self,
+        repo: &Repository,
+        pkg_name: &str,
+        spec_path: &Path,
+    ) -> Result<Option<Meta>> {
+        let tip = repo.get_branch_oid(&repo.branch)?;
+        let defines_paths = spec_path_to_defines_path(repo, tip, spec_path)?;
+        let spec_path = spec_path.to_path_buf();
+        let res = scan_spec_packages(
+            repo,
+            tip,
+            &spec_path,
+            &defines_paths.iter().collect_vec(),
+            &self.architectures,
+        )
+        .into_iter()
+        .find_map(|(res, errors)| {
+            let (pkg, context, source) = res?;
+            (pkg.name == pkg_name).then_some((pkg, context, errors, source))
+        });
+
+        Ok(res)
     }
 
     /// Collect package commit history
@@ -411,6 +998,16 @@ impl CommitDb {
         pkg_name: &str,
     ) -> Result<Vec<Change>> {
         let changes = self.get_commits_by_packages(pkg_name).await?;
+        let packages_touched: HashMap<_, _> = CommitMeta::find()
+            .filter(
+                commit_meta::Column::CommitId
+                    .is_in(changes.iter().map(|change| change.commit_id.clone())),
+            )
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|meta| (meta.commit_id, meta.packages_touched as usize))
+            .collect();
 
         let changes = changes
             .into_iter()
@@ -421,14 +1018,21 @@ impl CommitDb {
                      tree,
                      branch,
                      commit_id,
+                     changed_files,
                      ..
                  }| {
-                    let commit = repo.find_commit(Oid::from_str(&commit_id).ok()?).ok()?;
+                    let oid = parse_stored(
+                        &self.warnings,
+                        format_args!("commits row {pkg_name}@{branch}"),
+                        &commit_id,
+                    )?;
+                    let commit = repo.find_commit(oid).ok()?;
                     let message = commit.message()?.to_string();
                     let maintainer = commit.committer();
                     let branch = branch.strip_prefix("origin/").unwrap_or(branch.as_str());
 
                     let change = Change {
+                        packages_touched: packages_touched.get(&commit_id).copied().unwrap_or(1),
                         pkg_name,
                         version: pkg_version,
                         tree,
@@ -442,6 +1046,7 @@ impl CommitDb {
                         maintainer_name: maintainer.name()?.to_string(),
                         maintainer_email: maintainer.email()?.to_string(),
                         timestamp: to_datetime(&commit.time()),
+                        changed_files,
                     };
                     Some(change)
                 },
@@ -451,6 +1056,99 @@ impl CommitDb {
         Ok(changes)
     }
 
+    /// Packages changed by the commit, ordered by package name
+    pub async fn get_packages_by_commit(&self, commit_id: &str) -> Result<Vec<commits::Model>> {
+        let v = Commits::find()
+            .filter(commits::Column::CommitId.eq(commit_id))
+            .order_by_asc(commits::Column::PkgName)
+            .order_by_asc(commits::Column::Branch)
+            .all(&self.conn)
+            .await?;
+        Ok(v)
+    }
+
+    /// Packages changed by commits whose id starts with the prefix
+    pub async fn get_packages_by_commit_prefix(&self, prefix: &str) -> Result<Vec<commits::Model>> {
+        let v = Commits::find()
+            .filter(commits::Column::CommitId.starts_with(prefix.to_lowercase()))
+            .order_by_asc(commits::Column::CommitId)
+            .order_by_asc(commits::Column::PkgName)
+            .order_by_asc(commits::Column::Branch)
+            .all(&self.conn)
+            .await?;
+        Ok(v)
+    }
+
+    /// Commit ids which are not recorded in the commits table
+    pub async fn missing_commits(&self, commit_ids: &[String]) -> Result<Vec<String>> {
+        let found: HashSet<String> = Commits::find()
+            .select_only()
+            .column(commits::Column::CommitId)
+            .distinct()
+            .filter(commits::Column::CommitId.is_in(commit_ids.iter().cloned()))
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .collect();
+
+        Ok(commit_ids
+            .iter()
+            .filter(|id| !found.contains(*id))
+            .unique()
+            .cloned()
+            .collect())
+    }
+
+    /// Newest commit of the package on the branch at or before `time`
+    ///
+    /// None if the package didn't exist yet or was deleted by then.
+    pub async fn get_package_state_at(
+        &self,
+        pkg_name: &str,
+        tree: &TreeId,
+        branch: &str,
+        time: DateTimeWithTimeZone,
+    ) -> Result<Option<commits::Model>> {
+        Ok(self
+            .state_at(tree, branch, time, Some(pkg_name))
+            .await?
+            .pop())
+    }
+
+    /// Newest commit of each package on the branch at or before `time`,
+    /// ordered by package name, see [Self::get_package_state_at]
+    pub async fn get_tree_state_at(
+        &self,
+        tree: &TreeId,
+        branch: &str,
+        time: DateTimeWithTimeZone,
+    ) -> Result<Vec<commits::Model>> {
+        self.state_at(tree, branch, time, None).await
+    }
+
+    async fn state_at(
+        &self,
+        tree: &TreeId,
+        branch: &str,
+        time: DateTimeWithTimeZone,
+        pkg_name: Option<&str>,
+    ) -> Result<Vec<commits::Model>> {
+        Ok(Commits::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                STATE_AT,
+                [
+                    tree.to_string().into(),
+                    branch.into(),
+                    time.into(),
+                    pkg_name.map(str::to_string).into(),
+                ],
+            ))
+            .all(&self.conn)
+            .await?)
+    }
+
     /// Commits are sorted by timestamp in descending order, return Vec<(commit_id,pkg_version,spec_path,defines_path)>
     pub async fn get_commits_by_packages(&self, pkg_name: &str) -> Result<Vec<commits::Model>> {
         let v = Commits::find()
@@ -462,6 +1160,95 @@ impl CommitDb {
     }
 }
 
+/// Newest commits row of each package of the branch at or before $3, only of
+/// the package $4 unless it is null, leaving out packages deleted by then
+const STATE_AT: &str = "
+    SELECT * FROM (
+        SELECT DISTINCT ON (pkg_name) *
+        FROM commits
+        WHERE tree = $1 AND branch = $2 AND commit_time <= $3
+            AND ($4::varchar IS NULL OR pkg_name = $4)
+        -- a package moved within a commit is deleted at its old path, the
+        -- row of the new path wins
+        ORDER BY pkg_name, commit_time DESC, commit_id DESC, status = 'Deleted'
+    ) latest
+    WHERE status <> 'Deleted'
+    ORDER BY pkg_name";
+
+/// Names of local and remote branches except symbolic references (e.g. origin/HEAD)
+/// and copies of the main branch
+fn topic_branches(repo: &Repository) -> Result<Vec<String>> {
+    let git2repo = repo.get_git2repo();
+    let main_branch = repo.get_repo_branch();
+
+    let mut result = vec![];
+    for branch in git2repo.branches(None)? {
+        let (branch, branch_type) = branch?;
+        let reference = branch.get();
+        if reference.symbolic_target().is_some() {
+            continue;
+        }
+        let Some(name) = branch.name()? else {
+            continue;
+        };
+
+        // strip remote name of remote branches, e.g. upstream/stable -> stable
+        let short_name = match (branch_type, reference.name()) {
+            (BranchType::Remote, Some(refname)) => git2repo
+                .branch_remote_name(refname)
+                .ok()
+                .and_then(|remote| {
+                    let remote = remote.as_str()?;
+                    Some(name.strip_prefix(remote)?.strip_prefix('/')?.to_string())
+                })
+                .unwrap_or_else(|| name.to_string()),
+            _ => name.to_string(),
+        };
+        if short_name == main_branch || short_name == "HEAD" {
+            continue;
+        }
+
+        result.push(name.to_string());
+    }
+
+    Ok(result)
+}
+
+/// Error of a package whose spec or defines is missing
+///
+/// The package is named by PKGNAME of its defines at `from`, or at `to` for
+/// new packages, falling back to the directory name if neither can be read.
+fn missing_file_error(
+    repo: &Repository,
+    from: Option<Oid>,
+    to: Oid,
+    defines_path: &Path,
+    missing: &Path,
+) -> PackageError {
+    let package = from
+        .into_iter()
+        .chain([to])
+        .find_map(|commit| defines_package_name(repo, commit, defines_path))
+        .or_else(|| {
+            defines_path
+                .iter()
+                .nth_back(2)
+                .and_then(|name| name.to_str())
+                .map(String::from)
+        })
+        .unwrap_or_default();
+    PackageError {
+        package,
+        path: missing.to_string_lossy().to_string(),
+        message: format!("{} is missing", missing.display()),
+        err_type: ErrorType::Package,
+        line: None,
+        col: None,
+        end_line: None,
+        end_col: None,
+    }
+}
+
 /// Walk and collect files changed in the diff between two commits
 fn walk_diff_tree(
     repo: &Repository,
@@ -490,3 +1277,44 @@ fn walk_diff_tree(
         .collect_vec();
     Ok(res)
 }
+
+/// Create and migrate the tables and indexes of the commit database
+async fn create_schema(conn: &DatabaseTransaction) -> Result<()> {
+    Commits.create_table(conn).await?;
+    Commits
+        .create_index(
+            conn,
+            "idx_commits_commit_id",
+            vec![commits::Column::CommitId],
+        )
+        .await?;
+    Commits
+        .create_index(
+            conn,
+            "idx_commits_pkg_name_branch",
+            vec![commits::Column::PkgName, commits::Column::Branch],
+        )
+        .await?;
+    Commits
+        .add_columns(conn, vec![commits::Column::ChangedFiles])
+        .await?;
+    Histories.create_table(conn).await?;
+    CommitMeta.create_table(conn).await?;
+
+    set_schema_meta(conn, SCHEMA_VERSION_KEY, &schema_version()).await
+}
+
+/// schema_meta key of [schema_version]
+const SCHEMA_VERSION_KEY: &str = "commits_schema_version";
+
+/// Identifier of the tables created by [create_schema], see [CommitDb::open_read_only]
+fn schema_version() -> String {
+    digest(
+        [
+            table_columns(Commits),
+            table_columns(Histories),
+            table_columns(CommitMeta),
+        ]
+        .join(";"),
+    )
+}
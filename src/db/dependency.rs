@@ -0,0 +1,390 @@
+use super::entities::package_dependencies;
+use abbs_meta_tree::Package;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Relational operator of a versioned dependency
+///
+/// Stored in the database in its string form, e.g. `>=`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum RelOp {
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "=")]
+    Eq,
+}
+
+impl RelOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ge => ">=",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Lt => "<",
+            Self::Eq => "=",
+        }
+    }
+}
+
+impl RelOp {
+    /// The version satisfies this operator with the required version, compared like dpkg
+    pub fn satisfied_by(&self, version: &str, required: &str) -> bool {
+        let ordering = compare_versions(version, required);
+        match self {
+            Self::Ge => ordering.is_ge(),
+            Self::Le => ordering.is_le(),
+            Self::Gt => ordering.is_gt(),
+            Self::Lt => ordering.is_lt(),
+            Self::Eq => ordering.is_eq(),
+        }
+    }
+}
+
+/// Compare versions like `epoch:version-release` the way dpkg does, epoch and release are optional
+pub fn compare_versions(left: &str, right: &str) -> Ordering {
+    let (left_epoch, left_version, left_release) = split_version(left);
+    let (right_epoch, right_version, right_release) = split_version(right);
+
+    left_epoch
+        .cmp(&right_epoch)
+        .then_with(|| compare_part(left_version, right_version))
+        .then_with(|| compare_part(left_release, right_release))
+}
+
+/// Split into epoch, version and release, a missing epoch is 0 and a missing release is empty
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) if !epoch.is_empty() && epoch.bytes().all(|b| b.is_ascii_digit()) => {
+            (epoch.parse().unwrap_or(u64::MAX), rest)
+        }
+        _ => (0, version),
+    };
+    let (version, release) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+    (epoch, version, release)
+}
+
+/// Order of a character of a non-digit run, `~` sorts before the end of a run and
+/// letters before other characters
+fn char_order(c: Option<u8>) -> i32 {
+    match c {
+        Some(b'~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// Compare alternating runs of non-digits and digits, like verrevcmp of dpkg
+fn compare_part(left: &str, right: &str) -> Ordering {
+    let (mut left, mut right) = (left.as_bytes(), right.as_bytes());
+    while !left.is_empty() || !right.is_empty() {
+        loop {
+            let l = left.first().copied().filter(|c| !c.is_ascii_digit());
+            let r = right.first().copied().filter(|c| !c.is_ascii_digit());
+            if l.is_none() && r.is_none() {
+                break;
+            }
+            let ordering = char_order(l).cmp(&char_order(r));
+            if ordering.is_ne() {
+                return ordering;
+            }
+            // only equal characters get here, the end of a run never equals a character
+            left = &left[1..];
+            right = &right[1..];
+        }
+
+        let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let (left_len, right_len) = (digits(left), digits(right));
+        let trim = |s: &[u8]| -> Vec<u8> { s.iter().copied().skip_while(|c| *c == b'0').collect() };
+        let (l, r) = (trim(&left[..left_len]), trim(&right[..right_len]));
+        let ordering = l.len().cmp(&r.len()).then_with(|| l.cmp(&r));
+        if ordering.is_ne() {
+            return ordering;
+        }
+        left = &left[left_len..];
+        right = &right[right_len..];
+    }
+
+    Ordering::Equal
+}
+
+impl RelOp {
+    /// Parse an operator as written in specs, accepting the aliases `==`,
+    /// `>>`, `<<`, `=>` and `=<` of the stored operators
+    pub fn parse_spec(s: &str) -> Result<Self> {
+        Ok(match s {
+            "==" => Self::Eq,
+            ">>" => Self::Gt,
+            "<<" => Self::Lt,
+            "=>" => Self::Ge,
+            "=<" => Self::Le,
+            _ => s.parse()?,
+        })
+    }
+}
+
+impl FromStr for RelOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            ">=" => Self::Ge,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            "<" => Self::Lt,
+            "=" => Self::Eq,
+            _ => bail!("unknown relational operator {s:?}"),
+        })
+    }
+}
+
+impl Display for RelOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A dependency like `glibc>=2.38`
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Dependency {
+    pub name: String,
+    pub relop: Option<RelOp>,
+    pub version: Option<String>,
+}
+
+/// Dependencies of each architecture, `default` for all architectures
+pub type Dependencies = HashMap<String, Vec<Dependency>>;
+
+/// Dependencies as parsed by abbs-meta-tree
+pub type PkgDep = HashMap<String, Vec<(String, Option<String>, Option<String>)>>;
+
+/// Relationships dependencies are saved as, in the order of [relationships]
+pub const RELATIONSHIPS: [&str; 8] = [
+    "PKGDEP",
+    "BUILDDEP",
+    "PKGSUG",
+    "PKGPROV",
+    "PKGRECOM",
+    "PKGREP",
+    "PKGBREAK",
+    "PKGCONFIG",
+];
+
+/// Dependency fields of a package, with the relationship they are saved as
+pub fn relationships(pkg: &Package) -> [(&'static str, &PkgDep); 8] {
+    [
+        ("PKGDEP", &pkg.dependencies),
+        ("BUILDDEP", &pkg.build_dependencies),
+        ("PKGSUG", &pkg.package_suggests),
+        ("PKGPROV", &pkg.package_provides),
+        ("PKGRECOM", &pkg.package_recommands),
+        ("PKGREP", &pkg.package_replaces),
+        ("PKGBREAK", &pkg.package_breaks),
+        ("PKGCONFIG", &pkg.package_configs),
+    ]
+}
+
+/// Convert dependencies of abbs-meta-tree, invalid ones are returned as errors
+pub fn typed_dependencies(pkgdep: PkgDep) -> (Dependencies, Vec<anyhow::Error>) {
+    let mut errors = vec![];
+    let dependencies = pkgdep
+        .into_iter()
+        .map(|(architecture, deps)| {
+            let deps = deps
+                .into_iter()
+                .filter_map(|dep| Dependency::try_from(dep).map_err(|e| errors.push(e)).ok())
+                .collect();
+            (architecture, deps)
+        })
+        .collect();
+
+    (dependencies, errors)
+}
+
+impl TryFrom<(String, Option<String>, Option<String>)> for Dependency {
+    type Error = anyhow::Error;
+
+    /// Convert the (name, relop, version) triple of abbs-meta-tree
+    ///
+    /// Operators are normalized with [RelOp::parse_spec], tokens with a bad
+    /// name, an operator without a version or a malformed version are errors.
+    fn try_from((name, relop, version): (String, Option<String>, Option<String>)) -> Result<Self> {
+        if !is_dependency_name(&name) {
+            bail!("invalid dependency name {name:?}");
+        }
+        let version = version.filter(|version| !version.is_empty());
+        let (relop, version) = match (relop, version) {
+            (Some(relop), Some(version)) => {
+                if !is_dependency_version(&version) {
+                    bail!("invalid version {version:?} of dependency {name}");
+                }
+                (Some(RelOp::parse_spec(&relop)?), Some(version))
+            }
+            (None, None) => (None, None),
+            (Some(relop), None) => bail!("dependency {name}{relop} has no version"),
+            (None, Some(version)) => {
+                bail!("dependency {name} has version {version:?} without an operator")
+            }
+        };
+
+        Ok(Self {
+            name,
+            relop,
+            version,
+        })
+    }
+}
+
+/// A package name, without whitespace or operator characters
+fn is_dependency_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '<' | '>' | '=' | '"' | '\''))
+}
+
+/// A version like 2.38, 1:2.0-1 or 0~rc1, the epoch is optional
+fn is_dependency_version(version: &str) -> bool {
+    let upstream = match version.split_once(':') {
+        Some((epoch, upstream))
+            if !epoch.is_empty() && epoch.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            upstream
+        }
+        Some(_) => return false,
+        None => version,
+    };
+    let mut chars = upstream.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '~' | '_' | '-'))
+}
+
+impl TryFrom<&package_dependencies::Model> for Dependency {
+    type Error = anyhow::Error;
+
+    fn try_from(model: &package_dependencies::Model) -> Result<Self> {
+        Ok(Self {
+            name: model.dependency.clone(),
+            relop: model.relop.as_deref().map(str::parse).transpose()?,
+            version: model.version.clone(),
+        })
+    }
+}
+
+impl Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let (Some(relop), Some(version)) = (&self.relop, &self.version) {
+            write!(f, "{relop}{version}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        // each version sorts before the next one, like dpkg --compare-versions
+        let ordered = [
+            "1.0~rc1", "1.0", "1.0-1", "1.0-2", "1.0a", "1.0+git1", "1.1", "1.10", "2.0", "1:0.9",
+        ];
+        for (older, newer) in ordered.iter().zip(&ordered[1..]) {
+            assert_eq!(
+                compare_versions(older, newer),
+                Ordering::Less,
+                "{older} < {newer}"
+            );
+            assert_eq!(
+                compare_versions(newer, older),
+                Ordering::Greater,
+                "{newer} > {older}"
+            );
+        }
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(compare_versions("0:1.0", "1.0"), Ordering::Equal);
+
+        assert!(RelOp::Ge.satisfied_by("2.0", "2.0"));
+        assert!(!RelOp::Lt.satisfied_by("2.0", "2.0"));
+        assert!(RelOp::Gt.satisfied_by("2.0-1", "2.0"));
+    }
+
+    #[test]
+    fn test_relop_round_trip() {
+        for relop in [RelOp::Ge, RelOp::Le, RelOp::Gt, RelOp::Lt, RelOp::Eq] {
+            let s = relop.to_string();
+            assert_eq!(s.parse::<RelOp>().unwrap(), relop);
+            assert_eq!(RelOp::parse_spec(&s).unwrap(), relop);
+            // serialized like it is stored
+            assert_eq!(serde_json::to_string(&relop).unwrap(), format!("{s:?}"));
+            assert_eq!(
+                serde_json::from_str::<RelOp>(&format!("{s:?}")).unwrap(),
+                relop
+            );
+        }
+
+        // aliases are only accepted in specs, and formatted as the stored operator
+        for (alias, relop) in [
+            ("==", RelOp::Eq),
+            (">>", RelOp::Gt),
+            ("<<", RelOp::Lt),
+            ("=>", RelOp::Ge),
+            ("=<", RelOp::Le),
+        ] {
+            assert_eq!(RelOp::parse_spec(alias).unwrap(), relop);
+            assert!(alias.parse::<RelOp>().is_err(), "{alias}");
+        }
+        for invalid in ["", "!=", "~=", ">= "] {
+            assert!(RelOp::parse_spec(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_dependency_tokens() {
+        let token = |name: &str, relop: Option<&str>, version: Option<&str>| {
+            Dependency::try_from((
+                name.to_string(),
+                relop.map(String::from),
+                version.map(String::from),
+            ))
+        };
+
+        let dep = token("glibc", Some(">>"), Some("1:2.38-1")).unwrap();
+        assert_eq!(dep.relop, Some(RelOp::Gt));
+        assert_eq!(dep.version.as_deref(), Some("1:2.38-1"));
+        assert_eq!(
+            token("gcc", Some("=<"), Some("13")).unwrap().relop,
+            Some(RelOp::Le)
+        );
+        assert_eq!(token("gcc", None, Some("")).unwrap().version, None);
+
+        for (name, relop, version) in [
+            ("", None, None),
+            ("gcc\"", None, None),
+            ("gcc", Some(">="), None),
+            ("gcc", None, Some("13")),
+            ("gcc", Some(">="), Some("x:13")),
+            ("gcc", Some(">="), Some("-13")),
+            ("gcc", Some("=>="), Some("13")),
+        ] {
+            assert!(
+                token(name, relop, version).is_err(),
+                "{name:?} {relop:?} {version:?}"
+            );
+        }
+    }
+}
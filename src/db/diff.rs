@@ -0,0 +1,427 @@
+use super::dependency::{relationships, typed_dependencies, Dependency};
+use super::entities::{
+    package_dependencies, package_errors, package_versions, packages, prelude::*,
+};
+use super::get_full_version;
+use crate::package::Meta;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use sea_orm::{ColumnTrait, Database, DatabaseConnection, EntityTrait, QueryFilter, QueryTrait};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Differences between two abbs databases
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub versions: Vec<VersionChange>,
+    pub dependencies: Vec<DependencyChange>,
+    pub descriptions: Vec<DescriptionChange>,
+    pub errors: Vec<ErrorCountChange>,
+}
+
+/// Effect of merging a testing branch on the packages of the main branch
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeSimulation {
+    /// testing branch, e.g. glibc-2.40
+    pub branch: String,
+    /// tip of the testing branch the packages were parsed at
+    pub commit: String,
+    /// from the main branch to the tip, limited to packages of the testing branch
+    pub diff: DbDiff,
+    /// packages of the testing branch also overridden by other testing branches
+    pub conflicts: Vec<OverrideConflict>,
+    /// packages which failed to parse at the tip, left out of the diff
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverrideConflict {
+    pub package: String,
+    /// other testing branches overriding the package
+    pub branches: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionChange {
+    pub package: String,
+    pub branch: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyChange {
+    pub package: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptionChange {
+    pub package: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorCountChange {
+    pub package: String,
+    pub old: usize,
+    pub new: usize,
+}
+
+/// Package metadata loaded from one database
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    /// package -> description
+    pub packages: BTreeMap<String, String>,
+    /// (package, branch) -> full version
+    pub versions: BTreeMap<(String, String), String>,
+    /// package -> formatted dependencies
+    pub dependencies: BTreeMap<String, BTreeSet<String>>,
+    /// package -> number of errors
+    pub errors: BTreeMap<String, usize>,
+}
+
+impl Snapshot {
+    /// Load every package, or only `packages` on `branch` if given
+    pub async fn load(
+        conn: &DatabaseConnection,
+        filter: Option<(&[String], &str)>,
+    ) -> Result<Self> {
+        let packages = Packages::find()
+            .apply_if(filter, |query, (names, _)| {
+                query.filter(packages::Column::Name.is_in(names))
+            })
+            .all(conn)
+            .await?
+            .into_iter()
+            .map(|pkg| (pkg.name, pkg.description))
+            .collect();
+
+        let versions = PackageVersions::find()
+            .apply_if(filter, |query, (names, branch)| {
+                query
+                    .filter(package_versions::Column::Package.is_in(names))
+                    .filter(package_versions::Column::Branch.eq(branch))
+            })
+            .all(conn)
+            .await?
+            .into_iter()
+            .map(|v| ((v.package, v.branch), v.full_version))
+            .collect();
+
+        let mut dependencies: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        let rows = PackageDependencies::find()
+            .apply_if(filter, |query, (names, _)| {
+                query.filter(package_dependencies::Column::Package.is_in(names))
+            })
+            .all(conn)
+            .await?;
+        for dep in rows {
+            dependencies
+                .entry(dep.package.clone())
+                .or_default()
+                .insert(format_dependency(&dep));
+        }
+
+        let errors = PackageErrors::find()
+            .apply_if(filter, |query, (names, branch)| {
+                query
+                    .filter(package_errors::Column::Package.is_in(names))
+                    .filter(package_errors::Column::Branch.eq(branch))
+            })
+            .all(conn)
+            .await?
+            .into_iter()
+            .counts_by(|e| e.package)
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            packages,
+            versions,
+            dependencies,
+            errors,
+        })
+    }
+}
+
+impl Snapshot {
+    /// Packages parsed from a tree, as they would be saved for `branch`
+    ///
+    /// Only parse errors and invalid dependencies are counted as errors.
+    pub fn from_packages(metas: &[Meta], branch: &str) -> Self {
+        let mut snapshot = Self::default();
+        for (pkg, _, errors, _) in metas {
+            let mut error_count = errors.len();
+            let mut dependencies = BTreeSet::new();
+            for (relationship, pkgdep) in relationships(pkg) {
+                let (typed, invalid) = typed_dependencies(pkgdep.clone());
+                error_count += invalid.len();
+                for (architecture, deps) in typed {
+                    let architecture = if architecture == "default" {
+                        ""
+                    } else {
+                        &architecture
+                    };
+                    for dependency in deps {
+                        dependencies.insert(format_typed_dependency(
+                            relationship,
+                            &dependency,
+                            architecture,
+                        ));
+                    }
+                }
+            }
+
+            let description = pkg.description.split_whitespace().join(" ");
+            snapshot.packages.insert(pkg.name.clone(), description);
+            snapshot.versions.insert(
+                (pkg.name.clone(), branch.to_string()),
+                get_full_version(pkg),
+            );
+            if !dependencies.is_empty() {
+                snapshot.dependencies.insert(pkg.name.clone(), dependencies);
+            }
+            if error_count > 0 {
+                snapshot.errors.insert(pkg.name.clone(), error_count);
+            }
+        }
+
+        snapshot
+    }
+}
+
+/// e.g. PKGDEP glibc>=2.38 [amd64]
+pub(crate) fn format_dependency(dep: &package_dependencies::Model) -> String {
+    let res = match Dependency::try_from(dep) {
+        Ok(dependency) => {
+            return format_typed_dependency(&dep.relationship, &dependency, &dep.architecture)
+        }
+        // keep rows with unknown operators comparable
+        Err(_) => format!(
+            "{} {}{}{}",
+            dep.relationship,
+            dep.dependency,
+            dep.relop.as_deref().unwrap_or_default(),
+            dep.version.as_deref().unwrap_or_default()
+        ),
+    };
+    with_architecture(res, &dep.architecture)
+}
+
+/// Same as [format_dependency], for dependencies not read from the database
+pub(crate) fn format_typed_dependency(
+    relationship: &str,
+    dependency: &Dependency,
+    architecture: &str,
+) -> String {
+    with_architecture(format!("{relationship} {dependency}"), architecture)
+}
+
+fn with_architecture(mut res: String, architecture: &str) -> String {
+    if !architecture.is_empty() {
+        let _ = write!(res, " [{architecture}]");
+    }
+    res
+}
+
+/// Compare two abbs databases, only SELECT statements are executed
+pub async fn diff_databases(old_url: &str, new_url: &str) -> Result<DbDiff> {
+    if old_url.trim_end_matches('/') == new_url.trim_end_matches('/') {
+        bail!("old and new point at the same database");
+    }
+    let old = Snapshot::load(&Database::connect(old_url).await?, None).await?;
+    let new = Snapshot::load(&Database::connect(new_url).await?, None).await?;
+
+    Ok(diff_snapshots(&old, &new))
+}
+
+/// Differences from `old` to `new`
+pub(crate) fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> DbDiff {
+    let old_names: BTreeSet<_> = old.packages.keys().collect();
+    let new_names: BTreeSet<_> = new.packages.keys().collect();
+
+    let added = (&new_names - &old_names).into_iter().cloned().collect();
+    let removed = (&old_names - &new_names).into_iter().cloned().collect();
+
+    let versions = old
+        .versions
+        .keys()
+        .chain(new.versions.keys())
+        .unique()
+        .filter_map(|key| {
+            let (old, new) = (old.versions.get(key), new.versions.get(key));
+            (old != new).then(|| VersionChange {
+                package: key.0.clone(),
+                branch: key.1.clone(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .sorted_by(|l, r| (&l.package, &l.branch).cmp(&(&r.package, &r.branch)))
+        .collect();
+
+    let empty = BTreeSet::new();
+    let dependencies = old
+        .dependencies
+        .keys()
+        .chain(new.dependencies.keys())
+        .unique()
+        .sorted()
+        .filter_map(|package| {
+            let old = old.dependencies.get(package).unwrap_or(&empty);
+            let new = new.dependencies.get(package).unwrap_or(&empty);
+            (old != new).then(|| DependencyChange {
+                package: package.clone(),
+                added: new.difference(old).cloned().collect(),
+                removed: old.difference(new).cloned().collect(),
+            })
+        })
+        .collect();
+
+    let descriptions = old
+        .packages
+        .iter()
+        .filter_map(|(package, old_description)| {
+            let new_description = new.packages.get(package)?;
+            (old_description != new_description).then(|| DescriptionChange {
+                package: package.clone(),
+                old: old_description.clone(),
+                new: new_description.clone(),
+            })
+        })
+        .collect();
+
+    let errors = old
+        .errors
+        .keys()
+        .chain(new.errors.keys())
+        .unique()
+        .sorted()
+        .filter_map(|package| {
+            let old = old.errors.get(package).copied().unwrap_or_default();
+            let new = new.errors.get(package).copied().unwrap_or_default();
+            (old != new).then(|| ErrorCountChange {
+                package: package.clone(),
+                old,
+                new,
+            })
+        })
+        .collect();
+
+    DbDiff {
+        added,
+        removed,
+        versions,
+        dependencies,
+        descriptions,
+        errors,
+    }
+}
+
+impl DbDiff {
+    /// Render as markdown for release notes
+    pub fn to_markdown(&self) -> String {
+        let mut res = String::new();
+
+        if !self.added.is_empty() {
+            res += "## Added packages\n\n";
+            for package in &self.added {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        if !self.removed.is_empty() {
+            res += "## Removed packages\n\n";
+            for package in &self.removed {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        if !self.versions.is_empty() {
+            res += "## Version changes\n\n";
+            res += "| Package | Branch | Old | New |\n|---|---|---|---|\n";
+            for v in &self.versions {
+                let _ = writeln!(
+                    res,
+                    "| {} | {} | {} | {} |",
+                    v.package,
+                    v.branch,
+                    v.old.as_deref().unwrap_or("-"),
+                    v.new.as_deref().unwrap_or("-")
+                );
+            }
+            res += "\n";
+        }
+
+        if !self.dependencies.is_empty() {
+            res += "## Dependency changes\n\n";
+            for dep in &self.dependencies {
+                let _ = writeln!(res, "- {}", dep.package);
+                for added in &dep.added {
+                    let _ = writeln!(res, "  - added `{added}`");
+                }
+                for removed in &dep.removed {
+                    let _ = writeln!(res, "  - removed `{removed}`");
+                }
+            }
+            res += "\n";
+        }
+
+        if !self.descriptions.is_empty() {
+            res += "## Description changes\n\n";
+            for d in &self.descriptions {
+                let _ = writeln!(res, "- {}: {} -> {}", d.package, d.old, d.new);
+            }
+            res += "\n";
+        }
+
+        if !self.errors.is_empty() {
+            res += "## Error count changes\n\n";
+            for e in &self.errors {
+                let _ = writeln!(res, "- {}: {} -> {}", e.package, e.old, e.new);
+            }
+            res += "\n";
+        }
+
+        res
+    }
+}
+
+impl MergeSimulation {
+    /// Render as markdown for pull requests
+    pub fn to_markdown(&self) -> String {
+        let mut res = format!("# Merging {} at {}\n\n", self.branch, self.commit);
+        res += &self.diff.to_markdown();
+
+        if !self.conflicts.is_empty() {
+            res += "## Conflicting overrides\n\n";
+            for conflict in &self.conflicts {
+                let _ = writeln!(
+                    res,
+                    "- {}: also overridden by {}",
+                    conflict.package,
+                    conflict.branches.join(", ")
+                );
+            }
+            res += "\n";
+        }
+
+        if !self.failed.is_empty() {
+            res += "## Failed to parse\n\n";
+            for package in &self.failed {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        res
+    }
+}
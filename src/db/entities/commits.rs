@@ -19,6 +19,7 @@ pub struct Model {
     pub commit_id: String,
     pub commit_time: DateTimeWithTimeZone,
     pub status: String,
+    pub changed_files: Option<Json>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -2,15 +2,34 @@
 
 pub mod prelude;
 
+pub mod collector_meta;
+pub mod commit_meta;
 pub mod commits;
+pub mod deferred_packages;
+pub mod event_consumers;
+pub mod events_outbox;
 pub mod histories;
+pub mod meta_snapshots;
+pub mod package_arch_versions;
+pub mod package_architectures;
 pub mod package_changes;
 pub mod package_dependencies;
+pub mod package_dependency_counts;
 pub mod package_duplicate;
+pub mod package_duplicate_resolution;
+pub mod package_error_events;
 pub mod package_errors;
+pub mod package_groups;
+pub mod package_moves;
 pub mod package_spec;
+pub mod package_stats;
+pub mod package_sync_status;
 pub mod package_testing;
+pub mod package_testing_spec;
+pub mod package_update_sources;
 pub mod package_versions;
 pub mod packages;
+pub mod scan_run_packages;
+pub mod schema_meta;
 pub mod tree_branches;
 pub mod trees;
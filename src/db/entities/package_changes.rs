@@ -17,6 +17,9 @@ pub struct Model {
     pub maintainer_name: String,
     pub maintainer_email: String,
     pub timestamp: DateTimeWithTimeZone,
+    #[sea_orm(default_value = false)]
+    pub mass_change: bool,
+    pub changed_files: Option<Json>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -15,6 +15,7 @@ pub struct Model {
     pub section: String,
     #[sea_orm(primary_key, auto_increment = false)]
     pub directory: String,
+    pub full_version: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
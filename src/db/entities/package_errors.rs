@@ -15,6 +15,9 @@ pub struct Model {
     pub col: Option<i32>,
     #[sea_orm(primary_key)]
     pub id: i32,
+    pub last_run_id: Option<String>,
+    pub end_line: Option<i32>,
+    pub end_col: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -16,6 +16,7 @@ pub struct Model {
     pub branch: String,
     pub commit: String,
     pub full_version: String,
+    pub last_run_id: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
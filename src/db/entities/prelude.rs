@@ -1,14 +1,33 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.15
 
+pub use super::collector_meta::Entity as CollectorMeta;
+pub use super::commit_meta::Entity as CommitMeta;
 pub use super::commits::Entity as Commits;
+pub use super::deferred_packages::Entity as DeferredPackages;
+pub use super::event_consumers::Entity as EventConsumers;
+pub use super::events_outbox::Entity as EventsOutbox;
 pub use super::histories::Entity as Histories;
+pub use super::meta_snapshots::Entity as MetaSnapshots;
+pub use super::package_arch_versions::Entity as PackageArchVersions;
+pub use super::package_architectures::Entity as PackageArchitectures;
 pub use super::package_changes::Entity as PackageChanges;
 pub use super::package_dependencies::Entity as PackageDependencies;
+pub use super::package_dependency_counts::Entity as PackageDependencyCounts;
 pub use super::package_duplicate::Entity as PackageDuplicate;
+pub use super::package_duplicate_resolution::Entity as PackageDuplicateResolution;
+pub use super::package_error_events::Entity as PackageErrorEvents;
 pub use super::package_errors::Entity as PackageErrors;
+pub use super::package_groups::Entity as PackageGroups;
+pub use super::package_moves::Entity as PackageMoves;
 pub use super::package_spec::Entity as PackageSpec;
+pub use super::package_stats::Entity as PackageStats;
+pub use super::package_sync_status::Entity as PackageSyncStatus;
 pub use super::package_testing::Entity as PackageTesting;
+pub use super::package_testing_spec::Entity as PackageTestingSpec;
+pub use super::package_update_sources::Entity as PackageUpdateSources;
 pub use super::package_versions::Entity as PackageVersions;
 pub use super::packages::Entity as Packages;
+pub use super::scan_run_packages::Entity as ScanRunPackages;
+pub use super::schema_meta::Entity as SchemaMeta;
 pub use super::tree_branches::Entity as TreeBranches;
 pub use super::trees::Entity as Trees;
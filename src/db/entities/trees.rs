@@ -11,6 +11,9 @@ pub struct Model {
     pub category: String,
     pub url: String,
     pub mainbranch: String,
+    pub head_commit: Option<String>,
+    pub head_commit_time: Option<DateTimeWithTimeZone>,
+    pub updated_at: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
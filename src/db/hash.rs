@@ -0,0 +1,152 @@
+use super::entities::prelude::*;
+use super::entities::{commits, histories, package_testing, package_versions};
+use super::{connect_read_only, Role};
+use crate::config::Global;
+use crate::warnings::Warnings;
+use anyhow::{bail, Result};
+use git2::Oid;
+use sea_orm::sea_query::{BinOper, Expr};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Full hex commit hash as stored in the database
+///
+/// `Oid::from_str` pads short strings with zeros, so a truncated hash would
+/// silently turn into a commit which doesn't exist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CommitHash(Oid);
+
+/// Matches a full lowercase hex commit hash
+const HASH_PATTERN: &str = "^[0-9a-f]{40}$";
+
+impl CommitHash {
+    pub fn oid(&self) -> Oid {
+        self.0
+    }
+}
+
+impl FromStr for CommitHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 40 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            bail!("malformed commit hash {s:?}");
+        }
+
+        Ok(Self(Oid::from_str(s)?))
+    }
+}
+
+impl From<Oid> for CommitHash {
+    fn from(oid: Oid) -> Self {
+        Self(oid)
+    }
+}
+
+impl Display for CommitHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Parse a hash read from a row, warning about malformed values
+///
+/// `row` identifies the row in the warning, e.g. `commits bash@stable`.
+pub(crate) fn parse_stored(warnings: &Warnings, row: impl Display, value: &str) -> Option<Oid> {
+    match value.parse::<CommitHash>() {
+        Ok(hash) => Some(hash.oid()),
+        Err(e) => {
+            warnings.warn("malformed hash", format_args!("{row}: {e}"));
+            None
+        }
+    }
+}
+
+/// Number of rows with malformed commit hashes in each table
+pub async fn malformed_hashes(global_config: &Global) -> Result<Vec<(String, u64)>> {
+    let performance = &global_config.performance;
+    let commits_conn =
+        &connect_read_only(Role::Commits.database_url(global_config), performance).await?;
+    let conn = &connect_read_only(Role::Abbs.database_url(global_config), performance).await?;
+    Ok(vec![
+        count_malformed(commits_conn, Commits, commits::Column::CommitId).await?,
+        count_malformed(commits_conn, Histories, histories::Column::CommitId).await?,
+        count_malformed(conn, PackageVersions, package_versions::Column::Githash).await?,
+        count_malformed(conn, PackageTesting, package_testing::Column::Commit).await?,
+    ])
+}
+
+async fn count_malformed<E>(
+    conn: &DatabaseConnection,
+    entity: E,
+    column: E::Column,
+) -> Result<(String, u64)>
+where
+    E: EntityTrait,
+    E::Model: Sync,
+{
+    let count = E::find()
+        .filter(
+            Expr::col(column.as_column_ref())
+                .binary(BinOper::Custom("!~"), Expr::val(HASH_PATTERN)),
+        )
+        .count(conn)
+        .await?;
+
+    Ok((entity.table_name().to_string(), count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn test_commit_hash_from_str() {
+        let hash: CommitHash = HASH.parse().unwrap();
+        assert_eq!(hash.to_string(), HASH);
+        assert_eq!(hash.oid(), Oid::from_str(HASH).unwrap());
+
+        for malformed in [
+            // Oid::from_str would pad these with zeros
+            "0123456",
+            &HASH[..39],
+            &HASH.to_uppercase(),
+            "0123456789abcdef0123456789abcdef0123456g",
+            &format!("{HASH}0"),
+            "",
+        ] {
+            let e = malformed.parse::<CommitHash>().unwrap_err();
+            assert!(e.to_string().starts_with("malformed commit hash"), "{e}");
+        }
+    }
+
+    #[test]
+    fn test_parse_stored() {
+        let warnings = Warnings::new();
+        assert_eq!(
+            parse_stored(&warnings, "commits foo@stable", HASH),
+            Some(Oid::from_str(HASH).unwrap())
+        );
+        assert!(warnings.counts().is_empty());
+
+        assert_eq!(
+            parse_stored(&warnings, "commits foo@stable", "0123456"),
+            None
+        );
+        assert_eq!(
+            parse_stored(&warnings, "histories 3", &HASH.to_uppercase()),
+            None
+        );
+        assert_eq!(parse_stored(&warnings, "histories 4", "not a hash"), None);
+        let counts = warnings.counts();
+        assert_eq!(counts["malformed hash"].total, 3);
+        assert!(
+            counts["malformed hash"].messages[0].starts_with("commits foo@stable: "),
+            "{:?}",
+            counts["malformed hash"].messages
+        );
+    }
+}
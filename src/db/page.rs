@@ -0,0 +1,83 @@
+//! Keyset pagination of list queries
+//!
+//! A page continues after the key of the last row of the previous page instead
+//! of skipping an offset, so rows inserted or deleted by a concurrent scan
+//! don't shift the other rows between pages: rows present during the whole
+//! iteration are listed exactly once.
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Rows of a page unless a limit is requested
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+/// Larger requested limits are capped to this
+pub const MAX_PAGE_SIZE: u64 = 1000;
+
+/// Where a page starts and how many rows it has at most
+#[derive(Debug, Clone, Default)]
+pub struct PageRequest {
+    /// `next` of the previous page, the first page if none
+    pub cursor: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl PageRequest {
+    /// The requested limit within 1 and [MAX_PAGE_SIZE]
+    pub fn limit(&self) -> u64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Key of the last row of the previous page
+    pub fn after<K: DeserializeOwned>(&self) -> Result<Option<K>> {
+        self.cursor.as_deref().map(decode_cursor).transpose()
+    }
+}
+
+/// A page of a list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// cursor of the next page, none on the last page
+    pub next: Option<String>,
+}
+
+/// Opaque cursor of a key, URL-safe base64 of its JSON
+pub fn encode_cursor<K: Serialize>(key: &K) -> Result<String> {
+    Ok(URL_SAFE_NO_PAD.encode(serde_json::to_vec(key)?))
+}
+
+pub fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> Result<K> {
+    let json = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .with_context(|| format!("invalid cursor {cursor}"))?;
+    serde_json::from_slice(&json).with_context(|| format!("invalid cursor {cursor}"))
+}
+
+/// Cut rows queried with a limit of one more than `limit` into a page
+///
+/// The extra row only tells whether there is a next page.
+pub(crate) fn into_page<T, K: Serialize>(
+    mut rows: Vec<T>,
+    limit: u64,
+    key: impl Fn(&T) -> K,
+) -> Result<Page<T>> {
+    let limit = limit as usize;
+    if rows.len() <= limit {
+        return Ok(Page {
+            items: rows,
+            next: None,
+        });
+    }
+
+    rows.truncate(limit);
+    let next = rows
+        .last()
+        .map(|row| encode_cursor(&key(row)))
+        .transpose()?;
+    Ok(Page { items: rows, next })
+}
@@ -0,0 +1,229 @@
+use super::{connect_read_only, Role};
+use crate::config::Global;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use sea_orm::{ConnectionTrait, DatabaseConnection, Statement, TransactionTrait, Value};
+use serde::Serialize;
+use serde_json::Map;
+
+/// Rows returned by an ad-hoc query
+#[derive(Debug, Serialize)]
+pub struct QueryOutput {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// more rows are available than the limit
+    pub truncated: bool,
+}
+
+/// Reject anything but a single SELECT or WITH statement
+pub fn check_read_only(sql: &str) -> Result<()> {
+    let sql = strip_comments(sql);
+    let sql = sql.trim().trim_end_matches(';').trim();
+
+    if sql.contains(';') {
+        bail!("only a single statement is allowed");
+    }
+
+    let keyword = sql
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if keyword != "SELECT" && keyword != "WITH" {
+        bail!("only SELECT or WITH statements are allowed");
+    }
+
+    Ok(())
+}
+
+/// Remove comments outside string literals
+fn strip_comments(sql: &str) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match (quote, c, chars.peek()) {
+            (Some(q), c, _) => {
+                if c == q {
+                    quote = None;
+                }
+                result.push(c);
+            }
+            (None, '\'' | '"', _) => {
+                quote = Some(c);
+                result.push(c);
+            }
+            (None, '-', Some('-')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                result.push('\n');
+            }
+            (None, '/', Some('*')) => {
+                chars.next();
+                let mut prev = None;
+                for c in chars.by_ref() {
+                    if prev == Some('*') && c == '/' {
+                        break;
+                    }
+                    prev = Some(c);
+                }
+                result.push(' ');
+            }
+            (None, c, _) => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Run a read-only query and return at most `limit` rows
+///
+/// The statement runs in a read-only transaction on its own read-only
+/// connection, it only takes snapshots and never blocks a running scan.
+/// With `commits`, it runs against the database of commits_database_url.
+pub async fn query(
+    global_config: &Global,
+    commits: bool,
+    sql: &str,
+    limit: usize,
+) -> Result<QueryOutput> {
+    check_read_only(sql)?;
+    let sql = strip_comments(sql);
+    let sql = sql.trim().trim_end_matches(';');
+
+    let role = if commits { Role::Commits } else { Role::Abbs };
+    let conn =
+        connect_read_only(role.database_url(global_config), &global_config.performance).await?;
+    run_query(&conn, sql, limit).await
+}
+
+async fn run_query(conn: &DatabaseConnection, sql: &str, limit: usize) -> Result<QueryOutput> {
+    let txn = conn.begin().await?;
+    txn.execute_unprepared("SET TRANSACTION READ ONLY").await?;
+
+    let rows = txn
+        .query_all(Statement::from_sql_and_values(
+            conn.get_database_backend(),
+            format!("SELECT row_to_json(q)::text AS row FROM ({sql}) AS q LIMIT $1"),
+            [Value::from(limit as i64 + 1)],
+        ))
+        .await?;
+    txn.rollback().await?;
+
+    let mut rows = rows
+        .into_iter()
+        .map(|row| -> Result<Map<String, serde_json::Value>> {
+            Ok(serde_json::from_str(&row.try_get::<String>("", "row")?)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let truncated = rows.len() > limit;
+    rows.truncate(limit);
+
+    let columns = rows
+        .first()
+        .map(|row| row.keys().cloned().collect_vec())
+        .unwrap_or_default();
+    let rows = rows
+        .into_iter()
+        .map(|row| row.into_iter().map(|(_, v)| v).collect_vec())
+        .collect_vec();
+
+    Ok(QueryOutput {
+        columns,
+        rows,
+        truncated,
+    })
+}
+
+impl QueryOutput {
+    /// Render rows as a plain text table
+    pub fn to_table(&self) -> String {
+        let cells = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell_to_string).collect_vec())
+            .collect_vec();
+
+        let widths = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                cells
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([column.chars().count()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect_vec();
+
+        let format_row = |row: &[String]| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .join(" | ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut result = String::new();
+        result += &format_row(&self.columns);
+        result += "\n";
+        result += &widths.iter().map(|width| "-".repeat(*width)).join("-+-");
+        result += "\n";
+        for row in &cells {
+            result += &format_row(row);
+            result += "\n";
+        }
+        if self.truncated {
+            result += &format!("(truncated to {} rows)\n", self.rows.len());
+        }
+
+        result
+    }
+}
+
+fn cell_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_read_only() {
+        assert!(check_read_only("SELECT * FROM packages").is_ok());
+        assert!(check_read_only("  select name from packages;  ").is_ok());
+        assert!(check_read_only("WITH p AS (SELECT name FROM packages) SELECT * FROM p").is_ok());
+        assert!(check_read_only("-- comment\nSELECT 1").is_ok());
+        assert!(check_read_only("/* comment; */ SELECT 1").is_ok());
+
+        assert!(check_read_only("INSERT INTO packages (name) VALUES ('foo')").is_err());
+        assert!(check_read_only("UPDATE packages SET name = 'foo'").is_err());
+        assert!(check_read_only("DELETE FROM packages").is_err());
+        assert!(check_read_only("DROP TABLE packages").is_err());
+        assert!(check_read_only("CREATE TABLE foo (id int)").is_err());
+        assert!(check_read_only("TRUNCATE packages").is_err());
+        assert!(check_read_only("SELECT 1; DROP TABLE packages").is_err());
+        assert!(check_read_only("SELECT 1 -- comment\n; DELETE FROM packages").is_err());
+        assert!(check_read_only("/* SELECT */ DELETE FROM packages").is_err());
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        assert_eq!(strip_comments("SELECT 1 -- one\n"), "SELECT 1 \n");
+        assert_eq!(strip_comments("SELECT /* one */1"), "SELECT  1");
+        assert_eq!(strip_comments("SELECT '--', \"/*\""), "SELECT '--', \"/*\"");
+    }
+}
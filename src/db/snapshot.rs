@@ -0,0 +1,366 @@
+use super::abbs::refresh_dependency_counts;
+use super::entities::prelude::*;
+use super::entities::{
+    meta_snapshots, package_arch_versions, package_architectures, package_dependencies,
+    package_dependency_counts, package_duplicate, package_errors, package_spec, package_testing,
+    package_testing_spec, package_update_sources, package_versions, packages,
+};
+use super::{connect, digest, setup_schema, table_columns, CreateTable, Role};
+use crate::config::{Global, Repo};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use itertools::Itertools;
+use sea_orm::sea_query::Query;
+use sea_orm::{
+    AccessMode, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    DatabaseTransaction, EntityTrait, Insert, IntoActiveModel, IsolationLevel, QueryFilter,
+    QueryOrder, QuerySelect, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Read;
+use tracing::info;
+
+/// Rows of a tree saved by a snapshot
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SnapshotData {
+    packages: Vec<packages::Model>,
+    package_versions: Vec<package_versions::Model>,
+    package_spec: Vec<package_spec::Model>,
+    package_dependencies: Vec<package_dependencies::Model>,
+}
+
+/// A saved snapshot, without its data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub tree: String,
+    pub created_at: String,
+    pub packages: i32,
+    /// compressed size in bytes
+    pub size: i64,
+    /// the snapshot can be restored with the current schema
+    pub compatible: bool,
+}
+
+/// Logical snapshots of package metadata of a tree, saved in meta_snapshots
+///
+/// Only packages, package_versions, package_spec and package_dependencies
+/// are saved. Other tables are left alone on restore, except for rows of
+/// packages added after the snapshot, which are deleted with the packages.
+pub struct SnapshotStore {
+    conn: DatabaseConnection,
+    tree: String,
+    max_bytes: u64,
+}
+
+/// Number of rows inserted by one statement on restore
+const RESTORE_CHUNK: usize = 2048;
+
+/// Identifier of the columns of the snapshotted tables
+///
+/// Snapshots taken with different columns can't be restored.
+fn schema_version() -> String {
+    digest(
+        [
+            table_columns(Packages),
+            table_columns(PackageVersions),
+            table_columns(PackageSpec),
+            table_columns(PackageDependencies),
+        ]
+        .join(";"),
+    )
+}
+
+impl SnapshotStore {
+    pub async fn open(global_config: &Global, repo_config: &Repo) -> Result<Self> {
+        let conn = connect(&global_config.database_url, &global_config.performance).await?;
+        setup_schema(&conn, Role::Abbs.layout(global_config), |txn| {
+            Box::pin(MetaSnapshots.create_table(txn))
+        })
+        .await?;
+
+        Ok(Self {
+            conn,
+            tree: repo_config.name.clone(),
+            max_bytes: global_config.snapshot_max_mb * 1024 * 1024,
+        })
+    }
+
+    /// Save current rows of the tree under the name, replacing an existing snapshot only with `force`
+    pub async fn create(&self, name: &str, force: bool) -> Result<SnapshotInfo> {
+        if !force && self.find(name).await?.is_some() {
+            bail!("snapshot {name} exists, use --force to replace it");
+        }
+
+        // the tables are read at the same point, not across a concurrent scan
+        let txn = self
+            .conn
+            .begin_with_config(
+                Some(IsolationLevel::RepeatableRead),
+                Some(AccessMode::ReadOnly),
+            )
+            .await?;
+        let packages = Packages::find()
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .order_by_asc(packages::Column::Name)
+            .all(&txn)
+            .await?;
+        let data = SnapshotData {
+            package_versions: PackageVersions::find()
+                .filter(package_versions::Column::Package.in_subquery(self.tree_packages()))
+                .all(&txn)
+                .await?,
+            package_spec: PackageSpec::find()
+                .filter(package_spec::Column::Package.in_subquery(self.tree_packages()))
+                .all(&txn)
+                .await?,
+            package_dependencies: PackageDependencies::find()
+                .filter(package_dependencies::Column::Package.in_subquery(self.tree_packages()))
+                .all(&txn)
+                .await?,
+            packages,
+        };
+        txn.commit().await?;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        serde_json::to_writer(&mut encoder, &data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() as u64 > self.max_bytes {
+            bail!(
+                "snapshot is {} MiB, larger than snapshot_max_mb",
+                compressed.len() / 1024 / 1024
+            );
+        }
+
+        let model = meta_snapshots::Model {
+            name: name.to_string(),
+            tree: self.tree.clone(),
+            schema: schema_version(),
+            created_at: Local::now().fixed_offset(),
+            packages: data.packages.len() as i32,
+            size: compressed.len() as i64,
+            data: compressed,
+        };
+        MetaSnapshots::delete_by_id((name.to_string(), self.tree.clone()))
+            .exec(&self.conn)
+            .await?;
+        MetaSnapshots::insert(model.clone().into_active_model())
+            .exec(&self.conn)
+            .await?;
+        info!(
+            "saved {} packages of {} in snapshot {name}",
+            model.packages, self.tree
+        );
+
+        Ok(to_info(model))
+    }
+
+    /// Replace current rows of the tree with the snapshot in one transaction
+    pub async fn restore(&self, name: &str) -> Result<SnapshotInfo> {
+        let model = MetaSnapshots::find_by_id((name.to_string(), self.tree.clone()))
+            .one(&self.conn)
+            .await?
+            .with_context(|| format!("snapshot {name} not found"))?;
+        if model.schema != schema_version() {
+            bail!("snapshot {name} was taken with another schema and can't be restored");
+        }
+
+        let mut json = vec![];
+        GzDecoder::new(model.data.as_slice()).read_to_end(&mut json)?;
+        let data: SnapshotData = serde_json::from_slice(&json)?;
+        if data.packages.iter().any(|pkg| pkg.tree != self.tree) {
+            bail!("snapshot {name} contains packages of another tree");
+        }
+
+        let txn = self.conn.begin().await?;
+        self.delete_added(&data, &txn).await?;
+        PackageVersions::delete_many()
+            .filter(package_versions::Column::Package.in_subquery(self.tree_packages()))
+            .exec(&txn)
+            .await?;
+        PackageSpec::delete_many()
+            .filter(package_spec::Column::Package.in_subquery(self.tree_packages()))
+            .exec(&txn)
+            .await?;
+        PackageDependencies::delete_many()
+            .filter(package_dependencies::Column::Package.in_subquery(self.tree_packages()))
+            .exec(&txn)
+            .await?;
+        PackageDependencyCounts::delete_many()
+            .filter(package_dependency_counts::Column::Package.in_subquery(self.tree_packages()))
+            .exec(&txn)
+            .await?;
+        Packages::delete_many()
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .exec(&txn)
+            .await?;
+
+        // packages first, the other tables reference them
+        insert_chunks::<packages::ActiveModel, _>(data.packages, &txn).await?;
+        insert_chunks::<package_versions::ActiveModel, _>(data.package_versions, &txn).await?;
+        insert_chunks::<package_spec::ActiveModel, _>(data.package_spec, &txn).await?;
+        insert_chunks::<package_dependencies::ActiveModel, _>(data.package_dependencies, &txn)
+            .await?;
+        refresh_dependency_counts(&txn, self.tree_packages()).await?;
+        txn.commit().await?;
+        info!(
+            "restored {} packages of {} from snapshot {name}",
+            model.packages, self.tree
+        );
+
+        Ok(to_info(model))
+    }
+
+    /// Delete rows of tables not in snapshots of the packages which aren't in the snapshot
+    async fn delete_added(&self, data: &SnapshotData, txn: &DatabaseTransaction) -> Result<()> {
+        let saved: HashSet<_> = data.packages.iter().map(|pkg| pkg.name.as_str()).collect();
+        let added = Packages::find()
+            .select_only()
+            .column(packages::Column::Name)
+            .filter(packages::Column::Tree.eq(self.tree.clone()))
+            .into_tuple::<String>()
+            .all(txn)
+            .await?
+            .into_iter()
+            .filter(|name| !saved.contains(name.as_str()))
+            .collect_vec();
+        if added.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "deleting {} packages added after the snapshot: {}",
+            added.len(),
+            added.join(" ")
+        );
+
+        PackageArchVersions::delete_many()
+            .filter(package_arch_versions::Column::Package.is_in(added.clone()))
+            .exec(txn)
+            .await?;
+        PackageArchitectures::delete_many()
+            .filter(package_architectures::Column::Package.is_in(added.clone()))
+            .exec(txn)
+            .await?;
+        PackageUpdateSources::delete_many()
+            .filter(package_update_sources::Column::Package.is_in(added.clone()))
+            .exec(txn)
+            .await?;
+        PackageTesting::delete_many()
+            .filter(package_testing::Column::Package.is_in(added.clone()))
+            .filter(package_testing::Column::Tree.eq(self.tree.clone()))
+            .exec(txn)
+            .await?;
+        PackageTestingSpec::delete_many()
+            .filter(package_testing_spec::Column::Package.is_in(added.clone()))
+            .filter(package_testing_spec::Column::Tree.eq(self.tree.clone()))
+            .exec(txn)
+            .await?;
+        PackageDuplicate::delete_many()
+            .filter(package_duplicate::Column::Package.is_in(added.clone()))
+            .filter(package_duplicate::Column::Tree.eq(self.tree.clone()))
+            .exec(txn)
+            .await?;
+        PackageErrors::delete_many()
+            .filter(package_errors::Column::Package.is_in(added))
+            .filter(package_errors::Column::Tree.eq(self.tree.clone()))
+            .exec(txn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Snapshots of the tree, oldest first
+    pub async fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let res = MetaSnapshots::find()
+            .select_only()
+            .columns([
+                meta_snapshots::Column::Name,
+                meta_snapshots::Column::Tree,
+                meta_snapshots::Column::Schema,
+                meta_snapshots::Column::CreatedAt,
+                meta_snapshots::Column::Packages,
+                meta_snapshots::Column::Size,
+            ])
+            .filter(meta_snapshots::Column::Tree.eq(self.tree.clone()))
+            .order_by_asc(meta_snapshots::Column::CreatedAt)
+            .into_tuple()
+            .all(&self.conn)
+            .await?
+            .into_iter()
+            .map(|(name, tree, schema, created_at, packages, size)| {
+                to_info(meta_snapshots::Model {
+                    name,
+                    tree,
+                    schema,
+                    created_at,
+                    packages,
+                    size,
+                    data: vec![],
+                })
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        let res = MetaSnapshots::delete_by_id((name.to_string(), self.tree.clone()))
+            .exec(&self.conn)
+            .await?;
+        if res.rows_affected == 0 {
+            bail!("snapshot {name} not found");
+        }
+
+        Ok(())
+    }
+
+    async fn find(&self, name: &str) -> Result<Option<String>> {
+        Ok(
+            MetaSnapshots::find_by_id((name.to_string(), self.tree.clone()))
+                .select_only()
+                .column(meta_snapshots::Column::Name)
+                .into_tuple()
+                .one(&self.conn)
+                .await?,
+        )
+    }
+
+    /// SELECT name FROM packages WHERE tree = ?
+    fn tree_packages(&self) -> sea_orm::sea_query::SelectStatement {
+        Query::select()
+            .column(packages::Column::Name)
+            .from(Packages)
+            .and_where(packages::Column::Tree.eq(self.tree.clone()))
+            .to_owned()
+    }
+}
+
+fn to_info(model: meta_snapshots::Model) -> SnapshotInfo {
+    SnapshotInfo {
+        compatible: model.schema == schema_version(),
+        name: model.name,
+        tree: model.tree,
+        created_at: model.created_at.to_rfc3339(),
+        packages: model.packages,
+        size: model.size,
+    }
+}
+
+async fn insert_chunks<A, M>(models: Vec<M>, db: &impl ConnectionTrait) -> Result<()>
+where
+    A: ActiveModelTrait + Send,
+    M: IntoActiveModel<A> + Send,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+{
+    for chunk in &models.into_iter().chunks(RESTORE_CHUNK) {
+        Insert::many(chunk.map(|model| model.into_active_model()))
+            .exec_without_returning(db)
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! Invariants of rows written to the hot tables, checked with `--strict-writes`
+//!
+//! The entity definitions only describe column types, so rows which fit the
+//! schema but break the conventions readers rely on, like an empty primary key
+//! component or an unknown relationship, would be written silently otherwise.
+
+use super::dependency::{RelOp, RELATIONSHIPS};
+use super::entities::{package_changes, package_dependencies, package_versions, packages};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// A field of a row which breaks an invariant of its table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    pub table: &'static str,
+    pub field: &'static str,
+    pub value: String,
+    /// what the value should be like
+    pub rule: &'static str,
+}
+
+impl Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{} {:?} {}",
+            self.table, self.field, self.value, self.rule
+        )
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Rows checked before they are written
+pub trait Validate {
+    /// The first broken invariant of the row
+    fn validate(&self) -> Result<(), InvariantViolation>;
+}
+
+/// Checks of the fields of one table, stopping at the first violation
+struct Checker {
+    table: &'static str,
+}
+
+impl Checker {
+    fn check(
+        &self,
+        field: &'static str,
+        value: &str,
+        valid: bool,
+        rule: &'static str,
+    ) -> Result<(), InvariantViolation> {
+        if valid {
+            return Ok(());
+        }
+
+        Err(InvariantViolation {
+            table: self.table,
+            field,
+            value: value.to_string(),
+            rule,
+        })
+    }
+
+    fn non_empty(&self, field: &'static str, value: &str) -> Result<(), InvariantViolation> {
+        self.check(field, value, !value.trim().is_empty(), "must not be empty")
+    }
+
+    fn branch(&self, field: &'static str, value: &str) -> Result<(), InvariantViolation> {
+        self.check(
+            field,
+            value,
+            is_normalized_branch(value),
+            "must be a branch name without whitespace or a refs/ prefix",
+        )
+    }
+
+    fn version(&self, field: &'static str, value: &str) -> Result<(), InvariantViolation> {
+        self.check(
+            field,
+            value,
+            is_version(value),
+            "must start with a letter or digit followed by letters, digits, '.', '+', '~' or '_'",
+        )
+    }
+
+    fn number(&self, field: &'static str, value: &str) -> Result<(), InvariantViolation> {
+        self.check(
+            field,
+            value,
+            !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()),
+            "must be a number",
+        )
+    }
+
+    fn githash(&self, field: &'static str, value: &str) -> Result<(), InvariantViolation> {
+        self.check(
+            field,
+            value,
+            value.len() == 40
+                && value
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')),
+            "must be a full lowercase hex commit hash",
+        )
+    }
+}
+
+/// e.g. stable or origin/topic, but not refs/heads/stable
+fn is_normalized_branch(branch: &str) -> bool {
+    !branch.is_empty() && !branch.chars().any(char::is_whitespace) && !branch.starts_with("refs/")
+}
+
+/// PKGVER like 2.38, 1.0+git20240101 or 0~rc1
+fn is_version(version: &str) -> bool {
+    let mut chars = version.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '~' | '_'))
+}
+
+impl Validate for packages::Model {
+    fn validate(&self) -> Result<(), InvariantViolation> {
+        let checker = Checker { table: "packages" };
+        checker.non_empty("name", &self.name)?;
+        checker.non_empty("tree", &self.tree)?;
+        checker.non_empty("section", &self.section)?;
+        checker.non_empty("directory", &self.directory)?;
+        if let Some(source) = &self.section_source {
+            checker.check(
+                "section_source",
+                source,
+                matches!(source.as_str(), "directory" | "pkgsec"),
+                "must be directory or pkgsec",
+            )?;
+        }
+        checker.non_empty("spec_path", &self.spec_path)
+    }
+}
+
+impl Validate for package_versions::Model {
+    fn validate(&self) -> Result<(), InvariantViolation> {
+        let checker = Checker {
+            table: "package_versions",
+        };
+        checker.non_empty("package", &self.package)?;
+        checker.branch("branch", &self.branch)?;
+        checker.version("version", &self.version)?;
+        if let Some(release) = &self.release {
+            checker.number("release", release)?;
+        }
+        if let Some(epoch) = &self.epoch {
+            checker.number("epoch", epoch)?;
+        }
+        checker.githash("githash", &self.githash)?;
+        checker.non_empty("full_version", &self.full_version)
+    }
+}
+
+impl Validate for package_dependencies::Model {
+    fn validate(&self) -> Result<(), InvariantViolation> {
+        let checker = Checker {
+            table: "package_dependencies",
+        };
+        checker.non_empty("package", &self.package)?;
+        checker.non_empty("dependency", &self.dependency)?;
+        checker.check(
+            "relationship",
+            &self.relationship,
+            RELATIONSHIPS.contains(&self.relationship.as_str()),
+            "must be one of PKGDEP, BUILDDEP, PKGSUG, PKGPROV, PKGRECOM, PKGREP, PKGBREAK and PKGCONFIG",
+        )?;
+        checker.check(
+            "architecture",
+            &self.architecture,
+            self.architecture.trim() == self.architecture && self.architecture != "default",
+            "must be empty for all architectures or an architecture name",
+        )?;
+        match (&self.relop, &self.version) {
+            (Some(relop), Some(version)) => {
+                checker.check(
+                    "relop",
+                    relop,
+                    RelOp::from_str(relop).is_ok(),
+                    "must be one of >=, <=, >, < and =",
+                )?;
+                checker.non_empty("version", version)
+            }
+            (None, None) => Ok(()),
+            (Some(relop), None) => checker.check("relop", relop, false, "must come with a version"),
+            (None, Some(version)) => {
+                checker.check("version", version, false, "must come with a relop")
+            }
+        }
+    }
+}
+
+impl Validate for package_changes::Model {
+    fn validate(&self) -> Result<(), InvariantViolation> {
+        let checker = Checker {
+            table: "package_changes",
+        };
+        checker.non_empty("package", &self.package)?;
+        checker.githash("githash", &self.githash)?;
+        checker.non_empty("tree", &self.tree)?;
+        checker.branch("branch", &self.branch)
+    }
+}
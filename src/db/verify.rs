@@ -0,0 +1,179 @@
+//! Consistency check of the stored packages of a tree against a fresh parse of the tree
+//!
+//! Scans only look at packages changed by new commits, so rows left behind by
+//! interrupted runs, like packages deleted from the tree or stale spec keys,
+//! are never corrected by later scans.
+
+use super::abbs::StoredPackage;
+use super::get_full_version;
+use crate::config::ValueLimits;
+use crate::package::Meta;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Differences between the stored packages of a tree and the packages parsed at a commit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Verification {
+    /// commit the tree was parsed at
+    pub commit: String,
+    /// packages of the tree which are not stored
+    pub missing: Vec<String>,
+    /// stored packages which are no longer in the tree
+    pub extra: Vec<String>,
+    pub mismatched: Vec<Mismatch>,
+    /// packages which failed to parse, they are neither missing nor extra
+    pub failed: Vec<String>,
+}
+
+/// A stored value differing from the parsed one, none if there is no such value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub package: String,
+    /// e.g. version, description, spec PKGDEP or dependency
+    pub field: String,
+    pub stored: Option<String>,
+    pub scanned: Option<String>,
+}
+
+impl Verification {
+    /// Nothing is missing, extra or mismatched
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+
+    /// Packages to write again, missing ones and those with mismatched values
+    pub fn outdated(&self) -> BTreeSet<&str> {
+        self.missing
+            .iter()
+            .map(String::as_str)
+            .chain(self.mismatched.iter().map(|m| m.package.as_str()))
+            .collect()
+    }
+
+    /// Render as markdown for cron mails
+    pub fn to_markdown(&self) -> String {
+        let mut res = format!("# Verification at {}\n\n", self.commit);
+        if self.is_consistent() {
+            res += "No differences found.\n\n";
+        }
+
+        if !self.missing.is_empty() {
+            res += "## Missing packages\n\n";
+            for package in &self.missing {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        if !self.extra.is_empty() {
+            res += "## Extra packages\n\n";
+            for package in &self.extra {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        if !self.mismatched.is_empty() {
+            res += "## Mismatched values\n\n";
+            res += "| Package | Field | Stored | Scanned |\n|---|---|---|---|\n";
+            for m in &self.mismatched {
+                let _ = writeln!(
+                    res,
+                    "| {} | {} | {} | {} |",
+                    m.package,
+                    m.field,
+                    m.stored.as_deref().unwrap_or("-"),
+                    m.scanned.as_deref().unwrap_or("-")
+                );
+            }
+            res += "\n";
+        }
+
+        if !self.failed.is_empty() {
+            res += "## Failed to parse\n\n";
+            for package in &self.failed {
+                let _ = writeln!(res, "- {package}");
+            }
+            res += "\n";
+        }
+
+        res
+    }
+}
+
+/// The value as a scan writes it, see the byte limits of [ValueLimits]
+///
+/// Values with NUL bytes are not written.
+fn saved_value(value: &str, limit: usize) -> Option<&str> {
+    if value.contains('\0') {
+        return None;
+    }
+    let mut end = value.len().min(limit);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(&value[..end])
+}
+
+/// Differences of the stored rows from the parsed package
+///
+/// `dependencies` are the parsed dependencies formatted like the stored ones.
+pub(crate) fn compare_package(
+    stored: &StoredPackage,
+    meta: &Meta,
+    dependencies: &BTreeSet<String>,
+    limits: &ValueLimits,
+) -> Vec<Mismatch> {
+    let (pkg, context, _, _) = meta;
+    let mut res = vec![];
+    let mut check = |field: String, stored: Option<&str>, scanned: Option<&str>| {
+        if stored != scanned {
+            res.push(Mismatch {
+                package: pkg.name.clone(),
+                field,
+                stored: stored.map(str::to_string),
+                scanned: scanned.map(str::to_string),
+            });
+        }
+    };
+
+    check(
+        "spec_path".to_string(),
+        Some(&stored.package.spec_path),
+        Some(&pkg.spec_path),
+    );
+    let description = pkg.description.split_whitespace().join(" ");
+    check(
+        "description".to_string(),
+        Some(&stored.package.description),
+        Some(saved_value(&description, limits.description).unwrap_or_default()),
+    );
+    check(
+        "version".to_string(),
+        stored.version.as_deref(),
+        Some(&get_full_version(pkg)),
+    );
+
+    let keys: BTreeSet<_> = stored.spec.keys().chain(context.keys()).collect();
+    for key in keys {
+        let scanned = context
+            .get(key)
+            .and_then(|value| saved_value(value, limits.spec_value));
+        check(
+            format!("spec {key}"),
+            stored.spec.get(key).map(String::as_str),
+            scanned,
+        );
+    }
+
+    for dependency in stored.dependencies.difference(dependencies) {
+        check("dependency".to_string(), Some(dependency), None);
+    }
+    for dependency in dependencies.difference(&stored.dependencies) {
+        check("dependency".to_string(), None, Some(dependency));
+    }
+
+    res
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Warn when free space is less than SOFT_FACTOR times the required space
+const SOFT_FACTOR: u64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskCheck {
+    Ok,
+    /// enough space, but close to running out
+    Low,
+    /// not enough space, scanning must not start
+    Insufficient,
+}
+
+/// Compare free space against the estimated usage plus the reserve
+pub fn check_threshold(available: u64, estimated: u64, reserve: u64) -> DiskCheck {
+    let required = estimated.saturating_add(reserve);
+    if available < required {
+        DiskCheck::Insufficient
+    } else if available < required.saturating_mul(SOFT_FACTOR) {
+        DiskCheck::Low
+    } else {
+        DiskCheck::Ok
+    }
+}
+
+/// Check free space of filesystems hosting the given paths
+pub fn preflight<P: AsRef<Path>>(paths: &[P], estimated: u64, reserve: u64) -> Result<()> {
+    preflight_with(paths, estimated, reserve, |path| {
+        Ok(fs2::available_space(path)?)
+    })
+}
+
+/// Same as [preflight], with free space provided by `available_space`
+pub fn preflight_with<P, F>(
+    paths: &[P],
+    estimated: u64,
+    reserve: u64,
+    available_space: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: Fn(&Path) -> Result<u64>,
+{
+    for path in paths {
+        let path = path.as_ref();
+        let available = available_space(path)?;
+        let (available_mib, required_mib) =
+            (to_mib(available), to_mib(estimated.saturating_add(reserve)));
+
+        match check_threshold(available, estimated, reserve) {
+            DiskCheck::Ok => info!(
+                "{}: {available_mib} MiB available, {required_mib} MiB required",
+                path.display()
+            ),
+            DiskCheck::Low => warn!(
+                "{}: low disk space, {available_mib} MiB available, {required_mib} MiB required",
+                path.display()
+            ),
+            DiskCheck::Insufficient => bail!(
+                "{}: not enough disk space, {available_mib} MiB available, {required_mib} MiB required",
+                path.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn to_mib(bytes: u64) -> u64 {
+    bytes / 1024 / 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Fake statvfs reporting `available[i]` for the i-th path of `paths`
+    fn fake<'a>(
+        paths: &'a [PathBuf],
+        available: [u64; 2],
+        checked: &'a RefCell<Vec<PathBuf>>,
+    ) -> impl Fn(&Path) -> Result<u64> + 'a {
+        move |path| {
+            checked.borrow_mut().push(path.to_path_buf());
+            let i = paths.iter().position(|p| p == path).unwrap();
+            Ok(available[i])
+        }
+    }
+
+    #[test]
+    fn test_check_threshold() {
+        assert_eq!(
+            check_threshold(400 * MIB, 100 * MIB, 100 * MIB),
+            DiskCheck::Ok
+        );
+        assert_eq!(
+            check_threshold(399 * MIB, 100 * MIB, 100 * MIB),
+            DiskCheck::Low
+        );
+        assert_eq!(
+            check_threshold(200 * MIB, 100 * MIB, 100 * MIB),
+            DiskCheck::Low
+        );
+        assert_eq!(
+            check_threshold(199 * MIB, 100 * MIB, 100 * MIB),
+            DiskCheck::Insufficient
+        );
+        // the required space saturates instead of overflowing
+        assert_eq!(check_threshold(u64::MAX, u64::MAX, 1), DiskCheck::Ok);
+        assert_eq!(
+            check_threshold(u64::MAX - 1, u64::MAX, 1),
+            DiskCheck::Insufficient
+        );
+    }
+
+    #[test]
+    fn test_preflight_with() {
+        let paths = [PathBuf::from("/var/lib/abbs"), PathBuf::from("/srv/abbs")];
+        let checked = RefCell::new(vec![]);
+
+        let ok = fake(&paths, [400 * MIB; 2], &checked);
+        assert!(preflight_with(&paths, 100 * MIB, 100 * MIB, ok).is_ok());
+        assert_eq!(*checked.borrow(), paths);
+        // low space is only warned about
+        let low = fake(&paths, [300 * MIB; 2], &checked);
+        assert!(preflight_with(&paths, 100 * MIB, 100 * MIB, low).is_ok());
+
+        let second = fake(&paths, [400 * MIB, 150 * MIB], &checked);
+        let e = preflight_with(&paths, 100 * MIB, 100 * MIB, second).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "/srv/abbs: not enough disk space, 150 MiB available, 200 MiB required"
+        );
+
+        let e = preflight_with(&paths, 0, 0, |path: &Path| {
+            if path == paths[1] {
+                bail!("statvfs failed");
+            }
+            Ok(0)
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "statvfs failed");
+
+        let empty = fake(&paths, [0; 2], &checked);
+        let e = preflight_with(&paths[..1], u64::MAX, u64::MAX, empty).unwrap_err();
+        assert!(
+            e.to_string()
+                .ends_with(&format!("{} MiB required", u64::MAX / MIB)),
+            "{e}"
+        );
+    }
+}
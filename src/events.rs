@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+/// Progress of a scan, for embedders driving their own interface
+///
+/// Register a handler with [crate::progress::Progress::with_event_handler].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanEvent {
+    RepoStarted {
+        repo: String,
+        branch: String,
+    },
+    /// sent every [COMMITS_EVERY] commits and after the last one
+    CommitsScanned {
+        done: usize,
+        total: usize,
+    },
+    /// new commits of a branch are saved
+    BranchScanned {
+        branch: String,
+        /// changed packages found in the new commits
+        packages: usize,
+    },
+    /// the `done`th of `total` packages to write in this scan is written
+    PackageWritten {
+        package: String,
+        done: usize,
+        total: usize,
+    },
+    ErrorsRecorded {
+        package: String,
+        count: usize,
+    },
+    RepoFinished {
+        repo: String,
+        updated: usize,
+        deleted: usize,
+        errors: usize,
+    },
+}
+
+/// Number of scanned commits between [ScanEvent::CommitsScanned] events
+pub const COMMITS_EVERY: usize = 1000;
+
+/// Number of events waiting for the handler before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+enum Message {
+    Event(ScanEvent),
+    /// answered once the events queued before are handled
+    Flush(SyncSender<()>),
+}
+
+/// Queue of events to a handler running on its own thread
+///
+/// Sending never blocks, events are dropped while the queue is full, so a
+/// slow handler can't stall scanning threads.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSender {
+    /// Start a thread calling the handler, it exits when every sender is dropped
+    pub fn spawn(handler: impl Fn(ScanEvent) + Send + Sync + 'static) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Event(event) => handler(event),
+                    Message::Flush(done) => {
+                        done.send(()).ok();
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn send(&self, event: ScanEvent) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Message::Event(event)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until the events sent before are handled
+    ///
+    /// Returns the number of events dropped since the last flush because the
+    /// handler fell behind.
+    pub fn flush(&self) -> u64 {
+        let (done, handled) = sync_channel(1);
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            handled.recv().ok();
+        }
+
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_flush_counts_dropped_events() {
+        let (started, wait_started) = channel();
+        let (release, wait_release) = channel::<()>();
+        let wait_release = Mutex::new(wait_release);
+        let handled = Arc::new(AtomicU64::new(0));
+        let counter = handled.clone();
+        let sender = EventSender::spawn(move |_| {
+            if counter.fetch_add(1, Ordering::Relaxed) == 0 {
+                started.send(()).unwrap();
+                wait_release.lock().unwrap().recv().unwrap();
+            }
+        });
+        let event = || ScanEvent::CommitsScanned { done: 1, total: 1 };
+
+        // the handler is stuck on the first event while the queue fills up
+        sender.send(event());
+        wait_started.recv().unwrap();
+        for _ in 0..QUEUE_SIZE + 5 {
+            sender.send(event());
+        }
+        release.send(()).unwrap();
+
+        assert_eq!(sender.flush(), 5);
+        assert_eq!(handled.load(Ordering::Relaxed), QUEUE_SIZE as u64 + 1);
+        assert_eq!(sender.flush(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Atom feeds of recent changes and new errors, for deployments serving static files
+//!
+//! Entry ids only depend on the package and commit, so readers don't see
+//! duplicates when a feed is written again by the next scan.
+
+use crate::config::{Feeds, Repo};
+use crate::db::abbs::{AbbsDb, ChangelogEntry, ErrorEvent};
+use crate::db::digest;
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Updated time of feeds without entries, so an empty feed doesn't change between runs
+const EMPTY_FEED_UPDATED: &str = "1970-01-01T00:00:00+00:00";
+
+/// An Atom feed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    pub id: String,
+    pub title: String,
+    /// url of the repository
+    pub link: String,
+    pub entries: Vec<Entry>,
+}
+
+/// An entry of an Atom feed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub id: String,
+    pub title: String,
+    /// RFC 3339 time
+    pub updated: String,
+    pub content: String,
+}
+
+impl Feed {
+    /// Render the feed as an Atom document, updated at its newest entry
+    pub fn to_atom(&self) -> String {
+        let updated = self
+            .entries
+            .iter()
+            .map(|entry| entry.updated.as_str())
+            .max()
+            .unwrap_or(EMPTY_FEED_UPDATED);
+
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        res.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        res.push_str(&format!("  <id>{}</id>\n", escape(&self.id)));
+        res.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        res.push_str(&format!("  <updated>{}</updated>\n", escape(updated)));
+        res.push_str(&format!("  <link href=\"{}\"/>\n", escape(&self.link)));
+        res.push_str("  <author><name>abbs-meta</name></author>\n");
+        for entry in &self.entries {
+            res.push_str("  <entry>\n");
+            res.push_str(&format!("    <id>{}</id>\n", escape(&entry.id)));
+            res.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+            res.push_str(&format!(
+                "    <updated>{}</updated>\n",
+                escape(&entry.updated)
+            ));
+            res.push_str(&format!(
+                "    <content type=\"text\">{}</content>\n",
+                escape(&entry.content)
+            ));
+            res.push_str("  </entry>\n");
+        }
+        res.push_str("</feed>\n");
+
+        res
+    }
+}
+
+/// Escape text for XML content and attributes, dropping characters XML can't contain
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            '\t' | '\n' | '\r' => res.push(c),
+            c if c < ' ' => {}
+            c => res.push(c),
+        }
+    }
+
+    res
+}
+
+/// Id of the change of the package in the commit
+fn change_id(tree: &str, package: &str, githash: &str) -> String {
+    format!("urn:abbs-meta:{tree}:change:{package}:{githash}")
+}
+
+/// One entry for each package of each change
+fn change_entries(tree: &str, changelog: &[ChangelogEntry]) -> Vec<(String, Entry)> {
+    changelog
+        .iter()
+        .flat_map(|change| {
+            change.packages.iter().map(move |(package, version)| {
+                let entry = Entry {
+                    id: change_id(tree, package, &change.githash),
+                    title: format!("{package} {version}"),
+                    updated: change.timestamp.clone(),
+                    content: format!("{}\n\ncommit {}", change.summary, change.githash),
+                };
+                (package.clone(), entry)
+            })
+        })
+        .collect()
+}
+
+/// One entry for each introduced error
+fn error_entries(tree: &str, errors: &[(String, ErrorEvent)]) -> Vec<Entry> {
+    errors
+        .iter()
+        .map(|(package, error)| {
+            let githash = error.githash.as_deref().unwrap_or("unknown");
+            Entry {
+                id: format!(
+                    "urn:abbs-meta:{tree}:error:{package}:{githash}:{}",
+                    digest(&error.message)
+                ),
+                title: format!("{package}: {} error", error.err_type),
+                updated: error.recorded_at.clone(),
+                content: error.message.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Write the feed to the path, through a temporary file so readers never see a partial feed
+fn write_atomically(path: &Path, feed: &Feed) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let tmp = path.with_extension("atom.tmp");
+    fs::write(&tmp, feed.to_atom())
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to rename {}", tmp.display()))?;
+
+    Ok(())
+}
+
+/// Write the configured feeds of the repository, returns the written files
+pub async fn write_feeds(config: &Feeds, repo: &Repo, abbs_db: &AbbsDb) -> Result<Vec<PathBuf>> {
+    let tree = abbs_db.tree().to_string();
+    let dir = Path::new(&config.output_dir).join(&tree);
+    let feed = |id: &str, title: String, entries: Vec<Entry>| Feed {
+        id: format!("urn:abbs-meta:{tree}:{id}"),
+        title,
+        link: repo.url.clone(),
+        entries,
+    };
+    let mut written = vec![];
+
+    if config.updates || config.sections {
+        let changelog = abbs_db.get_changelog(config.entries, true).await?;
+        let entries = change_entries(&tree, &changelog);
+
+        if config.sections {
+            let sections = abbs_db.get_package_sections().await?;
+            let by_section: BTreeMap<&str, Vec<Entry>> = entries
+                .iter()
+                .filter_map(|(package, entry)| {
+                    Some((sections.get(package)?.as_str(), entry.clone()))
+                })
+                .into_group_map()
+                .into_iter()
+                .collect();
+            for (section, entries) in by_section {
+                let path = dir.join("sections").join(format!("{section}.atom"));
+                let title = format!("{tree}: updates of {section}");
+                write_atomically(&path, &feed(&format!("section:{section}"), title, entries))?;
+                written.push(path);
+            }
+        }
+
+        if config.updates {
+            let path = dir.join("updates.atom");
+            let entries = entries.into_iter().map(|(_, entry)| entry).collect();
+            write_atomically(&path, &feed("updates", format!("{tree}: updates"), entries))?;
+            written.push(path);
+        }
+    }
+
+    if config.new_errors {
+        let errors = abbs_db.get_new_errors(config.entries).await?;
+        let path = dir.join("new-errors.atom");
+        let title = format!("{tree}: new errors");
+        write_atomically(
+            &path,
+            &feed("new-errors", title, error_entries(&tree, &errors)),
+        )?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
@@ -1,10 +1,14 @@
 use super::{Repository, SyncRepository};
+use crate::events::{ScanEvent, COMMITS_EVERY};
+use crate::progress::Progress;
 use anyhow::Result;
 use git2::{Delta, Oid, Time};
 use indicatif::ParallelProgressIterator;
 use itertools::Itertools;
 use rayon::prelude::*;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use thread_local::ThreadLocal;
 use tracing::{info, warn};
 
@@ -52,7 +56,24 @@ impl ToString for FileStatus {
 
 impl Repository {
     // from old commit to new commit
+    //
+    // If `from` is not an ancestor of `to`, like after a force-push, the walk
+    // stops at their merge base instead of walking the whole history.
     pub fn get_commits_by_range(&self, from: Option<Oid>, to: Oid) -> Result<Vec<Oid>> {
+        let from = match from {
+            Some(from)
+                if from != to && !self.repo.graph_descendant_of(to, from).unwrap_or(false) =>
+            {
+                let base = self.repo.merge_base(from, to).ok();
+                match base {
+                    Some(base) => warn!("{from} is not an ancestor of {to}, walking from {base}"),
+                    None => warn!("{from} is not an ancestor of {to}, walking all commits"),
+                }
+                base
+            }
+            from => from,
+        };
+
         let mut revwalk = self.repo.revwalk()?;
         revwalk.push(to)?;
 
@@ -68,49 +89,68 @@ impl Repository {
         Ok(oids)
     }
 
+    /// Commits reachable from `from` but not from `to`, like those dropped by a force-push
+    pub fn get_dropped_commits(&self, from: Oid, to: Oid) -> Result<Vec<Oid>> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push(from)?;
+        revwalk.hide(to)?;
+
+        Ok(revwalk.collect::<Result<_, _>>()?)
+    }
+
     /// Scan changed files in the specified commits
-    pub fn scan_commits(&self, oids: Vec<Oid>) -> Result<Vec<(Oid, Time, PathBuf, FileStatus)>> {
+    ///
+    /// Merge commits are compared with each of their parents, see [merge_statuses].
+    pub fn scan_commits(
+        &self,
+        oids: Vec<Oid>,
+        progress: &Progress,
+    ) -> Result<Vec<(Oid, Time, PathBuf, FileStatus)>> {
         info!("scanning commit info");
         let sync_repo: &SyncRepository = &self.into();
         let repo: ThreadLocal<Repository> = ThreadLocal::new();
+        let bar = progress.bar(oids.len() as u64, "scan commits");
+        let total = oids.len();
+        let scanned = AtomicUsize::new(0);
         let result = oids
             .into_par_iter()
-            .progress()
+            .progress_with(bar.clone())
             .filter_map(|oid| {
+                let done = scanned.fetch_add(1, Ordering::Relaxed) + 1;
+                if done.is_multiple_of(COMMITS_EVERY) || done == total {
+                    progress.emit(|| ScanEvent::CommitsScanned { done, total });
+                }
                 let repo = repo.get_or(|| sync_repo.try_into().unwrap());
                 let commit = repo.find_commit(oid).ok()?;
 
-                let parents: Vec<_> = commit.parents().collect();
-
-                // locate parent commit and compare
-                let parent_tree = match parents.len() {
-                    0 => None,
-                    1 | 2 => Some(parents[0].tree().ok()?),
-                    n => {
-                        warn!("{n} parents in commit {commit:?}");
-                        return None;
-                    }
-                };
-                let parent_tree = parent_tree.as_ref();
-                let diff = repo
-                    .get_git2repo()
-                    .diff_tree_to_tree(parent_tree, Some(&commit.tree().ok()?), None)
+                let tree = commit.tree().ok()?;
+                let parent_trees = commit
+                    .parents()
+                    .map(|parent| parent.tree())
+                    .collect::<Result<Vec<_>, _>>()
                     .ok()?;
 
+                // compare with each parent, or the empty tree for a root commit
+                let mut diffs = vec![];
+                for parent_tree in parent_trees.iter().map(Some).pad_using(1, |_| None) {
+                    let diff = repo
+                        .get_git2repo()
+                        .diff_tree_to_tree(parent_tree, Some(&tree), None)
+                        .ok()?;
+                    let statuses = diff
+                        .deltas()
+                        .filter_map(|delta| {
+                            let path = delta.new_file().path()?;
+                            Some((path.to_path_buf(), FileStatus::from(delta.status())))
+                        })
+                        .collect_vec();
+                    diffs.push(statuses);
+                }
+
                 // save info for each changed file
-                let changes = diff
-                    .deltas()
+                let changes = merge_statuses(diffs)
                     .into_iter()
-                    .filter_map(|delta| {
-                        let new_file = delta.new_file();
-                        let path = new_file.path()?;
-                        Some((
-                            commit.id(),
-                            commit.time(),
-                            path.to_path_buf(),
-                            delta.status().into(),
-                        ))
-                    })
+                    .map(|(path, status)| (commit.id(), commit.time(), path, status))
                     .collect_vec();
                 Some(changes)
             })
@@ -120,3 +160,27 @@ impl Repository {
         Ok(result)
     }
 }
+
+/// Changed files of a commit from its diffs against each parent, first parent first
+///
+/// Paths differing from the first parent keep their status. Paths which only
+/// differ from other parents, e.g. changed by the merged branch, are deleted
+/// if the commit doesn't have them, and modified otherwise, as the first
+/// parent has them too. Each path is listed once.
+fn merge_statuses(diffs: Vec<Vec<(PathBuf, FileStatus)>>) -> Vec<(PathBuf, FileStatus)> {
+    let mut diffs = diffs.into_iter();
+    let mut merged = diffs.next().unwrap_or_default();
+    let mut seen: HashSet<PathBuf> = merged.iter().map(|(path, _)| path.clone()).collect();
+    for (path, status) in diffs.flatten() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let status = match status {
+            FileStatus::Deleted | FileStatus::Unsupported => status,
+            FileStatus::Added | FileStatus::Modified => FileStatus::Modified,
+        };
+        merged.push((path, status));
+    }
+
+    merged
+}
@@ -1,20 +1,77 @@
-use crate::config::Repo;
+use crate::config::{Repo, TreeId};
 use anyhow::{Context, Result};
 use git2::{Blob, Commit, Error, Oid, Repository as Git2Repository, TreeWalkResult};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 pub mod commit;
 
 pub struct Repository {
     repo_path: PathBuf,
     repo: git2::Repository,
+    /// configured branch, the name rows of the main branch are saved under
     pub branch: String,
-    pub tree: String,
+    /// where the commits of [Self::branch] come from
+    pub head: BranchRef,
+    pub tree: TreeId,
+}
+
+/// Reference the configured branch was found as
+///
+/// Mirrors and CI checkouts often have no local branch, only remote-tracking
+/// branches or a detached commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BranchRef {
+    /// refs/heads/<branch>
+    Local,
+    /// a remote-tracking branch, e.g. origin/stable
+    Remote(String),
+    /// a commit, from a hash, a tag or any other reference
+    Detached(Oid),
+}
+
+impl BranchRef {
+    /// Find the branch as a local branch, a remote-tracking branch of origin
+    /// or another remote, or else any reference or commit hash
+    fn resolve(repo: &Git2Repository, branch: &str) -> std::result::Result<Self, git2::Error> {
+        if repo.find_branch(branch, git2::BranchType::Local).is_ok() {
+            return Ok(BranchRef::Local);
+        }
+        if repo.find_branch(branch, git2::BranchType::Remote).is_ok() {
+            return Ok(BranchRef::Remote(branch.to_string()));
+        }
+
+        let remotes = repo.remotes()?;
+        let mut remotes = remotes.iter().flatten().collect::<Vec<_>>();
+        remotes.sort_by_key(|remote| *remote != "origin");
+        if let Some(name) = remotes
+            .into_iter()
+            .map(|remote| format!("{remote}/{branch}"))
+            .find(|name| repo.find_branch(name, git2::BranchType::Remote).is_ok())
+        {
+            return Ok(BranchRef::Remote(name));
+        }
+
+        let commit = repo.revparse_single(branch)?.peel_to_commit()?;
+        Ok(BranchRef::Detached(commit.id()))
+    }
+}
+
+impl Display for BranchRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchRef::Local => write!(f, "local branch"),
+            BranchRef::Remote(name) => write!(f, "remote-tracking branch {name}"),
+            BranchRef::Detached(oid) => write!(f, "detached commit {oid}"),
+        }
+    }
 }
 
 pub struct SyncRepository {
     pub repo_path: PathBuf,
     pub branch: String,
-    pub tree: String,
+    pub tree: TreeId,
 }
 
 impl From<&Repository> for SyncRepository {
@@ -31,29 +88,68 @@ impl TryFrom<&SyncRepository> for Repository {
     type Error = git2::Error;
 
     fn try_from(repo: &SyncRepository) -> Result<Self, Self::Error> {
-        Self::open_inner(&repo.repo_path, &repo.tree, &repo.branch)
+        Self::open_inner(&repo.repo_path, repo.tree.clone(), &repo.branch)
     }
 }
 
 impl Repository {
     pub fn open(repo_config: &Repo) -> std::result::Result<Repository, git2::Error> {
-        let Repo { branch, name, .. } = &repo_config;
+        let branch = &repo_config.branch;
         let abbs_path = PathBuf::from(&repo_config.repo_path);
-        Self::open_inner(&abbs_path, name, branch)
+        if repo_config.sync_branch {
+            let repo = Git2Repository::open(&abbs_path)?;
+            sync_local_branch(&repo, branch, repo_config.force_branch_sync)?;
+        }
+        Self::open_inner(&abbs_path, repo_config.tree_id(), branch)
+    }
+
+    /// Open without syncing the local branch even if sync_branch is set, for
+    /// reading references without moving them
+    pub fn open_unsynced(repo_config: &Repo) -> std::result::Result<Repository, git2::Error> {
+        Self::open_inner(
+            Path::new(&repo_config.repo_path),
+            repo_config.tree_id(),
+            &repo_config.branch,
+        )
+    }
+
+    /// The configured branch is checked out but has no commits yet, like in a
+    /// freshly created tree, and no remote-tracking branch can provide them
+    pub fn is_unborn(repo_config: &Repo) -> std::result::Result<bool, git2::Error> {
+        let repo = Git2Repository::open(&repo_config.repo_path)?;
+        let branch = &repo_config.branch;
+        let target = format!("refs/heads/{branch}");
+        if repo.find_reference("HEAD")?.symbolic_target() != Some(target.as_str())
+            || repo.find_reference(&target).is_ok()
+        {
+            return Ok(false);
+        }
+
+        let remotes = repo.remotes()?;
+        let tracked = remotes.iter().flatten().any(|remote| {
+            repo.find_branch(&format!("{remote}/{branch}"), git2::BranchType::Remote)
+                .is_ok()
+        });
+
+        Ok(!(repo_config.sync_branch && tracked))
     }
 
     fn open_inner(
         abbs_path: &Path,
-        tree: &str,
+        tree: TreeId,
         branch: &str,
     ) -> std::result::Result<Repository, git2::Error> {
         let repo = Git2Repository::open(abbs_path)?;
-        repo.find_branch(branch, git2::BranchType::Local)?;
+        let head = BranchRef::resolve(&repo, branch)?;
+        if head != BranchRef::Local {
+            info!("no local branch {branch}, using the {head}");
+        }
         Ok(Repository {
-            tree: tree.into(),
+            tree,
             repo_path: PathBuf::from(abbs_path),
             repo,
             branch: branch.into(),
+            head,
         })
     }
 
@@ -61,11 +157,20 @@ impl Repository {
         &self.branch
     }
 
+    /// Commit of a branch, the configured branch resolves to the reference it was found as
     pub fn get_branch_oid(&self, branch_name: &str) -> Result<Oid> {
+        if branch_name == self.branch {
+            match &self.head {
+                BranchRef::Local => (),
+                BranchRef::Remote(name) => return self.get_branch_oid(name),
+                BranchRef::Detached(oid) => return Ok(*oid),
+            }
+        }
         let branch = self
             .repo
             .find_branch(branch_name, git2::BranchType::Local)
-            .or_else(|_| self.repo.find_branch(branch_name, git2::BranchType::Remote))?;
+            .or_else(|_| self.repo.find_branch(branch_name, git2::BranchType::Remote))
+            .with_context(|| format!("branch {branch_name} not found or has no commits yet"))?;
         let branch = branch
             .into_reference()
             .target()
@@ -85,6 +190,11 @@ impl Repository {
     pub fn get_git2repo(&self) -> &Git2Repository {
         &self.repo
     }
+
+    /// The repository is a shallow clone, commits past its boundary are missing
+    pub fn is_shallow(&self) -> bool {
+        self.repo.is_shallow()
+    }
     pub fn walk_commit(&self, commit: Oid) -> Result<Vec<PathBuf>> {
         let commit = self.repo.find_commit(commit)?;
         let tree = commit.tree()?;
@@ -104,6 +214,115 @@ impl Repository {
         Ok(dirs)
     }
 
+    /// Commit of each direct reference, e.g. refs/heads/stable
+    pub fn ref_targets(&self) -> Result<BTreeMap<String, Oid>> {
+        let mut result = BTreeMap::new();
+        for reference in self.repo.references()? {
+            let reference = reference?;
+            if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+                result.insert(name.to_string(), target);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Size of the git object database in bytes
+    pub fn object_db_size(&self) -> Result<u64> {
+        dir_size(&self.repo.path().join("objects"))
+    }
+
+    /// Whether the path exists in the tree of the commit
+    pub fn path_exists(&self, path: impl AsRef<Path>, commit: Oid) -> Result<bool> {
+        let tree = self.repo.find_commit(commit)?.tree()?;
+        match tree.get_path(path.as_ref()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Newest commit reachable from `from` which changed the path, like `git log -1 -- <path>`
+    ///
+    /// A merge commit counts only if the path differs from every parent.
+    pub fn last_commit_touching(&self, path: impl AsRef<Path>, from: Oid) -> Result<Option<Oid>> {
+        let path = path.as_ref();
+        let entry_id = |commit: &Commit| -> Result<Option<Oid>> {
+            match commit.tree()?.get_path(path) {
+                Ok(entry) => Ok(Some(entry.id())),
+                Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        };
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        revwalk.push(from)?;
+        for oid in revwalk {
+            let commit = self.repo.find_commit(oid?)?;
+            let current = entry_id(&commit)?;
+            if current.is_none() {
+                continue;
+            }
+            let mut touched = true;
+            for parent in commit.parents() {
+                if entry_id(&parent)? == current {
+                    touched = false;
+                    break;
+                }
+            }
+            if touched {
+                return Ok(Some(commit.id()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Commits reachable from `from` changing the path, newest first, at most `max` of them
+    ///
+    /// Like `git log -- path`, merges are only included if the path differs
+    /// from every parent.
+    pub fn commits_touching(
+        &self,
+        path: impl AsRef<Path>,
+        from: Oid,
+        max: usize,
+    ) -> Result<Vec<Oid>> {
+        let path = path.as_ref();
+        let entry_id = |commit: &Commit| -> Result<Option<Oid>> {
+            match commit.tree()?.get_path(path) {
+                Ok(entry) => Ok(Some(entry.id())),
+                Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        };
+
+        let mut res = vec![];
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        revwalk.push(from)?;
+        for oid in revwalk {
+            if res.len() >= max {
+                break;
+            }
+            let commit = self.repo.find_commit(oid?)?;
+            let current = entry_id(&commit)?;
+            let mut touched = commit.parent_count() > 0 || current.is_some();
+            for parent in commit.parents() {
+                if entry_id(&parent)? == current {
+                    touched = false;
+                    break;
+                }
+            }
+            if touched {
+                res.push(commit.id());
+            }
+        }
+
+        Ok(res)
+    }
+
     #[inline(always)]
     pub fn read_file(&self, path: impl AsRef<Path>, commit: Oid) -> Result<String> {
         let commit = self.repo.find_commit(commit)?;
@@ -116,3 +335,83 @@ impl Repository {
         )?)
     }
 }
+
+/// Make the local branch point at its remote-tracking branch
+///
+/// The local branch is created if missing, and fast-forwarded if behind. A
+/// diverged local branch is only moved with `force`.
+fn sync_local_branch(
+    repo: &Git2Repository,
+    branch: &str,
+    force: bool,
+) -> std::result::Result<(), git2::Error> {
+    // prefer origin, then the first remote carrying the branch
+    let remotes = repo.remotes()?;
+    let mut remotes = remotes.iter().flatten().collect::<Vec<_>>();
+    remotes.sort_by_key(|remote| *remote != "origin");
+    let Some(upstream) = remotes.into_iter().find_map(|remote| {
+        repo.find_branch(&format!("{remote}/{branch}"), git2::BranchType::Remote)
+            .ok()
+    }) else {
+        warn!("no remote-tracking branch of {branch} found");
+        return Ok(());
+    };
+    let upstream_name = upstream.name()?.unwrap_or_default().to_string();
+    let Some(target) = upstream.get().target() else {
+        return Ok(());
+    };
+
+    let mut local = match repo.find_branch(branch, git2::BranchType::Local) {
+        Ok(local) => local,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            let mut local = repo.branch(branch, &repo.find_commit(target)?, false)?;
+            local.set_upstream(Some(&upstream_name))?;
+            info!("created local branch {branch} from {upstream_name}");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let Some(current) = local.get().target() else {
+        return Ok(());
+    };
+    if current == target || repo.graph_descendant_of(current, target)? {
+        return Ok(());
+    }
+    let fast_forward = repo.graph_descendant_of(target, current)?;
+    if !fast_forward && !force {
+        warn!(
+            "local branch {branch} diverged from {upstream_name}, set force_branch_sync to move it"
+        );
+        return Ok(());
+    }
+    if local.is_head() && !repo.is_bare() {
+        warn!("local branch {branch} is checked out, not moving it to {upstream_name}");
+        return Ok(());
+    }
+
+    let message = format!("abbs-meta: sync {branch} with {upstream_name}");
+    local.get_mut().set_target(target, &message)?;
+    if fast_forward {
+        info!("fast-forwarded local branch {branch} to {upstream_name}");
+    } else {
+        warn!("moved diverged local branch {branch} to {upstream_name}");
+    }
+
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
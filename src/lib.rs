@@ -1,7 +1,15 @@
 pub mod config;
 pub mod db;
+pub mod disk;
+pub mod events;
+pub mod feed;
 pub mod git;
 pub mod package;
+pub mod progress;
+pub mod report;
+#[cfg(feature = "test-util")]
+pub mod test_support;
+pub mod warnings;
 
 macro_rules! skip_error {
     ($res:expr) => {
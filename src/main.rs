@@ -1,55 +1,1561 @@
 use abbs_meta::{
-    config::{Config, Global, Repo},
-    db::{abbs::AbbsDb, commits::CommitDb},
+    config::{Config, CorruptionPolicy, Global, Repo},
+    db::{
+        abbs::{
+            refresh_materialized_views, AbbsDb, ErrorType, NameClass, PackageFilter, PendingPackage,
+        },
+        commits::{Change, CommitDb, UpdatedPackages},
+        diff::diff_databases,
+        entities::commits,
+        get_full_version,
+        hash::malformed_hashes,
+        is_corrupted,
+        page::PageRequest,
+        query::query,
+        reindex,
+        snapshot::SnapshotStore,
+    },
+    disk,
+    events::ScanEvent,
+    feed::write_feeds,
     git::Repository,
+    package::{scan_tree, PackageDump},
+    progress::{LogWriter, Progress},
+    report::{ExitStatus, FailOn, LatencySummary, PackageTiming, ScanReport, StorageSizes},
+    warnings::Warnings,
 };
-use anyhow::Result;
-use clap::Parser;
+use abbs_meta_tree::Package;
+use anyhow::{bail, Context, Result};
+use async_std::task;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use indicatif::{MultiProgress, ProgressDrawTarget};
 use itertools::Itertools;
-use std::collections::HashSet;
-use tracing::info;
+use rayon::ThreadPoolBuilder;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    after_help = "Exit status of scanning:
+  0  all repositories are scanned
+  2  all repositories are scanned, but package errors are recorded (see --fail-on)
+  3  some repositories failed
+  4  fatal error, nothing could be scanned"
+)]
 struct Opt {
     /// specify configuration file
     #[arg(short, long, default_value = "config.toml")]
     config: String,
+    #[command(flatten)]
+    scan: ScanOptions,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Args, Debug, Default)]
+struct ScanOptions {
+    /// don't insert packages whose name violates naming policy
+    #[arg(long)]
+    reject_invalid_names: bool,
+    /// which package errors make scanning exit with status 2
+    #[arg(long, value_enum, default_value_t)]
+    fail_on: FailOn,
+    /// check for orphan rows after deleting packages, always done in debug builds
+    #[arg(long)]
+    check_integrity: bool,
+    /// check rows against the invariants of their tables before writing them,
+    /// packages breaking one are recorded as errors, always done in debug builds
+    #[arg(long)]
+    strict_writes: bool,
+    /// print packages which would be written without writing either database,
+    /// exits with status 2 if any package error would be recorded
+    #[arg(long)]
+    dry_run: bool,
+    /// write the reports of the scanned repositories to this file as a JSON array
+    #[arg(long)]
+    report: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// scan repositories and update database (default)
+    Scan,
+    /// list packages in database
+    List {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// show the number of testing branches overriding each package
+        #[arg(long)]
+        testing: bool,
+        /// only list packages in the section, can be repeated
+        #[arg(long)]
+        section: Vec<String>,
+        /// only list packages in the category, can be repeated
+        #[arg(long)]
+        category: Vec<String>,
+        /// tree to list, defaults to the one of the repository
+        #[arg(long)]
+        tree: Option<String>,
+        /// branch of package versions, defaults to the one of the repository
+        #[arg(long)]
+        branch: Option<String>,
+        /// print at most this many packages and the cursor of the next page
+        #[arg(long)]
+        page_size: Option<u64>,
+        /// print the page starting at the cursor printed with the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+    /// compare two abbs databases
+    DiffDb {
+        /// url of the old database
+        #[arg(long)]
+        old: String,
+        /// url of the new database
+        #[arg(long)]
+        new: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// show package information in JSON
+    Show {
+        package: String,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// scan a repository whenever its references move, until interrupted
+    Watch {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// seconds between checking references
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// seconds without further changes before scanning
+        #[arg(long, default_value_t = 10)]
+        quiet_period: u64,
+    },
+    /// list package errors of a branch
+    Errors {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// branch of the errors, defaults to the main branch of the repository
+        #[arg(long)]
+        branch: Option<String>,
+        /// print links to the locations, needs url_template of the repository
+        #[arg(long)]
+        links: bool,
+        /// only list errors of packages without description
+        #[arg(long)]
+        missing_descriptions: bool,
+        /// print at most this many errors and the cursor of the next page
+        #[arg(long)]
+        page_size: Option<u64>,
+        /// print the page starting at the cursor printed with the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+    /// parse every package of a repository into JSON, without any database
+    Dump {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// commit, branch or tag to read, defaults to the branch of the repository
+        #[arg(long = "ref")]
+        rev: Option<String>,
+        /// file to write, defaults to stdout
+        #[arg(long)]
+        out: Option<String>,
+        /// only dump directories starting with the prefix like app-, can be repeated
+        #[arg(long)]
+        section: Vec<String>,
+    },
+    /// list packages found in multiple locations
+    Duplicates {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[command(subcommand)]
+        command: Option<DuplicatesCommand>,
+    },
+    /// list package groups, or members of a group
+    Groups {
+        /// group name, defaults to listing every group
+        group: Option<String>,
+        /// list groups of the package instead
+        #[arg(long, conflicts_with = "group")]
+        package: Option<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// add commits of packages missing in the commits table and update the packages
+    Backfill {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// only backfill the package, defaults to every package of the repository
+        #[arg(long)]
+        package: Option<String>,
+        /// commits of each package to look at, newest first
+        #[arg(long, default_value_t = 10000)]
+        max_commits: usize,
+    },
+    /// write dependencies of every package as JSON, for dependency solvers
+    ExportDepmatrix {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// file to write
+        #[arg(long)]
+        out: String,
+    },
+    /// list packages depending on a package
+    Revdeps {
+        package: String,
+        /// only dependencies in effect on the architecture, e.g. riscv64
+        #[arg(long)]
+        arch: Option<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// list versioned dependencies the versions of the tree don't satisfy
+    ///
+    /// Dependencies on packages the tree doesn't provide can't be evaluated and are listed as unevaluable.
+    Constraints {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: QueryFormat,
+    },
+    /// list packages without changes for a while, oldest first
+    Inactive {
+        /// how long ago, like 90d, 8w, 18months or 1y
+        #[arg(long, value_parser = parse_age)]
+        since: chrono::Duration,
+        /// only list packages in the section, can be repeated
+        #[arg(long)]
+        section: Vec<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: QueryFormat,
+    },
+    /// show the latest changes of a repository
+    Changelog {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// number of commits to show, at most 1000
+        #[arg(long, default_value_t = 50)]
+        limit: u64,
+        /// list each package of mass changes instead of a summary line
+        #[arg(long)]
+        expand: bool,
+        /// show the commits after the cursor printed by the previous run
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+    /// record packages picked up by the build system
+    ///
+    /// Reads a JSON array like [{"package": "bash", "githash": "..."}]
+    MarkSynced {
+        /// JSON file to read, defaults to stdin
+        file: Option<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// who synced the packages
+        #[arg(long, default_value = "build")]
+        source: String,
+        /// accept commits which are not in the commits table
+        #[arg(long)]
+        force: bool,
+    },
+    /// show packages changed by a commit
+    Commit {
+        /// commit hash, can be abbreviated
+        hash: String,
+        /// repository used to resolve abbreviated hashes, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// show the commit each package was last changed by as of a date, from the commit database
+    ///
+    /// Packages which didn't exist yet or were deleted by then are left out.
+    Asof {
+        /// package to show, all packages of the tree if unset
+        package: Option<String>,
+        /// date like 2023-06-01, meaning the end of the day in local time, or an RFC 3339 time
+        #[arg(long, value_parser = parse_date)]
+        date: chrono::DateTime<chrono::FixedOffset>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// branch to look at, defaults to the branch of the repository
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// show spec keys changed by a testing branch, needs store_testing_spec
+    TopicDiff {
+        /// testing branch name
+        branch: String,
+        /// package name, defaults to every package of the branch
+        package: Option<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// show how merging a testing branch would change packages of the main branch
+    SimulateMerge {
+        /// testing branch name
+        #[arg(long)]
+        branch: String,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// check the database for malformed data
+    Doctor,
+    /// compare stored packages with a fresh parse of the tip of the main branch,
+    /// exits with an error if they differ
+    Verify {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// delete extra packages and write missing and mismatched ones again
+        #[arg(long)]
+        fix: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    /// tell which names are packages, provided by packages, or unknown
+    CheckNames {
+        /// names to check, read from stdin separated by whitespace if empty
+        names: Vec<String>,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: QueryFormat,
+    },
+    /// save and restore package metadata of a repository
+    SnapshotMeta {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// inspect scan runs
+    Runs {
+        #[command(subcommand)]
+        command: RunsCommand,
+    },
+    /// read the feed of package changes in events_outbox
+    Events {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[command(subcommand)]
+        command: EventsCommand,
+    },
+    /// delete changes of packages beyond max_changes_per_package and reclaim their space
+    Compact {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        /// newest changes to keep for each package, defaults to max_changes_per_package
+        #[arg(long, value_parser = parse_change_limit)]
+        limit: Option<usize>,
+    },
+    /// full text search of package names and descriptions
+    Fts {
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+        #[command(subcommand)]
+        command: FtsCommand,
+    },
+    /// run a read-only SELECT statement against the database
+    Query {
+        sql: String,
+        /// maximum number of rows, defaults to query_row_limit in configuration
+        #[arg(long)]
+        limit: Option<usize>,
+        /// query the commits database, which is database_url unless commits_database_url is set
+        #[arg(long)]
+        commits: bool,
+        #[arg(long, value_enum, default_value_t)]
+        format: QueryFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// save packages, versions, specs and dependencies under a name
+    Create {
+        name: String,
+        /// replace an existing snapshot with the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// replace current package metadata with a snapshot
+    Restore { name: String },
+    /// list snapshots
+    List,
+    /// delete a snapshot
+    Delete { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum DuplicatesCommand {
+    /// keep the copy at a location as the package in future scans
+    Resolve {
+        package: String,
+        /// location like aosc-os-abbs/app-shells/bash
+        #[arg(long)]
+        prefer: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RunsCommand {
+    /// list rows last written by a run
+    Show {
+        /// run id, logged at the start of each scan
+        run_id: String,
+        /// repository name, defaults to the first one in configuration
+        #[arg(long)]
+        repo: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum EventsCommand {
+    /// print events not read by the consumer yet as JSON lines, and mark them read
+    Tail {
+        /// name the read position is saved under
+        #[arg(long)]
+        consumer: String,
+        /// read events after this id instead of the saved position
+        #[arg(long)]
+        after: Option<i64>,
+        /// maximum number of events read at once
+        #[arg(long, default_value_t = 100)]
+        limit: u64,
+        /// keep waiting for new events
+        #[arg(long)]
+        follow: bool,
+        /// seconds between polls with --follow
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+    },
+    /// delete events read by every consumer
+    Prune {
+        /// only delete events older than this, like 90d, 8w, 18months or 1y
+        #[arg(long, value_parser = parse_age, default_value = "30d")]
+        older_than: chrono::Duration,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum FtsCommand {
+    /// drop and recreate the search index
+    Rebuild,
+    /// print name and description of matching packages, best matches first
+    Search { query: String },
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum Format {
+    #[default]
+    Json,
+    Markdown,
+}
+
+/// A package synced by the build system, input of mark-synced
+#[derive(Debug, Deserialize)]
+struct SyncedPackage {
+    package: String,
+    githash: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum QueryFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+/// Parse ages like 90d, 8w, 18months or 1y, a month is 30 days and a year 365 days
+fn parse_age(s: &str) -> Result<chrono::Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .with_context(|| format!("missing unit in {s:?}, e.g. 90d"))?;
+    let (count, unit) = s.split_at(split);
+    let count: i64 = count
+        .parse()
+        .with_context(|| format!("missing number in {s:?}, e.g. 90d"))?;
+    let days = match unit.trim() {
+        "d" | "day" | "days" => 1,
+        "w" | "week" | "weeks" => 7,
+        "mo" | "month" | "months" => 30,
+        "y" | "year" | "years" => 365,
+        unit => bail!("unknown unit {unit:?}, expected d, w, months or y"),
+    };
+
+    Ok(chrono::Duration::days(count * days))
+}
+
+/// Parse a number of changes to keep, at least one so no package loses its history
+fn parse_change_limit(s: &str) -> Result<usize> {
+    let limit: usize = s
+        .parse()
+        .with_context(|| format!("invalid number of changes {s:?}"))?;
+    if limit == 0 {
+        bail!("at least one change of each package must be kept");
+    }
+
+    Ok(limit)
+}
+
+/// Parse dates like 2023-06-01, meaning the end of the day in local time, or RFC 3339 times
+fn parse_date(s: &str) -> Result<chrono::DateTime<chrono::FixedOffset>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(time);
+    }
+    let end_of_day = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(23, 59, 59))
+        .with_context(|| format!("invalid date {s:?}, e.g. 2023-06-01"))?;
+    let time = chrono::TimeZone::from_local_datetime(&chrono::Local, &end_of_day)
+        .earliest()
+        .with_context(|| format!("{s} has no end of day in local time"))?;
+
+    Ok(time.fixed_offset())
+}
+
+/// number of slowest packages shown after scanning
+const SLOWEST_PACKAGES: usize = 10;
+
+/// packages written in each transaction when more than batch_threshold are updated
+///
+/// The transaction is held while all of them are written, so this bounds how
+/// long their rows stay locked and how stale readers are during large imports.
+const ADD_BATCH_SIZE: usize = 256;
+
 #[async_std::main]
-async fn main() -> Result<()> {
-    init_log();
+async fn main() -> ExitCode {
+    let multi = MultiProgress::new();
+    init_log(&multi);
     let opt = Opt::parse();
 
-    let Config {
-        ref global,
-        repo: ref repos,
-    } = Config::from_file(opt.config)?;
+    match run(opt, &multi).await {
+        Ok(status) => ExitCode::from(status.code()),
+        Err(e) => {
+            error!("{e:?}");
+            ExitCode::from(ExitStatus::Fatal.code())
+        }
+    }
+}
+
+async fn run(opt: Opt, multi: &MultiProgress) -> Result<ExitStatus> {
+    let config = Config::from_file(opt.config)?;
+    multi.set_draw_target(ProgressDrawTarget::stderr_with_hz(
+        config.global.performance.refresh_rate(),
+    ));
+
+    match opt.command.unwrap_or(Command::Scan) {
+        Command::Scan => {
+            let config_digest = config.digest()?;
+            let mut reports = vec![];
+            // the most important trees are scanned first
+            let repos = config
+                .repo
+                .iter()
+                .sorted_by_key(|repo| Reverse(repo.priority))
+                .collect_vec();
+            info!(
+                "scan order: {}",
+                repos.iter().map(|repo| repo.name.as_str()).join(", ")
+            );
+            for repo in repos {
+                let progress = Progress::new(multi, &repo.name);
+                let report = do_scan_and_update(
+                    &config.global,
+                    repo,
+                    &config_digest,
+                    &opt.scan,
+                    progress.clone(),
+                )
+                .await
+                .unwrap_or_else(|e| {
+                    error!("failed to scan {}/{}: {e:?}", repo.name, repo.branch);
+                    ScanReport::failed(&repo.name, &repo.branch, &e)
+                });
+                progress.flush_events();
+                reports.push(report);
+            }
+            if let Some(path) = &opt.scan.report {
+                std::fs::write(path, serde_json::to_string_pretty(&reports)?)
+                    .with_context(|| format!("failed to write {path}"))?;
+            }
+
+            let fail_on = if opt.scan.dry_run {
+                FailOn::AnyErrors
+            } else {
+                opt.scan.fail_on
+            };
+            let status = ExitStatus::from_reports(&reports, fail_on);
+            // the trees are scanned already, only m_packages is left outdated
+            if !opt.scan.dry_run {
+                if let Err(e) = refresh_materialized_views(&config.global).await {
+                    error!("failed to refresh materialized views: {e:?}");
+                    if status != ExitStatus::Fatal {
+                        return Ok(ExitStatus::PartialFailure);
+                    }
+                }
+            }
+            return Ok(status);
+        }
+        Command::Watch {
+            repo,
+            interval,
+            quiet_period,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let config_digest = config.digest()?;
+            let scan = || async {
+                let progress = Progress::new(multi, &repo.name);
+                if let Err(e) = do_scan_and_update(
+                    &config.global,
+                    repo,
+                    &config_digest,
+                    &opt.scan,
+                    progress.clone(),
+                )
+                .await
+                {
+                    error!("failed to scan {}/{}: {e:?}", repo.name, repo.branch);
+                }
+                progress.flush_events();
+                if !opt.scan.dry_run {
+                    if let Err(e) = refresh_materialized_views(&config.global).await {
+                        error!("failed to refresh materialized views: {e:?}");
+                    }
+                }
+            };
+
+            // the scans sync the branch, polls only read references, and a
+            // repository briefly unreadable, e.g. during a fetch, is polled again
+            let poll = || match Repository::open_unsynced(repo)
+                .map_err(anyhow::Error::from)
+                .and_then(|repo| repo.ref_targets())
+            {
+                Ok(targets) => Some(targets),
+                Err(e) => {
+                    warn!("failed to read references of {}: {e}", repo.repo_path);
+                    None
+                }
+            };
 
-    for repo in repos {
-        info!("scan {}/{}", repo.name, repo.branch);
-        do_scan_and_update(global, repo).await?;
+            info!("watching {} every {interval}s", repo.repo_path);
+            let mut last = Repository::open(repo)?.ref_targets()?;
+            scan().await;
+            loop {
+                task::sleep(Duration::from_secs(interval)).await;
+                let Some(mut pending) = poll() else {
+                    continue;
+                };
+                if pending == last {
+                    continue;
+                }
+
+                // wait until references stop moving
+                loop {
+                    task::sleep(Duration::from_secs(quiet_period)).await;
+                    let Some(current) = poll() else {
+                        continue;
+                    };
+                    if current == pending {
+                        break;
+                    }
+                    pending = current;
+                }
+
+                let moved = pending
+                    .iter()
+                    .filter(|(name, target)| last.get(*name) != Some(target))
+                    .map(|(name, _)| name.as_str())
+                    .chain(
+                        last.keys()
+                            .filter(|name| !pending.contains_key(*name))
+                            .map(|name| name.as_str()),
+                    )
+                    .join(", ");
+                info!("references moved: {moved}");
+                scan().await;
+                last = pending;
+            }
+        }
+        Command::List {
+            repo,
+            testing,
+            section,
+            category,
+            tree,
+            branch,
+            page_size,
+            cursor,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let filter = PackageFilter {
+                sections: section,
+                categories: category,
+                tree,
+                branch,
+            };
+            let packages = if page_size.is_some() || cursor.is_some() {
+                let page = PageRequest {
+                    cursor,
+                    limit: page_size,
+                };
+                let page = abbs_db.list_packages_page(&filter, &page).await?;
+                if let Some(next) = page.next {
+                    info!("next page: --cursor {next}");
+                }
+                page.items
+            } else {
+                abbs_db.list_packages(&filter).await?
+            };
+            for pkg in packages {
+                let version = pkg.version.as_deref().unwrap_or("-");
+                if testing {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        pkg.name, pkg.section, version, pkg.testing
+                    );
+                } else {
+                    println!("{}\t{}\t{}", pkg.name, pkg.section, version);
+                }
+            }
+        }
+        Command::Show { package, repo } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let pkg = abbs_db
+                .get_package(&package)
+                .await?
+                .with_context(|| format!("package {package} not found"))?;
+            println!("{}", serde_json::to_string_pretty(&pkg)?);
+        }
+        Command::Errors {
+            repo,
+            branch,
+            links,
+            missing_descriptions,
+            page_size,
+            cursor,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            if links && repo.url_template.is_none() {
+                bail!("url_template of {} is not set", repo.name);
+            }
+            // errors of testing branches are linked to the branch itself
+            let commit = match &branch {
+                Some(branch) => branch.clone(),
+                None => abbs_db
+                    .get_head_commit()
+                    .await?
+                    .unwrap_or_else(|| repo.branch.clone()),
+            };
+            let err_type = missing_descriptions.then_some(ErrorType::Description);
+            let errors = if page_size.is_some() || cursor.is_some() {
+                let page = PageRequest {
+                    cursor,
+                    limit: page_size,
+                };
+                let page = abbs_db
+                    .get_errors_page(branch.as_deref(), err_type, &page)
+                    .await?;
+                if let Some(next) = page.next {
+                    info!("next page: --cursor {next}");
+                }
+                page.items
+            } else {
+                abbs_db.get_errors(branch.as_deref(), err_type).await?
+            };
+            for error in errors {
+                let link = links
+                    .then(|| abbs_db.render_location(&error, &commit))
+                    .flatten()
+                    .map(|link| format!("\t{link}"))
+                    .unwrap_or_default();
+                let location = match (error.line, error.col) {
+                    (Some(line), Some(col)) => format!("{}:{line}:{col}", error.path),
+                    (Some(line), None) => format!("{}:{line}", error.path),
+                    _ => error.path,
+                };
+                println!(
+                    "{}\t{}\t{location}\t{}{link}",
+                    error.package,
+                    error.err_type.to_string(),
+                    error.message
+                );
+            }
+        }
+        Command::Dump {
+            repo,
+            rev,
+            out,
+            section,
+        } => {
+            let repo_config = config.get_repo(repo.as_deref())?;
+            let repo = Repository::open(repo_config)?;
+            let commit = match rev {
+                Some(rev) => repo
+                    .get_git2repo()
+                    .revparse_single(&rev)?
+                    .peel_to_commit()?
+                    .id(),
+                None => repo.get_branch_oid(&repo.branch)?,
+            };
+            let (packages, failed) =
+                scan_tree(&repo, commit, &section, &config.global.architectures)?;
+            let packages = packages
+                .into_iter()
+                .map(PackageDump::from)
+                .sorted_by(|a, b| a.name.cmp(&b.name))
+                .collect_vec();
+            info!(
+                "dumped {} packages at {commit}, {} failed to parse, {} with errors",
+                packages.len(),
+                failed.len(),
+                packages.iter().filter(|pkg| !pkg.errors.is_empty()).count()
+            );
+            if !failed.is_empty() {
+                warn!("failed to parse {}", failed.iter().sorted().join(" "));
+            }
+
+            let json = serde_json::to_string_pretty(&packages)?;
+            match out {
+                Some(out) => {
+                    std::fs::write(&out, json).with_context(|| format!("failed to write {out}"))?
+                }
+                None => println!("{json}"),
+            }
+        }
+        Command::Duplicates { repo, command } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = match command {
+                Some(_) => AbbsDb::open(&config.global, repo).await?,
+                None => AbbsDb::open_read_only(&config.global, repo).await?,
+            };
+            match command {
+                Some(DuplicatesCommand::Resolve { package, prefer }) => {
+                    abbs_db.resolve_duplicate(&package, &prefer).await?;
+                }
+                None => {
+                    for dup in abbs_db.get_duplicates().await? {
+                        for location in dup.locations {
+                            let flags = [
+                                (location.canonical, "canonical"),
+                                (location.preferred, "preferred"),
+                                (location.stale, "stale"),
+                            ]
+                            .into_iter()
+                            .filter_map(|(set, flag)| set.then_some(flag))
+                            .join(",");
+                            println!(
+                                "{}\t{}\t{}\t{flags}",
+                                dup.package,
+                                location.location,
+                                location.version.as_deref().unwrap_or("-")
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Command::Groups {
+            group,
+            package,
+            repo,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            match (group, package) {
+                (Some(group), _) => {
+                    let members = abbs_db.get_group(&group).await?;
+                    if members.is_empty() {
+                        bail!("group {group} not found");
+                    }
+                    for package in members {
+                        println!("{package}");
+                    }
+                }
+                (None, Some(package)) => {
+                    for group in abbs_db.get_groups_for_package(&package).await? {
+                        println!("{group}");
+                    }
+                }
+                (None, None) => {
+                    for (group, count) in abbs_db.get_groups().await? {
+                        println!("{group}\t{count}");
+                    }
+                }
+            }
+        }
+        Command::Backfill {
+            repo,
+            package,
+            max_commits,
+        } => {
+            let repo_config = config.get_repo(repo.as_deref())?;
+            let repo = Repository::open(repo_config)?;
+            let commit_db = CommitDb::open(&config.global).await?;
+            let abbs_db = AbbsDb::open(&config.global, repo_config).await?;
+            let targets = abbs_db.get_spec_paths(package.as_deref()).await?;
+            if let (Some(package), true) = (&package, targets.is_empty()) {
+                bail!("package {package} not found");
+            }
+
+            let mut backfilled = vec![];
+            let len = targets.len();
+            for (i, (name, spec_path)) in targets.into_iter().enumerate() {
+                info!("{}/{len} {name}", i + 1);
+                let spec_path = Path::new(&spec_path);
+                let directory = spec_path.parent().unwrap_or(spec_path);
+                let added = commit_db
+                    .backfill_package(&repo, &name, directory, max_commits)
+                    .await?;
+                if added.is_empty() {
+                    continue;
+                }
+                let Some(pkg_meta) = commit_db.rescan_package(&repo, &name, spec_path)? else {
+                    warn!("{name} can't be parsed at the tip of {}", repo.branch);
+                    continue;
+                };
+                let pkg_changes = commit_db.get_package_changes(&repo, &name).await?;
+                abbs_db.add_package(pkg_meta, pkg_changes).await?;
+                backfilled.push((name, added.len()));
+            }
+
+            info!("backfilled {} packages", backfilled.len());
+            for (name, count) in backfilled {
+                println!("{name}\t{count}");
+            }
+        }
+        Command::ExportDepmatrix { repo, out } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let matrix = abbs_db.get_dep_matrix().await?;
+            let file = File::create(&out).with_context(|| format!("failed to create {out}"))?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, &matrix)?;
+            writer.flush()?;
+            info!(
+                "exported dependencies of {} packages to {out}",
+                matrix.packages.len()
+            );
+        }
+        Command::Revdeps {
+            package,
+            arch,
+            repo,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            for revdep in abbs_db
+                .get_reverse_dependencies(&package, arch.as_deref())
+                .await?
+            {
+                let dep = &revdep.dependency;
+                let architecture = match dep.architecture.as_str() {
+                    "" => "all",
+                    architecture => architecture,
+                };
+                println!(
+                    "{}\t{}\t{architecture}\t{}",
+                    revdep.package, dep.relationship, dep.dependency
+                );
+            }
+        }
+        Command::Constraints { repo, format } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let constraints = abbs_db.get_unsatisfied_constraints().await?;
+            match format {
+                QueryFormat::Json => println!("{}", serde_json::to_string_pretty(&constraints)?),
+                QueryFormat::Table => {
+                    let checks = constraints
+                        .violated
+                        .iter()
+                        .map(|check| ("violated", check))
+                        .chain(
+                            constraints
+                                .unevaluable
+                                .iter()
+                                .map(|check| ("unevaluable", check)),
+                        );
+                    for (status, check) in checks {
+                        let architecture = match check.architecture.as_str() {
+                            "" => "all",
+                            architecture => architecture,
+                        };
+                        println!(
+                            "{status}\t{}\t{}\t{architecture}\t{}\t{}",
+                            check.package,
+                            check.relationship,
+                            check.constraint,
+                            check.version.as_deref().unwrap_or("-")
+                        );
+                    }
+                }
+            }
+        }
+        Command::Inactive {
+            since,
+            section,
+            repo,
+            format,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let since = chrono::Local::now().fixed_offset() - since;
+            let packages = abbs_db.get_inactive_packages(since, &section).await?;
+            match format {
+                QueryFormat::Table => {
+                    for pkg in packages {
+                        println!(
+                            "{}\t{}\t{}\t{}",
+                            pkg.package,
+                            pkg.section,
+                            pkg.last_change_time
+                                .map_or("never".to_string(), |time| time.to_rfc3339()),
+                            pkg.last_maintainer.unwrap_or_default()
+                        );
+                    }
+                }
+                QueryFormat::Json => println!("{}", serde_json::to_string_pretty(&packages)?),
+            }
+        }
+        Command::Changelog {
+            repo,
+            limit,
+            expand,
+            cursor,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let page = PageRequest {
+                cursor,
+                limit: Some(limit),
+            };
+            let page = abbs_db.get_changelog_page(&page, expand).await?;
+            if let Some(next) = page.next {
+                info!("next page: --cursor {next}");
+            }
+            for entry in page.items {
+                let githash = &entry.githash[..entry.githash.len().min(7)];
+                match entry.packages.as_slice() {
+                    [(name, version)] => println!(
+                        "{} {githash} {name} {version}: {}",
+                        entry.timestamp, entry.summary
+                    ),
+                    packages => println!(
+                        "{} {githash} [{} packages]: {}",
+                        entry.timestamp,
+                        packages.len(),
+                        entry.summary
+                    ),
+                }
+            }
+        }
+        Command::TopicDiff {
+            branch,
+            package,
+            repo,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let packages = match package {
+                Some(package) => vec![package],
+                None => abbs_db.get_testing_spec_packages(&branch).await?,
+            };
+            let mut diffs = vec![];
+            for package in packages {
+                diffs.push(abbs_db.diff_testing_spec(&package, &branch).await?);
+            }
+            println!("{}", serde_json::to_string_pretty(&diffs)?);
+        }
+        Command::SimulateMerge {
+            branch,
+            repo,
+            format,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let simulation = abbs_db
+                .simulate_merge(&Repository::open(repo)?, &branch)
+                .await?;
+            match format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&simulation)?),
+                Format::Markdown => print!("{}", simulation.to_markdown()),
+            }
+        }
+        Command::MarkSynced {
+            file,
+            repo,
+            source,
+            force,
+        } => {
+            let content = match file {
+                Some(file) => std::fs::read_to_string(&file)
+                    .with_context(|| format!("failed to read {file}"))?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            mark_synced(&config, &content, repo.as_deref(), &source, force).await?;
+        }
+        Command::Commit { hash, repo } => {
+            let packages = commit_packages(&config, &hash, repo.as_deref()).await?;
+            if packages.is_empty() {
+                info!("no packages recorded for commit {hash}");
+            }
+            for pkg in packages {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    pkg.commit_id,
+                    pkg.branch,
+                    pkg.pkg_name,
+                    pkg.pkg_version,
+                    pkg.status,
+                    pkg.defines_path,
+                    pkg.spec_path
+                );
+            }
+        }
+        Command::Asof {
+            package,
+            date,
+            repo,
+            branch,
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let branch = branch.unwrap_or_else(|| repo.branch.clone());
+            let commit_db = CommitDb::open_read_only(&config.global).await?;
+            let tree = repo.tree_id();
+            let rows = match &package {
+                Some(package) => {
+                    let row = commit_db
+                        .get_package_state_at(package, &tree, &branch, date)
+                        .await?;
+                    if row.is_none() {
+                        info!("{package} is not in {branch} as of {date}");
+                    }
+                    row.into_iter().collect()
+                }
+                None => commit_db.get_tree_state_at(&tree, &branch, date).await?,
+            };
+            for pkg in rows {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    pkg.pkg_name,
+                    pkg.pkg_version,
+                    pkg.commit_id,
+                    pkg.commit_time.to_rfc3339()
+                );
+            }
+        }
+        Command::Doctor => {
+            if !doctor(&config).await? {
+                bail!("database check failed");
+            }
+            info!("no problems found");
+        }
+        Command::Verify { repo, fix, format } => {
+            let repo_config = config.get_repo(repo.as_deref())?;
+            let repo = Repository::open(repo_config)?;
+            let abbs_db = if fix {
+                AbbsDb::open(&config.global, repo_config).await?
+            } else {
+                AbbsDb::open_read_only(&config.global, repo_config).await?
+            };
+            let commit = repo.get_branch_oid(&repo.branch)?;
+            let (metas, failed) = scan_tree(&repo, commit, &[], &config.global.architectures)?;
+            let verification = abbs_db.verify(commit, &metas, failed).await?;
+            match format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&verification)?),
+                Format::Markdown => print!("{}", verification.to_markdown()),
+            }
+
+            if verification.is_consistent() {
+                info!("stored packages match {commit}");
+            } else if !fix {
+                bail!("stored packages differ from {commit}, run verify --fix to correct them");
+            } else {
+                for name in &verification.extra {
+                    abbs_db.delete_package(name).await?;
+                }
+
+                let commit_db = CommitDb::open(&config.global).await?;
+                let warnings = Warnings::new();
+                let outdated = verification.outdated();
+                let mut unfixed = BTreeSet::new();
+                for meta in metas
+                    .into_iter()
+                    .filter(|meta| outdated.contains(meta.0.name.as_str()))
+                {
+                    let changes =
+                        get_package_changes(&config.global, &commit_db, &repo, &meta.0, &warnings)
+                            .await?;
+                    if changes.is_empty() {
+                        unfixed.insert(meta.0.name.clone());
+                        continue;
+                    }
+                    abbs_db.add_package(meta, changes).await?;
+                }
+
+                info!(
+                    "deleted {} extra packages, wrote {} packages again",
+                    verification.extra.len(),
+                    outdated.len() - unfixed.len()
+                );
+                if !unfixed.is_empty() {
+                    bail!(
+                        "no recorded changes of {}, scan the repository first",
+                        unfixed.iter().join(" ")
+                    );
+                }
+            }
+        }
+        Command::SnapshotMeta { repo, command } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let store = SnapshotStore::open(&config.global, repo).await?;
+            match command {
+                SnapshotCommand::Create { name, force } => {
+                    let info = store.create(&name, force).await?;
+                    println!("{}", serde_json::to_string_pretty(&info)?);
+                }
+                SnapshotCommand::Restore { name } => {
+                    store.restore(&name).await?;
+                }
+                SnapshotCommand::List => {
+                    for info in store.list().await? {
+                        println!(
+                            "{}\t{}\t{} packages\t{} KiB{}",
+                            info.name,
+                            info.created_at,
+                            info.packages,
+                            info.size / 1024,
+                            if info.compatible {
+                                ""
+                            } else {
+                                "\tincompatible"
+                            }
+                        );
+                    }
+                }
+                SnapshotCommand::Delete { name } => store.delete(&name).await?,
+            }
+        }
+        Command::CheckNames {
+            names,
+            repo,
+            format,
+        } => {
+            let names = if names.is_empty() {
+                std::io::read_to_string(std::io::stdin())?
+                    .split_whitespace()
+                    .map(|name| name.to_string())
+                    .collect()
+            } else {
+                names
+            };
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let classes = abbs_db.classify_names(&names).await?;
+            match format {
+                QueryFormat::Table => {
+                    for name in names.iter().unique() {
+                        let class = match &classes[name] {
+                            NameClass::Package {
+                                name,
+                                tree,
+                                section,
+                            } => format!("package\t{tree}/{section}/{name}"),
+                            NameClass::Provided { by } => {
+                                format!("provided\t{}", by.join(", "))
+                            }
+                            NameClass::Unknown => "unknown".to_string(),
+                        };
+                        println!("{name}\t{class}");
+                    }
+                }
+                QueryFormat::Json => {
+                    let classes: serde_json::Map<_, _> = names
+                        .iter()
+                        .unique()
+                        .map(|name| Ok((name.clone(), serde_json::to_value(&classes[name])?)))
+                        .collect::<Result<_>>()?;
+                    println!("{}", serde_json::to_string_pretty(&classes)?);
+                }
+            }
+        }
+        Command::Events { repo, command } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open(&config.global, repo).await?;
+            match command {
+                EventsCommand::Tail {
+                    consumer,
+                    mut after,
+                    limit,
+                    follow,
+                    interval,
+                } => loop {
+                    let events = abbs_db.poll_events(&consumer, after, limit).await?;
+                    for event in &events {
+                        println!("{}", serde_json::to_string(event)?);
+                    }
+                    // continue from the saved position, which is past these events
+                    after = None;
+                    if events.len() as u64 == limit {
+                        continue;
+                    }
+                    if !follow {
+                        break;
+                    }
+                    task::sleep(Duration::from_secs(interval)).await;
+                },
+                EventsCommand::Prune { older_than } => {
+                    let before = chrono::Local::now().fixed_offset() - older_than;
+                    let deleted = abbs_db.prune_events(before).await?;
+                    info!("deleted {deleted} events");
+                }
+            }
+        }
+        Command::Compact { repo, limit } => {
+            let Some(limit) = limit.or(config.global.max_changes_per_package) else {
+                bail!("max_changes_per_package is not set, pass --limit");
+            };
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open(&config.global, repo).await?;
+            let deleted = abbs_db.compact_changes(limit).await?;
+            info!("deleted {deleted} changes");
+        }
+        Command::Fts { repo, command } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = match command {
+                FtsCommand::Rebuild => AbbsDb::open(&config.global, repo).await?,
+                FtsCommand::Search { .. } => AbbsDb::open_read_only(&config.global, repo).await?,
+            };
+            match command {
+                FtsCommand::Rebuild => {
+                    let indexed = abbs_db.rebuild_fts().await?;
+                    info!("indexed {indexed} packages");
+                }
+                FtsCommand::Search { query } => {
+                    for (name, description) in abbs_db.fts_search(&query).await? {
+                        println!("{name}\t{description}");
+                    }
+                }
+            }
+        }
+        Command::Runs {
+            command: RunsCommand::Show { run_id, repo },
+        } => {
+            let repo = config.get_repo(repo.as_deref())?;
+            let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+            let rows = abbs_db.get_run_rows(&run_id).await?;
+            if rows.is_empty() {
+                info!("no rows recorded for run {run_id}");
+            }
+            for row in rows {
+                println!(
+                    "{}\t{}\t{}",
+                    row.table,
+                    row.package,
+                    row.branch.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Command::Query {
+            sql,
+            limit,
+            commits,
+            format,
+        } => {
+            let limit = limit.unwrap_or(config.global.query_row_limit);
+            let output = query(&config.global, commits, &sql, limit).await?;
+            match format {
+                QueryFormat::Table => print!("{}", output.to_table()),
+                QueryFormat::Json => println!("{}", serde_json::to_string_pretty(&output)?),
+            }
+        }
+        Command::DiffDb { old, new, format } => {
+            let diff = diff_databases(&old, &new).await?;
+            match format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+                Format::Markdown => print!("{}", diff.to_markdown()),
+            }
+        }
     }
 
-    Ok(())
+    Ok(ExitStatus::Success)
 }
 
-pub async fn do_scan_and_update(global_config: &Global, repo_config: &Repo) -> Result<()> {
-    let repo = &Repository::open(repo_config)?;
-    let commit_db = &CommitDb::open(&global_config.database_url).await?;
-    let abbs_db = &AbbsDb::open(global_config, repo_config).await?;
+async fn do_scan_and_update(
+    global_config: &Global,
+    repo_config: &Repo,
+    config_digest: &str,
+    options: &ScanOptions,
+    progress: Progress,
+) -> Result<ScanReport> {
+    let started = Instant::now();
+    progress.emit(|| ScanEvent::RepoStarted {
+        repo: repo_config.name.clone(),
+        branch: repo_config.branch.clone(),
+    });
+    let mut report = ScanReport::new(&repo_config.name, &repo_config.branch);
+    report.run_id = Uuid::new_v4().to_string();
+    info!("run id {}", report.run_id);
+    let repo = &match Repository::open(repo_config) {
+        Ok(repo) => repo,
+        Err(_) if Repository::is_unborn(repo_config).unwrap_or(false) => {
+            info!(
+                "branch {} of {} has no commits yet, skipping",
+                repo_config.branch, repo_config.name
+            );
+            return Ok(report);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    check_remote_url(global_config, repo_config, repo);
+    check_shallow(global_config, repo_config, repo)?;
+    if options.dry_run {
+        return dry_run(global_config, repo_config, repo, progress, report).await;
+    }
+    report.storage = Some(check_disk_space(global_config, repo_config, repo).await?);
+    let warnings = Warnings::new();
+    let mut commit_db = CommitDb::open(global_config)
+        .await?
+        .with_progress(progress.clone())
+        .with_warnings(warnings.clone());
+    if let Some(threads) = global_config.performance.parse_threads() {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+        commit_db = commit_db.with_thread_pool(Arc::new(pool));
+    }
+    let commit_db = &commit_db;
+    let abbs_db = &open_abbs_db(global_config, repo_config, &mut report)
+        .await?
+        .reject_invalid_names(options.reject_invalid_names)
+        .strict_writes(cfg!(debug_assertions) || options.strict_writes)
+        .with_run_id(&report.run_id)
+        .with_warnings(warnings.clone());
+    abbs_db.debug_assert_tree(repo, &repo_config.tree_id());
     abbs_db
-        .update_testing_branch(commit_db, repo, &HashSet::new())
+        .record_collector_meta(config_digest, report.recovery.as_deref())
         .await?;
+    if repo_config.scan_testing_branches {
+        report.skipped_branches = abbs_db.update_testing_branch(commit_db, repo).await?;
+    } else {
+        info!(
+            "scanning testing branches is disabled for {}",
+            repo_config.name
+        );
+        abbs_db.clear_testing_branches().await?;
+    }
+    let commit_scan_started = Instant::now();
     commit_db.update_branch(repo, &repo.branch).await?;
 
-    let (deleted, updated) = commit_db.get_updated_packages(repo, &repo.branch).await?;
+    let UpdatedPackages {
+        deleted,
+        mut updated,
+        moved,
+        broken,
+        failed,
+        commit,
+        from,
+    } = commit_db.get_updated_packages(repo, &repo.branch).await?;
+    report.from_commit = from.map(|from| from.to_string());
+    report.to_commit = Some(commit.to_string());
+    report.commit_scan_ms = commit_scan_started.elapsed().as_millis() as u64;
+    let db_update_started = Instant::now();
 
-    let deleted = deleted
+    let mut deleted = deleted
         .into_iter()
-        .map(|(pkg, _, _)| pkg.name)
+        .map(|(pkg, _, _, _)| {
+            report
+                .versions
+                .insert(pkg.name.clone(), get_full_version(&pkg));
+            pkg.name
+        })
         .collect_vec();
+    if from.is_none() {
+        // every package of the tree is scanned, so stored ones not found were
+        // deleted after the lost history or the restored snapshot
+        let scanned: HashSet<&str> = updated
+            .iter()
+            .map(|(pkg, _, _, _)| pkg.name.as_str())
+            .chain(broken.iter().map(|error| error.package.as_str()))
+            .chain(failed.iter().map(String::as_str))
+            .collect();
+        let extra = abbs_db
+            .get_packages_name()
+            .await?
+            .into_iter()
+            .filter(|name| !scanned.contains(name.as_str()))
+            .sorted()
+            .collect_vec();
+        deleted.extend(extra);
+    } else {
+        let vanished = abbs_db
+            .get_vanished_packages(repo, commit, &updated)
+            .await?
+            .into_iter()
+            .filter(|name| !deleted.contains(name))
+            .collect_vec();
+        deleted.extend(vanished);
+    }
+    let resumed = abbs_db.get_deferred_packages().await?;
+    if !resumed.is_empty() {
+        info!(
+            "resume {} packages deferred by the last scan",
+            resumed.len()
+        );
+    }
+    for (name, spec_path) in resumed {
+        if deleted.contains(&name) || updated.iter().any(|(pkg, _, _, _)| pkg.name == name) {
+            continue;
+        }
+        match commit_db.rescan_package(repo, &name, Path::new(&spec_path)) {
+            Ok(Some(pkg_meta)) => updated.push(pkg_meta),
+            Ok(None) => warn!("deferred package {name} is no longer at {spec_path}"),
+            Err(e) => warn!("failed to resume deferred package {name}: {e:?}"),
+        }
+    }
     let sep = if !deleted.is_empty() { ":" } else { "" };
     info!(
         "delete {} packages{} {}",
@@ -58,23 +1564,1133 @@ pub async fn do_scan_and_update(global_config: &Global, repo_config: &Repo) -> R
         deleted.join(" ")
     );
     info!("update {} packages", updated.len());
-    abbs_db.delete_packages(deleted).await?;
+    abbs_db.delete_packages(&deleted).await?;
+    report.deleted = deleted;
+    let moves = abbs_db
+        .move_packages(repo, &moved, &updated, commit)
+        .await?;
+    if moves > 0 {
+        info!("moved {moves} packages");
+    }
+    if cfg!(debug_assertions) || options.check_integrity {
+        report.integrity_violations = abbs_db.check_integrity().await?;
+        for violation in &report.integrity_violations {
+            error!(
+                "{} references nonexistent package {}",
+                violation.table, violation.package
+            );
+        }
+    }
+
+    abbs_db.preserve_versions(&failed).await?;
+    for error in &broken {
+        *report
+            .package_errors
+            .entry(error.package.clone())
+            .or_default() += 1;
+    }
+    let errors = abbs_db.add_errors(broken).await?;
+    report.errors += errors.total;
+    report.new_errors += errors.new;
 
+    let mut timings = vec![];
     let len = updated.len();
-    for (i, pkg_meta) in updated.into_iter().enumerate() {
-        let pkg_name = pkg_meta.0.name.clone();
-        let pkg_changes = commit_db.get_package_changes(repo, &pkg_name).await?;
-        abbs_db.add_package(pkg_meta, pkg_changes).await?;
-        info!("{}/{} {}", i + 1, len, pkg_name);
+    // one transaction per package is slow for large imports, write them in batches instead
+    let batch_size = if len > global_config.batch_threshold {
+        info!("writing packages in batches of {ADD_BATCH_SIZE}");
+        ADD_BATCH_SIZE
+    } else {
+        1
+    };
+    let batches = updated
+        .into_iter()
+        .chunks(batch_size)
+        .into_iter()
+        .map(|batch| batch.collect_vec())
+        .collect_vec();
+    let mut written = 0;
+    let mut latencies = vec![];
+    let deadline = repo_config
+        .max_duration_secs
+        .map(|secs| started + Duration::from_secs(secs));
+    let mut deferred = vec![];
+    for batch in batches {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            deferred.extend(
+                batch
+                    .into_iter()
+                    .map(|(pkg, _, _, _)| (pkg.name, pkg.spec_path)),
+            );
+            continue;
+        }
+        let mut pkgs = vec![];
+        let mut changes_elapsed = vec![];
+        let mut newest_changes = vec![];
+        for pkg_meta in batch {
+            report
+                .versions
+                .insert(pkg_meta.0.name.clone(), get_full_version(&pkg_meta.0));
+            let start = Instant::now();
+            let pkg_changes =
+                get_package_changes(global_config, commit_db, repo, &pkg_meta.0, &warnings).await?;
+            changes_elapsed.push(start.elapsed());
+            newest_changes.push(pkg_changes.iter().map(|change| change.timestamp).max());
+            pkgs.push((pkg_meta, pkg_changes));
+        }
+
+        let names = pkgs
+            .iter()
+            .map(|(pkg_meta, _)| pkg_meta.0.name.clone())
+            .collect_vec();
+        let start = Instant::now();
+        let counts = if batch_size > 1 {
+            abbs_db.add_packages_batch(pkgs).await?
+        } else {
+            let mut counts = vec![];
+            for (pkg_meta, pkg_changes) in pkgs {
+                counts.push(abbs_db.add_package(pkg_meta, pkg_changes).await?);
+            }
+            counts
+        };
+        let committed = chrono::Local::now();
+        latencies.extend(
+            newest_changes
+                .into_iter()
+                .flatten()
+                .map(|newest| committed.signed_duration_since(newest).num_seconds()),
+        );
+        // time of a batch is shared by its packages
+        let add_ms = start.elapsed().as_millis() as u64 / names.len().max(1) as u64;
+
+        for ((pkg_name, errors), changes_elapsed) in
+            names.into_iter().zip(counts).zip(changes_elapsed)
+        {
+            written += 1;
+            timings.push(PackageTiming {
+                package: pkg_name.clone(),
+                changes_ms: changes_elapsed.as_millis() as u64,
+                add_ms,
+            });
+            progress.emit(|| ScanEvent::PackageWritten {
+                package: pkg_name.clone(),
+                done: written,
+                total: len,
+            });
+            if errors.total > 0 {
+                progress.emit(|| ScanEvent::ErrorsRecorded {
+                    package: pkg_name.clone(),
+                    count: errors.total,
+                });
+                *report.package_errors.entry(pkg_name.clone()).or_default() += errors.total;
+            }
+            report.errors += errors.total;
+            report.new_errors += errors.new;
+            report.updated.push(pkg_name);
+        }
+        if let Some(ms) = global_config.performance.write_throttle_ms {
+            task::sleep(Duration::from_millis(ms)).await;
+        }
+    }
+    if !deferred.is_empty() {
+        warn!(
+            "{} ran out of its {}s budget, {} packages are left to the next scan",
+            repo_config.name,
+            repo_config.max_duration_secs.unwrap_or_default(),
+            deferred.len()
+        );
+    }
+    abbs_db.set_deferred_packages(&deferred).await?;
+    report.deferred = deferred.into_iter().map(|(name, _)| name).collect();
+
+    report.set_slowest(timings, SLOWEST_PACKAGES);
+    report.latency = LatencySummary::from_secs(&latencies);
+    if let Some(latency) = &report.latency {
+        info!(
+            "commit to database latency of {} packages: p50 {}s, p90 {}s, p99 {}s, max {}s",
+            latency.samples, latency.p50_secs, latency.p90_secs, latency.p99_secs, latency.max_secs
+        );
+        if latency.clamped > 0 {
+            warn!(
+                "{} packages have commits dated in the future, their latency is counted as zero",
+                latency.clamped
+            );
+        }
+        abbs_db.record_latency(latency).await?;
+    }
+    if !report.slowest.is_empty() {
+        info!(
+            "slowest packages: {}",
+            report
+                .slowest
+                .iter()
+                .map(|t| format!(
+                    "{} {}ms (changes {}ms, update {}ms)",
+                    t.package,
+                    t.total_ms(),
+                    t.changes_ms,
+                    t.add_ms
+                ))
+                .join(", ")
+        );
+    }
+
+    abbs_db.update_groups(repo).await?;
+    abbs_db.reconcile(repo).await?;
+    abbs_db.refresh_stats().await?;
+    abbs_db.record_head(repo, commit).await?;
+    abbs_db
+        .record_run_packages(&report.updated, &report.deleted)
+        .await?;
+    report.flapping = abbs_db.get_flapping().await?;
+    for pkg in &report.flapping {
+        error!(
+            "{} is flapping: switched between updated and deleted {} times in {} recent runs",
+            pkg.package, pkg.flaps, pkg.runs
+        );
+    }
+    if let Some(feeds) = &global_config.feeds {
+        // static files are a convenience, the scan itself succeeded
+        match write_feeds(feeds, repo_config, abbs_db).await {
+            Ok(written) => info!("wrote {} feeds", written.len()),
+            Err(e) => warn!("failed to write feeds of {}: {e:?}", repo_config.name),
+        }
+    }
+
+    report.db_update_ms = db_update_started.elapsed().as_millis() as u64;
+    warnings.log_summary();
+    report.warnings = warnings.counts();
+    progress.emit(|| ScanEvent::RepoFinished {
+        repo: repo_config.name.clone(),
+        updated: report.updated.len(),
+        deleted: report.deleted.len(),
+        errors: report.errors,
+    });
+
+    Ok(report)
+}
+
+/// Changes of the package to save with it
+///
+/// Falls back to the last commit of its spec if the commits database has no
+/// changes of the package, unless strict_changelog is set.
+async fn get_package_changes(
+    global_config: &Global,
+    commit_db: &CommitDb,
+    repo: &Repository,
+    pkg: &Package,
+    warnings: &Warnings,
+) -> Result<Vec<Change>> {
+    let mut pkg_changes = commit_db.get_package_changes(repo, &pkg.name).await?;
+    let truncated = repo.is_shallow() && global_config.allow_shallow;
+    if pkg_changes.is_empty() && (!global_config.strict_changelog || truncated) {
+        if let Some(change) = commit_db.fallback_change(repo, pkg)? {
+            warnings.warn(
+                "truncated changelog",
+                format_args!(
+                    "no recorded changes of {}, using the last commit of its spec",
+                    pkg.name
+                ),
+            );
+            pkg_changes.push(change);
+        }
+    }
+
+    Ok(pkg_changes)
+}
+
+/// Report packages a scan would write, without writing anything
+///
+/// Testing branches are not scanned, their packages are read from both databases.
+async fn dry_run(
+    global_config: &Global,
+    repo_config: &Repo,
+    repo: &Repository,
+    progress: Progress,
+    mut report: ScanReport,
+) -> Result<ScanReport> {
+    report.dry_run = true;
+    // both connections are read only, and a corrupted database is reported
+    // instead of recovered, as recovering it writes
+    let commit_db = CommitDb::open_read_only(global_config)
+        .await?
+        .with_progress(progress);
+    let abbs_db = AbbsDb::open_read_only(global_config, repo_config).await?;
+    let UpdatedPackages {
+        deleted,
+        updated,
+        broken,
+        commit,
+        ..
+    } = commit_db.get_pending_packages(repo, &repo.branch).await?;
+
+    let existing = abbs_db.get_packages_name().await?;
+    for pkg_meta in &updated {
+        let changes = commit_db
+            .get_package_changes(repo, &pkg_meta.0.name)
+            .await?;
+        report
+            .pending
+            .push(PendingPackage::updated(pkg_meta, &existing, changes.len()));
+    }
+    let mut deleted = deleted.into_iter().map(|pkg| pkg.0.name).collect_vec();
+    for name in abbs_db
+        .get_vanished_packages(repo, commit, &updated)
+        .await?
+    {
+        if !deleted.contains(&name) {
+            deleted.push(name);
+        }
+    }
+    for name in &deleted {
+        report.pending.push(PendingPackage::deleted(name));
+    }
+    report
+        .pending
+        .extend(broken.into_iter().map(PendingPackage::broken));
+
+    for pending in &report.pending {
+        println!(
+            "{}\t{}\t{}",
+            pending.action.as_str(),
+            pending.package,
+            pending.full_version.as_deref().unwrap_or("-")
+        );
+        for error in &pending.errors {
+            println!("\terror\t{}: {}", error.path, error.message);
+        }
+    }
+    report.errors = report.pending.iter().map(|p| p.errors.len()).sum();
+    report.updated = updated.into_iter().map(|pkg| pkg.0.name).collect();
+    report.deleted = deleted;
+    info!(
+        "dry run: {} packages to update, {} to delete, {} errors",
+        report.updated.len(),
+        report.deleted.len(),
+        report.errors
+    );
+
+    Ok(report)
+}
+
+/// Packages changed by the commit, empty for unknown commits
+///
+/// Abbreviated hashes are resolved in the repository if it can be opened,
+/// otherwise they are matched as prefixes of the recorded commits.
+async fn commit_packages(
+    config: &Config,
+    hash: &str,
+    repo: Option<&str>,
+) -> Result<Vec<commits::Model>> {
+    let repo_config = config.get_repo(repo)?;
+    let commit_db = CommitDb::open_read_only(&config.global).await?;
+    // resolve abbreviated hash in repository if possible
+    let resolved = Repository::open(repo_config).ok().and_then(|repo| {
+        let object = repo.get_git2repo().revparse_single(hash).ok()?;
+        Some(object.peel_to_commit().ok()?.id().to_string())
+    });
+    match resolved {
+        Some(commit_id) => commit_db.get_packages_by_commit(&commit_id).await,
+        None => commit_db.get_packages_by_commit_prefix(hash).await,
+    }
+}
+
+/// Record the package/hash pairs of the JSON content as synced by `source`
+///
+/// Commits which are not in the commits table are refused unless `force`.
+async fn mark_synced(
+    config: &Config,
+    content: &str,
+    repo: Option<&str>,
+    source: &str,
+    force: bool,
+) -> Result<()> {
+    let synced: Vec<SyncedPackage> = serde_json::from_str(content)?;
+
+    let repo = config.get_repo(repo)?;
+    let hashes = synced.iter().map(|s| s.githash.clone()).collect_vec();
+    let missing = CommitDb::open_read_only(&config.global)
+        .await?
+        .missing_commits(&hashes)
+        .await?;
+    if !missing.is_empty() {
+        if !force {
+            bail!(
+                "unknown commits {}, use --force to accept them",
+                missing.join(", ")
+            );
+        }
+        warn!("accept unknown commits {}", missing.join(", "));
+    }
+
+    let abbs_db = AbbsDb::open(&config.global, repo).await?;
+    for (githash, packages) in synced
+        .into_iter()
+        .map(|s| (s.githash, s.package))
+        .into_group_map()
+    {
+        abbs_db.mark_synced(&packages, &githash, source).await?;
     }
 
     Ok(())
 }
 
-fn init_log() {
+/// Check the databases of every tree, returns false if problems are found
+///
+/// Flapping packages and mixed branch names are only warned about.
+async fn doctor(config: &Config) -> Result<bool> {
+    let mut healthy = true;
+    for (table, count) in malformed_hashes(&config.global).await? {
+        if count > 0 {
+            error!("{table}: {count} rows with malformed commit hashes");
+            healthy = false;
+        }
+    }
+    for repo in &config.repo {
+        let abbs_db = AbbsDb::open_read_only(&config.global, repo).await?;
+        for violation in abbs_db.check_integrity().await? {
+            error!(
+                "{}: {} references nonexistent package {}",
+                repo.name, violation.table, violation.package
+            );
+            healthy = false;
+        }
+        for pkg in abbs_db.get_flapping().await? {
+            warn!(
+                "{}: {} switched between updated and deleted {} times in {} recent runs",
+                repo.name, pkg.package, pkg.flaps, pkg.runs
+            );
+        }
+        for (table, branch) in abbs_db.get_mixed_branch_names().await? {
+            warn!(
+                "{}: {table} has rows of both {branch} and origin/{branch}",
+                repo.name
+            );
+        }
+    }
+
+    Ok(healthy)
+}
+
+/// Warn if no remote of the repository has the configured url, either original or rewritten
+fn check_remote_url(global_config: &Global, repo_config: &Repo, repo: &Repository) {
+    let fetch_url = repo_config.fetch_url(&global_config.url_rewrites);
+    let expected = [repo_config.url.as_str(), fetch_url.as_str()].map(|url| {
+        url.trim_end_matches('/')
+            .trim_end_matches(".git")
+            .to_string()
+    });
+
+    let git2repo = repo.get_git2repo();
+    let Ok(remotes) = git2repo.remotes() else {
+        return;
+    };
+    let urls = remotes
+        .iter()
+        .flatten()
+        .filter_map(|name| Some(git2repo.find_remote(name).ok()?.url()?.to_string()))
+        .collect_vec();
+    if urls.is_empty() {
+        return;
+    }
+
+    let matched = urls.iter().any(|url| {
+        let url = url.trim_end_matches('/').trim_end_matches(".git");
+        expected.iter().any(|expected| expected == url)
+    });
+    if !matched {
+        warn!(
+            "remotes of {} ({}) don't match the configured url {fetch_url}",
+            repo_config.name,
+            urls.join(", ")
+        );
+    }
+}
+
+/// Refuse to scan a shallow clone unless allowed, its history is truncated
+fn check_shallow(global_config: &Global, repo_config: &Repo, repo: &Repository) -> Result<()> {
+    if !repo.is_shallow() {
+        return Ok(());
+    }
+
+    if !global_config.allow_shallow {
+        bail!(
+            "{} at {} is a shallow clone, changes before its shallow boundary can't be found; \
+             run `git fetch --unshallow` in it or set global.allow_shallow",
+            repo_config.name,
+            repo_config.repo_path
+        );
+    }
+    warn!(
+        "!!! {} at {} is a shallow clone, changelogs are limited to the fetched history !!!",
+        repo_config.name, repo_config.repo_path
+    );
+
+    Ok(())
+}
+
+/// Open the abbs database, recovering from corruption as configured by corruption_policy
+async fn open_abbs_db(
+    global_config: &Global,
+    repo_config: &Repo,
+    report: &mut ScanReport,
+) -> Result<AbbsDb> {
+    let e = match AbbsDb::open(global_config, repo_config).await {
+        Err(e) if is_corrupted(&e) => e,
+        res => return res,
+    };
+
+    let recovery = match global_config.corruption_policy {
+        CorruptionPolicy::Fail => bail!(
+            "{} is corrupted in {}: {e}; restore the database from a backup, or set \
+             global.corruption_policy to \"reindex\" or \"restore-latest-snapshot\"",
+            repo_config.name,
+            global_config.database_url
+        ),
+        CorruptionPolicy::Reindex => {
+            error!(
+                "!!! {} is corrupted: {e}, rebuilding all indexes !!!",
+                repo_config.name
+            );
+            reindex(&global_config.database_url, &global_config.performance).await?;
+            "rebuilt all indexes".to_string()
+        }
+        CorruptionPolicy::RestoreLatestSnapshot => {
+            let store = SnapshotStore::open(global_config, repo_config).await?;
+            let Some(snapshot) = store.list().await?.into_iter().rev().find(|s| s.compatible)
+            else {
+                bail!(
+                    "{} is corrupted: {e}, and has no snapshot to restore",
+                    repo_config.name
+                );
+            };
+            error!(
+                "!!! {} is corrupted: {e}, restoring snapshot {} of {} !!!",
+                repo_config.name, snapshot.name, snapshot.created_at
+            );
+            store.restore(&snapshot.name).await?;
+            // histories of the branch are newer than the snapshot, rescan all of its commits
+            CommitDb::open(global_config)
+                .await?
+                .reset_branch_history(&repo_config.tree_id(), &repo_config.branch)
+                .await?;
+            format!("restored snapshot {}", snapshot.name)
+        }
+    };
+    report.recovery = Some(recovery);
+
+    AbbsDb::open(global_config, repo_config).await
+}
+
+/// Abort before writing anything if the disk is going to be full
+async fn check_disk_space(
+    global_config: &Global,
+    repo_config: &Repo,
+    repo: &Repository,
+) -> Result<StorageSizes> {
+    let (new_commits, database_bytes) = CommitDb::count_new_commits(global_config, repo).await?;
+    let object_db_bytes = repo.object_db_size()?;
+    info!(
+        "object database size: {} MiB, database size: {} MiB",
+        disk::to_mib(object_db_bytes),
+        disk::to_mib(database_bytes)
+    );
+
+    let new_commits = new_commits as u64;
+    let estimated = new_commits * global_config.disk_kib_per_commit * 1024;
+    let reserve = global_config.disk_reserve_mb * 1024 * 1024;
+
+    let mut paths = vec![repo_config.repo_path.as_str()];
+    if let Some(database_dir) = &global_config.database_dir {
+        paths.push(database_dir);
+    }
+
+    disk::preflight(&paths, estimated, reserve)?;
+
+    Ok(StorageSizes {
+        object_db_bytes,
+        database_bytes,
+        new_commits,
+        estimated_bytes: estimated,
+    })
+}
+
+fn init_log(multi: &MultiProgress) {
+    let multi = multi.clone();
     tracing_subscriber::fmt()
         .with_env_filter("sqlx::query=info,abbs_meta=info")
+        .with_writer(move || LogWriter(multi.clone()))
         .with_file(true)
         .with_line_number(true)
         .init();
 }
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abbs_meta::test_support::FixtureRepo;
+    use common::{add_package, TestDb};
+    use sea_orm::ConnectionTrait;
+    use std::fs;
+
+    /// Every row of every table of a database, in a stable order
+    async fn dump(db: &TestDb) -> Vec<String> {
+        let mut rows = vec![];
+        for table in db
+            .column(
+                "SELECT table_name::text FROM information_schema.tables \
+                 WHERE table_schema = 'public' ORDER BY table_name",
+            )
+            .await
+        {
+            rows.push(table.clone());
+            rows.extend(
+                db.column(&format!(
+                    "SELECT t::text FROM \"{table}\" t ORDER BY t::text COLLATE \"C\""
+                ))
+                .await,
+            );
+        }
+
+        rows
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90d").unwrap(), chrono::Duration::days(90));
+        assert_eq!(parse_age("8w").unwrap(), chrono::Duration::days(56));
+        assert_eq!(parse_age("18months").unwrap(), chrono::Duration::days(540));
+        assert_eq!(parse_age("1y").unwrap(), chrono::Duration::days(365));
+        assert!(parse_age("18").is_err());
+        assert!(parse_age("months").is_err());
+        assert!(parse_age("2fortnights").is_err());
+    }
+
+    #[test]
+    fn test_check_shallow() -> Result<()> {
+        let mut fixture = FixtureRepo::new("stable")?;
+        fixture.write_file("README", "abbs\n")?;
+        let head = fixture.commit("Initial commit", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        let global = |extra: &str| -> Result<Global> {
+            Ok(toml::from_str(&format!(
+                "database_url = \"postgres:///abbs\"\n{extra}"
+            ))?)
+        };
+
+        let repo = Repository::open(&repo_config)?;
+        assert!(check_shallow(&global("")?, &repo_config, &repo).is_ok());
+
+        // like a clone made with git clone --depth 1
+        fs::write(fixture.path().join(".git/shallow"), format!("{head}\n"))?;
+        let repo = Repository::open(&repo_config)?;
+        let e =
+            check_shallow(&global("")?, &repo_config, &repo).expect_err("scanned a shallow clone");
+        assert!(e.to_string().contains("git fetch --unshallow"), "{e}");
+        assert!(check_shallow(&global("allow_shallow = true")?, &repo_config, &repo).is_ok());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dry_run_writes_nothing() -> Result<()> {
+        let (Some(db), Some(commits)) = (TestDb::new().await, TestDb::new().await) else {
+            return Ok(());
+        };
+        let global = db.global_with(&format!("commits_database_url = \"{}\"", commits.url));
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+        fixture.commit("foo, bar: new", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        let scan = |dry_run| {
+            let options = ScanOptions {
+                dry_run,
+                ..Default::default()
+            };
+            let (global, repo_config) = (&global, &repo_config);
+            async move {
+                do_scan_and_update(global, repo_config, "", &options, Progress::hidden()).await
+            }
+        };
+        scan(false).await?;
+
+        add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+        fixture.remove_package("app-utils/bar")?;
+        add_package(&mut fixture, "app-utils", "baz", "3.0", "")?;
+        fixture.commit("foo: update to 1.1\nbar: drop\nbaz: new, 3.0", "Bob")?;
+        let before = (dump(&db).await, dump(&commits).await);
+        assert!(before.1.contains(&"commits".to_string()), "{:?}", before.1);
+
+        let report = scan(true).await?;
+        assert!(report.dry_run);
+        assert_eq!(report.updated, ["baz", "foo"]);
+        assert_eq!(report.deleted, ["bar"]);
+        assert!(report.storage.is_none(), "disk space is not checked");
+        assert_eq!((dump(&db).await, dump(&commits).await), before);
+
+        let report = scan(false).await?;
+        assert_eq!(report.updated, ["baz", "foo"]);
+        assert_ne!(dump(&db).await, before.0);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_scan_events() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        // without PKGDES, bar has an error
+        fixture.add_package("app-utils", "bar", &common::spec("2.0"), "PKGNAME=bar\n")?;
+        fixture.commit("foo, bar: new", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = events.clone();
+        let progress = Progress::hidden()
+            .with_event_handler(move |event| recorded.lock().unwrap().push(event));
+
+        let options = ScanOptions::default();
+        do_scan_and_update(&global, &repo_config, "", &options, progress.clone()).await?;
+        assert_eq!(progress.flush_events(), 0);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ScanEvent::RepoStarted {
+                    repo: "aosc-os-abbs".to_string(),
+                    branch: "stable".to_string(),
+                },
+                ScanEvent::CommitsScanned { done: 1, total: 1 },
+                ScanEvent::BranchScanned {
+                    branch: "stable".to_string(),
+                    packages: 2,
+                },
+                ScanEvent::PackageWritten {
+                    package: "bar".to_string(),
+                    done: 1,
+                    total: 2,
+                },
+                ScanEvent::ErrorsRecorded {
+                    package: "bar".to_string(),
+                    count: 1,
+                },
+                ScanEvent::PackageWritten {
+                    package: "foo".to_string(),
+                    done: 2,
+                    total: 2,
+                },
+                ScanEvent::RepoFinished {
+                    repo: "aosc-os-abbs".to_string(),
+                    updated: 2,
+                    deleted: 0,
+                    errors: 1,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// Make the next write to trees, done by every [AbbsDb::open], fail as corrupted once
+    async fn corrupt_once(db: &TestDb) {
+        let conn = db.connect().await;
+        conn.execute_unprepared(
+            "DROP SEQUENCE IF EXISTS corrupt_once;
+             CREATE SEQUENCE corrupt_once;
+             CREATE OR REPLACE FUNCTION corrupt_once() RETURNS trigger AS $$ BEGIN
+                 IF nextval('corrupt_once') = 1 THEN
+                     RAISE EXCEPTION 'injected' USING ERRCODE = 'data_corrupted';
+                 END IF;
+                 RETURN NEW;
+             END $$ LANGUAGE plpgsql;
+             DROP TRIGGER IF EXISTS corrupt_once ON trees;
+             CREATE TRIGGER corrupt_once BEFORE INSERT OR UPDATE ON trees
+                 FOR EACH ROW EXECUTE FUNCTION corrupt_once();",
+        )
+        .await
+        .expect("failed to inject corruption");
+    }
+
+    /// Scan foo 1.0 and bar 2.0, then update foo to 1.1 without scanning it
+    async fn corruption_fixture(db: &TestDb, policy: &str) -> Result<(FixtureRepo, Global, Repo)> {
+        let global = db.global_with(&format!("corruption_policy = \"{policy}\""));
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+        fixture.commit("foo, bar: new", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        let options = ScanOptions::default();
+        do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+        fixture.commit("foo: update to 1.1", "Alice")?;
+
+        Ok((fixture, global, repo_config))
+    }
+
+    #[async_std::test]
+    async fn test_corruption_policy_fail() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let (_fixture, global, repo_config) = corruption_fixture(&db, "fail").await?;
+        corrupt_once(&db).await;
+
+        let options = ScanOptions::default();
+        let e = do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden())
+            .await
+            .expect_err("scanned a corrupted database");
+        assert!(e.to_string().contains("is corrupted"), "{e}");
+        assert!(e.to_string().contains("corruption_policy"), "{e}");
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_corruption_policy_reindex() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let (_fixture, global, repo_config) = corruption_fixture(&db, "reindex").await?;
+        corrupt_once(&db).await;
+
+        let options = ScanOptions::default();
+        let report =
+            do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        assert_eq!(report.recovery.as_deref(), Some("rebuilt all indexes"));
+        assert_eq!(report.updated, ["foo"]);
+        assert_eq!(
+            db.column("SELECT recovery FROM collector_meta ORDER BY id")
+                .await,
+            ["", "rebuilt all indexes"]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_corruption_policy_restore_latest_snapshot() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let (mut fixture, global, repo_config) =
+            corruption_fixture(&db, "restore-latest-snapshot").await?;
+        corrupt_once(&db).await;
+        let options = ScanOptions::default();
+        let e = do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden())
+            .await
+            .expect_err("recovered without a snapshot");
+        assert!(e.to_string().contains("has no snapshot to restore"), "{e}");
+
+        // the snapshot has foo 1.0 and bar, taken before bar is dropped and foo updated
+        let store = SnapshotStore::open(&global, &repo_config).await?;
+        store.create("before", false).await?;
+        fixture.remove_package("app-utils/bar")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.2", "")?;
+        fixture.commit("bar: drop\nfoo: update to 1.2", "Bob")?;
+        do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        corrupt_once(&db).await;
+
+        let report =
+            do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        assert_eq!(report.recovery.as_deref(), Some("restored snapshot before"));
+        // every commit is scanned again, not only those after the restored data
+        assert_eq!(report.updated, ["foo"]);
+        assert_eq!(report.deleted, ["bar"]);
+        assert_eq!(
+            db.column("SELECT name || ' ' || version FROM v_packages ORDER BY name")
+                .await,
+            ["foo 1.2"]
+        );
+        assert_eq!(
+            db.column("SELECT recovery FROM collector_meta WHERE recovery IS NOT NULL")
+                .await,
+            ["restored snapshot before"]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_doctor_finds_orphans() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        fixture.commit("foo: new, 1.0", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        common::scan(&global, &repo_config).await?;
+        let config = Config {
+            global,
+            repo: vec![repo_config],
+        };
+        assert!(doctor(&config).await?);
+
+        // a version left behind by a package deleted without its rows
+        db.connect()
+            .await
+            .execute_unprepared(
+                "INSERT INTO package_versions \
+                 (package, branch, version, commit_time, committer, githash, full_version) \
+                 SELECT 'ghost', branch, version, commit_time, committer, githash, full_version \
+                 FROM package_versions WHERE package = 'foo'",
+            )
+            .await?;
+        assert!(!doctor(&config).await?);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_batches_do_not_block_readers() -> Result<()> {
+        use sea_orm::Statement;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global_with("batch_threshold = 0");
+        let mut fixture = FixtureRepo::new("stable")?;
+        let total = ADD_BATCH_SIZE + 44;
+        for i in 0..total {
+            add_package(&mut fixture, "app-utils", &format!("pkg{i}"), "1.0", "")?;
+        }
+        fixture.commit("a lot of packages: new", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        // the tables are created before reading them
+        AbbsDb::open(&global, &repo_config).await?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let conn = db.connect().await;
+        let reader = task::spawn({
+            let done = done.clone();
+            async move {
+                let mut samples = vec![];
+                while !done.load(Ordering::Relaxed) {
+                    let start = Instant::now();
+                    let row = conn
+                        .query_one(Statement::from_string(
+                            conn.get_database_backend(),
+                            "SELECT count(*) FROM packages".to_string(),
+                        ))
+                        .await?
+                        .context("no count")?;
+                    let count = row.try_get_by_index::<i64>(0)? as usize;
+                    samples.push((count, start.elapsed()));
+                    task::sleep(Duration::from_millis(5)).await;
+                }
+                anyhow::Ok(samples)
+            }
+        });
+        let report = do_scan_and_update(
+            &global,
+            &repo_config,
+            "",
+            &ScanOptions::default(),
+            Progress::hidden(),
+        )
+        .await;
+        done.store(true, Ordering::Relaxed);
+        let samples = reader.await?;
+        assert_eq!(report?.updated.len(), total);
+
+        // readers see whole batches only, and never wait for one to commit
+        assert!(samples.len() > 1, "{samples:?}");
+        for (count, latency) in samples {
+            assert!([0, ADD_BATCH_SIZE, total].contains(&count), "{count}");
+            assert!(latency < Duration::from_secs(1), "{latency:?}");
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_disabled_testing_branches_are_cleared() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        fixture.commit("foo: new, 1.0", "Alice")?;
+        fixture.branch("foo-1.1")?;
+        let broken = format!("{}not an assignment\n", common::spec("1.1"));
+        fixture.add_package("app-utils", "foo", &broken, &common::defines("foo", ""))?;
+        fixture.commit("foo: update to 1.1", "Bob")?;
+        fixture.checkout("stable")?;
+        let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        let options = ScanOptions::default();
+        let testing_rows = "SELECT 'package_testing ' || branch FROM package_testing \
+                            UNION ALL SELECT 'package_errors ' || branch FROM package_errors \
+                            ORDER BY 1";
+
+        do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        assert_eq!(
+            db.column(testing_rows).await,
+            ["package_errors foo-1.1", "package_testing foo-1.1"]
+        );
+
+        repo_config.scan_testing_branches = false;
+        do_scan_and_update(&global, &repo_config, "", &options, Progress::hidden()).await?;
+        assert!(db.column(testing_rows).await.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_old_testing_branches_are_skipped() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "bar", "1.0", "")?;
+        fixture.commit("foo, bar: new, 1.0", "Alice")?;
+        fixture.branch("foo-1.1")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+        fixture.commit("foo: update to 1.1", "Bob")?;
+        fixture.checkout("stable")?;
+        fixture.branch("bar-1.1")?;
+        fixture.set_time(chrono::Local::now().timestamp() - 24 * 3600);
+        add_package(&mut fixture, "app-utils", "bar", "1.1", "")?;
+        fixture.commit("bar: update to 1.1", "Bob")?;
+        fixture.checkout("stable")?;
+        let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        repo_config.testing_branch_max_age_days = Some(30);
+
+        let report = do_scan_and_update(
+            &global,
+            &repo_config,
+            "",
+            &ScanOptions::default(),
+            Progress::hidden(),
+        )
+        .await?;
+        assert_eq!(report.skipped_branches, ["foo-1.1"]);
+        assert_eq!(
+            db.column("SELECT package || ' ' || branch FROM package_testing")
+                .await,
+            ["bar bar-1.1"]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_mark_synced() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "bar", "1.0", "")?;
+        let first = fixture.commit("foo, bar: new, 1.0", "Alice")?;
+        add_package(&mut fixture, "app-utils", "bar", "1.1", "")?;
+        fixture.commit("bar: update to 1.1", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        common::scan(&global, &repo_config).await?;
+        let config = Config {
+            global: global.clone(),
+            repo: vec![repo_config.clone()],
+        };
+        let out_of_sync = || async {
+            let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+            let mut res = vec![];
+            for name in ["bar", "foo"] {
+                let pkg = abbs_db
+                    .get_package(name)
+                    .await?
+                    .context("missing package")?;
+                res.push((name, pkg.out_of_sync));
+            }
+            anyhow::Ok(res)
+        };
+        assert_eq!(out_of_sync().await?, [("bar", true), ("foo", true)]);
+
+        // bar is synced at a commit before its latest change
+        let synced = format!(
+            r#"[{{"package": "foo", "githash": "{first}"}}, {{"package": "bar", "githash": "{first}"}}]"#
+        );
+        mark_synced(&config, &synced, None, "build", false).await?;
+        assert_eq!(out_of_sync().await?, [("bar", true), ("foo", false)]);
+        assert_eq!(
+            db.column("SELECT package || ' ' || source FROM package_sync_status ORDER BY 1")
+                .await,
+            ["bar build", "foo build"]
+        );
+
+        let unknown =
+            r#"[{"package": "foo", "githash": "0123456789abcdef0123456789abcdef01234567"}]"#;
+        let e = mark_synced(&config, unknown, None, "build", false)
+            .await
+            .expect_err("accepted an unknown commit");
+        assert!(e.to_string().contains("--force"), "{e}");
+        assert_eq!(out_of_sync().await?, [("bar", true), ("foo", false)]);
+        mark_synced(&config, unknown, None, "manual", true).await?;
+        assert_eq!(out_of_sync().await?, [("bar", true), ("foo", true)]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_commit_lists_packages() -> Result<()> {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+        let commit = fixture.commit("foo, bar: new", "Alice")?.to_string();
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        common::scan(&global, &repo_config).await?;
+        let mut config = Config {
+            global,
+            repo: vec![repo_config],
+        };
+        async fn packages(config: &Config, hash: &str) -> Result<Vec<String>> {
+            let packages = commit_packages(config, hash, None).await?;
+            Ok(packages
+                .into_iter()
+                .map(|pkg| {
+                    format!(
+                        "{} {} {} {} {}",
+                        pkg.commit_id,
+                        pkg.pkg_name,
+                        pkg.pkg_version,
+                        pkg.defines_path,
+                        pkg.spec_path
+                    )
+                })
+                .collect())
+        }
+        let expected = [
+            format!("{commit} bar 2.0 app-utils/bar/autobuild/defines app-utils/bar/spec"),
+            format!("{commit} foo 1.0 app-utils/foo/autobuild/defines app-utils/foo/spec"),
+        ];
+
+        assert_eq!(packages(&config, &commit).await?, expected);
+        // abbreviated hashes are resolved in the repository
+        assert_eq!(packages(&config, &commit[..7]).await?, expected);
+        assert!(packages(&config, "0000000").await?.is_empty());
+
+        // or matched as prefixes without it
+        config.repo[0].repo_path = "/nonexistent".to_string();
+        assert_eq!(
+            packages(&config, &commit[..7].to_uppercase()).await?,
+            expected
+        );
+        assert!(packages(&config, "0000000").await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -1,33 +1,371 @@
 use crate::db::abbs::ErrorType;
 use crate::db::abbs::PackageError;
-use crate::git::Repository;
-use crate::skip_none;
+use crate::db::dependency::{relationships, typed_dependencies, Dependencies};
+use crate::db::get_full_version;
+use crate::git::{Repository, SyncRepository};
 use abbs_meta_apml::parse;
 use abbs_meta_tree::Package;
 use anyhow::Context as AnyhowContext;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use git2::Oid;
 use git2::TreeWalkResult;
-use itertools::Itertools;
+use itertools::{Either, Itertools};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 use std::{collections::HashMap, path::PathBuf};
+use thread_local::ThreadLocal;
+use tracing::info;
 pub type Context = HashMap<String, String>;
-pub type Meta = (Package, Context, Vec<PackageError>);
+pub type Meta = (Package, Context, Vec<PackageError>, VersionSource);
+pub type ScanResult = (Option<(Package, Context, VersionSource)>, Vec<PackageError>);
 
+/// Where the version of a package comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionSource {
+    /// VER in spec
+    Spec,
+    /// PKGVER in defines, overriding VER in spec
+    Defines,
+    /// kept from an earlier scan, as the package failed to parse
+    Preserved,
+}
+
+impl VersionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Spec => "spec",
+            Self::Defines => "defines",
+            Self::Preserved => "preserved",
+        }
+    }
+}
+
+/// Where the effective section of a package comes from, see [section_source]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionSource {
+    /// the section directory, like admin of app-admin/htop
+    Directory,
+    /// PKGSEC in defines, overriding the section directory
+    Pkgsec,
+}
+
+impl SectionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Directory => "directory",
+            Self::Pkgsec => "pkgsec",
+        }
+    }
+}
+
+/// Decide where the effective section of a package comes from, a non-empty
+/// PKGSEC wins over the section directory
+pub fn section_source(context: &Context) -> SectionSource {
+    match context.get("PKGSEC") {
+        Some(pkgsec) if !pkgsec.trim().is_empty() => SectionSource::Pkgsec,
+        _ => SectionSource::Directory,
+    }
+}
+
+/// Group defines by their spec, ordered by the paths of both
+///
+/// Results follow this order rather than the order of the hash map behind the
+/// grouping, so scans of the same commit are identical.
+fn group_by_spec<P: Ord + Eq + std::hash::Hash>(
+    pkg_dirs: impl IntoIterator<Item = (P, P)>,
+) -> Vec<(P, Vec<P>)> {
+    pkg_dirs
+        .into_iter()
+        .into_group_map()
+        .into_iter()
+        .map(|(spec, mut defines)| {
+            defines.sort();
+            (spec, defines)
+        })
+        .sorted_by(|(left, _), (right, _)| left.cmp(right))
+        .collect()
+}
+
+/// Scan packages, names of packages which failed to parse are returned as well
+///
+/// Packages are ordered by the paths of their spec and defines.
 pub fn scan_packages(
     repo: &Repository,
     commit: Oid,
     pkg_dirs: Vec<(&PathBuf, &PathBuf)>,
-) -> Vec<Meta> {
-    pkg_dirs
+    architectures: &[String],
+) -> (Vec<Meta>, Vec<String>) {
+    // parse each spec only once for all of its subpackages
+    group_by_spec(pkg_dirs)
+        .into_iter()
+        .flat_map(|(spec, defines)| scan_group(repo, commit, spec, &defines, architectures))
+        .partition_map(|res| match res {
+            Ok(meta) => Either::Left(meta),
+            Err(name) => Either::Right(name),
+        })
+}
+
+/// Scan every package of the tree at the commit in parallel, like [scan_packages]
+///
+/// Packages are discovered by their defines files. Only packages in
+/// directories starting with one of `sections`, like `app-`, are scanned, or
+/// all of them if empty. Hidden directories and groups are skipped silently.
+///
+/// No database is read or written, this is the entry point for tools which
+/// only need the packages of a tree. Returns the parsed packages with their
+/// errors in the order of [scan_packages], and the names of packages which
+/// failed to parse.
+pub fn scan_tree(
+    repo: &Repository,
+    commit: Oid,
+    sections: &[String],
+    architectures: &[String],
+) -> Result<(Vec<Meta>, Vec<String>)> {
+    let groups = repo
+        .walk_commit(commit)?
+        .into_iter()
+        .filter(|path| path.file_name() == Some(OsStr::new("defines")))
+        .filter(|path| !is_irrelevant_path(path))
+        .filter(|path| {
+            let dir = path
+                .components()
+                .next()
+                .map(|c| c.as_os_str().to_string_lossy());
+            sections.is_empty()
+                || dir.is_some_and(|dir| sections.iter().any(|s| dir.starts_with(s.as_str())))
+        })
+        .filter_map(|defines| Some((defines_path_to_spec_path(&defines).ok()?, defines)));
+    let groups = group_by_spec(groups);
+    info!("scanning {} specs at {commit}", groups.len());
+
+    let sync_repo: &SyncRepository = &repo.into();
+    let local_repo: ThreadLocal<Repository> = ThreadLocal::new();
+    let res = groups
+        .into_par_iter()
+        .flat_map_iter(|(spec, defines)| {
+            let repo = local_repo.get_or(|| sync_repo.try_into().unwrap());
+            let defines = defines.iter().collect_vec();
+            scan_group(repo, commit, &spec, &defines, architectures)
+        })
+        .partition_map(|res| match res {
+            Ok(meta) => Either::Left(meta),
+            Err(name) => Either::Right(name),
+        });
+
+    Ok(res)
+}
+
+/// Scan packages sharing a spec, returns the names of packages which failed to parse as errors
+fn scan_group(
+    repo: &Repository,
+    commit: Oid,
+    spec: &PathBuf,
+    defines: &[&PathBuf],
+    architectures: &[String],
+) -> Vec<std::result::Result<Meta, String>> {
+    defines
         .iter()
-        .filter_map(|(spec, defines)| {
-            let (pkg, errors) = scan_package(repo, commit, spec, defines);
-            let pkg = pkg?;
-            Some((pkg.0, pkg.1, errors))
+        .map(|defines| package_name(defines).unwrap_or_default().to_string())
+        .zip(scan_spec_packages(
+            repo,
+            commit,
+            spec,
+            defines,
+            architectures,
+        ))
+        .map(|(name, (pkg, errors))| match pkg {
+            Some((pkg, context, source)) => Ok((pkg, context, errors, source)),
+            None => Err(name),
         })
-        .collect_vec()
+        .collect()
+}
+
+/// A parsed package as written by the dump subcommand
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageDump {
+    pub name: String,
+    pub category: String,
+    pub section: String,
+    pub pkg_section: String,
+    pub directory: String,
+    pub spec_path: String,
+    pub description: String,
+    pub full_version: String,
+    pub version_source: &'static str,
+    /// dependencies of each relationship, like PKGDEP
+    pub dependencies: BTreeMap<&'static str, Dependencies>,
+    /// all keys of spec and defines
+    pub spec: BTreeMap<String, String>,
+    pub errors: Vec<PackageError>,
+}
+
+impl From<Meta> for PackageDump {
+    fn from((pkg, context, mut errors, source): Meta) -> Self {
+        let full_version = get_full_version(&pkg);
+        let (typed, invalid) = typed_relationships(&pkg);
+        errors.extend(invalid);
+        let dependencies = typed
+            .into_iter()
+            .filter(|(_, deps)| !deps.is_empty())
+            .collect();
+
+        Self {
+            full_version,
+            name: pkg.name,
+            category: pkg.category,
+            section: pkg.section,
+            pkg_section: pkg.pkg_section,
+            directory: pkg.directory,
+            spec_path: pkg.spec_path,
+            description: pkg.description,
+            version_source: source.as_str(),
+            dependencies,
+            spec: context.into_iter().collect(),
+            errors,
+        }
+    }
+}
+
+/// Keys of spec and defines read by abbs-meta-tree and this crate
+///
+/// VER and REL of spec are saved as PKGVER and PKGREL, see [spec_decorator].
+pub const KNOWN_SPEC_KEYS: [&str; 20] = [
+    "PKGNAME",
+    "PKGSEC",
+    "PKGDES",
+    "PKGVER",
+    "PKGREL",
+    "PKGEPOCH",
+    "PKGDEP",
+    "BUILDDEP",
+    "PKGSUG",
+    "PKGPROV",
+    "PKGRECOM",
+    "PKGREP",
+    "PKGBREAK",
+    "PKGCONFIG",
+    "SRCS",
+    "CHKSUMS",
+    "CHKUPDATE",
+    "DUMMYSRC",
+    "SUBDIR",
+    "FAIL_ARCH",
+];
+
+/// Unknown keys within this edit distance of a known key are reported as misspelled
+const MISSPELLING_DISTANCE: usize = 2;
+
+/// Keys of a package which no parser reads
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnknownKeys {
+    /// (key, known key it is likely a misspelling of)
+    pub misspelled: Vec<(String, String)>,
+    /// number of other unknown keys, like variables of autobuild
+    pub other: usize,
+}
+
+/// Compare the keys of the context with [KNOWN_SPEC_KEYS] and `extra_keys`
+///
+/// Architecture suffixes like __AMD64 are ignored, and keys starting with an
+/// underscore are helper variables by convention.
+pub fn unknown_keys(context: &Context, extra_keys: &[String]) -> UnknownKeys {
+    let known = KNOWN_SPEC_KEYS
+        .into_iter()
+        .chain(extra_keys.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    let mut res = UnknownKeys::default();
+    for key in context.keys().sorted() {
+        let base = key.split_once("__").map_or(key.as_str(), |(base, _)| base);
+        if base.is_empty() || base.starts_with('_') || known.contains(&base) {
+            continue;
+        }
+
+        let closest = known
+            .iter()
+            .map(|known| (edit_distance(base, known), *known))
+            .filter(|(distance, _)| *distance <= MISSPELLING_DISTANCE)
+            .min();
+        match closest {
+            Some((_, known)) => res.misspelled.push((key.clone(), known.to_string())),
+            None => res.other += 1,
+        }
+    }
+
+    res
+}
+
+/// Levenshtein distance of two keys
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect_vec();
+    let mut row = (0..=b.len()).collect_vec();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Relationships a package can't have with itself
+const SELF_DEPENDENCY_RELATIONSHIPS: [&str; 2] = ["PKGDEP", "BUILDDEP"];
+
+/// Typed dependencies of each relationship of the package
+///
+/// Tokens which can't be parsed are left out and returned as package errors,
+/// so no garbage rows are written. Packages depending on themselves are
+/// reported too, their dependencies are kept.
+pub fn typed_relationships(
+    pkg: &Package,
+) -> (Vec<(&'static str, Dependencies)>, Vec<PackageError>) {
+    let error = |message| PackageError {
+        package: pkg.name.clone(),
+        path: pkg.spec_path.clone(),
+        message,
+        err_type: ErrorType::Package,
+        line: None,
+        col: None,
+        end_line: None,
+        end_col: None,
+    };
+    let mut errors = vec![];
+    let mut res = vec![];
+    for (relationship, pkgdep) in relationships(pkg) {
+        let (deps, invalid) = typed_dependencies(pkgdep.clone());
+        errors.extend(
+            invalid
+                .into_iter()
+                .map(|e| error(format!("{relationship}: {e}"))),
+        );
+        if SELF_DEPENDENCY_RELATIONSHIPS.contains(&relationship)
+            && deps
+                .values()
+                .flatten()
+                .any(|dependency| dependency.name == pkg.name)
+        {
+            errors.push(error(format!(
+                "{relationship}: {} depends on itself",
+                pkg.name
+            )));
+        }
+        res.push((relationship, deps));
+    }
+
+    (res, errors)
+}
+
+/// extra-doc/jade/autobuild/defines -> jade
+fn package_name(defines_path: &Path) -> Option<&str> {
+    defines_path.iter().nth_back(2)?.to_str()
 }
 
 #[inline(always)]
@@ -36,87 +374,233 @@ pub fn scan_package(
     commit: Oid,
     spec_path: &PathBuf,
     defines_path: &PathBuf,
-) -> (Option<(Package, Context)>, Vec<PackageError>) {
-    macro_rules! skip_none {
-        ($res:expr) => {
-            match $res {
-                Some(val) => val,
-                None => return (None, vec![]),
+    architectures: &[String],
+) -> ScanResult {
+    scan_spec_packages(repo, commit, spec_path, &[defines_path], architectures)
+        .pop()
+        .unwrap_or_default()
+}
+
+/// Scan packages sharing the same spec, the result is in the order of `defines_paths`
+///
+/// The spec is read and parsed only once, and its errors are attributed to the
+/// first package whose defines can be read. See [parse_defines] for `architectures`.
+pub fn scan_spec_packages(
+    repo: &Repository,
+    commit: Oid,
+    spec_path: &PathBuf,
+    defines_paths: &[&PathBuf],
+    architectures: &[String],
+) -> Vec<ScanResult> {
+    let Some((base_context, mut spec_errors)) = parse_spec(repo, commit, spec_path) else {
+        return defines_paths.iter().map(|_| (None, vec![])).collect();
+    };
+
+    defines_paths
+        .iter()
+        .map(|defines_path| {
+            macro_rules! skip_none {
+                ($res:expr) => {
+                    match $res {
+                        Some(val) => val,
+                        None => return (None, vec![]),
+                    }
+                };
             }
-        };
-    }
-
-    let (context, mut errors) = skip_none!(parse_spec_and_defines(
-        repo,
-        commit,
-        spec_path,
-        defines_path,
-    ));
-
-    match Package::from(&context, spec_path) {
-        Ok(pkg) => (Some((pkg, context)), errors),
-        Err(e) => {
-            let pkg_name = skip_none!(skip_none!(defines_path.iter().nth_back(2)).to_str());
-
-            // extra-doc/jade/autobuild/defines -> extra-doc/jade
-            let path = skip_none!(skip_none!(defines_path.ancestors().nth(2)).to_str()).to_string();
-            errors.push(PackageError {
-                package: pkg_name.to_string(),
-                path,
-                message: e.to_string(),
-                err_type: ErrorType::Package,
-                line: None,
-                col: None,
-            });
-            (None, errors)
-        }
-    }
+
+            let pkg_name = skip_none!(package_name(defines_path));
+            let mut context = base_context.clone();
+            // Modify context so that defines can understand
+            spec_decorator(&mut context);
+            let spec_version = context.get("PKGVER").cloned();
+            let defines_errors = skip_none!(parse_defines(
+                repo,
+                commit,
+                defines_path,
+                &mut context,
+                architectures
+            ));
+            let source = if context.get("PKGVER") == spec_version.as_ref() {
+                VersionSource::Spec
+            } else {
+                VersionSource::Defines
+            };
+
+            let mut errors = spec_errors
+                .drain(..)
+                .map(|e| PackageError {
+                    package: pkg_name.to_string(),
+                    ..e
+                })
+                .collect_vec();
+            errors.extend(defines_errors);
+
+            match Package::from(&context, spec_path) {
+                Ok(pkg) => (Some((pkg, context, source)), errors),
+                Err(e) => {
+                    // extra-doc/jade/autobuild/defines -> extra-doc/jade
+                    let path = skip_none!(skip_none!(defines_path.ancestors().nth(2)).to_str())
+                        .to_string();
+                    errors.push(PackageError {
+                        package: pkg_name.to_string(),
+                        path,
+                        message: e.to_string(),
+                        err_type: ErrorType::Package,
+                        line: None,
+                        col: None,
+                        end_line: None,
+                        end_col: None,
+                    });
+                    (None, errors)
+                }
+            }
+        })
+        .collect()
 }
 
-fn parse_spec_and_defines(
+/// Parse spec into a base context, package names of the errors are left empty
+fn parse_spec(
     repo: &Repository,
     commit: Oid,
     spec_path: &PathBuf,
-    defines_path: &PathBuf,
 ) -> Option<(Context, Vec<PackageError>)> {
     let spec = repo.read_file(spec_path, commit).ok()?;
-    let defines = repo.read_file(defines_path, commit).ok()?;
     let mut context = Context::new();
+    let errors = parse_apml(&spec, &mut context, "", spec_path);
+
+    Some((context, errors))
+}
+
+/// Parse defines on top of the context from spec
+///
+/// Defines referencing `ARCH` or `CROSS` are parsed again for each of the
+/// architectures, and values differing from the default parse are added with
+/// the architecture suffix, e.g. `PKGDEP__AMD64`.
+fn parse_defines(
+    repo: &Repository,
+    commit: Oid,
+    defines_path: &PathBuf,
+    context: &mut Context,
+    architectures: &[String],
+) -> Option<Vec<PackageError>> {
+    let defines = repo.read_file(defines_path, commit).ok()?;
     let pkg_name = defines_path.iter().nth_back(2)?.to_str()?;
-    let mut errors = vec![];
 
-    // First parse spec
-    if let Err(e) = parse(&spec, &mut context) {
-        let iter = e.iter().filter_map(|e| {
-            Some(PackageError {
-                package: pkg_name.to_string(),
-                path: spec_path.to_str()?.to_string(),
-                message: e.to_string(),
-                err_type: ErrorType::Parse,
-                line: Some(e.line as i32),
-                col: Some(e.col as i32),
-            })
-        });
-        errors.extend(iter);
-    }
-    // Modify context so that defines can understand
-    spec_decorator(&mut context);
-    // Then parse defines
-    if let Err(e) = parse(&defines, &mut context) {
-        let iter = e.iter().filter_map(|e| {
-            Some(PackageError {
-                package: pkg_name.to_string(),
-                path: defines_path.to_str()?.to_string(),
-                message: e.to_string(),
-                err_type: ErrorType::Parse,
-                line: Some(e.line as i32),
-                col: Some(e.col as i32),
+    let spec_context =
+        (!architectures.is_empty() && references_arch(&defines)).then(|| context.clone());
+    let errors = parse_apml(&defines, context, pkg_name, defines_path);
+    if let Some(spec_context) = spec_context {
+        add_arch_overrides(&defines, &spec_context, context, architectures);
+    }
+
+    Some(errors)
+}
+
+/// PKGNAME set by the defines at the commit, like `bar` for `app-utils/foo/01-bar/defines`
+pub fn defines_package_name(repo: &Repository, commit: Oid, defines_path: &Path) -> Option<String> {
+    let defines = repo.read_file(defines_path, commit).ok()?;
+    let mut context = Context::new();
+    // names are still read from defines with errors after PKGNAME
+    parse(&defines, &mut context).ok();
+
+    context.remove("PKGNAME").filter(|name| !name.is_empty())
+}
+
+/// Variables set by autobuild for the target architecture
+const ARCH_VARIABLES: [&str; 2] = ["ARCH", "CROSS"];
+
+/// Cheap check whether the content may expand an architecture variable
+fn references_arch(content: &str) -> bool {
+    ARCH_VARIABLES
+        .iter()
+        .any(|var| content.contains(&format!("${var}")) || content.contains(&format!("${{{var}")))
+}
+
+/// Parse defines with `ARCH` set to each architecture, adding keys whose values differ
+///
+/// `CROSS` is left unset like in native builds, so `${CROSS:-$ARCH}` expands
+/// to the architecture. Keys already overridden in defines are kept, and
+/// errors are already reported by the default parse.
+fn add_arch_overrides(
+    defines: &str,
+    spec_context: &Context,
+    context: &mut Context,
+    architectures: &[String],
+) {
+    let mut overrides = vec![];
+    for arch in architectures {
+        let mut arch_context = spec_context.clone();
+        arch_context.insert("ARCH".to_string(), arch.clone());
+        if parse(defines, &mut arch_context).is_err() {
+            continue;
+        }
+
+        for (key, value) in arch_context {
+            if ARCH_VARIABLES.contains(&key.as_str()) || key.contains("__") {
+                continue;
+            }
+            let arch_key = format!("{key}__{}", arch.to_uppercase());
+            if context.get(&key) != Some(&value) && !context.contains_key(&arch_key) {
+                overrides.push((arch_key, value));
+            }
+        }
+    }
+
+    context.extend(overrides);
+}
+
+fn parse_apml(
+    content: &str,
+    context: &mut Context,
+    pkg_name: &str,
+    path: &Path,
+) -> Vec<PackageError> {
+    match parse(content, context) {
+        Ok(()) => vec![],
+        Err(e) => e
+            .iter()
+            .filter_map(|e| {
+                Some(PackageError {
+                    package: pkg_name.to_string(),
+                    path: path.to_str()?.to_string(),
+                    message: e.to_string(),
+                    err_type: ErrorType::Parse,
+                    line: Some(e.line as i32),
+                    col: Some(e.col as i32),
+                    end_line: None,
+                    end_col: None,
+                })
             })
-        });
-        errors.extend(iter);
+            .collect(),
+    }
+}
+
+/// Parse CHKUPDATE like `anitya::id=1234` or `github::repo=foo/bar;pattern=v(.+)`
+/// into (method, [(key, value)])
+pub fn parse_chkupdate(chkupdate: &str) -> Result<(String, Vec<(String, String)>)> {
+    let (method, params) = chkupdate
+        .trim()
+        .split_once("::")
+        .with_context(|| format!("missing method in CHKUPDATE {chkupdate}"))?;
+    if method.is_empty() {
+        bail!("empty method in CHKUPDATE {chkupdate}");
     }
 
-    Some((context, errors))
+    let params = params
+        .split(';')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| {
+            let (key, value) = param
+                .split_once('=')
+                .with_context(|| format!("invalid parameter {param} in CHKUPDATE {chkupdate}"))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if params.is_empty() {
+        bail!("no parameters in CHKUPDATE {chkupdate}");
+    }
+
+    Ok((method.to_string(), params))
 }
 
 fn spec_decorator(c: &mut Context) {
@@ -186,7 +670,94 @@ pub fn defines_path_to_spec_path(defines_path: &Path) -> Result<PathBuf> {
     Ok(pkg_dir)
 }
 
-pub fn path_to_defines_path(repo: &Repository, commit: Oid, path: &Path) -> Result<Vec<PathBuf>> {
+type DefinesPaths = Vec<PathBuf>;
+
+/// Cache of defines paths located from the ancestors of changed files
+///
+/// All files in the same directory resolve to the same defines paths within
+/// one tree, so the cache is keyed by (tree oid, directory).
+#[derive(Default)]
+pub struct DefinesCache {
+    cache: RwLock<HashMap<(Oid, PathBuf), Option<DefinesPaths>>>,
+    lookups: AtomicUsize,
+}
+
+impl DefinesCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tree lookups performed on cache misses
+    pub fn tree_lookups(&self) -> usize {
+        self.lookups.load(Ordering::Relaxed)
+    }
+}
+
+/// Directory of package group files, e.g. groups/kde
+const GROUPS_DIR: &str = "groups";
+
+/// Files which never belong to a package: top-level files, groups and hidden directories like .github
+fn is_irrelevant_path(path: &Path) -> bool {
+    let mut components = path.components();
+    let first = components.next();
+    components.next().is_none()
+        || first.is_some_and(|c| {
+            let name = c.as_os_str().to_string_lossy();
+            name.starts_with('.') || name == GROUPS_DIR
+        })
+}
+
+/// Members of each group in the groups directory at the commit
+pub fn scan_groups(repo: &Repository, commit: Oid) -> Result<HashMap<String, Vec<String>>> {
+    let tree = repo.find_commit(commit)?.tree()?;
+    let dir = match tree.get_path(Path::new(GROUPS_DIR)) {
+        Ok(entry) => entry.to_object(repo.get_git2repo())?.peel_to_tree()?,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut groups = HashMap::new();
+    for entry in dir.iter() {
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            continue;
+        }
+        let name = entry
+            .name()
+            .with_context(|| format!("group name of {} is not UTF-8", entry.id()))?;
+        let blob = repo.find_blob(entry.id())?;
+        groups.insert(
+            name.to_string(),
+            parse_group(&String::from_utf8_lossy(blob.content())),
+        );
+    }
+
+    Ok(groups)
+}
+
+/// Package names listed by a group file
+///
+/// One package per line, optionally prefixed by its section like `app-admin/htop`.
+/// `#` starts a comment.
+fn parse_group(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.rsplit('/').next().unwrap_or(line).to_string())
+        .unique()
+        .collect()
+}
+
+pub fn path_to_defines_path(
+    repo: &Repository,
+    commit: Oid,
+    path: &Path,
+    cache: &DefinesCache,
+) -> Result<Vec<PathBuf>> {
+    if is_irrelevant_path(path) {
+        bail!("{} doesn't belong to any package", path.display());
+    }
+
     let file_name = path
         .file_name()
         .with_context(|| format!("failed to convert {} to str", path.display()))?
@@ -198,18 +769,30 @@ pub fn path_to_defines_path(repo: &Repository, commit: Oid, path: &Path) -> Resu
         "spec" => Ok(spec_path_to_defines_path(repo, commit, path)?),
         _ => {
             let tree = repo.find_commit(commit)?.tree()?;
-            path.ancestors()
-                .find_map(|path| {
-                    let mut path = path.to_path_buf();
-                    path.push(Path::new("defines"));
-                    tree.get_path(&path).ok().map(|_| vec![path.to_path_buf()])
-                })
-                .with_context(|| {
-                    format!(
-                        "failed to find defines path at the ancestors of {}",
-                        path.display()
-                    )
-                })
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let key = (tree.id(), dir.to_path_buf());
+
+            let cached = cache.cache.read().unwrap().get(&key).cloned();
+            let defines = match cached {
+                Some(defines) => defines,
+                None => {
+                    let defines = dir.ancestors().find_map(|path| {
+                        cache.lookups.fetch_add(1, Ordering::Relaxed);
+                        let mut path = path.to_path_buf();
+                        path.push(Path::new("defines"));
+                        tree.get_path(&path).ok().map(|_| vec![path.to_path_buf()])
+                    });
+                    cache.cache.write().unwrap().insert(key, defines.clone());
+                    defines
+                }
+            };
+
+            defines.with_context(|| {
+                format!(
+                    "failed to find defines path at the ancestors of {}",
+                    path.display()
+                )
+            })
         }
     }
 }
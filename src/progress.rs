@@ -0,0 +1,254 @@
+use crate::events::{EventSender, ScanEvent};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{IsTerminal, Write};
+use std::ops::Deref;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// Progress bars of one repository, registered in a MultiProgress shared by all repositories
+///
+/// When stderr is not a terminal, bars are hidden and their start and end are
+/// logged with the repository name instead.
+#[derive(Clone)]
+pub struct Progress {
+    multi: MultiProgress,
+    prefix: String,
+    tty: bool,
+    events: Vec<EventSender>,
+}
+
+impl Progress {
+    /// Progress logging [ScanEvent]s and drawing the bar of written packages,
+    /// see [Self::log_event]
+    pub fn new(multi: &MultiProgress, prefix: &str) -> Self {
+        let progress = Self {
+            multi: multi.clone(),
+            prefix: prefix.to_string(),
+            tty: std::io::stderr().is_terminal(),
+            events: vec![],
+        };
+        // the handler only draws bars, it doesn't hold a sender of its own queue
+        let bars = progress.clone();
+        let written = Mutex::new(None);
+        progress.with_event_handler(move |event| bars.log_event(event, &written))
+    }
+
+    /// Progress which draws nothing
+    pub fn hidden() -> Self {
+        Self {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            prefix: String::new(),
+            tty: false,
+            events: vec![],
+        }
+    }
+
+    /// Also report [ScanEvent]s to the handler, which runs on its own thread
+    pub fn with_event_handler(
+        mut self,
+        handler: impl Fn(ScanEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.events.push(EventSender::spawn(handler));
+        self
+    }
+
+    /// Send an event to the handlers if any, the event is only built when needed
+    pub fn emit(&self, event: impl FnOnce() -> ScanEvent) {
+        let Some((last, others)) = self.events.split_last() else {
+            return;
+        };
+        let event = event();
+        for events in others {
+            events.send(event.clone());
+        }
+        last.send(event);
+    }
+
+    /// Wait until the handlers have handled the events sent before, call at the end of a scan
+    ///
+    /// Returns the number of events dropped because a handler fell behind, they are logged.
+    pub fn flush_events(&self) -> u64 {
+        let dropped = self.events.iter().map(EventSender::flush).sum();
+        if dropped > 0 {
+            warn!(
+                "[{}] {dropped} scan events were dropped as their handler fell behind",
+                self.prefix
+            );
+        }
+
+        dropped
+    }
+
+    /// The default handler, logging events and drawing the bar of written packages
+    ///
+    /// Commits are scanned on many threads with a bar of their own, as
+    /// [ScanEvent::CommitsScanned] is only sent every [COMMITS_EVERY] commits.
+    ///
+    /// [COMMITS_EVERY]: crate::events::COMMITS_EVERY
+    fn log_event(&self, event: ScanEvent, written: &Mutex<Option<Bar>>) {
+        let mut written = written.lock().unwrap_or_else(|e| e.into_inner());
+        match event {
+            ScanEvent::RepoStarted { repo, branch } => info!("scan {repo}/{branch}"),
+            ScanEvent::CommitsScanned { .. } => {}
+            ScanEvent::BranchScanned { branch, packages } => {
+                info!("[{}] {branch}: {packages} packages changed", self.prefix)
+            }
+            ScanEvent::PackageWritten {
+                package,
+                done,
+                total,
+            } => {
+                info!("{done}/{total} {package}");
+                written
+                    .get_or_insert_with(|| self.bar(total as u64, "update packages"))
+                    .set_position(done as u64);
+                if done == total {
+                    *written = None;
+                }
+            }
+            ScanEvent::ErrorsRecorded { package, count } => {
+                info!("[{}] {package}: {count} errors", self.prefix)
+            }
+            ScanEvent::RepoFinished {
+                repo,
+                updated,
+                deleted,
+                errors,
+            } => {
+                // packages deferred to the next scan leave the bar unfinished
+                *written = None;
+                info!("{repo}: updated {updated}, deleted {deleted} packages, {errors} errors");
+            }
+        }
+    }
+
+    /// Add a bar of `len` steps, it's removed when dropped
+    pub fn bar(&self, len: u64, message: &str) -> Bar {
+        let bar = if self.tty {
+            self.multi.add(ProgressBar::new(len))
+        } else {
+            if !self.prefix.is_empty() {
+                info!("[{}] {message}: {len}", self.prefix);
+            }
+            ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden())
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{prefix} {msg} {wide_bar} {pos}/{len} ({eta})")
+                .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        );
+        bar.set_prefix(self.prefix.clone());
+        bar.set_message(message.to_string());
+
+        Bar {
+            bar,
+            progress: self.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A progress bar removed from the terminal when dropped, including on errors
+pub struct Bar {
+    bar: ProgressBar,
+    progress: Progress,
+    message: String,
+}
+
+impl Deref for Bar {
+    type Target = ProgressBar;
+
+    fn deref(&self) -> &Self::Target {
+        &self.bar
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        // finishing moves the bar to its end, unfinished bars are logged as such
+        let position = self.bar.position();
+        self.bar.finish_and_clear();
+        if self.progress.tty {
+            self.progress.multi.remove(&self.bar);
+        } else if !self.progress.prefix.is_empty() {
+            info!(
+                "[{}] {}: {}/{} done",
+                self.progress.prefix,
+                self.message,
+                position,
+                self.bar.length().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// Writer which hides progress bars while writing logs, so that they don't interleave
+#[derive(Clone)]
+pub struct LogWriter(pub MultiProgress);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.suspend(|| std::io::stdout().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.suspend(|| std::io::stdout().flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Log lines written by the closure
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logs(f: impl FnOnce()) -> Vec<String> {
+        let lines = Lines::default();
+        let writer = lines.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+
+        let output = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        output.lines().map(|line| line.trim().to_string()).collect()
+    }
+
+    #[test]
+    fn test_bars_are_logged_without_tty() {
+        let progress = Progress {
+            prefix: "aosc-os-abbs/stable".to_string(),
+            ..Progress::hidden()
+        };
+        assert_eq!(
+            logs(|| {
+                let bar = progress.bar(3, "scan commits");
+                bar.inc(2);
+            }),
+            [
+                "[aosc-os-abbs/stable] scan commits: 3",
+                "[aosc-os-abbs/stable] scan commits: 2/3 done",
+            ]
+        );
+
+        // bars of progress without a repository are silent
+        assert!(logs(|| drop(Progress::hidden().bar(3, "scan commits"))).is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::db::abbs::{FlappingPackage, IntegrityViolation, PendingPackage};
+use crate::warnings::WarningCount;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Summary of scanning one repository
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanReport {
+    pub repo: String,
+    pub branch: String,
+    /// identifier of the scan, saved in last_run_id of written rows
+    #[serde(default)]
+    pub run_id: String,
+    /// names of updated packages
+    pub updated: Vec<String>,
+    /// names of deleted packages
+    pub deleted: Vec<String>,
+    /// number of package errors recorded in this run
+    pub errors: usize,
+    /// number of package errors which were not recorded before this run
+    pub new_errors: usize,
+    /// error message if the scan failed
+    pub failure: Option<String>,
+    /// packages which took the longest time to update
+    #[serde(default)]
+    pub slowest: Vec<PackageTiming>,
+    /// number of warnings of each category
+    #[serde(default)]
+    pub warnings: BTreeMap<String, WarningCount>,
+    /// testing branches skipped for being older than testing_branch_max_age_days
+    #[serde(default)]
+    pub skipped_branches: Vec<String>,
+    /// rows referencing packages which don't exist, found after deleting packages
+    #[serde(default)]
+    pub integrity_violations: Vec<IntegrityViolation>,
+    /// packages repeatedly switching between updated and deleted in recent runs
+    #[serde(default)]
+    pub flapping: Vec<FlappingPackage>,
+    /// nothing was written, see `pending` for what would have been
+    #[serde(default)]
+    pub dry_run: bool,
+    /// packages a dry run would have written
+    #[serde(default)]
+    pub pending: Vec<PendingPackage>,
+    /// time from the newest commit of updated packages to their rows being committed
+    #[serde(default)]
+    pub latency: Option<LatencySummary>,
+    /// packages left to the next scan after max_duration_secs of the repository ran out
+    #[serde(default)]
+    pub deferred: Vec<String>,
+    /// commit of the previous scan, none on the first scan of the branch
+    #[serde(default)]
+    pub from_commit: Option<String>,
+    /// commit the packages were scanned at
+    #[serde(default)]
+    pub to_commit: Option<String>,
+    /// full versions of updated packages, and the last versions of deleted ones
+    #[serde(default)]
+    pub versions: BTreeMap<String, String>,
+    /// number of package errors recorded in this run of each package with errors
+    #[serde(default)]
+    pub package_errors: BTreeMap<String, usize>,
+    /// time spent saving new commits and finding changed packages
+    #[serde(default)]
+    pub commit_scan_ms: u64,
+    /// time spent writing packages and refreshing derived tables
+    #[serde(default)]
+    pub db_update_ms: u64,
+    /// recovery from a corrupted database taken before scanning, see corruption_policy
+    #[serde(default)]
+    pub recovery: Option<String>,
+    /// sizes measured by the free space check before scanning
+    #[serde(default)]
+    pub storage: Option<StorageSizes>,
+}
+
+/// Storage used before a scan and the estimated growth, in bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageSizes {
+    pub object_db_bytes: u64,
+    pub database_bytes: u64,
+    /// commits of the branch which are not in database yet
+    pub new_commits: u64,
+    /// growth of the database estimated from disk_kib_per_commit
+    pub estimated_bytes: u64,
+}
+
+/// Percentiles of commit to database latency over the packages of a run, in seconds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub samples: usize,
+    /// samples of commits dated in the future, counted as zero
+    pub clamped: usize,
+    pub p50_secs: i64,
+    pub p90_secs: i64,
+    pub p99_secs: i64,
+    pub max_secs: i64,
+}
+
+impl LatencySummary {
+    /// Summarize latencies in seconds, None without samples
+    pub fn from_secs(samples: &[i64]) -> Option<Self> {
+        let clamped = samples.iter().filter(|secs| **secs < 0).count();
+        let mut sorted: Vec<_> = samples.iter().map(|secs| (*secs).max(0)).collect();
+        sorted.sort_unstable();
+        let max_secs = *sorted.last()?;
+        // nearest rank
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100) - 1];
+
+        Some(Self {
+            samples: sorted.len(),
+            clamped,
+            p50_secs: percentile(50),
+            p90_secs: percentile(90),
+            p99_secs: percentile(99),
+            max_secs,
+        })
+    }
+}
+
+/// Time spent on updating one package in milliseconds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageTiming {
+    pub package: String,
+    /// querying changes of the package
+    pub changes_ms: u64,
+    /// writing the package into database
+    pub add_ms: u64,
+}
+
+impl PackageTiming {
+    pub fn total_ms(&self) -> u64 {
+        self.changes_ms + self.add_ms
+    }
+}
+
+impl ScanReport {
+    pub fn new(repo: &str, branch: &str) -> Self {
+        Self {
+            repo: repo.to_string(),
+            branch: branch.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(repo: &str, branch: &str, error: &anyhow::Error) -> Self {
+        Self {
+            failure: Some(format!("{error:#}")),
+            ..Self::new(repo, branch)
+        }
+    }
+
+    /// Keep the `n` slowest packages of the timings
+    pub fn set_slowest(&mut self, mut timings: Vec<PackageTiming>, n: usize) {
+        timings.sort_by(|left, right| {
+            right
+                .total_ms()
+                .cmp(&left.total_ms())
+                .then_with(|| left.package.cmp(&right.package))
+        });
+        timings.truncate(n);
+        self.slowest = timings;
+    }
+}
+
+/// Which package errors make a successful run exit with [ExitStatus::NewErrors]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FailOn {
+    /// errors which were not recorded before
+    #[default]
+    NewErrors,
+    /// any errors recorded in this run
+    AnyErrors,
+    /// never
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// all repositories are scanned
+    Success,
+    /// all repositories are scanned, but package errors are recorded
+    NewErrors,
+    /// some repositories failed
+    PartialFailure,
+    /// nothing could be scanned
+    Fatal,
+}
+
+impl ExitStatus {
+    pub fn from_reports(reports: &[ScanReport], fail_on: FailOn) -> Self {
+        let failed = reports.iter().filter(|r| r.failure.is_some()).count();
+        if failed > 0 {
+            return if failed == reports.len() {
+                Self::Fatal
+            } else {
+                Self::PartialFailure
+            };
+        }
+
+        let has_errors = match fail_on {
+            FailOn::NewErrors => reports.iter().any(|r| r.new_errors > 0),
+            FailOn::AnyErrors => reports.iter().any(|r| r.errors > 0),
+            FailOn::Never => false,
+        };
+        if has_errors {
+            Self::NewErrors
+        } else {
+            Self::Success
+        }
+    }
+
+    /// Process exit code
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::NewErrors => 2,
+            Self::PartialFailure => 3,
+            Self::Fatal => 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_without_newer_fields() {
+        let report: ScanReport = serde_json::from_str(
+            r#"{"repo": "aosc-os-abbs", "branch": "stable", "updated": ["foo"],
+                "deleted": [], "errors": 0, "new_errors": 0, "failure": null}"#,
+        )
+        .unwrap();
+        assert_eq!(report.updated, ["foo"]);
+        assert_eq!(report.from_commit, None);
+        assert!(report.versions.is_empty() && report.package_errors.is_empty());
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<ScanReport>(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        assert_eq!(LatencySummary::from_secs(&[]), None);
+
+        // one commit dated in the future, the rest 1 to 99 seconds ago
+        let mut samples = vec![-30];
+        samples.extend(1..100);
+        assert_eq!(
+            LatencySummary::from_secs(&samples),
+            Some(LatencySummary {
+                samples: 100,
+                clamped: 1,
+                p50_secs: 49,
+                p90_secs: 89,
+                p99_secs: 98,
+                max_secs: 99,
+            })
+        );
+        assert_eq!(
+            LatencySummary::from_secs(&[600]).map(|latency| latency.p50_secs),
+            Some(600)
+        );
+    }
+
+    #[test]
+    fn test_set_slowest() {
+        let timing = |package: &str, changes_ms, add_ms| PackageTiming {
+            package: package.to_string(),
+            changes_ms,
+            add_ms,
+        };
+        let mut report = ScanReport::default();
+        report.set_slowest(
+            vec![
+                timing("foo", 10, 5),
+                timing("bar", 1, 30),
+                timing("qux", 5, 10),
+                timing("baz", 15, 0),
+                timing("quux", 0, 1),
+            ],
+            3,
+        );
+        // slowest first by total, ties by name
+        let slowest = report
+            .slowest
+            .iter()
+            .map(|timing| (timing.package.as_str(), timing.total_ms()))
+            .collect::<Vec<_>>();
+        assert_eq!(slowest, [("bar", 31), ("baz", 15), ("foo", 15)]);
+
+        report.set_slowest(vec![timing("foo", 1, 1)], 3);
+        assert_eq!(
+            report.slowest.len(),
+            1,
+            "fewer packages than n are all kept"
+        );
+        report.set_slowest(vec![timing("foo", 1, 1)], 0);
+        assert!(report.slowest.is_empty());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let report = |errors, new_errors, failure: Option<&str>| ScanReport {
+            errors,
+            new_errors,
+            failure: failure.map(String::from),
+            ..Default::default()
+        };
+        let clean = report(0, 0, None);
+        let old_errors = report(2, 0, None);
+        let new_errors = report(2, 1, None);
+        let failed = report(0, 0, Some("failed to open repository"));
+
+        for fail_on in [FailOn::NewErrors, FailOn::AnyErrors, FailOn::Never] {
+            let status = |reports: &[&ScanReport]| {
+                let reports = reports.iter().map(|&r| r.clone()).collect::<Vec<_>>();
+                ExitStatus::from_reports(&reports, fail_on)
+            };
+            // failures take precedence over package errors
+            assert_eq!(
+                status(&[&failed, &new_errors]),
+                ExitStatus::PartialFailure,
+                "{fail_on:?}"
+            );
+            assert_eq!(status(&[&failed, &clean]), ExitStatus::PartialFailure);
+            assert_eq!(status(&[&failed, &failed]), ExitStatus::Fatal);
+            assert_eq!(status(&[&clean, &clean]), ExitStatus::Success);
+
+            let expected = match fail_on {
+                FailOn::Never => (ExitStatus::Success, ExitStatus::Success),
+                FailOn::NewErrors => (ExitStatus::Success, ExitStatus::NewErrors),
+                FailOn::AnyErrors => (ExitStatus::NewErrors, ExitStatus::NewErrors),
+            };
+            assert_eq!(
+                (
+                    status(&[&clean, &old_errors]),
+                    status(&[&old_errors, &new_errors])
+                ),
+                expected,
+                "{fail_on:?}"
+            );
+        }
+    }
+}
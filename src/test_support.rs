@@ -0,0 +1,218 @@
+//! Small abbs trees in temporary git repositories, for tests of this crate and its users
+//!
+//! Enabled by the `test-util` feature.
+
+use crate::config::Repo;
+use anyhow::{bail, Context, Result};
+use git2::build::CheckoutBuilder;
+use git2::{BranchType, IndexAddOption, Oid, Repository, RepositoryInitOptions, Signature, Time};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Time of the first commit, later commits are a minute apart
+const START_TIME: i64 = 1_700_000_000;
+
+/// Builder of a git repository laid out like an abbs tree
+///
+/// Packages live in `<section>/<name>/spec` and `<section>/<name>/autobuild/defines`.
+/// Commits have deterministic times, so hashes are stable across runs as long
+/// as the same steps are taken. The repository is deleted when dropped.
+pub struct FixtureRepo {
+    path: PathBuf,
+    repo: Repository,
+    time: i64,
+}
+
+impl FixtureRepo {
+    /// Create an empty repository whose first commit will be on `main_branch`
+    pub fn new(main_branch: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("abbs-meta-fixture-{}", Uuid::new_v4()));
+        let repo = Repository::init_opts(
+            &path,
+            RepositoryInitOptions::new().initial_head(main_branch),
+        )?;
+
+        Ok(Self {
+            path,
+            repo,
+            time: START_TIME,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn git2repo(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// Configuration to open the fixture with [crate::git::Repository::open]
+    pub fn repo_config(&self, tree: &str, branch: &str) -> Repo {
+        Repo {
+            repo_path: self.path.to_string_lossy().to_string(),
+            branch: branch.to_string(),
+            priority: 1,
+            category: "base".to_string(),
+            name: tree.to_string(),
+            url: format!("https://example.org/{tree}"),
+            scan_testing_branches: true,
+            testing_branch_max_age_days: None,
+            testing_branch_include: vec![],
+            testing_branch_exclude: vec![],
+            sync_branch: false,
+            force_branch_sync: false,
+            url_template: None,
+            architectures: None,
+            max_duration_secs: None,
+            extra_spec_keys: vec![],
+        }
+    }
+
+    /// Write spec and defines of a package, returns the path of defines
+    ///
+    /// Nothing is committed until [Self::commit].
+    pub fn add_package(
+        &mut self,
+        section: &str,
+        name: &str,
+        spec: &str,
+        defines: &str,
+    ) -> Result<PathBuf> {
+        let dir = Path::new(section).join(name);
+        let defines_path = dir.join("autobuild").join("defines");
+        fs::create_dir_all(self.path.join(dir.join("autobuild")))?;
+        fs::write(self.path.join(dir.join("spec")), spec)?;
+        fs::write(self.path.join(&defines_path), defines)?;
+
+        Ok(defines_path)
+    }
+
+    /// Write a file relative to the root, e.g. groups/kde
+    pub fn write_file(&mut self, path: impl AsRef<Path>, content: &str) -> Result<()> {
+        let path = self.path.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, content)?;
+
+        Ok(())
+    }
+
+    /// Delete the directory of a package, `package` is like app-shells/bash
+    pub fn remove_package(&mut self, package: &str) -> Result<()> {
+        fs::remove_dir_all(self.path.join(package))
+            .with_context(|| format!("failed to remove {package}"))
+    }
+
+    /// Move a package directory, both are like app-shells/bash, returns the new path of defines
+    pub fn rename_package(&mut self, old: &str, new: &str) -> Result<PathBuf> {
+        let to = self.path.join(new);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.path.join(old), to)
+            .with_context(|| format!("failed to rename {old} to {new}"))?;
+
+        Ok(Path::new(new).join("autobuild").join("defines"))
+    }
+
+    /// Commit every change of the work tree on the current branch
+    pub fn commit(&mut self, message: &str, author: &str) -> Result<Oid> {
+        let signature = self.signature(author)?;
+        let mut index = self.repo.index()?;
+        index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+        index.update_all(["*"], None)?;
+        index.write()?;
+        let tree = self.repo.find_tree(index.write_tree()?)?;
+
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => None,
+            Err(e) => return Err(e.into()),
+        };
+        let parents = parent.iter().collect::<Vec<_>>();
+
+        Ok(self.repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )?)
+    }
+
+    /// Date the following commits at `time`, in seconds since the epoch
+    pub fn set_time(&mut self, time: i64) {
+        self.time = time;
+    }
+
+    /// Create a branch at the current commit and switch to it
+    pub fn branch(&mut self, name: &str) -> Result<Oid> {
+        let head = self.repo.head()?.peel_to_commit()?.id();
+        self.repo
+            .branch(name, &self.repo.find_commit(head)?, false)?;
+        self.checkout(name)?;
+
+        Ok(head)
+    }
+
+    /// Switch to a branch, discarding uncommitted changes
+    pub fn checkout(&mut self, name: &str) -> Result<()> {
+        self.repo.set_head(&format!("refs/heads/{name}"))?;
+        self.repo
+            .checkout_head(Some(CheckoutBuilder::new().force().remove_untracked(true)))?;
+
+        Ok(())
+    }
+
+    /// Merge `from` into `into` with a merge commit, returns the merge commit
+    pub fn merge(&mut self, from: &str, into: &str) -> Result<Oid> {
+        let signature = self.signature("Fixture")?;
+        let oid = {
+            let ours = self.branch_commit(into)?;
+            let theirs = self.branch_commit(from)?;
+            let mut index = self.repo.merge_commits(&ours, &theirs, None)?;
+            if index.has_conflicts() {
+                bail!("merging {from} into {into} conflicts");
+            }
+            let tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+            self.repo.commit(
+                Some(&format!("refs/heads/{into}")),
+                &signature,
+                &signature,
+                &format!("Merge branch '{from}' into {into}"),
+                &tree,
+                &[&ours, &theirs],
+            )?
+        };
+        self.checkout(into)?;
+
+        Ok(oid)
+    }
+
+    fn branch_commit(&self, name: &str) -> Result<git2::Commit<'_>> {
+        Ok(self
+            .repo
+            .find_branch(name, BranchType::Local)?
+            .get()
+            .peel_to_commit()?)
+    }
+
+    /// Signature of the author at the next commit time
+    fn signature(&mut self, author: &str) -> Result<Signature<'static>> {
+        let email = format!("{}@example.org", author.to_lowercase().replace(' ', "."));
+        let signature = Signature::new(author, &email, &Time::new(self.time, 0))?;
+        self.time += 60;
+
+        Ok(signature)
+    }
+}
+
+impl Drop for FixtureRepo {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Number of warnings of a category logged before suppressing
+const LOG_FIRST: usize = 5;
+/// Log a line for every this many suppressed warnings
+const SUPPRESSED_EVERY: usize = 1000;
+
+/// Count of warnings in one category
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarningCount {
+    pub total: usize,
+    pub suppressed: usize,
+    /// the logged warnings, the suppressed ones are only counted
+    #[serde(default)]
+    pub messages: Vec<String>,
+}
+
+/// Coalesce repeated warnings of the same category
+///
+/// The first few warnings of each category are logged, the rest are counted
+/// and summarized periodically and at the end of a scan. Clones share counts.
+#[derive(Debug, Clone, Default)]
+pub struct Warnings {
+    counts: Arc<Mutex<HashMap<&'static str, WarningCount>>>,
+}
+
+impl Warnings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log a warning unless too many warnings of the category were logged
+    pub fn warn(&self, category: &'static str, message: impl Display) {
+        let count = {
+            let mut counts = self.counts.lock().unwrap();
+            let count = counts.entry(category).or_default();
+            count.total += 1;
+            if count.total <= LOG_FIRST {
+                count.messages.push(message.to_string());
+            } else {
+                count.suppressed += 1;
+            }
+            count.total
+        };
+
+        if count <= LOG_FIRST {
+            warn!("{message}");
+            if count == LOG_FIRST {
+                warn!("further \"{category}\" warnings are suppressed");
+            }
+        } else if (count - LOG_FIRST).is_multiple_of(SUPPRESSED_EVERY) {
+            warn!(
+                "... suppressed {} similar \"{category}\" warnings",
+                count - LOG_FIRST
+            );
+        }
+    }
+
+    /// Number of warnings of each category
+    pub fn counts(&self) -> BTreeMap<String, WarningCount> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(category, count)| (category.to_string(), count.clone()))
+            .collect()
+    }
+
+    /// Log the number of suppressed warnings of each category
+    pub fn log_summary(&self) {
+        for (category, count) in self.counts() {
+            if count.suppressed > 0 {
+                info!(
+                    "\"{category}\": {} warnings, {} suppressed",
+                    count.total, count.suppressed
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce() {
+        let warnings = Warnings::new();
+        let shared = warnings.clone();
+        for i in 0..LOG_FIRST + 3 {
+            shared.warn("malformed hash", format_args!("commits foo@stable: {i}"));
+        }
+        warnings.warn("unknown key", "foo: FOO");
+
+        let counts = warnings.counts();
+        let hash = &counts["malformed hash"];
+        assert_eq!(hash.total, LOG_FIRST + 3);
+        assert_eq!(hash.suppressed, 3);
+        assert_eq!(
+            hash.messages,
+            (0..LOG_FIRST)
+                .map(|i| format!("commits foo@stable: {i}"))
+                .collect::<Vec<_>>(),
+            "messages of suppressed warnings are not kept"
+        );
+        assert_eq!(
+            counts["unknown key"],
+            WarningCount {
+                total: 1,
+                suppressed: 0,
+                messages: vec!["foo: FOO".to_string()],
+            }
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Changes of packages saved from the commits touching them
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::commits::CommitDb;
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::{Context, Result};
+use common::{add_package, scan, spec, write_updated, TestDb};
+use sea_orm::ConnectionTrait;
+use serde_json::{json, Value};
+
+/// A tree where foo was changed 50 times, from 1.0 to 1.49
+fn fifty_changes() -> Result<FixtureRepo> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    for i in 0..50 {
+        add_package(&mut fixture, "app-utils", "foo", &format!("1.{i}"), "")?;
+        fixture.commit(&format!("foo: update to 1.{i}"), "Alice")?;
+    }
+
+    Ok(fixture)
+}
+
+/// Versions of the saved changes of foo, newest first
+const FOO_CHANGES: &str =
+    "SELECT version FROM package_changes WHERE package = 'foo' ORDER BY timestamp DESC";
+
+fn newest_ten() -> Vec<String> {
+    (40..50).rev().map(|i| format!("1.{i}")).collect()
+}
+
+#[async_std::test]
+async fn writes_keep_the_newest_changes() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("max_changes_per_package = 10");
+    let fixture = fifty_changes()?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    assert_eq!(db.column(FOO_CHANGES).await, newest_ten());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn compact_keeps_the_newest_changes() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let fixture = fifty_changes()?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column(FOO_CHANGES).await.len(), 50);
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    assert!(
+        abbs_db.compact_changes(0).await.is_err(),
+        "every change would be deleted"
+    );
+    assert_eq!(abbs_db.compact_changes(10).await?, 40);
+    assert_eq!(db.column(FOO_CHANGES).await, newest_ten());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_without_recorded_changes_get_the_last_spec_commit() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let spec_commit = fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("bar: new, 2.0", "Bob")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    // the commits database starts after foo was added
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    commit_db.update_branch(&repo, "stable").await?;
+    db.connect()
+        .await
+        .execute_unprepared("DELETE FROM commits WHERE pkg_name = 'foo'")
+        .await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config)
+        .await?
+        .strict_writes(true);
+    let scanned = write_updated(&repo, &commit_db, &abbs_db).await?;
+    assert_eq!(scanned.updated, ["bar", "foo"]);
+    assert_eq!(
+        db.column(
+            "SELECT version || ' ' || githash || ' ' || maintainer_name || ' ' || message \
+             FROM package_changes WHERE package = 'foo'"
+        )
+        .await,
+        [format!("1.0 {spec_commit} Alice foo: new, 1.0")]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn changes_of_commits_touching_many_packages_are_flagged() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("mass_change_threshold = 2");
+    let mut fixture = FixtureRepo::new("stable")?;
+    for name in ["foo", "bar"] {
+        add_package(&mut fixture, "app-utils", name, "1.0", "")?;
+    }
+    let pair = fixture.commit("foo, bar: new, 1.0", "Alice")?;
+    for name in ["foo", "bar", "baz"] {
+        add_package(&mut fixture, "app-utils", name, "2.0", "")?;
+    }
+    let treewide = fixture.commit("treewide: update to 2.0", "Bot")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column("SELECT commit_id || ' ' || packages_touched FROM commit_meta ORDER BY 1")
+            .await,
+        {
+            let mut touched = [format!("{pair} 2"), format!("{treewide} 3")];
+            touched.sort();
+            touched
+        }
+    );
+    // only commits touching more packages than the threshold are mass changes
+    assert_eq!(
+        db.column(
+            "SELECT package || ' ' || version || ' ' || mass_change FROM package_changes \
+             ORDER BY package, version"
+        )
+        .await,
+        [
+            "bar 1.0 false",
+            "bar 2.0 true",
+            "baz 2.0 true",
+            "foo 1.0 false",
+            "foo 2.0 true",
+        ]
+    );
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let changelog = abbs_db.get_changelog(10, false).await?;
+    assert_eq!(
+        changelog.len(),
+        3,
+        "the mass change is collapsed: {changelog:?}"
+    );
+    assert!(changelog[0].mass_change);
+    assert_eq!(changelog[0].packages.len(), 3);
+    assert_eq!(abbs_db.get_changelog(10, true).await?.len(), 5);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_only_touched_by_mass_changes_are_inactive() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("mass_change_threshold = 2");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("bar: new, 2.0", "Bob")?;
+    // touches three packages, more than the threshold
+    for (section, name) in [
+        ("app-utils", "foo"),
+        ("app-utils", "bar"),
+        ("lang-python", "baz"),
+    ] {
+        add_package(&mut fixture, section, name, "3.0", "")?;
+    }
+    fixture.commit("treewide: update to 3.0", "Bot")?;
+    add_package(&mut fixture, "app-utils", "bar", "3.1", "")?;
+    let active = fixture.commit("bar: update to 3.1", "Bob")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let since = fixture.git2repo().find_commit(active)?.time().seconds();
+    let since = chrono::DateTime::from_timestamp(since, 0)
+        .context("invalid time")?
+        .fixed_offset();
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let inactive = abbs_db
+        .get_inactive_packages(since, &[])
+        .await?
+        .into_iter()
+        .map(|pkg| (pkg.package, pkg.section, pkg.last_maintainer))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        inactive,
+        [
+            ("baz".to_string(), "python".to_string(), None),
+            (
+                "foo".to_string(),
+                "utils".to_string(),
+                Some("Alice <alice@example.org>".to_string())
+            ),
+        ],
+        "packages without changes but mass changes come first"
+    );
+    let inactive = abbs_db
+        .get_inactive_packages(since, &["utils".to_string()])
+        .await?;
+    assert_eq!(inactive.len(), 1);
+    assert_eq!(inactive[0].package, "foo");
+
+    Ok(())
+}
+
+/// foo whose second commit updates the spec and adds two patches
+fn patched() -> Result<FixtureRepo> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.write_file("app-utils/foo/spec", &spec("1.1"))?;
+    for patch in ["0001-fix-build.patch", "0002-fix-tests.patch"] {
+        fixture.write_file(format!("app-utils/foo/autobuild/patches/{patch}"), "fix\n")?;
+    }
+    fixture.commit("foo: update to 1.1", "Alice")?;
+
+    Ok(fixture)
+}
+
+/// Changed files of the change of foo to 1.1
+const PATCHED_FILES: &str =
+    "SELECT changed_files::text FROM package_changes WHERE package = 'foo' AND version = '1.1'";
+
+#[async_std::test]
+async fn changes_list_the_changed_files() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let fixture = patched()?;
+    scan(&db.global(), &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    let files: Vec<Value> = db
+        .column(PATCHED_FILES)
+        .await
+        .iter()
+        .map(|files| serde_json::from_str(files))
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(
+        files,
+        [json!({
+            "files": [
+                { "path": "autobuild/patches/0001-fix-build.patch", "status": "Added" },
+                { "path": "autobuild/patches/0002-fix-tests.patch", "status": "Added" },
+                { "path": "spec", "status": "Modified" },
+            ],
+            "more": 0
+        })]
+    );
+    assert_eq!(
+        db.column("SELECT changed_files::text FROM commits WHERE pkg_version = '1.1'")
+            .await,
+        db.column(PATCHED_FILES).await,
+        "the files are copied from the commits table"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn changed_files_over_the_limit_are_counted() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("changed_files_limit = 2");
+    let fixture = patched()?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    let files: Value = serde_json::from_str(&db.column(PATCHED_FILES).await[0])?;
+    assert_eq!(files["files"].as_array().map(Vec::len), Some(2));
+    assert_eq!(files["more"], 1);
+
+    Ok(())
+}
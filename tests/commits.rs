@@ -0,0 +1,408 @@
+//! Commit database of fixture trees
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::commits::CommitDb;
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::{Context, Result};
+use common::{add_package, defines, scan, spec, TestDb};
+use sea_orm::ConnectionTrait;
+use std::fs;
+use std::path::Path;
+
+#[async_std::test]
+async fn new_commits_are_counted_before_tables_exist() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+
+    let (new_commits, size) =
+        CommitDb::count_new_commits(&global, &Repository::open(&repo_config)?).await?;
+    assert_eq!(new_commits, 2);
+    assert!(size > 0);
+    assert!(
+        db.column("SELECT tablename::text FROM pg_tables WHERE schemaname = 'public'")
+            .await
+            .is_empty(),
+        "counting doesn't set up the database"
+    );
+
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "foo", "1.2", "")?;
+    fixture.commit("foo: update to 1.2", "Alice")?;
+    let (new_commits, _) =
+        CommitDb::count_new_commits(&global, &Repository::open(&repo_config)?).await?;
+    assert_eq!(new_commits, 1, "commits of the last scan are not counted");
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn histories_are_ordered_by_id() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let first = fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let second = fixture.commit("foo: update to 1.1", "Alice")?;
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    commit_db.update_branch(&repo, "stable").await?;
+
+    // the clock stood still between the two runs
+    db.connect()
+        .await
+        .execute_unprepared("UPDATE histories SET timestamp = '2024-01-01T00:00:00Z'")
+        .await?;
+    let updated = commit_db.get_updated_packages(&repo, "stable").await?;
+    assert_eq!(updated.from, Some(first));
+    assert_eq!(updated.commit, second);
+    assert_eq!(
+        updated
+            .updated
+            .iter()
+            .map(|pkg| pkg.0.version.as_str())
+            .collect::<Vec<_>>(),
+        ["1.1"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn idle_runs_add_one_history() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let count = "SELECT count(*)::text FROM histories";
+
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column(count).await, ["1"]);
+    // the second history at the same commit marks that nothing changed
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column(count).await, ["2"]);
+    scan(&global, &repo_config).await?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(count).await,
+        ["2"],
+        "the latest two histories are already at the commit"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_deleted_and_added_again() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    let mut times = vec![];
+    add_package(&mut fixture, "app-utils", "bar", "1.0", "")?;
+    times.push(fixture.commit("bar: new, 1.0", "Alice")?);
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.write_file("app-utils/foo/autobuild/patches/0001-fix.patch", "fix\n")?;
+    times.push(fixture.commit("foo: new, 1.0", "Alice")?);
+    fs::remove_file(
+        fixture
+            .path()
+            .join("app-utils/foo/autobuild/patches/0001-fix.patch"),
+    )?;
+    times.push(fixture.commit("foo: drop upstreamed patch", "Alice")?);
+    fixture.remove_package("app-utils/foo")?;
+    times.push(fixture.commit("foo: drop", "Alice")?);
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    times.push(fixture.commit("foo: new, 1.1", "Alice")?);
+    let times = times
+        .into_iter()
+        .map(|oid| {
+            let time = fixture.git2repo().find_commit(oid)?.time().seconds();
+            let time = chrono::DateTime::from_timestamp(time, 0).context("invalid time")?;
+            Ok(time.fixed_offset())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let commit_db = CommitDb::open(&global).await?;
+    let tree = repo_config.tree_id();
+    let mut states = vec![];
+    for time in times {
+        let state = commit_db
+            .get_package_state_at("foo", &tree, "stable", time)
+            .await?
+            .map(|row| (row.pkg_version, row.status));
+        states.push(state);
+    }
+    let state = |version: &str, status: &str| Some((version.to_string(), status.to_string()));
+    assert_eq!(
+        states,
+        [
+            None,
+            state("1.0", "Added"),
+            state("1.0", "Modified"),
+            None,
+            state("1.1", "Added"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_moved_within_a_commit_are_not_deleted() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let commit_db = CommitDb::open(&global).await?;
+    let tree = repo_config.tree_id();
+    let state = || async {
+        let now = chrono::Local::now().fixed_offset();
+        let state = commit_db
+            .get_package_state_at("foo", &tree, "stable", now)
+            .await?
+            .map(|row| (row.pkg_version, row.defines_path));
+        anyhow::Ok(state)
+    };
+
+    // deleted at the old path and added at the new one, at another version
+    fixture.rename_package("app-utils/foo", "app-admin/foo")?;
+    add_package(&mut fixture, "app-admin", "foo", "1.1", "")?;
+    fixture.commit("foo: move to app-admin, update to 1.1", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        state().await?,
+        Some((
+            "1.1".to_string(),
+            "app-admin/foo/autobuild/defines".to_string()
+        ))
+    );
+
+    // both rows share the key of the commits table at the same version
+    fixture.rename_package("app-admin/foo", "app-utils/foo")?;
+    fixture.commit("foo: move back to app-utils", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        state().await?,
+        Some((
+            "1.1".to_string(),
+            "app-utils/foo/autobuild/defines".to_string()
+        ))
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_missing_spec_or_defines_are_broken_until_deleted() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "quux", "4.0", "")?;
+    // subpackages of qux named by their PKGNAME, not the directory
+    fixture.write_file("app-utils/qux/spec", &spec("3.0"))?;
+    fixture.write_file("app-utils/qux/01-bar/defines", &defines("bar", ""))?;
+    fixture.write_file("app-utils/qux/02-baz/defines", &defines("baz", ""))?;
+    fixture.commit("foo, quux, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let packages = "SELECT name FROM packages ORDER BY name";
+    assert_eq!(db.column(packages).await, ["bar", "baz", "foo", "quux"]);
+
+    let errors = "SELECT package || ': ' || message FROM package_errors ORDER BY 1";
+    fs::remove_file(fixture.path().join("app-utils/foo/spec"))?;
+    fs::remove_file(fixture.path().join("app-utils/qux/spec"))?;
+    fs::remove_file(fixture.path().join("app-utils/quux/autobuild/defines"))?;
+    fixture.commit("foo, quux, qux: drop spec or defines", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(errors).await,
+        [
+            "bar: app-utils/qux/spec is missing",
+            "baz: app-utils/qux/spec is missing",
+            "foo: app-utils/foo/spec is missing",
+            "quux: app-utils/quux/autobuild/defines is missing",
+        ]
+    );
+    assert_eq!(
+        db.column(packages).await,
+        ["bar", "baz", "foo", "quux"],
+        "broken packages are kept"
+    );
+
+    fixture.remove_package("app-utils/foo")?;
+    fixture.remove_package("app-utils/qux")?;
+    fixture.remove_package("app-utils/quux")?;
+    fixture.commit("foo, quux, qux: drop", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert!(db.column(packages).await.is_empty());
+    assert!(db.column(errors).await.is_empty());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn backfill_restores_missing_commits() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    let mut commits = vec![];
+    for version in ["1.0", "1.1", "1.2"] {
+        add_package(&mut fixture, "app-utils", "foo", version, "")?;
+        commits.push(fixture.commit(&format!("foo: update to {version}"), "Alice")?);
+    }
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    db.connect()
+        .await
+        .execute_unprepared("DELETE FROM commits WHERE pkg_version IN ('1.0', '1.1')")
+        .await?;
+
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    let directory = Path::new("app-utils/foo");
+    assert_eq!(
+        commit_db
+            .backfill_package(&repo, "foo", directory, 2)
+            .await?,
+        [commits[1]],
+        "only the newest commits are looked at"
+    );
+    assert_eq!(
+        commit_db
+            .backfill_package(&repo, "foo", directory, 100)
+            .await?,
+        [commits[0]]
+    );
+    assert!(commit_db
+        .backfill_package(&repo, "foo", directory, 100)
+        .await?
+        .is_empty());
+    assert_eq!(
+        db.column("SELECT pkg_version FROM commits ORDER BY commit_time")
+            .await,
+        ["1.0", "1.1", "1.2"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn merge_commits_are_diffed_against_every_parent() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("bar-1.0")?;
+    add_package(&mut fixture, "app-utils", "bar", "1.0", "")?;
+    fixture.commit("bar: new, 1.0", "Bob")?;
+    fixture.checkout("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Alice")?;
+    let merge = fixture.merge("bar-1.0", "stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    // bar is new to the first parent, foo differs from the second one
+    assert_eq!(
+        db.column(&format!(
+            "SELECT pkg_name || ' ' || pkg_version || ' ' || status FROM commits \
+             WHERE branch = 'stable' AND commit_id = '{merge}' ORDER BY pkg_name"
+        ))
+        .await,
+        ["bar 1.0 Added", "foo 1.1 Modified"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn force_pushed_branches_are_rescanned() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let base = fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let dropped = fixture.commit("foo: update to 1.1", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let force_push = |fixture: &mut FixtureRepo, version: &str| -> Result<_> {
+        fixture
+            .git2repo()
+            .reference("refs/heads/stable", base, true, "force-push")?;
+        fixture.checkout("stable")?;
+        add_package(fixture, "app-utils", "foo", version, "")?;
+        Ok(fixture.commit(&format!("foo: update to {version}"), "Bob")?)
+    };
+    let version = || async {
+        let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+        let pkg = abbs_db
+            .get_package("foo")
+            .await?
+            .context("foo is missing")?;
+        anyhow::Ok(pkg.version)
+    };
+
+    // the stored commit is no longer an ancestor of the tip
+    let rewritten = force_push(&mut fixture, "1.2")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(version().await?.as_deref(), Some("1.2"));
+    assert_eq!(
+        db.column("SELECT commit_id FROM commits ORDER BY commit_time")
+            .await,
+        [base.to_string(), rewritten.to_string()],
+        "{dropped} is still in the repository, but no longer in stable"
+    );
+
+    // and then gone after garbage collection
+    force_push(&mut fixture, "1.3")?;
+    let hex = rewritten.to_string();
+    fs::remove_file(
+        fixture
+            .path()
+            .join(".git/objects")
+            .join(&hex[..2])
+            .join(&hex[2..]),
+    )?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(version().await?.as_deref(), Some("1.3"));
+
+    Ok(())
+}
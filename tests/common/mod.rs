@@ -0,0 +1,213 @@
+//! Shared helpers of the integration tests
+//!
+//! Tests needing PostgreSQL read the url of a server from
+//! `ABBS_META_TEST_DATABASE_URL`, e.g. `postgres://postgres@localhost/postgres`,
+//! and create a database of their own on it. They are skipped when it is unset.
+#![allow(dead_code)]
+
+use abbs_meta::config::{Global, Repo};
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::commits::{CommitDb, UpdatedPackages};
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection, Statement};
+use uuid::Uuid;
+
+pub const DATABASE_URL_VAR: &str = "ABBS_META_TEST_DATABASE_URL";
+
+/// A database created for one test, dropped with it
+pub struct TestDb {
+    admin_url: String,
+    name: String,
+    pub url: String,
+}
+
+impl TestDb {
+    /// Create an empty database, none if no server is configured
+    pub async fn new() -> Option<Self> {
+        let Ok(admin_url) = std::env::var(DATABASE_URL_VAR) else {
+            eprintln!("{DATABASE_URL_VAR} is not set, skipping");
+            return None;
+        };
+        let name = format!("abbs_meta_test_{}", Uuid::new_v4().simple());
+        let (server, _) = admin_url
+            .rsplit_once('/')
+            .expect("database url has no database name");
+        let url = format!("{server}/{name}");
+        let admin = Database::connect(&admin_url)
+            .await
+            .expect("failed to connect to the test server");
+        admin
+            .execute_unprepared(&format!("CREATE DATABASE {name}"))
+            .await
+            .expect("failed to create the test database");
+        admin.close().await.ok();
+
+        Some(Self {
+            admin_url,
+            name,
+            url,
+        })
+    }
+
+    /// Global configuration using this database, other fields are defaults
+    pub fn global(&self) -> Global {
+        self.global_with("")
+    }
+
+    /// Global configuration using this database, with extra lines of config.toml
+    pub fn global_with(&self, extra: &str) -> Global {
+        toml::from_str(&format!("database_url = \"{}\"\n{extra}", self.url))
+            .expect("invalid global configuration")
+    }
+
+    pub async fn connect(&self) -> DatabaseConnection {
+        Database::connect(&self.url)
+            .await
+            .expect("failed to connect to the test database")
+    }
+
+    /// Values of the first column of a query, which must be text, null is empty
+    pub async fn column(&self, sql: &str) -> Vec<String> {
+        let conn = self.connect().await;
+        let rows = conn
+            .query_all(Statement::from_string(
+                conn.get_database_backend(),
+                sql.to_string(),
+            ))
+            .await
+            .unwrap_or_else(|e| panic!("{sql}: {e}"));
+        conn.close().await.ok();
+
+        rows.iter()
+            .map(|row| {
+                row.try_get_by_index::<Option<String>>(0)
+                    .expect("first column is not text")
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let admin_url = self.admin_url.clone();
+        let name = self.name.clone();
+        async_std::task::block_on(async move {
+            if let Ok(admin) = Database::connect(&admin_url).await {
+                admin
+                    .execute_unprepared(&format!("DROP DATABASE IF EXISTS {name} WITH (FORCE)"))
+                    .await
+                    .ok();
+                admin.close().await.ok();
+            }
+        });
+    }
+}
+
+/// Spec of a package at a version
+pub fn spec(version: &str) -> String {
+    format!(
+        "VER={version}\nSRCS=\"tbl::https://example.org/src-$VER.tar.gz\"\nCHKSUMS=\"SKIP\"\nCHKUPDATE=\"anitya::id=1\"\n"
+    )
+}
+
+/// Defines of a package, `extra` is appended as is
+pub fn defines(name: &str, extra: &str) -> String {
+    format!("PKGNAME={name}\nPKGDES=\"Description of {name}\"\n{extra}")
+}
+
+/// Write a package with [spec] and [defines], `section` is like app-utils
+pub fn add_package(
+    fixture: &mut FixtureRepo,
+    section: &str,
+    name: &str,
+    version: &str,
+    extra: &str,
+) -> Result<()> {
+    fixture.add_package(section, name, &spec(version), &defines(name, extra))?;
+
+    Ok(())
+}
+
+/// Packages written and deleted by [scan]
+#[derive(Debug, Default)]
+pub struct Scanned {
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+/// Scan a tree into the database like the scan subcommand, without its reporting
+pub async fn scan(global: &Global, repo_config: &Repo) -> Result<Scanned> {
+    scan_with(global, repo_config, |abbs_db| abbs_db).await
+}
+
+/// [scan] with options of the abbs database set by `configure`
+pub async fn scan_with(
+    global: &Global,
+    repo_config: &Repo,
+    configure: impl FnOnce(AbbsDb) -> AbbsDb,
+) -> Result<Scanned> {
+    let repo = &Repository::open(repo_config)?;
+    let commit_db = &CommitDb::open(global).await?;
+    let abbs_db = &configure(AbbsDb::open(global, repo_config).await?.strict_writes(true));
+    if repo_config.scan_testing_branches {
+        abbs_db.update_testing_branch(commit_db, repo).await?;
+    }
+    commit_db.update_branch(repo, &repo.branch).await?;
+    write_updated(repo, commit_db, abbs_db).await
+}
+
+/// Write the packages updated since the last scan, after the branch was updated
+pub async fn write_updated(
+    repo: &Repository,
+    commit_db: &CommitDb,
+    abbs_db: &AbbsDb,
+) -> Result<Scanned> {
+    let UpdatedPackages {
+        deleted,
+        updated,
+        moved,
+        broken,
+        failed,
+        commit,
+        ..
+    } = commit_db.get_updated_packages(repo, &repo.branch).await?;
+
+    let mut deleted: Vec<_> = deleted.into_iter().map(|(pkg, ..)| pkg.name).collect();
+    for name in abbs_db
+        .get_vanished_packages(repo, commit, &updated)
+        .await?
+    {
+        if !deleted.contains(&name) {
+            deleted.push(name);
+        }
+    }
+    abbs_db.delete_packages(&deleted).await?;
+    abbs_db
+        .move_packages(repo, &moved, &updated, commit)
+        .await?;
+    abbs_db.preserve_versions(&failed).await?;
+    abbs_db.add_errors(broken).await?;
+
+    let mut names = vec![];
+    for pkg_meta in updated {
+        let mut changes = commit_db
+            .get_package_changes(repo, &pkg_meta.0.name)
+            .await?;
+        if changes.is_empty() {
+            changes.extend(commit_db.fallback_change(repo, &pkg_meta.0)?);
+        }
+        names.push(pkg_meta.0.name.clone());
+        abbs_db.add_package(pkg_meta, changes).await?;
+    }
+    abbs_db.update_groups(repo).await?;
+    abbs_db.reconcile(repo).await?;
+    abbs_db.record_head(repo, commit).await?;
+
+    Ok(Scanned {
+        updated: names,
+        deleted,
+    })
+}
@@ -0,0 +1,68 @@
+//! Differences between two abbs databases
+mod common;
+
+use abbs_meta::db::diff::diff_databases;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, defines, scan, spec, TestDb};
+use std::path::Path;
+
+const UPDATE_GOLDEN_VAR: &str = "ABBS_META_UPDATE_GOLDEN";
+
+#[async_std::test]
+async fn diff_matches_the_golden_markdown() -> Result<()> {
+    let (Some(old), Some(new)) = (TestDb::new().await, TestDb::new().await) else {
+        return Ok(());
+    };
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "PKGDEP=\"bar\"\n")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "baz", "3.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&old.global(), &repo_config).await?;
+
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.1",
+        "PKGDEP=\"bar>=2.1 qux\"\nPKGDEP__ARM64=\"bar>=2.1\"\n",
+    )?;
+    fixture.add_package(
+        "app-utils",
+        "bar",
+        &spec("2.1"),
+        "PKGNAME=bar\nPKGDES=\"Bar,   reworded\"\nPKGDEP=\"invalid>=\"\n",
+    )?;
+    fixture.remove_package("app-utils/baz")?;
+    fixture.add_package("app-utils", "qux", &spec("4.0"), &defines("qux", ""))?;
+    fixture.commit(
+        "foo: update to 1.1\nbar: update to 2.1\nbaz: drop\nqux: new",
+        "Bob",
+    )?;
+    scan(
+        &new.global_with("architectures = [\"amd64\", \"arm64\"]"),
+        &repo_config,
+    )
+    .await?;
+
+    let diff = diff_databases(&old.url, &new.url).await?;
+    let markdown = diff.to_markdown();
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/diff.md");
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        std::fs::write(&golden, &markdown)?;
+    }
+    assert_eq!(
+        markdown,
+        std::fs::read_to_string(&golden)?,
+        "set {UPDATE_GOLDEN_VAR} to update {}",
+        golden.display()
+    );
+    assert!(
+        diff_databases(&old.url, &old.url).await.is_err(),
+        "a database is compared with itself"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Packages found in more than one location
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+
+const CANONICAL: &str =
+    "SELECT category || '-' || section || '/' || directory FROM packages WHERE name = 'foo'";
+
+#[async_std::test]
+async fn preferred_copies_are_kept_whatever_is_scanned_last() -> Result<()> {
+    for (preferred, other) in [("app-admin", "app-utils"), ("app-utils", "app-admin")] {
+        let Some(db) = TestDb::new().await else {
+            return Ok(());
+        };
+        let global = db.global();
+        let mut fixture = FixtureRepo::new("stable")?;
+        add_package(&mut fixture, "app-admin", "foo", "1.0", "")?;
+        add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+        fixture.commit("foo: new, 1.0", "Alice")?;
+        let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+        scan(&global, &repo_config).await?;
+        let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+        abbs_db
+            .resolve_duplicate("foo", &format!("aosc-os-abbs/{preferred}/foo/"))
+            .await?;
+
+        // the preferred copy becomes canonical once scanned, and stays so
+        let mut version = 1;
+        for sections in [
+            vec![preferred],
+            vec![other],
+            vec![other, preferred],
+            vec![preferred, other],
+        ] {
+            for section in sections {
+                version += 1;
+                add_package(&mut fixture, section, "foo", &format!("1.{version}"), "")?;
+                fixture.commit(&format!("foo: update {section} copy"), "Alice")?;
+                scan(&global, &repo_config).await?;
+                assert_eq!(
+                    db.column(CANONICAL).await,
+                    [format!("{preferred}/foo")],
+                    "{section} scanned last"
+                );
+            }
+        }
+        let duplicates = abbs_db.get_duplicates().await?;
+        let locations = duplicates[0]
+            .locations
+            .iter()
+            .map(|l| (l.location.as_str(), l.canonical, l.preferred, l.stale))
+            .collect::<Vec<_>>();
+        let preferred_location = format!("aosc-os-abbs/{preferred}/foo");
+        let other_location = format!("aosc-os-abbs/{other}/foo");
+        let mut expected = vec![
+            (preferred_location.as_str(), true, true, false),
+            (other_location.as_str(), false, false, false),
+        ];
+        expected.sort();
+        assert_eq!(locations, expected);
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn resolutions_of_removed_locations_are_stale() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    for section in ["app-admin", "app-utils", "app-misc"] {
+        add_package(&mut fixture, section, "foo", "1.0", "")?;
+    }
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    abbs_db
+        .resolve_duplicate("foo", "aosc-os-abbs/app-misc/foo")
+        .await?;
+    let e = abbs_db
+        .resolve_duplicate("foo", "aosc-os-abbs/app-shells/foo")
+        .await
+        .expect_err("preferred a location which isn't recorded");
+    assert!(e.to_string().contains("not a location of foo"), "{e}");
+
+    fixture.remove_package("app-misc/foo")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: drop app-misc copy, update to 1.1", "Alice")?;
+    scan(&global, &repo_config).await?;
+    let stale = "SELECT stale::text FROM package_duplicate_resolution";
+    assert_eq!(db.column(stale).await, ["true"]);
+    assert_eq!(
+        db.column(CANONICAL).await,
+        ["app-utils/foo"],
+        "stale resolutions have no effect"
+    );
+
+    // and take effect again once the location returns
+    add_package(&mut fixture, "app-misc", "foo", "1.2", "")?;
+    fixture.commit("foo: restore app-misc copy", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column(stale).await, ["false"]);
+    assert_eq!(db.column(CANONICAL).await, ["app-misc/foo"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn removed_locations_are_reconciled() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    for section in ["app-admin", "app-misc", "app-utils"] {
+        add_package(&mut fixture, section, "foo", "1.0", "")?;
+    }
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let locations = "SELECT category || '-' || section || '/' || directory \
+                     FROM package_duplicate ORDER BY 1";
+    assert_eq!(
+        db.column(locations).await,
+        ["app-admin/foo", "app-misc/foo", "app-utils/foo"]
+    );
+
+    // the removed location is forgotten, the package is still a duplicate
+    fixture.remove_package("app-misc/foo")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: drop app-misc copy", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(locations).await,
+        ["app-admin/foo", "app-utils/foo"]
+    );
+
+    // and resolved once a single location is left
+    fixture.remove_package("app-admin/foo")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.2", "")?;
+    fixture.commit("foo: drop app-admin copy", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert!(db.column(locations).await.is_empty());
+    assert_eq!(db.column(CANONICAL).await, ["app-utils/foo"]);
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert!(abbs_db.get_duplicates().await?.is_empty());
+
+    Ok(())
+}
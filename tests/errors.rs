@@ -0,0 +1,165 @@
+//! Errors of packages saved by scans
+mod common;
+
+use abbs_meta::db::abbs::{AbbsDb, ErrorType, PackageError};
+use abbs_meta::test_support::FixtureRepo;
+use abbs_meta::warnings::Warnings;
+use anyhow::{Context, Result};
+use common::{defines, scan, scan_with, spec, TestDb};
+
+#[async_std::test]
+async fn error_locations_are_rendered_from_the_template() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    fixture.add_package(
+        "app-utils",
+        "foo",
+        &format!("{}not an assignment\n", spec("1.0")),
+        &defines("foo", ""),
+    )?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    repo_config.url_template =
+        Some("https://example.org/blob/{commit}/{path}#L{line}-L{end_line}".to_string());
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let error = abbs_db
+        .get_errors(None, None)
+        .await?
+        .into_iter()
+        .find(|error| error.err_type == ErrorType::Parse)
+        .context("no parse error is saved")?;
+    assert_eq!(
+        abbs_db.render_location(&error, "0123abc").as_deref(),
+        Some("https://example.org/blob/0123abc/app-utils/foo/spec#L5-L5"),
+        "errors at a single position end at their line"
+    );
+    let range = PackageError {
+        end_line: Some(7),
+        ..error.clone()
+    };
+    assert_eq!(
+        abbs_db.render_location(&range, "0123abc").as_deref(),
+        Some("https://example.org/blob/0123abc/app-utils/foo/spec#L5-L7")
+    );
+    let file = PackageError {
+        line: None,
+        col: None,
+        ..error.clone()
+    };
+    assert_eq!(
+        abbs_db.render_location(&file, "0123abc").as_deref(),
+        Some("https://example.org/blob/0123abc/app-utils/foo/spec")
+    );
+
+    repo_config.url_template = None;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert_eq!(abbs_db.render_location(&error, "0123abc"), None);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_without_description_have_errors() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    fixture.add_package("app-utils", "empty", &spec("1.0"), "PKGNAME=empty\n")?;
+    fixture.add_package(
+        "app-utils",
+        "blank",
+        &spec("1.0"),
+        "PKGNAME=blank\nPKGDES=\" \t \"\n",
+    )?;
+    fixture.add_package(
+        "app-utils",
+        "normal",
+        &spec("1.0"),
+        "PKGNAME=normal\nPKGDES=\"  A   tool\n  for things \"\n",
+    )?;
+    fixture.commit("empty, blank, normal: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let errors = abbs_db
+        .get_errors(None, Some(ErrorType::Description))
+        .await?
+        .into_iter()
+        .map(|error| (error.package, error.message))
+        .collect::<Vec<_>>();
+    let missing = "missing description (PKGDES)".to_string();
+    assert_eq!(
+        errors,
+        [
+            ("blank".to_string(), missing.clone()),
+            ("empty".to_string(), missing)
+        ]
+    );
+    assert_eq!(
+        db.column("SELECT name || ': ' || description FROM packages ORDER BY name")
+            .await,
+        ["blank: ", "empty: ", "normal: A tool for things"],
+        "whitespace is trimmed and collapsed"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn long_values_are_truncated_and_nul_bytes_rejected() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("[value_limits]\ndescription = 12\nchange_message = 8\n");
+    let mut fixture = FixtureRepo::new("stable")?;
+    fixture.add_package(
+        "app-utils",
+        "foo",
+        &spec("1.0"),
+        "PKGNAME=foo\nPKGDES=\"A description of foo\"\nPKGEPOCH=\"1\0\"\n",
+    )?;
+    let commit = fixture.commit("foo: new, 1.0", "Alice")?;
+    let warnings = Warnings::new();
+    scan_with(
+        &global,
+        &fixture.repo_config("aosc-os-abbs", "stable"),
+        |abbs_db| abbs_db.with_warnings(warnings.clone()),
+    )
+    .await?;
+
+    assert_eq!(
+        db.column("SELECT description FROM packages WHERE name = 'foo'")
+            .await,
+        ["A descriptio"]
+    );
+    assert_eq!(
+        db.column("SELECT message FROM package_changes WHERE package = 'foo'")
+            .await,
+        ["foo: new"]
+    );
+    assert!(
+        db.column("SELECT value FROM package_spec WHERE key = 'PKGEPOCH'")
+            .await
+            .is_empty(),
+        "values with NUL bytes are not saved"
+    );
+    assert_eq!(
+        db.column("SELECT message FROM package_errors WHERE err_type = 'value' ORDER BY message")
+            .await,
+        [
+            "PKGDES is 20 bytes, truncated to 12".to_string(),
+            "PKGEPOCH contains NUL bytes, not saved".to_string(),
+            format!("commit message of {commit} is 13 bytes, truncated to 8"),
+        ]
+    );
+    assert_eq!(warnings.counts()["truncated value"].total, 2);
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Atom feeds written at the end of each scan
+mod common;
+
+use abbs_meta::config::Feeds;
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::feed::write_feeds;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+use std::fs;
+
+#[async_std::test]
+async fn feeds_are_written_for_the_tree_and_sections() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "lang-python", "bar", "2.0", "")?;
+    let added = fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "lang-python", "bar", "2.1-beta", "")?;
+    let broken = fixture.commit("bar: update to 2.1-beta", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    let output_dir = fixture.path().join("feeds");
+    let config: Feeds = toml::from_str(&format!(
+        "output_dir = {:?}\nsections = true",
+        output_dir.display().to_string()
+    ))?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let written = write_feeds(&config, &repo_config, &abbs_db).await?;
+    let dir = output_dir.join("aosc-os-abbs");
+    assert_eq!(
+        written,
+        [
+            dir.join("sections/python.atom"),
+            dir.join("sections/utils.atom"),
+            dir.join("updates.atom"),
+            dir.join("new-errors.atom"),
+        ]
+    );
+
+    let updates = fs::read_to_string(dir.join("updates.atom"))?;
+    assert!(updates.contains(&format!(
+        "<id>urn:abbs-meta:aosc-os-abbs:change:foo:{added}</id>"
+    )));
+    assert!(updates.contains("<updated>2023-11-14T22:13:20+00:00</updated>"));
+    let section = fs::read_to_string(dir.join("sections/utils.atom"))?;
+    assert!(section.contains("<title>foo 1.0</title>"));
+    assert!(!section.contains("<title>bar"), "{section}");
+    let errors = fs::read_to_string(dir.join("new-errors.atom"))?;
+    assert!(errors.contains(&format!("urn:abbs-meta:aosc-os-abbs:error:bar:{broken}:")));
+    assert!(errors.contains("<title>bar: invariant error</title>"));
+
+    // entry ids and times don't change when written again
+    write_feeds(&config, &repo_config, &abbs_db).await?;
+    assert_eq!(fs::read_to_string(dir.join("updates.atom"))?, updates);
+
+    Ok(())
+}
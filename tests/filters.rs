@@ -0,0 +1,65 @@
+//! Filters of package listings
+mod common;
+
+use abbs_meta::db::abbs::{AbbsDb, PackageFilter};
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+
+#[async_std::test]
+async fn sections_are_combined_with_the_tree() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut bsps = FixtureRepo::new("stable")?;
+    add_package(&mut bsps, "app-utils", "bsp-utils", "1.0", "")?;
+    add_package(&mut bsps, "app-misc", "bsp-misc", "1.0", "")?;
+    bsps.commit("bsp-*: new, 1.0", "Alice")?;
+    let mut bsps_config = bsps.repo_config("aosc-os-bsps", "stable");
+    bsps_config.priority = 2;
+    scan(&global, &bsps_config).await?;
+
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-admin", "bar", "1.0", "")?;
+    add_package(&mut fixture, "app-misc", "baz", "1.0", "")?;
+    add_package(&mut fixture, "core-utils", "qux", "1.0", "")?;
+    fixture.commit("*: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+
+    let list = |filter: PackageFilter| {
+        let abbs_db = &abbs_db;
+        async move {
+            let packages = abbs_db.list_packages(&filter).await?;
+            anyhow::Ok(packages.into_iter().map(|pkg| pkg.name).collect::<Vec<_>>())
+        }
+    };
+    let sections = vec!["utils".to_string(), "admin".to_string()];
+
+    // sections are OR-ed with each other, AND-ed with the tree
+    let filter = PackageFilter {
+        sections: sections.clone(),
+        ..Default::default()
+    };
+    assert_eq!(list(filter).await?, ["bar", "foo", "qux"]);
+    let filter = PackageFilter {
+        sections: sections.clone(),
+        tree: Some("aosc-os-bsps".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(list(filter).await?, ["bsp-utils"]);
+
+    // and with the categories
+    let filter = PackageFilter {
+        sections,
+        categories: vec!["app".to_string()],
+        tree: Some("aosc-os-abbs".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(list(filter).await?, ["bar", "foo"]);
+
+    Ok(())
+}
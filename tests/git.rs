@@ -0,0 +1,150 @@
+//! Opening fixture repositories
+mod common;
+
+use abbs_meta::git::{BranchRef, Repository};
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+use git2::BranchType;
+
+#[test]
+fn open_unsynced_leaves_the_local_branch() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let old = fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("topic")?;
+    fixture.checkout("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let new = fixture.commit("foo: update to 1.1", "Alice")?;
+    // the remote-tracking branch of topic is ahead of it
+    let git2repo = fixture.git2repo();
+    git2repo.remote("origin", "https://example.org/aosc-os-abbs.git")?;
+    git2repo.reference("refs/remotes/origin/topic", new, false, "fetch")?;
+    let mut repo_config = fixture.repo_config("aosc-os-abbs", "topic");
+    repo_config.sync_branch = true;
+    let topic = || -> Result<_> {
+        Ok(fixture
+            .git2repo()
+            .find_reference("refs/heads/topic")?
+            .target())
+    };
+
+    Repository::open_unsynced(&repo_config)?;
+    assert_eq!(topic()?, Some(old));
+    Repository::open(&repo_config)?;
+    assert_eq!(topic()?, Some(new));
+
+    Ok(())
+}
+
+#[test]
+fn branches_without_commits_are_unborn() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    assert!(Repository::open(&repo_config).is_err());
+    assert!(Repository::is_unborn(&repo_config)?);
+
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    assert!(!Repository::is_unborn(&repo_config)?);
+    assert!(
+        !Repository::is_unborn(&fixture.repo_config("aosc-os-abbs", "missing"))?,
+        "branches which aren't checked out aren't unborn"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn branches_resolve_without_a_local_branch() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let old = fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let new = fixture.commit("foo: update to 1.1", "Alice")?;
+    // a mirror with remote-tracking branches only, origin is preferred
+    let git2repo = fixture.git2repo();
+    git2repo.remote("mirror", "https://mirror.example.org/aosc-os-abbs.git")?;
+    git2repo.remote("origin", "https://example.org/aosc-os-abbs.git")?;
+    git2repo.reference("refs/remotes/mirror/stable", old, false, "fetch")?;
+    git2repo.reference("refs/remotes/origin/stable", new, false, "fetch")?;
+    git2repo.set_head_detached(new)?;
+    git2repo
+        .find_branch("stable", BranchType::Local)?
+        .delete()?;
+
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let repo = Repository::open(&repo_config)?;
+    assert_eq!(repo.head, BranchRef::Remote("origin/stable".to_string()));
+    assert_eq!(repo.get_branch_oid("stable")?, new);
+
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", &old.to_string()))?;
+    assert_eq!(repo.head, BranchRef::Detached(old));
+
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    scan(&db.global(), &repo_config).await?;
+    assert_eq!(
+        db.column("SELECT DISTINCT branch FROM commits").await,
+        ["stable"],
+        "rows are saved under the configured name"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn local_branches_are_synced_with_their_remote() -> Result<()> {
+    // a clone whose HEAD is master, with stable only fetched from origin
+    let mut fixture = FixtureRepo::new("master")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let upstream = fixture.commit("foo: update to 1.1", "Alice")?;
+    fixture.checkout("master")?;
+    add_package(&mut fixture, "app-utils", "bar", "1.0", "")?;
+    let diverged = fixture.commit("bar: new, 1.0", "Bob")?;
+    let git2repo = fixture.git2repo();
+    git2repo.remote("origin", "https://example.org/aosc-os-abbs.git")?;
+    git2repo.reference("refs/remotes/origin/stable", upstream, false, "fetch")?;
+    git2repo
+        .find_branch("stable", BranchType::Local)?
+        .delete()?;
+    let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    repo_config.sync_branch = true;
+    let stable = || -> Result<_> {
+        Ok(fixture
+            .git2repo()
+            .find_reference("refs/heads/stable")?
+            .target())
+    };
+
+    // the missing local branch is created
+    let repo = Repository::open(&repo_config)?;
+    assert_eq!(repo.head, BranchRef::Local);
+    assert_eq!(stable()?, Some(upstream));
+
+    // a diverged one is only moved with force_branch_sync
+    fixture
+        .git2repo()
+        .reference("refs/heads/stable", diverged, true, "diverge")?;
+    Repository::open(&repo_config)?;
+    assert_eq!(stable()?, Some(diverged));
+    repo_config.force_branch_sync = true;
+    Repository::open(&repo_config)?;
+    assert_eq!(stable()?, Some(upstream));
+
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    scan(&db.global(), &repo_config).await?;
+    assert_eq!(
+        db.column("SELECT package || ' ' || version FROM package_versions")
+            .await,
+        ["foo 1.1"]
+    );
+
+    Ok(())
+}
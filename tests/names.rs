@@ -0,0 +1,312 @@
+//! Naming policy and collisions of package names
+mod common;
+
+use abbs_meta::db::abbs::{AbbsDb, NameClass};
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, scan_with, spec, TestDb};
+
+#[async_std::test]
+async fn collisions_are_recorded_for_both_packages_of_a_tree() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "Foo", "1.0", "")?;
+    fixture.commit("Foo: new, 1.0", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT package || ' ' || tree || ': ' || message FROM package_errors \
+             WHERE message LIKE '%collides%' ORDER BY package"
+        )
+        .await,
+        [
+            "Foo aosc-os-abbs: package name Foo collides with foo",
+            "foo aosc-os-abbs: package name foo collides with Foo",
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn collisions_keep_the_errors_of_the_other_package() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    // without PKGDES, foo has an error of its own
+    fixture.add_package("app-utils", "foo", &spec("1.0"), "PKGNAME=foo\n")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "Foo", "1.0", "")?;
+    fixture.commit("Foo: new, 1.0", "Alice")?;
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "Foo", "1.1", "")?;
+    fixture.commit("Foo: update to 1.1", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT err_type || ': ' || message FROM package_errors \
+             WHERE package = 'foo' ORDER BY err_type"
+        )
+        .await,
+        [
+            "description: missing description (PKGDES)",
+            "name: package name foo collides with Foo",
+        ],
+        "scans of Foo neither delete nor duplicate errors of foo"
+    );
+    assert_eq!(
+        db.column(
+            "SELECT event || ' ' || err_type FROM package_error_events \
+             WHERE package = 'foo' ORDER BY id"
+        )
+        .await,
+        ["introduced description", "introduced name"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn collisions_are_resolved_for_the_other_package() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "Foo", "1.0", "")?;
+    fixture.commit("Foo: new, 1.0", "Alice")?;
+    scan(&global, &repo_config).await?;
+    fixture.remove_package("app-utils/Foo")?;
+    fixture.commit("Foo: drop", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    let errors = "SELECT message FROM package_errors WHERE package = 'foo'";
+    assert!(db.column(errors).await.is_empty(), "dropping Foo fixes foo");
+    add_package(&mut fixture, "app-utils", "Foo", "1.0", "")?;
+    fixture.commit("Foo: new, 1.0", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(errors).await,
+        ["package name foo collides with Foo"]
+    );
+    assert_eq!(
+        db.column(
+            "SELECT event || ' ' || message FROM package_error_events \
+             WHERE package = 'foo' ORDER BY id"
+        )
+        .await,
+        [
+            "introduced package name foo collides with Foo",
+            "resolved package name foo collides with Foo",
+            "introduced package name foo collides with Foo",
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn collisions_across_trees_are_recorded_in_the_scanned_tree() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut main = FixtureRepo::new("stable")?;
+    add_package(&mut main, "app-utils", "foo", "1.0", "")?;
+    main.commit("foo: new, 1.0", "Alice")?;
+    scan(&global, &main.repo_config("aosc-os-abbs", "stable")).await?;
+    let mut bsps = FixtureRepo::new("stable")?;
+    add_package(&mut bsps, "app-utils", "Foo", "1.0", "")?;
+    bsps.commit("Foo: new, 1.0", "Alice")?;
+    let mut bsps_config = bsps.repo_config("aosc-os-bsps", "stable");
+    bsps_config.priority = 2;
+    scan(&global, &bsps_config).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT package || ' ' || tree || ': ' || message FROM package_errors \
+             WHERE message LIKE '%collides%'"
+        )
+        .await,
+        ["Foo aosc-os-bsps: package name Foo collides with foo of aosc-os-abbs"],
+        "no error of foo is written in aosc-os-bsps"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn rejected_names_delete_packages_written_before() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo_bar", "1.0", "")?;
+    fixture.commit("foo_bar: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column("SELECT name FROM packages").await, ["foo_bar"]);
+
+    add_package(&mut fixture, "app-utils", "foo_bar", "1.1", "")?;
+    fixture.commit("foo_bar: update to 1.1", "Alice")?;
+    scan_with(&global, &repo_config, |abbs_db| {
+        abbs_db.reject_invalid_names(true)
+    })
+    .await?;
+    assert!(db.column("SELECT name FROM packages").await.is_empty());
+    assert!(db
+        .column("SELECT package FROM package_versions")
+        .await
+        .is_empty());
+    assert!(db
+        .column("SELECT package FROM package_dependency_counts")
+        .await
+        .is_empty());
+    assert_eq!(
+        db.column("SELECT package || ' ' || err_type FROM package_errors")
+            .await,
+        ["foo_bar name"],
+        "the rejection is recorded as an error"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn shared_provides_are_recorded_for_each_provider() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "zsh",
+        "1.0",
+        "PKGPROV=\"sh-compat\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "dash",
+        "1.0",
+        "PKGPROV=\"sh-compat\"\n",
+    )?;
+    add_package(&mut fixture, "app-utils", "bash", "1.0", "")?;
+    fixture.commit("zsh, dash, bash: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let errors = "SELECT package || ': ' || message FROM package_errors \
+                  WHERE err_type = 'provider' ORDER BY package";
+    assert_eq!(
+        db.column(errors).await,
+        [
+            "dash: sh-compat is also provided by zsh",
+            "zsh: sh-compat is also provided by dash",
+        ]
+    );
+
+    // other providers are listed by name, not by the order they were scanned in
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "bash",
+        "1.1",
+        "PKGPROV=\"sh-compat\"\n",
+    )?;
+    fixture.commit("bash: provide sh-compat", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(errors).await,
+        [
+            "bash: sh-compat is also provided by dash, zsh",
+            "dash: sh-compat is also provided by bash, zsh",
+            "zsh: sh-compat is also provided by bash, dash",
+        ]
+    );
+
+    add_package(&mut fixture, "app-utils", "zsh", "1.1", "")?;
+    add_package(&mut fixture, "app-utils", "dash", "1.1", "")?;
+    fixture.commit("zsh, dash: drop sh-compat", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert!(db.column(errors).await.is_empty());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn names_are_classified_in_one_call() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-shells",
+        "zsh",
+        "1.0",
+        "PKGPROV=\"sh-compat\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-shells",
+        "dash",
+        "1.0",
+        "PKGPROV=\"sh-compat\"\n",
+    )?;
+    add_package(&mut fixture, "app-utils", "Foo", "1.0", "")?;
+    fixture.commit("zsh, dash, Foo: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+
+    let names = ["zsh", "foo", "sh-compat", "SH-COMPAT", "fish"].map(String::from);
+    let classes = abbs_db.classify_names(&names).await?;
+    assert_eq!(classes.len(), 5);
+    assert_eq!(
+        classes["zsh"],
+        NameClass::Package {
+            name: "zsh".to_string(),
+            tree: "aosc-os-abbs".to_string(),
+            section: "shells".to_string(),
+        }
+    );
+    assert_eq!(
+        classes["foo"],
+        NameClass::Package {
+            name: "Foo".to_string(),
+            tree: "aosc-os-abbs".to_string(),
+            section: "utils".to_string(),
+        },
+        "names are compared case-insensitively"
+    );
+    let provided = NameClass::Provided {
+        by: vec!["dash".to_string(), "zsh".to_string()],
+    };
+    assert_eq!(classes["sh-compat"], provided);
+    assert_eq!(classes["SH-COMPAT"], provided);
+    assert_eq!(classes["fish"], NameClass::Unknown);
+
+    Ok(())
+}
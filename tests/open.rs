@@ -0,0 +1,268 @@
+//! Opening databases for scans and for queries
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::commits::CommitDb;
+use abbs_meta::db::reindex;
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+use sea_orm::ConnectionTrait;
+use std::panic::{self, AssertUnwindSafe};
+
+#[async_std::test]
+async fn read_only_open_requires_a_scanned_database() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+
+    let e = AbbsDb::open_read_only(&global, &repo_config)
+        .await
+        .err()
+        .expect("opened a database which is not set up");
+    assert!(e.to_string().contains("run a scan first"), "{e}");
+    assert!(CommitDb::open_read_only(&global).await.is_err());
+    assert!(
+        db.column("SELECT tablename::text FROM pg_tables WHERE schemaname = 'public'")
+            .await
+            .is_empty(),
+        "read only opens don't set up the database"
+    );
+
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let pkg = abbs_db.get_package("foo").await?.expect("foo is not found");
+    assert_eq!(pkg.version.as_deref(), Some("1.0"));
+    assert!(
+        abbs_db.delete_package("foo").await.is_err(),
+        "writes through a read only open are rejected"
+    );
+    assert_eq!(db.column("SELECT name FROM packages").await, ["foo"]);
+    CommitDb::open_read_only(&global).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn read_only_open_refuses_another_schema_version() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    db.connect()
+        .await
+        .execute_unprepared(
+            "UPDATE schema_meta SET value = 'older' WHERE key = 'abbs_schema_version'",
+        )
+        .await?;
+    let e = AbbsDb::open_read_only(&global, &repo_config)
+        .await
+        .err()
+        .expect("opened a database of another schema version");
+    assert!(e.to_string().contains("run a scan to migrate it"), "{e}");
+
+    // a scan migrates the database and stamps the version again
+    AbbsDb::open(&global, &repo_config).await?;
+    AbbsDb::open_read_only(&global, &repo_config).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn separate_commits_database_holds_the_commit_tables() -> Result<()> {
+    let (Some(abbs), Some(commits)) = (TestDb::new().await, TestDb::new().await) else {
+        return Ok(());
+    };
+    let global = abbs.global_with(&format!("commits_database_url = \"{}\"", commits.url));
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let tables = "SELECT tablename::text FROM pg_tables \
+                  WHERE tablename IN ('commits', 'histories', 'packages') ORDER BY 1";
+    assert_eq!(commits.column(tables).await, ["commits", "histories"]);
+    assert_eq!(abbs.column(tables).await, ["packages"]);
+    let layout = "SELECT value FROM schema_meta WHERE key = 'layout'";
+    assert_eq!(commits.column(layout).await, ["commits"]);
+    assert_eq!(abbs.column(layout).await, ["abbs"]);
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let branches = abbs_db.get_package_branches("foo").await?;
+    assert_eq!(
+        branches
+            .iter()
+            .map(|b| (b.branch.as_str(), b.version.as_str()))
+            .collect::<Vec<_>>(),
+        [("stable", "1.0")]
+    );
+    CommitDb::open_read_only(&global).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn swapped_database_urls_are_refused() -> Result<()> {
+    let (Some(abbs), Some(commits)) = (TestDb::new().await, TestDb::new().await) else {
+        return Ok(());
+    };
+    let global = abbs.global_with(&format!("commits_database_url = \"{}\"", commits.url));
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let swapped = commits.global_with(&format!("commits_database_url = \"{}\"", abbs.url));
+    let e = CommitDb::open(&swapped)
+        .await
+        .err()
+        .expect("opened the abbs database as the commits one");
+    assert!(e.to_string().contains("swapped"), "{e}");
+    let e = AbbsDb::open(&swapped, &repo_config)
+        .await
+        .err()
+        .expect("opened the commits database as the abbs one");
+    assert!(e.to_string().contains("swapped"), "{e}");
+    assert!(AbbsDb::open_read_only(&swapped, &repo_config)
+        .await
+        .is_err());
+    assert!(
+        abbs.column("SELECT tablename::text FROM pg_tables WHERE tablename = 'commits'")
+            .await
+            .is_empty(),
+        "no commit tables are created in the abbs database"
+    );
+
+    // without commits_database_url both tables are expected in one database
+    let e = CommitDb::open(&abbs.global())
+        .await
+        .err()
+        .expect("opened the abbs database as a combined one");
+    assert!(e.to_string().contains("only holds abbs tables"), "{e}");
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn commits_database_url_of_the_same_database_is_combined() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with(&format!(
+        "commits_database_url = \"{}?application_name=commits\"",
+        db.url
+    ));
+    assert_eq!(global.separate_commits_database(), None);
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column("SELECT value FROM schema_meta WHERE key = 'layout'")
+            .await,
+        ["combined"]
+    );
+    // the same database as before, with or without the alias
+    AbbsDb::open_read_only(&db.global(), &repo_config).await?;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn mismatched_trees_fail_debug_assertions() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let other_config = fixture.repo_config("aosc-os-bsps", "stable");
+    let repo = Repository::open(&repo_config)?;
+    let other_repo = Repository::open(&other_config)?;
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+
+    let tree = repo_config.tree_id();
+    let other_tree = other_config.tree_id();
+    abbs_db.debug_assert_tree(&repo, &tree);
+    let fails = |repo: &Repository, tree| {
+        panic::catch_unwind(AssertUnwindSafe(|| abbs_db.debug_assert_tree(repo, tree))).is_err()
+    };
+    assert_eq!(fails(&other_repo, &tree), cfg!(debug_assertions));
+    assert_eq!(fails(&other_repo, &other_tree), cfg!(debug_assertions));
+    assert_eq!(fails(&repo, &other_tree), cfg!(debug_assertions));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn concurrent_opens_set_up_the_schema_once() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+
+    // like a webhook-triggered scan racing a cron run on a new database
+    let opens = (0..8)
+        .map(|_| {
+            let (global, repo_config) = (global.clone(), repo_config.clone());
+            async_std::task::spawn(async move {
+                AbbsDb::open(&global, &repo_config).await?;
+                CommitDb::open(&global).await?;
+                anyhow::Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for open in opens {
+        open.await?;
+    }
+
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column("SELECT name FROM packages").await, ["foo"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn reindexed_databases_keep_scanning() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    // what corruption_policy = "reindex" runs before opening again
+    reindex(&global.database_url, &global.performance).await?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Alice")?;
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let pkg = abbs_db.get_package("foo").await?.expect("foo is not found");
+    assert_eq!(pkg.version.as_deref(), Some("1.1"));
+
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! Parsing packages of fixture trees, without a database
+mod common;
+
+use abbs_meta::git::Repository;
+use abbs_meta::package::{
+    parse_chkupdate, path_to_defines_path, scan_packages, scan_tree, DefinesCache, PackageDump,
+};
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, defines, spec};
+use std::path::{Path, PathBuf};
+
+/// A spec with a line which doesn't parse, shared by three subpackages
+fn multi_defines_fixture() -> Result<FixtureRepo> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    fixture.write_file(
+        "app-utils/foo/spec",
+        &format!("{}not an assignment\n", spec("1.0")),
+    )?;
+    for (dir, name) in [
+        ("03-foo-doc", "foo-doc"),
+        ("01-foo", "foo"),
+        ("02-foo-dev", "foo-dev"),
+    ] {
+        fixture.write_file(
+            format!("app-utils/foo/{dir}/defines"),
+            &defines(name, "PKGSEC=utils\n"),
+        )?;
+    }
+    fixture.write_file("app-utils/bar/spec", &spec("2.0"))?;
+    fixture.write_file("app-utils/bar/autobuild/defines", &defines("bar", ""))?;
+    fixture.commit("foo, bar: new", "Alice")?;
+
+    Ok(fixture)
+}
+
+#[test]
+fn scan_packages_parses_shared_spec_once() -> Result<()> {
+    let fixture = multi_defines_fixture()?;
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", "stable"))?;
+    let commit = repo.get_branch_oid("stable")?;
+
+    let (packages, failed) = scan_tree(&repo, commit, &[], &[])?;
+    assert!(failed.is_empty());
+    let names = packages
+        .iter()
+        .map(|(pkg, ..)| pkg.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        ["bar", "foo", "foo-dev", "foo-doc"],
+        "packages are ordered by spec, then defines"
+    );
+    let spec_errors = packages
+        .iter()
+        .flat_map(|(_, _, errors, _)| errors)
+        .filter(|error| error.path == "app-utils/foo/spec")
+        .count();
+    assert_eq!(
+        spec_errors, 1,
+        "errors of a shared spec are attributed once"
+    );
+
+    let spec = PathBuf::from("app-utils/foo/spec");
+    let bar_spec = PathBuf::from("app-utils/bar/spec");
+    let dirs = [
+        (
+            spec.clone(),
+            PathBuf::from("app-utils/foo/02-foo-dev/defines"),
+        ),
+        (bar_spec, PathBuf::from("app-utils/bar/autobuild/defines")),
+        (
+            spec.clone(),
+            PathBuf::from("app-utils/foo/03-foo-doc/defines"),
+        ),
+        (spec, PathBuf::from("app-utils/foo/01-foo/defines")),
+    ];
+    for _ in 0..8 {
+        let (scanned, _) = scan_packages(
+            &repo,
+            commit,
+            dirs.iter().map(|(spec, defines)| (spec, defines)).collect(),
+            &[],
+        );
+        let scanned = scanned
+            .iter()
+            .map(|(pkg, _, errors, _)| (pkg.name.as_str(), errors.len()))
+            .collect::<Vec<_>>();
+        let whole_tree = packages
+            .iter()
+            .map(|(pkg, _, errors, _)| (pkg.name.as_str(), errors.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scanned, whole_tree,
+            "scanning changed packages gives the result of scanning the tree"
+        );
+    }
+
+    Ok(())
+}
+
+/// Set to rewrite golden files with the current output instead of comparing
+const UPDATE_GOLDEN_VAR: &str = "ABBS_META_UPDATE_GOLDEN";
+
+#[test]
+fn scan_tree_matches_the_golden_dump() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGREL=1\nPKGDEP=\"bar>=2.0 baz\"\nBUILDDEP=\"cmake\"\n",
+    )?;
+    add_package(&mut fixture, "lang-python", "bar", "2.0", "PKGSEC=python\n")?;
+    fixture.add_package("app-utils", "baz", &spec("3.0"), "PKGNAME=baz\n")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", "stable"))?;
+    let commit = repo.get_branch_oid("stable")?;
+
+    let (packages, failed) = scan_tree(&repo, commit, &[], &[])?;
+    assert!(failed.is_empty());
+    let mut packages = packages
+        .into_iter()
+        .map(PackageDump::from)
+        .collect::<Vec<_>>();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    let json = serde_json::to_string_pretty(&packages)? + "\n";
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/dump.json");
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        std::fs::write(&golden, &json)?;
+    }
+    assert_eq!(
+        json,
+        std::fs::read_to_string(&golden)?,
+        "set {UPDATE_GOLDEN_VAR} to update {}",
+        golden.display()
+    );
+
+    let (packages, _) = scan_tree(&repo, commit, &["lang-".to_string()], &[])?;
+    let names = packages
+        .iter()
+        .map(|(pkg, ..)| pkg.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["bar"], "only sections with the prefix are scanned");
+
+    Ok(())
+}
+
+#[test]
+fn scan_tree_skips_directories_without_packages() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "lang-python", "bar", "2.0", "")?;
+    fixture.write_file("groups/utils", "app-utils/foo\n")?;
+    fixture.write_file("README.md", "# aosc-os-abbs\n")?;
+    fixture.write_file(".github/workflows/defines", "not a package\n")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", "stable"))?;
+
+    let (packages, failed) = scan_tree(&repo, repo.get_branch_oid("stable")?, &[], &[])?;
+    assert!(failed.is_empty());
+    let packages = packages
+        .iter()
+        .map(|(pkg, _, errors, _)| (pkg.name.as_str(), errors.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(packages, [("foo", 0), ("bar", 0)]);
+
+    Ok(())
+}
+
+#[test]
+fn defines_of_files_in_the_same_directory_are_looked_up_once() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.write_file("app-utils/foo/autobuild/patches/0001-a.patch", "a\n")?;
+    fixture.write_file("app-utils/foo/autobuild/patches/0002-b.patch", "b\n")?;
+    let first = fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let repo = Repository::open(&repo_config)?;
+    let cache = DefinesCache::new();
+    let defines = |commit, path: &str| path_to_defines_path(&repo, commit, Path::new(path), &cache);
+    let expected = vec![PathBuf::from("app-utils/foo/autobuild/defines")];
+
+    // patches/, then autobuild/ where defines is found
+    assert_eq!(
+        defines(first, "app-utils/foo/autobuild/patches/0001-a.patch")?,
+        expected
+    );
+    assert_eq!(cache.tree_lookups(), 2);
+    assert_eq!(
+        defines(first, "app-utils/foo/autobuild/patches/0002-b.patch")?,
+        expected
+    );
+    assert_eq!(cache.tree_lookups(), 2, "the directory is cached");
+    // defines and spec are not looked up from their ancestors
+    assert_eq!(defines(first, "app-utils/foo/autobuild/defines")?, expected);
+    assert_eq!(cache.tree_lookups(), 2);
+
+    // the cache is keyed by tree, another tree is looked up again
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let second = fixture.commit("foo: update to 1.1", "Alice")?;
+    assert_eq!(
+        defines(second, "app-utils/foo/autobuild/patches/0001-a.patch")?,
+        expected
+    );
+    assert_eq!(cache.tree_lookups(), 4);
+    assert_eq!(
+        defines(first, "app-utils/foo/autobuild/patches/0002-b.patch")?,
+        expected
+    );
+    assert_eq!(cache.tree_lookups(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn chkupdate_is_split_into_method_and_parameters() {
+    let parse = |chkupdate| {
+        parse_chkupdate(chkupdate).map(|(method, params)| {
+            let params = params
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>();
+            (method, params)
+        })
+    };
+    assert_eq!(
+        parse("anitya::id=1234").unwrap(),
+        ("anitya".to_string(), vec!["id=1234".to_string()])
+    );
+    assert_eq!(
+        parse(" github::repo=foo/bar ; pattern=v(.+)=x ;").unwrap(),
+        (
+            "github".to_string(),
+            vec!["repo=foo/bar".to_string(), "pattern=v(.+)=x".to_string()]
+        ),
+        "values are split at the first =, and empty parameters skipped"
+    );
+
+    for (chkupdate, message) in [
+        ("anitya:id=1234", "missing method"),
+        ("::id=1234", "empty method"),
+        ("anitya::", "no parameters"),
+        ("anitya:: ; ", "no parameters"),
+        ("github::repo=foo/bar;pattern", "invalid parameter pattern"),
+    ] {
+        let e = parse(chkupdate).expect_err(chkupdate);
+        assert!(e.to_string().contains(message), "{chkupdate}: {e}");
+    }
+}
+
+#[test]
+fn defines_referencing_arch_are_parsed_for_each_architecture() -> Result<()> {
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"glibc libfoo-${ARCH}\"\nPKGRECOM__ARM64=\"bar\"\nPKGRECOM=\"bar-${ARCH}\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "bar",
+        "1.0",
+        "PKGDEP=\"glibc\"\n",
+    )?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", "stable"))?;
+    let architectures = ["amd64", "arm64"].map(String::from);
+
+    let (packages, _) = scan_tree(&repo, repo.get_branch_oid("stable")?, &[], &architectures)?;
+    let keys = |name: &str| {
+        let (_, context, ..) = packages.iter().find(|(pkg, ..)| pkg.name == name).unwrap();
+        let mut keys = context
+            .iter()
+            .filter(|(key, _)| key.starts_with("PKGDEP") || key.starts_with("PKGRECOM"))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+
+    // values differing by architecture are added, overrides in defines are kept
+    assert_eq!(
+        keys("foo"),
+        [
+            "PKGDEP=glibc libfoo-",
+            "PKGDEP__AMD64=glibc libfoo-amd64",
+            "PKGDEP__ARM64=glibc libfoo-arm64",
+            "PKGRECOM=bar-",
+            "PKGRECOM__AMD64=bar-amd64",
+            "PKGRECOM__ARM64=bar",
+        ]
+    );
+    // and defines without architecture variables are parsed once
+    assert_eq!(keys("bar"), ["PKGDEP=glibc"]);
+
+    Ok(())
+}
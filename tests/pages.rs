@@ -0,0 +1,84 @@
+//! Keyset pages of listings
+mod common;
+
+use abbs_meta::db::abbs::{AbbsDb, PackageFilter};
+use abbs_meta::db::page::PageRequest;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+
+#[async_std::test]
+async fn pages_continue_after_the_last_row() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    for name in ["a", "b", "c", "d", "e"] {
+        add_package(&mut fixture, "app-utils", name, "1.0", "")?;
+        fixture.commit(&format!("{name}: new, 1.0"), "Alice")?;
+    }
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+
+    let mut names = vec![];
+    let mut page = PageRequest {
+        cursor: None,
+        limit: Some(2),
+    };
+    loop {
+        let packages = abbs_db
+            .list_packages_page(&PackageFilter::default(), &page)
+            .await?;
+        assert!(packages.items.len() <= 2);
+        names.extend(packages.items.into_iter().map(|pkg| pkg.name));
+        // like a scan deleting a listed package between two pages
+        if names.len() == 2 {
+            abbs_db.delete_package("a").await?;
+        }
+        match packages.next {
+            Some(next) => page.cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(names, ["a", "b", "c", "d", "e"], "no package is skipped");
+
+    let changelog = abbs_db.get_changelog(10, false).await?;
+    let mut githashes = vec![];
+    let mut page = PageRequest {
+        cursor: None,
+        limit: Some(2),
+    };
+    loop {
+        let entries = abbs_db.get_changelog_page(&page, false).await?;
+        githashes.extend(entries.items.into_iter().map(|entry| entry.githash));
+        match entries.next {
+            Some(next) => page.cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(githashes.len(), 5);
+    assert_eq!(
+        githashes,
+        changelog
+            .into_iter()
+            .map(|entry| entry.githash)
+            .collect::<Vec<_>>()
+    );
+
+    let e = abbs_db
+        .list_packages_page(
+            &PackageFilter::default(),
+            &PageRequest {
+                cursor: Some("not a cursor".to_string()),
+                limit: None,
+            },
+        )
+        .await
+        .err()
+        .expect("accepted an invalid cursor");
+    assert!(e.to_string().contains("invalid cursor"), "{e}");
+
+    Ok(())
+}
@@ -0,0 +1,1257 @@
+//! Scans of fixture trees through the commit and abbs databases
+mod common;
+
+use abbs_meta::db::abbs::{
+    refresh_materialized_views, AbbsDb, ConstraintCheck, DepMatrix, PackageMove, PendingAction,
+    PendingPackage,
+};
+use abbs_meta::db::commits::CommitDb;
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use abbs_meta::warnings::Warnings;
+use anyhow::{Context, Result};
+use common::{add_package, defines, scan, scan_with, spec, TestDb};
+use itertools::Itertools;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[async_std::test]
+async fn add_commits_records_package_changes() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let first = fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    let second = fixture.commit("foo: update to 1.1\nbar: new, 2.0", "Bob")?;
+
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&db.global()).await?;
+    let infos = commit_db
+        .add_commits(&repo, "stable", vec![first, second])
+        .await?;
+    let mut recorded = infos
+        .iter()
+        .map(|info| (info.pkg_name.as_str(), info.commit_id))
+        .collect::<Vec<_>>();
+    recorded.sort();
+    let mut expected = vec![("bar", second), ("foo", first), ("foo", second)];
+    expected.sort();
+    assert_eq!(
+        recorded, expected,
+        "every package touched by a commit is recorded"
+    );
+
+    let rows = db
+        .column(
+            "SELECT pkg_name || ' ' || pkg_version || ' ' || status FROM commits \
+             ORDER BY commit_time, pkg_name",
+        )
+        .await;
+    assert_eq!(rows, ["foo 1.0 Added", "bar 2.0 Added", "foo 1.1 Modified"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn get_updated_packages_follows_the_branch() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let commit_db = CommitDb::open(&global).await?;
+
+    let repo = Repository::open(&repo_config)?;
+    commit_db.update_branch(&repo, "stable").await?;
+    let updated = commit_db.get_updated_packages(&repo, "stable").await?;
+    let mut names = updated
+        .updated
+        .iter()
+        .map(|pkg| pkg.0.name.as_str())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        ["bar", "foo"],
+        "the first scan updates every package"
+    );
+    assert!(updated.deleted.is_empty());
+    assert_eq!(updated.from, None);
+    // a scan records the head it reached, the next one starts there
+    scan(&global, &repo_config).await?;
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.remove_package("app-utils/bar")?;
+    let head = fixture.commit("foo: update to 1.1\nbar: drop", "Bob")?;
+    let repo = Repository::open(&repo_config)?;
+    commit_db.update_branch(&repo, "stable").await?;
+    let updated = commit_db.get_updated_packages(&repo, "stable").await?;
+    let names = updated
+        .updated
+        .iter()
+        .map(|pkg| (pkg.0.name.as_str(), pkg.0.version.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(names, [("foo", "1.1")]);
+    let deleted = updated
+        .deleted
+        .iter()
+        .map(|pkg| pkg.0.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(deleted, ["bar"]);
+    assert_eq!(updated.commit, head);
+    assert!(
+        updated.from.is_some(),
+        "later scans only look at new commits"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn add_package_writes_package_rows() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGREL=1\nPKGDEP=\"bar>=2.0\"\n",
+    )?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+
+    let scanned = scan(&global, &repo_config).await?;
+    assert_eq!(scanned.updated.len(), 2);
+    assert_eq!(
+        db.column("SELECT name || ' ' || tree || ' ' || section FROM packages ORDER BY name")
+            .await,
+        ["bar aosc-os-abbs utils", "foo aosc-os-abbs utils"]
+    );
+    assert_eq!(
+        db.column("SELECT full_version FROM package_versions WHERE package = 'foo'")
+            .await,
+        ["1.0-1"]
+    );
+    assert_eq!(
+        db.column(
+            "SELECT relationship || ' ' || dependency || ' ' || coalesce(relop, '') \
+             || coalesce(version, '') FROM package_dependencies WHERE package = 'foo'"
+        )
+        .await,
+        ["PKGDEP bar >=2.0"]
+    );
+    assert_eq!(
+        db.column("SELECT message FROM package_changes WHERE package = 'foo'")
+            .await,
+        ["foo, bar: new"]
+    );
+
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.1",
+        "PKGDEP=\"bar>=2.0\"\n",
+    )?;
+    fixture.remove_package("app-utils/bar")?;
+    fixture.commit("foo: update to 1.1\nbar: drop", "Bob")?;
+    let scanned = scan(&global, &repo_config).await?;
+    assert_eq!(scanned.updated, ["foo"]);
+    assert_eq!(scanned.deleted, ["bar"]);
+    assert_eq!(db.column("SELECT name FROM packages").await, ["foo"]);
+    assert_eq!(
+        db.column("SELECT full_version FROM package_versions WHERE package = 'foo'")
+            .await,
+        ["1.1"]
+    );
+    assert_eq!(
+        db.column(
+            "SELECT version || ' ' || maintainer_name FROM package_changes \
+             WHERE package = 'foo' ORDER BY timestamp"
+        )
+        .await,
+        ["1.0 Alice", "1.1 Bob"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn materialized_view_is_refreshed_after_scans() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("materialize_packages = true");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+    assert!(
+        db.column("SELECT name FROM m_packages").await.is_empty(),
+        "scanning a tree doesn't refresh m_packages"
+    );
+
+    refresh_materialized_views(&global).await?;
+    assert_eq!(db.column("SELECT name FROM m_packages").await, ["foo"]);
+    let columns = |view: &str| {
+        format!(
+            "SELECT attname::text FROM pg_attribute WHERE attrelid = '{view}'::regclass \
+             AND attnum > 0 AND NOT attisdropped ORDER BY attnum"
+        )
+    };
+    let m_columns = db.column(&columns("m_packages")).await;
+    assert_eq!(m_columns, db.column(&columns("v_packages")).await);
+    assert_eq!(
+        m_columns[..4],
+        ["name", "tree", "tree_category", "branch"],
+        "{m_columns:?}"
+    );
+    assert_eq!(
+        db.column(
+            "SELECT concat_ws(' ', name, tree, branch, version, full_version, pkgdep_count) \
+             FROM m_packages"
+        )
+        .await,
+        ["foo aosc-os-abbs stable 1.0 1.0 0"]
+    );
+    assert_eq!(
+        db.column(
+            "SELECT count(*)::text FROM \
+             (SELECT * FROM m_packages EXCEPT SELECT * FROM v_packages) changed"
+        )
+        .await,
+        ["0"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn arch_versions_are_read_from_keys_as_written() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGREL=2\nPKGVER__retro=0.9\nPKGREL__LOONGSON3=3\n",
+    )?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT architecture || ' ' || full_version FROM package_arch_versions \
+             WHERE package = 'foo' ORDER BY architecture"
+        )
+        .await,
+        ["loongson3 1.0-3", "retro 0.9-2"]
+    );
+    assert_eq!(
+        db.column("SELECT full_version FROM package_versions WHERE package = 'foo'")
+            .await,
+        ["1.0-2"],
+        "package_versions keeps the default version"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn dependency_counts_follow_rescans() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"bar baz qux\"\nBUILDDEP=\"cmake\"\n",
+    )?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let counts = || async {
+        let package = AbbsDb::open_read_only(&global, &repo_config)
+            .await?
+            .get_package("foo")
+            .await?
+            .context("foo is missing")?;
+        anyhow::Ok(package.dependency_counts)
+    };
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        counts().await?,
+        BTreeMap::from([("BUILDDEP".to_string(), 1), ("PKGDEP".to_string(), 3)])
+    );
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "PKGDEP=\"bar\"\n")?;
+    fixture.commit("foo: update to 1.1, drop baz, qux and cmake", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        counts().await?,
+        BTreeMap::from([("PKGDEP".to_string(), 1)]),
+        "relationships without dependencies are dropped"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn root_commits_add_every_package_once() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "lang-python", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+
+    let scanned = scan(&global, &repo_config).await?;
+    assert_eq!(scanned.updated, ["foo", "bar"]);
+    let commits = "SELECT pkg_name || ' ' || status FROM commits ORDER BY pkg_name";
+    assert_eq!(db.column(commits).await, ["bar Added", "foo Added"]);
+    assert_eq!(
+        db.column("SELECT name FROM packages ORDER BY name").await,
+        ["bar", "foo"]
+    );
+
+    let scanned = scan(&global, &repo_config).await?;
+    assert!(scanned.updated.is_empty() && scanned.deleted.is_empty());
+    assert_eq!(db.column(commits).await, ["bar Added", "foo Added"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn version_first_commit_is_kept_across_packaging_fixes() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    add_package(&mut fixture, "app-utils", "foo", "2.0", "PKGREL=1\n")?;
+    let landed = fixture.commit("foo: update to 2.0", "Alice")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "2.0",
+        "PKGREL=1\nPKGDEP=\"bar\"\n",
+    )?;
+    fixture.commit("foo: add bar to PKGDEP", "Bob")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "2.0",
+        "PKGREL=1\nPKGDEP=\"bar baz\"\n",
+    )?;
+    let fixed = fixture.commit("foo: add baz to PKGDEP", "Bob")?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT githash || ' ' || extract(epoch FROM commit_time)::bigint || ' ' \
+             || version_first_githash || ' ' \
+             || extract(epoch FROM version_first_commit_time)::bigint \
+             FROM package_versions WHERE package = 'foo'"
+        )
+        .await,
+        [format!("{fixed} 1700000180 {landed} 1700000060")]
+    );
+    assert_eq!(
+        db.column("SELECT version_first_githash FROM v_packages WHERE name = 'foo'")
+            .await,
+        [landed.to_string()]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn version_sources_are_recorded() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "1.0", "PKGVER=1.0+git1\n")?;
+    add_package(&mut fixture, "app-utils", "baz", "1.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let sources = "SELECT package || ' ' || version || ' ' || version_source \
+                   FROM package_versions ORDER BY package";
+    assert_eq!(
+        db.column(sources).await,
+        ["bar 1.0+git1 defines", "baz 1.0 spec", "foo 1.0 spec"]
+    );
+
+    // without VER, baz fails to parse and keeps the version of the last scan
+    fixture.add_package("app-utils", "baz", "REL=1\n", &defines("baz", ""))?;
+    fixture.commit("baz: drop VER", "Bob")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(sources).await,
+        ["bar 1.0+git1 defines", "baz 1.0 preserved", "foo 1.0 spec"]
+    );
+    assert_eq!(
+        db.column("SELECT version_source FROM v_packages WHERE name = 'baz'")
+            .await,
+        ["preserved"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn groups_listing_missing_packages_are_reported() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.write_file("groups/utils", "# utilities\napp-utils/foo\nbar\n")?;
+    fixture.commit("foo: new, groups: add utils", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let warnings = Warnings::new();
+    scan_with(&global, &repo_config, |abbs_db| {
+        abbs_db.with_warnings(warnings.clone())
+    })
+    .await?;
+
+    // missing members are kept, and warned about
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert_eq!(abbs_db.get_group("utils").await?, ["bar", "foo"]);
+    assert_eq!(abbs_db.get_groups_for_package("foo").await?, ["utils"]);
+    assert_eq!(
+        warnings.counts()["group member"].messages,
+        ["group utils lists nonexistent package bar"]
+    );
+
+    // changes of group files only refresh the groups
+    fixture.write_file("groups/utils", "app-utils/foo\n")?;
+    fixture.commit("groups: drop bar from utils", "Bob")?;
+    let warnings = Warnings::new();
+    let scanned = scan_with(&global, &repo_config, |abbs_db| {
+        abbs_db.with_warnings(warnings.clone())
+    })
+    .await?;
+    assert!(scanned.updated.is_empty(), "{:?}", scanned.updated);
+    assert_eq!(abbs_db.get_group("utils").await?, ["foo"]);
+    assert!(!warnings.counts().contains_key("group member"));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn trees_record_the_head_of_each_scan() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let first = fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let head = "SELECT head_commit || ' ' || extract(epoch FROM head_commit_time)::bigint \
+                FROM v_trees WHERE name = 'aosc-os-abbs'";
+    let updated_at = "SELECT (extract(epoch FROM updated_at) * 1000000)::bigint::text \
+                      FROM trees WHERE name = 'aosc-os-abbs'";
+    assert_eq!(db.column(head).await, [format!("{first} 1700000000")]);
+    let first_updated_at: i64 = db.column(updated_at).await[0].parse()?;
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let second = fixture.commit("foo: update to 1.1", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(db.column(head).await, [format!("{second} 1700000060")]);
+    let second_updated_at: i64 = db.column(updated_at).await[0].parse()?;
+    assert!(
+        second_updated_at > first_updated_at,
+        "{first_updated_at} {second_updated_at}"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn reverse_dependencies_leave_out_failing_architectures() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"bar\"\nFAIL_ARCH=\"riscv64\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "baz",
+        "3.0",
+        "PKGDEP=\"qux\"\nPKGDEP__ARM64=\"bar\"\n",
+    )?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let revdeps = |arch| {
+        let abbs_db = &abbs_db;
+        async move {
+            let revdeps = abbs_db
+                .get_reverse_dependencies("bar", arch)
+                .await?
+                .into_iter()
+                .map(|revdep| format!("{} {}", revdep.package, revdep.dependency.architecture))
+                .collect::<Vec<_>>();
+            anyhow::Ok(revdeps)
+        }
+    };
+    assert_eq!(revdeps(None).await?, ["baz arm64", "foo "]);
+    assert_eq!(revdeps(Some("arm64")).await?, ["baz arm64", "foo "]);
+    assert_eq!(revdeps(Some("amd64")).await?, ["foo "]);
+    assert!(
+        revdeps(Some("riscv64")).await?.is_empty(),
+        "foo isn't built for riscv64"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn dep_matrix_round_trips_through_json() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGREL=1\nPKGDEP=\"bar>=2.0\"\nPKGDEP__ARM64=\"bar>=2.0 baz\"\nPKGPROV=\"foo-compat\"\n",
+    )?;
+    let head = fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let matrix = abbs_db.get_dep_matrix().await?;
+    let json = serde_json::to_string(&matrix)?;
+    assert_eq!(serde_json::from_str::<DepMatrix>(&json)?, matrix);
+
+    assert_eq!(
+        (matrix.tree.as_str(), matrix.branch.as_str()),
+        ("aosc-os-abbs", "stable")
+    );
+    assert_eq!(matrix.head_commit, Some(head.to_string()));
+    assert_eq!(
+        matrix.provides,
+        BTreeMap::from([("foo-compat".to_string(), vec!["foo".to_string()])])
+    );
+    assert_eq!(
+        serde_json::to_value(&matrix.packages)?,
+        json!([
+            { "name": "bar", "version": "2.0", "dependencies": {} },
+            {
+                "name": "foo",
+                "version": "1.0-1",
+                "dependencies": {
+                    "PKGDEP": [
+                        { "architecture": "", "name": "bar", "relop": ">=", "version": "2.0" },
+                        { "architecture": "arm64", "name": "bar", "relop": ">=", "version": "2.0" },
+                        { "architecture": "arm64", "name": "baz", "relop": null, "version": null }
+                    ]
+                }
+            }
+        ])
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn pending_packages_are_found_without_writing() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "qux", "4.0", "")?;
+    fixture.commit("foo, bar, qux: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.remove_package("app-utils/bar")?;
+    fixture.add_package(
+        "app-utils",
+        "baz",
+        &format!("{}not an assignment\n", spec("3.0")),
+        &defines("baz", ""),
+    )?;
+    std::fs::remove_file(fixture.path().join("app-utils/qux/spec"))?;
+    fixture.commit(
+        "foo: update to 1.1\nbar: drop\nbaz: new, 3.0\nqux: drop spec",
+        "Bob",
+    )?;
+    let counts = "SELECT (SELECT count(*) FROM commits)::text || ' ' \
+                  || (SELECT count(*) FROM histories)::text";
+    let before = db.column(counts).await;
+
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let pending = commit_db.get_pending_packages(&repo, "stable").await?;
+    let existing = abbs_db.get_packages_name().await?;
+    let mut report = pending
+        .updated
+        .iter()
+        .map(|meta| PendingPackage::updated(meta, &existing, 0))
+        .chain(
+            pending
+                .deleted
+                .iter()
+                .map(|(pkg, ..)| PendingPackage::deleted(&pkg.name)),
+        )
+        .chain(pending.broken.into_iter().map(PendingPackage::broken))
+        .map(|pending| {
+            (
+                pending.package,
+                pending.action,
+                pending.full_version,
+                pending.errors.len(),
+            )
+        })
+        .collect::<Vec<_>>();
+    report.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        report,
+        [
+            ("bar".to_string(), PendingAction::Delete, None, 0),
+            (
+                "baz".to_string(),
+                PendingAction::Add,
+                Some("3.0".to_string()),
+                1
+            ),
+            (
+                "foo".to_string(),
+                PendingAction::Update,
+                Some("1.1".to_string()),
+                0
+            ),
+            ("qux".to_string(), PendingAction::Broken, None, 1),
+        ]
+    );
+    assert_eq!(db.column(counts).await, before, "nothing is written");
+
+    let scanned = scan(&global, &repo_config).await?;
+    assert_eq!(scanned.updated, ["baz", "foo"]);
+    assert_eq!(scanned.deleted, ["bar"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn batched_writes_match_single_writes() -> Result<()> {
+    let (Some(single), Some(batched)) = (TestDb::new().await, TestDb::new().await) else {
+        return Ok(());
+    };
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "bar",
+        "2.0",
+        "PKGPROV=\"bar-compat\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"bar>=2.0\"\n",
+    )?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.1",
+        "PKGDEP=\"bar>=2.0 baz\"\n",
+    )?;
+    add_package(&mut fixture, "lang-python", "baz", "3.0", "")?;
+    fixture.commit("foo: update to 1.1\nbaz: new, 3.0", "Bob")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&single.global(), &repo_config).await?;
+
+    let global = batched.global();
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    commit_db.update_branch(&repo, "stable").await?;
+    let mut pkgs = vec![];
+    for pkg_meta in commit_db
+        .get_updated_packages(&repo, "stable")
+        .await?
+        .updated
+    {
+        let changes = commit_db
+            .get_package_changes(&repo, &pkg_meta.0.name)
+            .await?;
+        pkgs.push((pkg_meta, changes));
+    }
+    let abbs_db = AbbsDb::open(&global, &repo_config)
+        .await?
+        .strict_writes(true);
+    let counts = abbs_db.add_packages_batch(pkgs).await?;
+    assert_eq!(counts.len(), 3);
+
+    for sql in [
+        "SELECT name || ' ' || section || ' ' || description FROM packages ORDER BY name",
+        "SELECT package || ' ' || full_version || ' ' || githash FROM package_versions \
+         ORDER BY package",
+        "SELECT package || ' ' || key || ' ' || value FROM package_spec \
+         ORDER BY package, key",
+        "SELECT package || ' ' || relationship || ' ' || dependency || ' ' \
+         || coalesce(relop, '') || coalesce(version, '') FROM package_dependencies \
+         ORDER BY package, relationship, dependency",
+        "SELECT package || ' ' || version || ' ' || githash FROM package_changes \
+         ORDER BY package, timestamp",
+    ] {
+        let rows = single.column(sql).await;
+        assert!(!rows.is_empty(), "{sql}");
+        assert_eq!(batched.column(sql).await, rows, "{sql}");
+    }
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn package_architectures_honor_fail_arch() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("architectures = [\"amd64\", \"arm64\", \"riscv64\"]");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "bar",
+        "2.0",
+        "FAIL_ARCH=\"!(amd64|arm64)\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "FAIL_ARCH=\"riscv64\"\n",
+    )?;
+    add_package(&mut fixture, "app-utils", "baz", "3.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    const ARCHITECTURES: &str = "SELECT package || ' ' || architecture \
+        FROM package_architectures ORDER BY package, architecture";
+    assert_eq!(
+        db.column(ARCHITECTURES).await,
+        [
+            "bar amd64",
+            "bar arm64",
+            "baz amd64",
+            "baz arm64",
+            "baz riscv64",
+            "foo amd64",
+            "foo arm64",
+        ]
+    );
+
+    fixture.remove_package("app-utils/baz")?;
+    fixture.commit("baz: drop", "Alice")?;
+    scan(&global, &repo_config).await?;
+    assert_eq!(
+        db.column(ARCHITECTURES).await,
+        ["bar amd64", "bar arm64", "foo amd64", "foo arm64"],
+        "rows are deleted with the package"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn section_renames_move_packages() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    fixture.rename_package("app-utils", "app-admin")?;
+    let moved = fixture.commit("app-utils: rename to app-admin", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT name || ' ' || category || '-' || section || ' ' || spec_path \
+             FROM packages ORDER BY name"
+        )
+        .await,
+        [
+            "bar app-admin app-admin/bar/spec",
+            "foo app-admin app-admin/foo/spec",
+        ]
+    );
+    assert!(
+        db.column("SELECT package FROM package_duplicate")
+            .await
+            .is_empty(),
+        "moves aren't duplicates"
+    );
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let foo = abbs_db
+        .get_package("foo")
+        .await?
+        .context("foo is missing")?;
+    assert_eq!(
+        foo.moves,
+        [PackageMove {
+            old_spec_path: "app-utils/foo/spec".to_string(),
+            new_spec_path: "app-admin/foo/spec".to_string(),
+            commit: moved.to_string(),
+            commit_time: "2023-11-14T22:14:20+00:00".to_string(),
+        }]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_are_found_by_full_text_search() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-editors",
+        "vim",
+        "9.1",
+        "PKGDES=\"Vi IMproved, a text editor\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-editors",
+        "nano",
+        "8.0",
+        "PKGDES=\"Small and friendly text editor\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDES=\"Editor for text files\"\n",
+    )?;
+    fixture.commit("vim, nano, foo: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    let search = |query| {
+        let abbs_db = &abbs_db;
+        async move {
+            let names = abbs_db
+                .fts_search(query)
+                .await?
+                .into_iter()
+                .map(|(name, _)| name)
+                .sorted()
+                .collect::<Vec<_>>();
+            anyhow::Ok(names)
+        }
+    };
+    assert_eq!(search("text editor").await?, ["foo", "nano", "vim"]);
+    assert_eq!(search("\"text editor\"").await?, ["nano", "vim"]);
+    assert_eq!(search("\"text editor\" -vim").await?, ["nano"]);
+
+    assert_eq!(abbs_db.rebuild_fts().await?, 3);
+    assert_eq!(search("\"text editor\" -vim").await?, ["nano"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn deferred_packages_are_kept_by_branch_and_rescanned() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    let deferred = |name: &str| (name.to_string(), format!("app-utils/{name}/spec"));
+    abbs_db
+        .set_deferred_packages(&[deferred("foo"), deferred("bar")])
+        .await?;
+    assert_eq!(
+        abbs_db.get_deferred_packages().await?,
+        [deferred("bar"), deferred("foo")]
+    );
+    let testing = AbbsDb::open(&global, &fixture.repo_config("aosc-os-abbs", "testing")).await?;
+    assert!(testing.get_deferred_packages().await?.is_empty());
+
+    // every scan replaces the packages left by the last one
+    abbs_db.set_deferred_packages(&[deferred("foo")]).await?;
+    assert_eq!(abbs_db.get_deferred_packages().await?, [deferred("foo")]);
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Alice")?;
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+    let (name, spec_path) = deferred("foo");
+    let (pkg, ..) = commit_db
+        .rescan_package(&repo, &name, Path::new(&spec_path))?
+        .context("foo is missing")?;
+    assert_eq!(pkg.version, "1.1", "parsed at the tip of the branch");
+    assert!(commit_db
+        .rescan_package(&repo, "bar", Path::new(&spec_path))?
+        .is_none());
+
+    abbs_db.set_deferred_packages(&[]).await?;
+    assert!(abbs_db.get_deferred_packages().await?.is_empty());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn strict_writes_skip_rows_breaking_invariants() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "1.0-beta", "")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(db.column("SELECT name FROM packages").await, ["foo"]);
+    let errors = db
+        .column("SELECT package || ' ' || err_type || ' ' || message FROM package_errors")
+        .await;
+    assert_eq!(errors.len(), 1);
+    assert!(
+        errors[0].starts_with(r#"bar invariant package_versions.version "1.0-beta" must start"#),
+        "{errors:?}"
+    );
+
+    // written as is otherwise
+    add_package(&mut fixture, "app-utils", "bar", "1.1-beta", "")?;
+    fixture.commit("bar: update to 1.1-beta", "Alice")?;
+    scan_with(&global, &repo_config, |abbs_db| {
+        abbs_db.strict_writes(false)
+    })
+    .await?;
+    assert_eq!(
+        db.column("SELECT name FROM packages ORDER BY name").await,
+        ["bar", "foo"]
+    );
+    assert!(db
+        .column("SELECT package FROM package_errors")
+        .await
+        .is_empty());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn package_stats_count_packages_and_errors_by_section() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "lang-python", "baz", "3.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    add_package(&mut fixture, "app-utils", "bar", "2.1-beta", "")?;
+    fixture.commit("bar: update to 2.1-beta", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    let stats = || async {
+        let stats = abbs_db
+            .get_stats("aosc-os-abbs")
+            .await?
+            .into_iter()
+            .map(|row| {
+                let (category, section) = (row.category, row.section);
+                format!("{category}-{section} {} {}", row.count, row.error_count)
+            })
+            .sorted()
+            .collect::<Vec<_>>();
+        anyhow::Ok(stats)
+    };
+    assert!(stats().await?.is_empty(), "counted at the end of runs");
+    abbs_db.refresh_stats().await?;
+    assert_eq!(stats().await?, ["app-utils 2 1", "lang-python 1 0"]);
+
+    fixture.remove_package("lang-python/baz")?;
+    fixture.commit("baz: drop", "Alice")?;
+    scan(&global, &repo_config).await?;
+    abbs_db.refresh_stats().await?;
+    assert_eq!(stats().await?, ["app-utils 2 1"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn section_sources_follow_pkgsec() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "PKGSEC=\"libs\"\n")?;
+    add_package(&mut fixture, "app-utils", "baz", "3.0", "PKGSEC=\"\"\n")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column("SELECT name || ' ' || section_source FROM packages ORDER BY name")
+            .await,
+        ["bar pkgsec", "baz directory", "foo directory"]
+    );
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let bar = abbs_db
+        .get_package("bar")
+        .await?
+        .context("bar is missing")?;
+    assert_eq!(bar.section_source.as_deref(), Some("pkgsec"));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn updated_packages_are_found_between_scanned_commits() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let first = fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let repo = Repository::open(&repo_config)?;
+    let commit_db = CommitDb::open(&global).await?;
+
+    commit_db.update_branch(&repo, "stable").await?;
+    let updated = commit_db.get_updated_packages(&repo, "stable").await?;
+    assert_eq!((updated.from, updated.commit), (None, first));
+    scan(&global, &repo_config).await?;
+
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let second = fixture.commit("foo: update to 1.1", "Alice")?;
+    let repo = Repository::open(&repo_config)?;
+    commit_db.update_branch(&repo, "stable").await?;
+    let updated = commit_db.get_updated_packages(&repo, "stable").await?;
+    assert_eq!((updated.from, updated.commit), (Some(first), second));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn versioned_dependencies_are_checked_against_the_tree() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"libfoo>=2.0 libfoo<2.0 libfoo-compat>=2 libbar>=1\"\n",
+    )?;
+    add_package(
+        &mut fixture,
+        "runtime-common",
+        "libfoo",
+        "2.0",
+        "PKGPROV=\"libfoo-compat=1.5\"\n",
+    )?;
+    fixture.commit("foo, libfoo: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    let constraints = abbs_db.get_unsatisfied_constraints().await?;
+    let checks = |checks: Vec<ConstraintCheck>| {
+        checks
+            .into_iter()
+            .map(|check| {
+                let provider = check
+                    .provider
+                    .zip(check.version)
+                    .map(|(p, v)| format!("{p} {v}"));
+                (check.constraint, provider)
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        checks(constraints.violated),
+        [
+            ("libfoo<2.0".to_string(), Some("libfoo 2.0".to_string())),
+            (
+                "libfoo-compat>=2".to_string(),
+                Some("libfoo 1.5".to_string())
+            ),
+        ]
+    );
+    assert_eq!(
+        checks(constraints.unevaluable),
+        [("libbar>=1".to_string(), None)],
+        "packages of other trees aren't violations"
+    );
+
+    abbs_db.check_constraints().await?;
+    assert_eq!(
+        db.column("SELECT package FROM package_errors WHERE err_type = 'constraint'")
+            .await,
+        ["foo", "foo"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn self_dependencies_are_reported_and_kept() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"foo bar\"\nPKGSUG=\"foo\"\n",
+    )?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column("SELECT package || ' ' || message FROM package_errors")
+            .await,
+        ["foo PKGDEP: foo depends on itself"],
+        "only PKGDEP and BUILDDEP can't list the package"
+    );
+    assert_eq!(
+        db.column(
+            "SELECT relationship || ' ' || dependency FROM package_dependencies \
+             WHERE package = 'foo' ORDER BY relationship, dependency"
+        )
+        .await,
+        ["PKGDEP bar", "PKGDEP foo", "PKGSUG foo"]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn misspelled_keys_are_reported() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEPS=\"bar\"\nPKGDEP__AMD64=\"bar\"\nPKGSUGG__ARM64=\"bar\"\n\
+         BUILDEPS=\"bar\"\n_PKGDES=\"helper\"\nABTYPE=\"self\"\n",
+    )?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    repo_config.extra_spec_keys = vec!["BUILDEPS".to_string()];
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT package || ': ' || message FROM package_errors \
+             WHERE err_type = 'spec_key' ORDER BY message"
+        )
+        .await,
+        [
+            "foo: unknown key PKGDEPS, did you mean PKGDEP?",
+            "foo: unknown key PKGSUGG__ARM64, did you mean PKGSUG?",
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Ad-hoc queries against a scanned database
+mod common;
+
+use abbs_meta::db::query::query;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+
+async fn scanned_db() -> Result<Option<TestDb>> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(None);
+    };
+    let mut fixture = FixtureRepo::new("stable")?;
+    for name in ["a", "b", "c", "d", "e"] {
+        add_package(&mut fixture, "app-utils", name, "1.0", "")?;
+    }
+    fixture.commit("a, b, c, d, e: new", "Alice")?;
+    scan(&db.global(), &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    Ok(Some(db))
+}
+
+#[async_std::test]
+async fn query_rejects_writes() -> Result<()> {
+    let Some(db) = scanned_db().await? else {
+        return Ok(());
+    };
+
+    for sql in [
+        "DELETE FROM packages",
+        "UPDATE packages SET description = ''",
+        "DROP TABLE packages",
+        "SELECT 1; DELETE FROM packages",
+        "WITH d AS (DELETE FROM packages RETURNING name) SELECT * FROM d",
+        "WITH u AS (UPDATE packages SET description = '' RETURNING name) SELECT * FROM u",
+    ] {
+        assert!(
+            query(&db.global(), false, sql, 10).await.is_err(),
+            "{sql} is accepted"
+        );
+    }
+    assert_eq!(db.column("SELECT name FROM packages").await.len(), 5);
+    assert!(!db
+        .column("SELECT description FROM packages")
+        .await
+        .contains(&String::new()));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn query_enforces_the_row_limit() -> Result<()> {
+    let Some(db) = scanned_db().await? else {
+        return Ok(());
+    };
+
+    let output = query(
+        &db.global(),
+        false,
+        "SELECT name FROM packages ORDER BY name",
+        2,
+    )
+    .await?;
+    assert_eq!(output.columns, ["name"]);
+    assert_eq!(output.rows, [["a"], ["b"]]);
+    assert!(output.truncated);
+
+    let output = query(&db.global(), false, "SELECT name FROM packages", 5).await?;
+    assert_eq!(output.rows.len(), 5);
+    assert!(!output.truncated, "exactly the limit is not truncated");
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn query_reads_the_commits_database() -> Result<()> {
+    let (Some(db), Some(commits)) = (TestDb::new().await, TestDb::new().await) else {
+        return Ok(());
+    };
+    let global = db.global_with(&format!("commits_database_url = \"{}\"", commits.url));
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "a", "1.0", "")?;
+    fixture.commit("a: new", "Alice")?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    let sql = "SELECT pkg_name, pkg_version FROM commits";
+    let output = query(&global, true, sql, 10).await?;
+    assert_eq!(output.columns, ["pkg_name", "pkg_version"]);
+    assert_eq!(output.rows, [["a", "1.0"]]);
+    let e = query(&global, false, sql, 10).await.unwrap_err();
+    assert!(e.to_string().contains("commits"), "{e}");
+    assert_eq!(
+        query(&global, false, "SELECT name FROM packages", 10)
+            .await?
+            .rows,
+        [["a"]]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,249 @@
+//! Rows recorded for each scan run
+mod common;
+
+use abbs_meta::config::Global;
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, scan_with, spec, Scanned, TestDb};
+
+/// Scan with a run id and record its packages, like the scan subcommand
+async fn run(global: &Global, fixture: &FixtureRepo, run_id: &str) -> Result<Scanned> {
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let scanned = scan_with(global, &repo_config, |abbs_db| abbs_db.with_run_id(run_id)).await?;
+    AbbsDb::open(global, &repo_config)
+        .await?
+        .with_run_id(run_id)
+        .record_run_packages(&scanned.updated, &scanned.deleted)
+        .await?;
+
+    Ok(scanned)
+}
+
+#[async_std::test]
+async fn each_run_appends_collector_meta() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let fixture = FixtureRepo::new("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    for (run_id, digest) in [("run-1", "digest-1"), ("run-2", "digest-2")] {
+        AbbsDb::open(&global, &repo_config)
+            .await?
+            .with_run_id(run_id)
+            .record_collector_meta(digest, None)
+            .await?;
+    }
+
+    assert_eq!(
+        db.column("SELECT run_id || ' ' || config_digest FROM collector_meta ORDER BY id")
+            .await,
+        ["run-1 digest-1", "run-2 digest-2"]
+    );
+    let latest = AbbsDb::open_read_only(&global, &repo_config)
+        .await?
+        .get_latest_collector_meta()
+        .await?;
+    assert_eq!(
+        latest.and_then(|meta| meta.run_id).as_deref(),
+        Some("run-2")
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn packages_deleted_and_added_again_are_flapping() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("flapping_threshold = 1");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    run(&global, &fixture, "run-1").await?;
+    fixture.remove_package("app-utils/foo")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.1", "")?;
+    fixture.commit("foo: drop\nbar: update to 2.1", "Alice")?;
+    run(&global, &fixture, "run-2").await?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let scanned = run(&global, &fixture, "run-3").await?;
+    assert_eq!(scanned.updated, ["foo"]);
+
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    let flapping = AbbsDb::open_read_only(&global, &repo_config)
+        .await?
+        .get_flapping()
+        .await?
+        .into_iter()
+        .map(|pkg| (pkg.package, pkg.flaps, pkg.runs))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        flapping,
+        [("foo".to_string(), 2, 3)],
+        "bar was only updated"
+    );
+
+    let global = db.global_with("flapping_runs = 2\nflapping_threshold = 1");
+    assert!(
+        AbbsDb::open_read_only(&global, &repo_config)
+            .await?
+            .get_flapping()
+            .await?
+            .is_empty(),
+        "only the last runs are searched"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn unchanged_spec_keys_keep_their_run() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "PKGDEP=\"bar\"\n")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    run(&global, &fixture, "run-1").await?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGDEP=\"bar baz\"\n",
+    )?;
+    fixture.commit("foo: add baz to PKGDEP", "Alice")?;
+    run(&global, &fixture, "run-2").await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT key || ' ' || last_run_id FROM package_spec \
+             WHERE package = 'foo' ORDER BY key"
+        )
+        .await,
+        [
+            "CHKSUMS run-1",
+            "CHKUPDATE run-1",
+            "PKGDEP run-2",
+            "PKGDES run-1",
+            "PKGNAME run-1",
+            "PKGVER run-1",
+            "SRCS run-1"
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn events_are_polled_once_per_consumer() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    fixture.remove_package("app-utils/bar")?;
+    fixture.commit("bar: drop", "Alice")?;
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open(&global, &repo_config).await?;
+    let poll = |consumer: &'static str, limit| {
+        let abbs_db = &abbs_db;
+        async move {
+            let events = abbs_db
+                .poll_events(consumer, None, limit)
+                .await?
+                .into_iter()
+                .map(|event| format!("{} {} {}", event.event_type, event.package, event.branch))
+                .collect::<Vec<_>>();
+            anyhow::Ok(events)
+        }
+    };
+    let expected = [
+        "testing_updated foo foo-1.1",
+        "package_updated bar stable",
+        "package_updated foo stable",
+        "package_deleted bar stable",
+    ];
+    assert_eq!(poll("irc", 100).await?, expected);
+    assert!(
+        poll("irc", 100).await?.is_empty(),
+        "events are returned once"
+    );
+    // another consumer has a mark of its own
+    assert_eq!(poll("website", 2).await?, expected[..2]);
+    assert_eq!(poll("website", 100).await?, expected[2..]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn run_rows_are_those_written_by_the_run() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.add_package("app-utils", "bar", &spec("1.0"), "PKGNAME=bar\n")?;
+    fixture.commit("foo, bar: new, 1.0", "Alice")?;
+    run(&global, &fixture, "run-1").await?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Alice")?;
+    fixture.branch("foo-1.2")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.2", "")?;
+    fixture.commit("foo: update to 1.2", "Bob")?;
+    fixture.checkout("stable")?;
+    run(&global, &fixture, "run-2").await?;
+
+    let abbs_db =
+        AbbsDb::open_read_only(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+    let rows = |run_id: &'static str| {
+        let abbs_db = &abbs_db;
+        async move {
+            let rows = abbs_db.get_run_rows(run_id).await?;
+            anyhow::Ok(
+                rows.into_iter()
+                    .map(|row| format!("{} {} {:?}", row.table, row.package, row.branch))
+                    .collect::<Vec<_>>(),
+            )
+        }
+    };
+    // spec keys of foo unchanged by run-2 are still from run-1
+    assert_eq!(
+        rows("run-1").await?,
+        [
+            "packages bar None",
+            "package_versions bar Some(\"stable\")",
+            "package_spec bar None",
+            "package_spec foo None",
+            "package_errors bar Some(\"stable\")",
+        ]
+    );
+    assert_eq!(
+        rows("run-2").await?,
+        [
+            "packages foo None",
+            "package_versions foo Some(\"stable\")",
+            "package_spec foo None",
+            "package_testing foo Some(\"foo-1.2\")",
+        ]
+    );
+    assert!(rows("run-3").await?.is_empty());
+
+    Ok(())
+}
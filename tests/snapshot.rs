@@ -0,0 +1,85 @@
+//! Snapshots of the package metadata of a tree
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::snapshot::SnapshotStore;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, spec, TestDb};
+
+/// Rows of the package in tables referencing it, like `package_errors 1`
+async fn row_counts(db: &TestDb, package: &str) -> Vec<String> {
+    // packages are named by name, other tables by package
+    let sql = format!("SELECT 'packages ' || count(*) FROM packages WHERE name = '{package}'");
+    let sql = [
+        "package_arch_versions",
+        "package_architectures",
+        "package_update_sources",
+        "package_testing",
+        "package_errors",
+    ]
+    .iter()
+    .fold(sql, |sql, table| {
+        format!("{sql} UNION ALL SELECT '{table} ' || count(*) FROM {table} WHERE package = '{package}'")
+    });
+
+    db.column(&sql).await
+}
+
+#[async_std::test]
+async fn restoring_deletes_rows_of_packages_added_after_the_snapshot() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("architectures = [\"amd64\", \"arm64\"]");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(
+        &mut fixture,
+        "app-utils",
+        "foo",
+        "1.0",
+        "PKGVER__ARM64=0.9\n",
+    )?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let store = SnapshotStore::open(&global, &repo_config).await?;
+    store.create("before", false).await?;
+
+    // without PKGDES, bar has an error, and it is updated on a topic branch
+    fixture.add_package(
+        "app-utils",
+        "bar",
+        &spec("2.0"),
+        "PKGNAME=bar\nPKGVER__ARM64=1.9\n",
+    )?;
+    fixture.commit("bar: new, 2.0", "Alice")?;
+    fixture.branch("bar-2.1")?;
+    fixture.add_package(
+        "app-utils",
+        "bar",
+        &spec("2.1"),
+        "PKGNAME=bar\nPKGVER__ARM64=1.9\n",
+    )?;
+    fixture.commit("bar: update to 2.1", "Bob")?;
+    fixture.checkout("stable")?;
+    scan(&global, &repo_config).await?;
+
+    let bar = row_counts(&db, "bar").await;
+    assert!(bar.iter().all(|count| !count.ends_with(" 0")), "{bar:?}");
+    let foo = row_counts(&db, "foo").await;
+
+    store.restore("before").await?;
+    let bar = row_counts(&db, "bar").await;
+    assert!(bar.iter().all(|count| count.ends_with(" 0")), "{bar:?}");
+    assert_eq!(
+        row_counts(&db, "foo").await,
+        foo,
+        "rows of foo are left alone"
+    );
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert!(abbs_db.fts_search("bar").await?.is_empty());
+    assert_eq!(abbs_db.fts_search("foo").await?.len(), 1);
+
+    Ok(())
+}
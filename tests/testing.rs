@@ -0,0 +1,440 @@
+//! Topic branches scanned next to the main branch
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::db::diff::{DependencyChange, OverrideConflict, VersionChange};
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, defines, scan, spec, TestDb};
+use git2::BranchType;
+
+#[async_std::test]
+async fn testing_spec_diff_compares_with_the_main_branch() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("store_testing_spec = true");
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let diff = abbs_db.diff_testing_spec("foo", "foo-1.1").await?;
+    assert_eq!(
+        diff.changed,
+        [
+            ("PKGVER".to_string(), "1.0".to_string(), "1.1".to_string()),
+            (
+                "SRCS".to_string(),
+                "tbl::https://example.org/src-1.0.tar.gz".to_string(),
+                "tbl::https://example.org/src-1.1.tar.gz".to_string()
+            )
+        ]
+    );
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn testing_spec_diff_fails_without_a_saved_spec() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    // store_testing_spec is off, rather than every key of foo being removed
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let e = abbs_db
+        .diff_testing_spec("foo", "foo-1.1")
+        .await
+        .err()
+        .expect("diffed a spec which is not saved");
+    assert!(e.to_string().contains("no spec of foo"), "{e}");
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn testing_spec_diff_ignores_packages_of_other_trees() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global_with("store_testing_spec = true");
+    let mut bsps = FixtureRepo::new("stable")?;
+    add_package(&mut bsps, "app-utils", "bar", "1.0", "")?;
+    bsps.commit("bar: new, 1.0", "Alice")?;
+    let mut bsps_config = bsps.repo_config("aosc-os-bsps", "stable");
+    bsps_config.priority = 2;
+    scan(&global, &bsps_config).await?;
+
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("bar-2.0")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("bar: new, 2.0", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let diff = abbs_db.diff_testing_spec("bar", "bar-2.0").await?;
+    assert!(
+        diff.changed.is_empty() && diff.removed.is_empty(),
+        "bar of aosc-os-bsps is not compared: {diff:?}"
+    );
+    assert!(diff
+        .added
+        .contains(&("PKGVER".to_string(), "2.0".to_string())));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn package_branches_follow_the_topic_branches() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    fixture.branch("kde-6")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert_eq!(
+        branches(&abbs_db, "foo").await?,
+        [
+            (
+                "kde-6".to_string(),
+                "1.1".to_string(),
+                "2023-11-14T22:14:20+00:00".to_string()
+            ),
+            (
+                "stable".to_string(),
+                "1.0".to_string(),
+                "2023-11-14T22:13:20+00:00".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        branches(&abbs_db, "bar").await?,
+        [(
+            "stable".to_string(),
+            "2.0".to_string(),
+            "2023-11-14T22:13:20+00:00".to_string()
+        )],
+        "only branches changing the package are listed"
+    );
+
+    // pruned topics are left out
+    fixture
+        .git2repo()
+        .find_branch("kde-6", BranchType::Local)?
+        .delete()?;
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert_eq!(
+        branches(&abbs_db, "foo").await?,
+        [(
+            "stable".to_string(),
+            "1.0".to_string(),
+            "2023-11-14T22:13:20+00:00".to_string()
+        )]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn errors_of_pruned_topics_are_resolved() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    // both topics break a spec
+    let broken = |version| format!("{}not an assignment\n", spec(version));
+    fixture.branch("foo-1.1")?;
+    fixture.add_package("app-utils", "foo", &broken("1.1"), &defines("foo", ""))?;
+    fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    fixture.branch("bar-2.1")?;
+    fixture.add_package("app-utils", "bar", &broken("2.1"), &defines("bar", ""))?;
+    fixture.commit("bar: update to 2.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let errors = "SELECT package || ' ' || branch FROM package_errors ORDER BY package";
+    assert_eq!(db.column(errors).await, ["bar bar-2.1", "foo foo-1.1"]);
+
+    // foo-1.1 is dropped, bar-2.1 merged
+    fixture.merge("bar-2.1", "stable")?;
+    for topic in ["foo-1.1", "bar-2.1"] {
+        fixture
+            .git2repo()
+            .find_branch(topic, BranchType::Local)?
+            .delete()?;
+    }
+    scan(&global, &repo_config).await?;
+
+    assert_eq!(
+        db.column(errors).await,
+        ["bar stable"],
+        "the error of bar is in stable now"
+    );
+    assert_eq!(
+        db.column(
+            "SELECT event || ' ' || package || ' ' || branch FROM package_error_events \
+             WHERE branch <> 'stable' ORDER BY package, id"
+        )
+        .await,
+        [
+            "introduced bar bar-2.1",
+            "resolved bar bar-2.1",
+            "introduced foo foo-1.1",
+            "resolved foo foo-1.1",
+        ]
+    );
+
+    Ok(())
+}
+
+/// Branch, version and commit time of [AbbsDb::get_package_branches]
+async fn branches(abbs_db: &AbbsDb, name: &str) -> Result<Vec<(String, String, String)>> {
+    Ok(abbs_db
+        .get_package_branches(name)
+        .await?
+        .into_iter()
+        .map(|branch| (branch.branch, branch.version, branch.commit_time))
+        .collect())
+}
+
+#[async_std::test]
+async fn newer_topic_commits_dont_replace_the_version_commit() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let stable = fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("foo-deps")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "PKGDEP=\"bar\"\n")?;
+    fixture.commit("foo: add bar to PKGDEP", "Bob")?;
+    fixture.checkout("stable")?;
+    scan(&global, &fixture.repo_config("aosc-os-abbs", "stable")).await?;
+
+    assert_eq!(
+        db.column(
+            "SELECT branch || ' ' || githash || ' ' || committer FROM package_versions \
+             WHERE package = 'foo'"
+        )
+        .await,
+        [format!("stable {stable} Alice <alice@example.org>")],
+        "the newer commit of the topic branch is left out"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn simulated_merges_diff_against_the_main_branch() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    fixture.commit("foo, bar: new", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "PKGDEP=\"bar\"\n")?;
+    fixture.commit("foo: update to 1.1, depend on bar", "Bob")?;
+    fixture.checkout("stable")?;
+    fixture.branch("foo-rebuild")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "PKGREL=1\n")?;
+    fixture.commit("foo: rebuild", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let simulation = abbs_db
+        .simulate_merge(&Repository::open(&repo_config)?, "foo-1.1")
+        .await?;
+    assert_eq!(
+        simulation.diff.versions,
+        [VersionChange {
+            package: "foo".to_string(),
+            branch: "stable".to_string(),
+            old: Some("1.0".to_string()),
+            new: Some("1.1".to_string()),
+        }]
+    );
+    assert_eq!(
+        simulation.diff.dependencies,
+        [DependencyChange {
+            package: "foo".to_string(),
+            added: vec!["PKGDEP bar".to_string()],
+            removed: vec![],
+        }]
+    );
+    assert!(simulation.diff.added.is_empty() && simulation.diff.removed.is_empty());
+    assert_eq!(
+        simulation.conflicts,
+        [OverrideConflict {
+            package: "foo".to_string(),
+            branches: vec!["foo-rebuild".to_string()],
+        }]
+    );
+    assert_eq!(
+        db.column("SELECT full_version FROM package_versions WHERE package = 'foo'")
+            .await,
+        ["1.0"],
+        "nothing is written"
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn branches_with_and_without_the_origin_prefix_are_reported() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let topic = fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert!(abbs_db.get_mixed_branch_names().await?.is_empty());
+
+    // the topic is fetched while its local branch is kept
+    fixture
+        .git2repo()
+        .reference("refs/remotes/origin/foo-1.1", topic, false, "fetch")?;
+    scan(&global, &repo_config).await?;
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    assert_eq!(
+        abbs_db.get_mixed_branch_names().await?,
+        [
+            ("commits".to_string(), "foo-1.1".to_string()),
+            ("histories".to_string(), "foo-1.1".to_string()),
+            ("package_testing".to_string(), "foo-1.1".to_string()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn topic_branches_are_selected_by_patterns() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo: new, 1.0", "Alice")?;
+    for (branch, version) in [
+        ("wip/foo-1.1", "1.1"),
+        ("wip/old-foo", "0.9"),
+        ("kde-6", "1.2"),
+        ("kde-next", "1.3"),
+    ] {
+        fixture.branch(branch)?;
+        add_package(&mut fixture, "app-utils", "foo", version, "")?;
+        fixture.commit(&format!("foo: update to {version}"), "Bob")?;
+        fixture.checkout("stable")?;
+    }
+    let mut repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    repo_config.testing_branch_include = vec!["wip/*".to_string(), "/^kde-[0-9]+$/".to_string()];
+    repo_config.testing_branch_exclude = vec!["wip/old-*".to_string()];
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let scanned = branches(&abbs_db, "foo")
+        .await?
+        .into_iter()
+        .map(|(branch, version, _)| format!("{branch} {version}"))
+        .collect::<Vec<_>>();
+    assert_eq!(scanned, ["kde-6 1.2", "stable 1.0", "wip/foo-1.1 1.1"]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn head_of_other_remotes_is_not_a_topic() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    let base = fixture.commit("foo: new, 1.0", "Alice")?;
+    fixture.branch("foo-1.1")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.1", "")?;
+    let topic = fixture.commit("foo: update to 1.1", "Bob")?;
+    fixture.checkout("stable")?;
+    fixture
+        .git2repo()
+        .find_branch("foo-1.1", BranchType::Local)?
+        .delete()?;
+
+    // a clone of a fork, whose HEAD points at a topic instead of the main branch
+    let git2repo = fixture.git2repo();
+    git2repo.remote("upstream", "https://example.org/aosc-os-abbs.git")?;
+    git2repo.reference("refs/remotes/upstream/stable", base, false, "fetch")?;
+    git2repo.reference("refs/remotes/upstream/foo-1.1", topic, false, "fetch")?;
+    git2repo.reference("refs/remotes/upstream/HEAD", topic, false, "fetch")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let scanned = branches(&abbs_db, "foo")
+        .await?
+        .into_iter()
+        .map(|(branch, version, _)| format!("{branch} {version}"))
+        .collect::<Vec<_>>();
+    assert_eq!(scanned, ["stable 1.0", "upstream/foo-1.1 1.1"]);
+    assert_eq!(
+        db.column("SELECT DISTINCT branch FROM commits ORDER BY branch")
+            .await,
+        ["stable", "upstream/foo-1.1"]
+    );
+
+    Ok(())
+}
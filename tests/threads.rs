@@ -0,0 +1,88 @@
+//! Parsing commits in the thread pool of the commit database
+mod common;
+
+use abbs_meta::db::commits::CommitDb;
+use abbs_meta::git::Repository;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, TestDb};
+use rayon::ThreadPoolBuilder;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::Layer;
+
+/// Threads which opened a repository to parse packages
+static PARSING_THREADS: Mutex<Vec<ThreadId>> = Mutex::new(vec![]);
+
+struct ParsingThreads;
+
+impl<S: Subscriber> Layer<S> for ParsingThreads {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        struct Message(String);
+        impl Visit for Message {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        if message.0 == "opening the repository for a parsing thread" {
+            PARSING_THREADS.lock().unwrap().push(thread::current().id());
+        }
+    }
+}
+
+#[async_std::test]
+async fn add_commits_parses_in_the_thread_pool() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    // threads of the pool don't see subscribers set for the test thread only
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(ParsingThreads))?;
+    let mut fixture = FixtureRepo::new("stable")?;
+    let mut commits = vec![];
+    for i in 0..32 {
+        add_package(&mut fixture, "app-utils", &format!("foo{i}"), "1.0", "")?;
+        commits.push(fixture.commit(&format!("foo{i}: new, 1.0"), "Alice")?);
+    }
+
+    let pool_threads = Arc::new(Mutex::new(HashSet::new()));
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(3)
+        .start_handler({
+            let pool_threads = pool_threads.clone();
+            move |_| {
+                pool_threads.lock().unwrap().insert(thread::current().id());
+            }
+        })
+        .build()?;
+    let repo = Repository::open(&fixture.repo_config("aosc-os-abbs", "stable"))?;
+    let commit_db = CommitDb::open(&db.global())
+        .await?
+        .with_thread_pool(Arc::new(pool));
+    let infos = commit_db.add_commits(&repo, "stable", commits).await?;
+    assert_eq!(infos.len(), 32);
+
+    let parsing = PARSING_THREADS.lock().unwrap().clone();
+    let distinct: HashSet<_> = parsing.iter().copied().collect();
+    assert_eq!(
+        distinct.len(),
+        parsing.len(),
+        "each thread opens the repository once"
+    );
+    assert!(!distinct.is_empty() && distinct.len() <= 3, "{distinct:?}");
+    assert!(
+        distinct.is_subset(&pool_threads.lock().unwrap()),
+        "packages are parsed by threads of the pool only"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Stored packages checked against a fresh parse of the tree
+mod common;
+
+use abbs_meta::db::abbs::AbbsDb;
+use abbs_meta::git::Repository;
+use abbs_meta::package::scan_tree;
+use abbs_meta::test_support::FixtureRepo;
+use anyhow::Result;
+use common::{add_package, scan, TestDb};
+
+#[async_std::test]
+async fn verify_finds_packages_left_behind_by_scans() -> Result<()> {
+    let Some(db) = TestDb::new().await else {
+        return Ok(());
+    };
+    let global = db.global();
+    let mut fixture = FixtureRepo::new("stable")?;
+    add_package(&mut fixture, "app-utils", "bar", "2.0", "")?;
+    add_package(&mut fixture, "app-utils", "baz", "3.0", "")?;
+    add_package(&mut fixture, "app-utils", "foo", "1.0", "")?;
+    fixture.commit("foo, bar, baz: new", "Alice")?;
+    let repo_config = fixture.repo_config("aosc-os-abbs", "stable");
+    scan(&global, &repo_config).await?;
+
+    // committed after the last scan, like changes missed by an interrupted run
+    add_package(&mut fixture, "app-utils", "bar", "2.1", "")?;
+    fixture.remove_package("app-utils/baz")?;
+    add_package(&mut fixture, "app-utils", "qux", "4.0", "")?;
+    add_package(&mut fixture, "app-utils", "broken", "1.0", "")?;
+    // a spec without VER fails to parse
+    fixture.write_file(
+        "app-utils/broken/spec",
+        "SRCS=\"tbl::https://example.org/src.tar.gz\"\n",
+    )?;
+    fixture.commit("bar: update to 2.1; baz: drop; qux, broken: new", "Alice")?;
+
+    let abbs_db = AbbsDb::open_read_only(&global, &repo_config).await?;
+    let verify = || async {
+        let repo = Repository::open(&repo_config)?;
+        let commit = repo.get_branch_oid(&repo.branch)?;
+        let (metas, failed) = scan_tree(&repo, commit, &[], &global.architectures)?;
+        abbs_db.verify(commit, &metas, failed).await
+    };
+    let verification = verify().await?;
+    assert_eq!(verification.missing, ["qux"]);
+    assert_eq!(verification.extra, ["baz"]);
+    assert_eq!(verification.failed, ["broken"]);
+    let version = verification
+        .mismatched
+        .iter()
+        .find(|m| m.field == "version")
+        .expect("the version of bar isn't reported");
+    assert_eq!(version.package, "bar");
+    assert_eq!(version.stored.as_deref(), Some("2.0"));
+    assert_eq!(version.scanned.as_deref(), Some("2.1"));
+    assert!(verification.mismatched.iter().all(|m| m.package == "bar"));
+    assert!(!verification.is_consistent());
+
+    scan(&global, &repo_config).await?;
+    let verification = verify().await?;
+    assert!(verification.is_consistent(), "{verification:?}");
+
+    Ok(())
+}